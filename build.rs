@@ -1,4 +1,56 @@
+use std::path::Path;
+
 fn main() {
     embuild::espidf::sysenv::relay();
     embuild::espidf::sysenv::output(); // Only necessary for building the examples
+
+    ws_client_version();
+}
+
+/// Emit `esp_idf_comp_espressif__esp_websocket_client_partial_send` when the managed
+/// `espressif/esp_websocket_client` component is at least version 1.2, which introduced
+/// the sending of fragmented messages
+///
+/// The component does not expose its version to Kconfig, so it is read from the
+/// manifest of the component, which is found via the C include paths of ESP-IDF.
+fn ws_client_version() {
+    let Some(cincl_args) = embuild::espidf::sysenv::cincl_args() else {
+        return;
+    };
+
+    let manifest = cincl_args
+        .args
+        .split_ascii_whitespace()
+        .map(|arg| arg.trim_start_matches("-isystem").trim_start_matches("-I"))
+        .map(|arg| arg.trim_matches('"'))
+        .map(Path::new)
+        .find(|path| {
+            path.parent()
+                .and_then(Path::file_name)
+                .is_some_and(|name| name == "espressif__esp_websocket_client")
+        })
+        .and_then(Path::parent)
+        .map(|component| component.join("idf_component.yml"));
+
+    let Some(manifest) = manifest.and_then(|manifest| std::fs::read_to_string(manifest).ok())
+    else {
+        return;
+    };
+
+    let version = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version:"))
+        .map(|version| version.trim().trim_matches(|c| c == '"' || c == '\''))
+        .and_then(|version| {
+            let mut parts = version.split(['.', '~', '-']);
+
+            let major = parts.next()?.parse::<u32>().ok()?;
+            let minor = parts.next()?.parse::<u32>().ok()?;
+
+            Some((major, minor))
+        });
+
+    if version.is_some_and(|version| version >= (1, 2)) {
+        println!("cargo:rustc-cfg=esp_idf_comp_espressif__esp_websocket_client_partial_send");
+    }
 }
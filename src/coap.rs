@@ -0,0 +1,1629 @@
+//! CoAP (Constrained Application Protocol) client and server
+//!
+//! `EspCoap` is a CoAP endpoint (RFC 7252) on top of any datagram transport implementing
+//! `CoapTransport`, like a `std::net::UdpSocket` - or a `tls::EspDtls` session (ESP-IDF < 6)
+//! for CoAP over DTLS with a pre-shared key, with a single server or client. An endpoint is both:
+//! - a client, sending confirmable or non-confirmable requests, with block-wise transfers
+//!   of the large request and response bodies (RFC 7959), and observing resources (RFC 7641)
+//! - a server, routing the requests to the handlers registered with `fn_handler` by path
+//!   and method, and notifying the observers of its resources with `notify`
+//!
+//! The endpoint is synchronous: the server handles the incoming requests in `poll` -
+//! and while waiting for the responses to its own requests as a client.
+//!
+//! Typical usage:
+//! ```ignore
+//! let socket = std::net::UdpSocket::bind("[::]:5683")?;
+//!
+//! let mut coap = EspCoap::new(socket, &Default::default());
+//!
+//! coap.fn_handler("sensors/temperature", CoapMethod::Get, |_request| {
+//!     Ok::<_, EspError>(CoapResponse::content(content_format::TEXT_PLAIN, "21.5"))
+//! })?;
+//!
+//! let response = coap.get(&"[fd00::1]:5683".parse()?, "config")?;
+//!
+//! loop {
+//!     coap.poll(Duration::from_secs(1))?;
+//! }
+//! ```
+
+use core::fmt::{self, Debug, Display};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ::log::{debug, warn};
+
+use crate::sys::*;
+
+const VERSION: u8 = 1;
+
+const MAX_MESSAGE_SIZE: usize = 1280;
+
+/// The number of responses to confirmable requests kept to answer the duplicates
+const MAX_CACHED_RESPONSES: usize = 8;
+
+/// The number of concurrent block-wise uploads to the server
+const MAX_UPLOADS: usize = 4;
+
+/// The CoAP option numbers
+pub mod option {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const PROXY_URI: u16 = 35;
+    pub const PROXY_SCHEME: u16 = 39;
+    pub const SIZE1: u16 = 60;
+}
+
+/// The common CoAP content formats
+pub mod content_format {
+    pub const TEXT_PLAIN: u16 = 0;
+    pub const LINK_FORMAT: u16 = 40;
+    pub const XML: u16 = 41;
+    pub const OCTET_STREAM: u16 = 42;
+    pub const EXI: u16 = 47;
+    pub const JSON: u16 = 50;
+    pub const CBOR: u16 = 60;
+}
+
+/// A datagram transport of a CoAP endpoint
+pub trait CoapTransport {
+    /// The address of a peer
+    type Address: Clone + PartialEq + Debug;
+
+    /// Send a datagram to the peer
+    fn send_to(&mut self, datagram: &[u8], peer: &Self::Address) -> Result<(), EspError>;
+
+    /// Wait up to `timeout` for a datagram, and return its length and its sender,
+    /// or `None` on timeout
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, Self::Address)>, EspError>;
+}
+
+impl<T> CoapTransport for &mut T
+where
+    T: CoapTransport,
+{
+    type Address = T::Address;
+
+    fn send_to(&mut self, datagram: &[u8], peer: &Self::Address) -> Result<(), EspError> {
+        (**self).send_to(datagram, peer)
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, Self::Address)>, EspError> {
+        (**self).recv_from(buf, timeout)
+    }
+}
+
+#[cfg(feature = "std")]
+impl CoapTransport for std::net::UdpSocket {
+    type Address = std::net::SocketAddr;
+
+    fn send_to(&mut self, datagram: &[u8], peer: &Self::Address) -> Result<(), EspError> {
+        std::net::UdpSocket::send_to(self, datagram, peer)
+            .map(|_| ())
+            .map_err(map_io_err)
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, Self::Address)>, EspError> {
+        self.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .map_err(map_io_err)?;
+
+        match std::net::UdpSocket::recv_from(self, buf) {
+            Ok(received) => Ok(Some(received)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(map_io_err(e)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn map_io_err(e: std::io::Error) -> EspError {
+    warn!("CoAP socket error: {e}");
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
+
+/// A DTLS session with a single peer - the server when `EspDtls::connect`ed, or the
+/// client when `EspDtls::accept`ed: the peer address is `()`
+#[cfg(all(
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_mbedtls_ssl_proto_dtls,
+    esp_idf_mbedtls_key_exchange_psk,
+    not(esp_idf_version_at_least_6_0_0)
+))]
+impl<S> CoapTransport for crate::tls::EspDtls<S>
+where
+    S: crate::tls::DatagramSocket,
+{
+    type Address = ();
+
+    fn send_to(&mut self, datagram: &[u8], _peer: &Self::Address) -> Result<(), EspError> {
+        self.send(datagram)
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, Self::Address)>, EspError> {
+        Ok(self.recv(buf, Some(timeout))?.map(|len| (len, ())))
+    }
+}
+
+/// The type of a CoAP message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoapType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl CoapType {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => Self::Confirmable,
+            1 => Self::NonConfirmable,
+            2 => Self::Acknowledgement,
+            _ => Self::Reset,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CoapMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl CoapMethod {
+    pub const fn code(&self) -> CoapCode {
+        match self {
+            Self::Get => CoapCode::new(0, 1),
+            Self::Post => CoapCode::new(0, 2),
+            Self::Put => CoapCode::new(0, 3),
+            Self::Delete => CoapCode::new(0, 4),
+        }
+    }
+
+    pub const fn from_code(code: CoapCode) -> Option<Self> {
+        match code.0 {
+            1 => Some(Self::Get),
+            2 => Some(Self::Post),
+            3 => Some(Self::Put),
+            4 => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// The code of a CoAP message: a request method, a response code, or empty
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CoapCode(pub u8);
+
+impl CoapCode {
+    pub const EMPTY: Self = Self(0);
+
+    pub const CREATED: Self = Self::new(2, 1);
+    pub const DELETED: Self = Self::new(2, 2);
+    pub const VALID: Self = Self::new(2, 3);
+    pub const CHANGED: Self = Self::new(2, 4);
+    pub const CONTENT: Self = Self::new(2, 5);
+    pub const CONTINUE: Self = Self::new(2, 31);
+
+    pub const BAD_REQUEST: Self = Self::new(4, 0);
+    pub const UNAUTHORIZED: Self = Self::new(4, 1);
+    pub const BAD_OPTION: Self = Self::new(4, 2);
+    pub const FORBIDDEN: Self = Self::new(4, 3);
+    pub const NOT_FOUND: Self = Self::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: Self = Self::new(4, 5);
+    pub const NOT_ACCEPTABLE: Self = Self::new(4, 6);
+    pub const REQUEST_ENTITY_INCOMPLETE: Self = Self::new(4, 8);
+    pub const PRECONDITION_FAILED: Self = Self::new(4, 12);
+    pub const REQUEST_ENTITY_TOO_LARGE: Self = Self::new(4, 13);
+    pub const UNSUPPORTED_CONTENT_FORMAT: Self = Self::new(4, 15);
+
+    pub const INTERNAL_SERVER_ERROR: Self = Self::new(5, 0);
+    pub const NOT_IMPLEMENTED: Self = Self::new(5, 1);
+    pub const SERVICE_UNAVAILABLE: Self = Self::new(5, 3);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self((class << 5) | (detail & 0x1f))
+    }
+
+    pub const fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub const fn detail(&self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    pub const fn is_request(&self) -> bool {
+        self.class() == 0 && self.0 != 0
+    }
+
+    pub const fn is_response(&self) -> bool {
+        self.class() >= 2
+    }
+
+    pub const fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+impl Display for CoapCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.class(), self.detail())
+    }
+}
+
+impl Debug for CoapCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// A CoAP message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoapMessage {
+    pub kind: CoapType,
+    pub code: CoapCode,
+    pub message_id: u16,
+    /// The token, of at most 8 bytes
+    pub token: Vec<u8>,
+    /// The options, sorted by number
+    options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl CoapMessage {
+    pub fn new(kind: CoapType, code: CoapCode) -> Self {
+        Self {
+            kind,
+            code,
+            message_id: 0,
+            token: Vec::new(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Return the value of the first option with that number
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options(number).next()
+    }
+
+    /// Return the values of all options with that number
+    pub fn options(&self, number: u16) -> impl Iterator<Item = &[u8]> {
+        self.options
+            .iter()
+            .filter(move |(n, _)| *n == number)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Return the value of the first option with that number, as an unsigned integer
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).map(|value| {
+            value
+                .iter()
+                .take(4)
+                .fold(0, |uint, byte| (uint << 8) | *byte as u32)
+        })
+    }
+
+    /// Add an option, after the existing options with the same number
+    pub fn add_option(&mut self, number: u16, value: &[u8]) {
+        let index = self
+            .options
+            .iter()
+            .position(|(n, _)| *n > number)
+            .unwrap_or(self.options.len());
+
+        self.options.insert(index, (number, value.to_vec()));
+    }
+
+    /// Replace the options with that number by a single unsigned integer option
+    pub fn set_uint_option(&mut self, number: u16, value: u32) {
+        self.remove_option(number);
+
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+
+        self.add_option(number, &bytes[skip..]);
+    }
+
+    pub fn remove_option(&mut self, number: u16) {
+        self.options.retain(|(n, _)| *n != number);
+    }
+
+    /// Return the path of the request, from its `Uri-Path` options
+    pub fn path(&self) -> String {
+        let mut path = String::new();
+
+        for segment in self.options(option::URI_PATH) {
+            if !path.is_empty() {
+                path.push('/');
+            }
+
+            path.push_str(&String::from_utf8_lossy(segment));
+        }
+
+        path
+    }
+
+    /// Set the `Uri-Path` and the `Uri-Query` options from a path like `a/b?c=1&d`
+    pub fn set_path(&mut self, path: &str) {
+        self.remove_option(option::URI_PATH);
+        self.remove_option(option::URI_QUERY);
+
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            self.add_option(option::URI_PATH, segment.as_bytes());
+        }
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            self.add_option(option::URI_QUERY, param.as_bytes());
+        }
+    }
+
+    fn block(&self, number: u16) -> Option<Block> {
+        self.uint_option(number).and_then(Block::decode)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let token = &self.token[..self.token.len().min(8)];
+
+        let mut buf = Vec::with_capacity(4 + token.len() + self.payload.len() + 16);
+
+        buf.push((VERSION << 6) | ((self.kind as u8) << 4) | token.len() as u8);
+        buf.push(self.code.0);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(token);
+
+        let mut last = 0;
+
+        for (number, value) in &self.options {
+            let (delta, delta_ext) = Self::nibble((number - last) as usize);
+            let (len, len_ext) = Self::nibble(value.len());
+
+            buf.push((delta << 4) | len);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&len_ext);
+            buf.extend_from_slice(value);
+
+            last = *number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(0xff);
+            buf.extend_from_slice(&self.payload);
+        }
+
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let header = data.get(..4)?;
+
+        let token_len = (header[0] & 0x0f) as usize;
+        if header[0] >> 6 != VERSION || token_len > 8 {
+            return None;
+        }
+
+        let mut message = Self {
+            kind: CoapType::from_bits(header[0] >> 4),
+            code: CoapCode(header[1]),
+            message_id: u16::from_be_bytes([header[2], header[3]]),
+            token: data.get(4..4 + token_len)?.to_vec(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        };
+
+        let mut data = &data[4 + token_len..];
+        let mut number: u16 = 0;
+
+        while let Some((&first, rest)) = data.split_first() {
+            if first == 0xff {
+                // A payload marker followed by an empty payload is a format error
+                if rest.is_empty() {
+                    return None;
+                }
+
+                message.payload = rest.to_vec();
+                break;
+            }
+
+            let (delta, rest) = Self::extended(first >> 4, rest)?;
+            let (len, rest) = Self::extended(first & 0x0f, rest)?;
+
+            number = number.checked_add(delta.try_into().ok()?)?;
+
+            message.options.push((number, rest.get(..len)?.to_vec()));
+
+            data = &rest[len..];
+        }
+
+        Some(message)
+    }
+
+    fn nibble(value: usize) -> (u8, Vec<u8>) {
+        if value < 13 {
+            (value as u8, Vec::new())
+        } else if value < 269 {
+            (13, vec![(value - 13) as u8])
+        } else {
+            (14, ((value - 269) as u16).to_be_bytes().to_vec())
+        }
+    }
+
+    fn extended(nibble: u8, data: &[u8]) -> Option<(usize, &[u8])> {
+        match nibble {
+            0..=12 => Some((nibble as usize, data)),
+            13 => Some((*data.first()? as usize + 13, &data[1..])),
+            14 => Some((
+                u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize + 269,
+                &data[2..],
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The value of a `Block1` or `Block2` option
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Block {
+    num: u32,
+    more: bool,
+    szx: u8,
+}
+
+impl Block {
+    fn decode(value: u32) -> Option<Self> {
+        let szx = (value & 0x07) as u8;
+
+        // SZX 7 is reserved (BERT)
+        (szx < 7).then_some(Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+
+    fn encode(&self) -> u32 {
+        (self.num << 4) | if self.more { 0x08 } else { 0 } | self.szx as u32
+    }
+
+    fn size(&self) -> usize {
+        block_size(self.szx)
+    }
+}
+
+fn block_size(szx: u8) -> usize {
+    16 << szx
+}
+
+/// A request received by a handler of the server
+pub struct CoapRequest<'r> {
+    message: &'r CoapMessage,
+    payload: &'r [u8],
+}
+
+impl CoapRequest<'_> {
+    pub fn method(&self) -> CoapMethod {
+        CoapMethod::from_code(self.message.code).unwrap()
+    }
+
+    pub fn path(&self) -> String {
+        self.message.path()
+    }
+
+    /// Return the `Uri-Query` parameters
+    pub fn query(&self) -> impl Iterator<Item = &str> {
+        self.message
+            .options(option::URI_QUERY)
+            .filter_map(|param| core::str::from_utf8(param).ok())
+    }
+
+    pub fn content_format(&self) -> Option<u16> {
+        self.message
+            .uint_option(option::CONTENT_FORMAT)
+            .map(|format| format as _)
+    }
+
+    pub fn accept(&self) -> Option<u16> {
+        self.message
+            .uint_option(option::ACCEPT)
+            .map(|format| format as _)
+    }
+
+    /// Return the payload - reassembled, for block-wise transfers
+    pub fn payload(&self) -> &[u8] {
+        self.payload
+    }
+
+    /// Return the message of the request, e.g. to access its other options
+    pub fn message(&self) -> &CoapMessage {
+        self.message
+    }
+}
+
+/// A response of a server, as returned by the handlers and by the client requests
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoapResponse {
+    pub code: CoapCode,
+    pub content_format: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+impl CoapResponse {
+    pub fn new(code: CoapCode) -> Self {
+        Self {
+            code,
+            content_format: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// A `2.05 Content` response
+    pub fn content(content_format: u16, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            code: CoapCode::CONTENT,
+            content_format: Some(content_format),
+            payload: payload.into(),
+        }
+    }
+
+    fn from_message(message: CoapMessage) -> Self {
+        Self {
+            code: message.code,
+            content_format: message
+                .uint_option(option::CONTENT_FORMAT)
+                .map(|format| format as _),
+            payload: message.payload,
+        }
+    }
+}
+
+/// An observation of a resource by the client, as returned by `EspCoap::observe`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoapObservation(Vec<u8>);
+
+#[derive(Clone, Debug)]
+pub struct CoapConfiguration {
+    /// The block size of the block-wise transfers: a power of two, from 16 to 1024
+    pub block_size: usize,
+    /// The initial time to wait for the acknowledgement of a confirmable message
+    pub ack_timeout: Duration,
+    pub max_retransmit: u8,
+    /// The time to wait for a separate response, or for the response to a non-confirmable request
+    pub response_timeout: Duration,
+    /// The maximum size of the bodies reassembled from block-wise transfers
+    pub max_body_size: usize,
+}
+
+impl Default for CoapConfiguration {
+    fn default() -> Self {
+        Self {
+            block_size: 512,
+            ack_timeout: Duration::from_secs(2),
+            max_retransmit: 4,
+            response_timeout: Duration::from_secs(30),
+            max_body_size: 16 * 1024,
+        }
+    }
+}
+
+type Handler<'a> = Box<dyn FnMut(&CoapRequest<'_>) -> CoapResponse + Send + 'a>;
+
+struct Resource<'a> {
+    path: String,
+    method: CoapMethod,
+    handler: Handler<'a>,
+}
+
+struct Observer<A> {
+    peer: A,
+    token: Vec<u8>,
+    path: String,
+    /// The message ID of the last notification, which the observer may reset
+    message_id: u16,
+}
+
+struct Observation<A> {
+    peer: A,
+    token: Vec<u8>,
+    path: String,
+}
+
+struct Upload<A> {
+    peer: A,
+    path: String,
+    data: Vec<u8>,
+}
+
+struct CachedResponse<A> {
+    peer: A,
+    message_id: u16,
+    datagram: Vec<u8>,
+}
+
+/// A CoAP endpoint, client and server
+pub struct EspCoap<'a, T>
+where
+    T: CoapTransport,
+{
+    transport: T,
+    szx: u8,
+    ack_timeout: Duration,
+    max_retransmit: u8,
+    response_timeout: Duration,
+    max_body_size: usize,
+    next_message_id: u16,
+    resources: Vec<Resource<'a>>,
+    observers: Vec<Observer<T::Address>>,
+    observe_seq: u32,
+    uploads: Vec<Upload<T::Address>>,
+    responses: VecDeque<CachedResponse<T::Address>>,
+    observations: Vec<Observation<T::Address>>,
+    notifications: VecDeque<(CoapObservation, CoapResponse)>,
+}
+
+impl<'a, T> EspCoap<'a, T>
+where
+    T: CoapTransport,
+{
+    pub fn new(transport: T, conf: &CoapConfiguration) -> Self {
+        let block_size = conf.block_size.clamp(16, 1024);
+
+        Self {
+            transport,
+            szx: (block_size.ilog2() - 4) as _,
+            ack_timeout: conf.ack_timeout,
+            max_retransmit: conf.max_retransmit,
+            response_timeout: conf.response_timeout,
+            max_body_size: conf.max_body_size,
+            next_message_id: unsafe { esp_random() } as _,
+            resources: Vec::new(),
+            observers: Vec::new(),
+            observe_seq: 0,
+            uploads: Vec::new(),
+            responses: VecDeque::new(),
+            observations: Vec::new(),
+            notifications: VecDeque::new(),
+        }
+    }
+
+    /// Register a handler for the requests with that path and method
+    ///
+    /// A handler error is answered with `5.00 Internal Server Error`.
+    pub fn fn_handler<E, F>(
+        &mut self,
+        path: &str,
+        method: CoapMethod,
+        mut f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: FnMut(&CoapRequest<'_>) -> Result<CoapResponse, E> + Send + 'a,
+        E: Debug,
+    {
+        let path = normalize(path);
+
+        if self
+            .resources
+            .iter()
+            .any(|resource| resource.path == path && resource.method == method)
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        self.resources.push(Resource {
+            path,
+            method,
+            handler: Box::new(move |request| {
+                f(request).unwrap_or_else(|e| {
+                    warn!("CoAP handler failed: {e:?}");
+
+                    CoapResponse::new(CoapCode::INTERNAL_SERVER_ERROR)
+                })
+            }),
+        });
+
+        Ok(self)
+    }
+
+    /// Send a `response` notification to the observers of the resource with that path,
+    /// and return their number
+    ///
+    /// The notifications are non-confirmable. A failure response ends the observations.
+    pub fn notify(&mut self, path: &str, response: &CoapResponse) -> Result<usize, EspError> {
+        let path = normalize(path);
+
+        self.observe_seq = (self.observe_seq + 1) & 0xff_ffff;
+
+        let mut notified = 0;
+
+        for index in 0..self.observers.len() {
+            if self.observers[index].path != path {
+                continue;
+            }
+
+            let message_id = self.next_message_id();
+
+            let mut message = CoapMessage::new(CoapType::NonConfirmable, response.code);
+            message.message_id = message_id;
+            message.token = self.observers[index].token.clone();
+
+            if response.code.is_success() {
+                message.set_uint_option(option::OBSERVE, self.observe_seq);
+            }
+
+            self.set_body(&mut message, response, None);
+
+            self.observers[index].message_id = message_id;
+
+            self.transport
+                .send_to(&message.encode(), &self.observers[index].peer)?;
+
+            notified += 1;
+        }
+
+        if !response.code.is_success() {
+            self.observers.retain(|observer| observer.path != path);
+        }
+
+        Ok(notified)
+    }
+
+    /// Send a confirmable `GET` request
+    pub fn get(&mut self, peer: &T::Address, path: &str) -> Result<CoapResponse, EspError> {
+        self.request(peer, CoapMethod::Get, path, None, &[], true)
+    }
+
+    /// Send a confirmable `POST` request
+    pub fn post(
+        &mut self,
+        peer: &T::Address,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> Result<CoapResponse, EspError> {
+        self.request(peer, CoapMethod::Post, path, content_format, payload, true)
+    }
+
+    /// Send a confirmable `PUT` request
+    pub fn put(
+        &mut self,
+        peer: &T::Address,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> Result<CoapResponse, EspError> {
+        self.request(peer, CoapMethod::Put, path, content_format, payload, true)
+    }
+
+    /// Send a confirmable `DELETE` request
+    pub fn delete(&mut self, peer: &T::Address, path: &str) -> Result<CoapResponse, EspError> {
+        self.request(peer, CoapMethod::Delete, path, None, &[], true)
+    }
+
+    /// Send a request to the path (with an optional query, like `a/b?c=1`) and wait for
+    /// its response
+    ///
+    /// The request and response bodies larger than the block size are transferred block-wise.
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_TIMEOUT` if the request is not acknowledged or answered in time
+    /// * `ESP_ERR_INVALID_RESPONSE` if the server resets the request
+    /// * `ESP_ERR_INVALID_SIZE` if the response body exceeds `max_body_size`
+    pub fn request(
+        &mut self,
+        peer: &T::Address,
+        method: CoapMethod,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+        confirmable: bool,
+    ) -> Result<CoapResponse, EspError> {
+        let mut request = CoapMessage::new(
+            if confirmable {
+                CoapType::Confirmable
+            } else {
+                CoapType::NonConfirmable
+            },
+            method.code(),
+        );
+
+        request.set_path(path);
+
+        if let Some(content_format) = content_format {
+            request.set_uint_option(option::CONTENT_FORMAT, content_format as _);
+        }
+
+        self.transfer(peer, &request, payload)
+    }
+
+    /// Observe the resource with that path
+    ///
+    /// Returns the current representation of the resource, and the observation - unless
+    /// the server does not accept the observation. The notifications are then received
+    /// with `next_notification`.
+    pub fn observe(
+        &mut self,
+        peer: &T::Address,
+        path: &str,
+    ) -> Result<(CoapResponse, Option<CoapObservation>), EspError> {
+        let mut request = CoapMessage::new(CoapType::Confirmable, CoapMethod::Get.code());
+        request.set_path(path);
+        request.set_uint_option(option::OBSERVE, 0);
+
+        let response = self.exchange(peer, request.clone())?;
+
+        let observation =
+            (response.code.is_success() && response.option(option::OBSERVE).is_some()).then(|| {
+                self.observations.push(Observation {
+                    peer: peer.clone(),
+                    token: response.token.clone(),
+                    path: normalize(path),
+                });
+
+                CoapObservation(response.token.clone())
+            });
+
+        // The remaining blocks of the representation are fetched without observing
+        request.remove_option(option::OBSERVE);
+
+        Ok((self.fetch_blocks(peer, &request, response)?, observation))
+    }
+
+    /// Stop an observation, and return the current representation of the resource
+    pub fn stop_observing(
+        &mut self,
+        observation: &CoapObservation,
+    ) -> Result<CoapResponse, EspError> {
+        let index = self
+            .observations
+            .iter()
+            .position(|o| o.token == observation.0)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let observation = self.observations.remove(index);
+
+        self.notifications
+            .retain(|(queued, _)| queued.0 != observation.token);
+
+        let mut request = CoapMessage::new(CoapType::Confirmable, CoapMethod::Get.code());
+        request.token = observation.token;
+        request.set_path(&observation.path);
+        request.set_uint_option(option::OBSERVE, 1);
+
+        self.exchange(&observation.peer, request)
+            .map(CoapResponse::from_message)
+    }
+
+    /// Wait up to `timeout` for a notification of an observation, handling the incoming
+    /// requests in the meantime
+    ///
+    /// Returns `None` on timeout.
+    pub fn next_notification(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<(CoapObservation, CoapResponse)>, EspError> {
+        let deadline = now() + timeout;
+
+        loop {
+            if let Some(notification) = self.notifications.pop_front() {
+                return Ok(Some(notification));
+            }
+
+            let wait = deadline.saturating_sub(now());
+            if wait.is_zero() {
+                return Ok(None);
+            }
+
+            self.poll(wait)?;
+        }
+    }
+
+    /// Wait up to `timeout` for a message, and handle it
+    ///
+    /// Returns `false` on timeout.
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool, EspError> {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+
+        let Some((len, peer)) = self.transport.recv_from(&mut buf, timeout)? else {
+            return Ok(false);
+        };
+
+        match CoapMessage::decode(&buf[..len]) {
+            Some(message) => self.handle(&peer, message)?,
+            None => debug!("Malformed CoAP message from {peer:?} ignored"),
+        }
+
+        Ok(true)
+    }
+
+    /// Release the transport
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    /// Send a request, block-wise if needed, and fetch the remaining blocks of its response
+    fn transfer(
+        &mut self,
+        peer: &T::Address,
+        request: &CoapMessage,
+        payload: &[u8],
+    ) -> Result<CoapResponse, EspError> {
+        let mut szx = self.szx;
+
+        let response = if payload.len() > block_size(szx) {
+            let mut offset = 0;
+
+            loop {
+                let size = block_size(szx);
+                let end = (offset + size).min(payload.len());
+
+                let block = Block {
+                    num: (offset / size) as _,
+                    more: end < payload.len(),
+                    szx,
+                };
+
+                let mut message = request.clone();
+                message.set_uint_option(option::BLOCK1, block.encode());
+                if offset == 0 {
+                    message.set_uint_option(option::SIZE1, payload.len() as _);
+                }
+                message.payload = payload[offset..end].to_vec();
+
+                let response = self.exchange(peer, message)?;
+
+                if !block.more || response.code != CoapCode::CONTINUE {
+                    break response;
+                }
+
+                // The server may ask for smaller blocks
+                if let Some(acked) = response.block(option::BLOCK1) {
+                    szx = szx.min(acked.szx);
+                }
+
+                offset = end;
+            }
+        } else {
+            let mut message = request.clone();
+            message.payload = payload.to_vec();
+
+            self.exchange(peer, message)?
+        };
+
+        self.fetch_blocks(peer, request, response)
+    }
+
+    /// Fetch the remaining blocks of a response, by repeating the request with the
+    /// `Block2` option
+    fn fetch_blocks(
+        &mut self,
+        peer: &T::Address,
+        request: &CoapMessage,
+        mut response: CoapMessage,
+    ) -> Result<CoapResponse, EspError> {
+        let mut body = core::mem::take(&mut response.payload);
+
+        while let Some(block) = response.block(option::BLOCK2).filter(|block| block.more) {
+            if body.len() > self.max_body_size {
+                warn!("CoAP response body exceeds {} bytes", self.max_body_size);
+
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            let mut message = request.clone();
+            message.set_uint_option(
+                option::BLOCK2,
+                Block {
+                    num: block.num + 1,
+                    more: false,
+                    szx: block.szx,
+                }
+                .encode(),
+            );
+
+            let mut next = self.exchange(peer, message)?;
+
+            if !next.code.is_success() {
+                return Ok(CoapResponse::from_message(next));
+            }
+
+            body.append(&mut next.payload);
+            response = next;
+        }
+
+        response.payload = body;
+
+        Ok(CoapResponse::from_message(response))
+    }
+
+    /// Send a single request message, retransmitting it if confirmable, and wait for its
+    /// response, handling the other incoming messages in the meantime
+    fn exchange(
+        &mut self,
+        peer: &T::Address,
+        mut request: CoapMessage,
+    ) -> Result<CoapMessage, EspError> {
+        request.message_id = self.next_message_id();
+
+        if request.token.is_empty() {
+            request.token = unsafe { esp_random() }.to_be_bytes().to_vec();
+        }
+
+        let datagram = request.encode();
+        let confirmable = request.kind == CoapType::Confirmable;
+
+        // Randomized between `ack_timeout` and 1.5 times `ack_timeout`
+        let mut timeout = self.ack_timeout
+            + self
+                .ack_timeout
+                .mul_f32((unsafe { esp_random() } % 1000) as f32 / 2000.0);
+
+        let mut acknowledged = !confirmable;
+        let mut retransmissions = 0;
+
+        self.transport.send_to(&datagram, peer)?;
+
+        let mut deadline = now()
+            + if acknowledged {
+                self.response_timeout
+            } else {
+                timeout
+            };
+
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+
+        loop {
+            let wait = deadline.saturating_sub(now());
+
+            if wait.is_zero() {
+                if acknowledged || retransmissions >= self.max_retransmit {
+                    warn!("No response to the CoAP request from {peer:?}");
+
+                    return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                }
+
+                retransmissions += 1;
+                timeout *= 2;
+
+                debug!("Retransmitting the CoAP request (attempt {retransmissions})");
+
+                self.transport.send_to(&datagram, peer)?;
+
+                deadline = now() + timeout;
+
+                continue;
+            }
+
+            let Some((len, from)) = self.transport.recv_from(&mut buf, wait)? else {
+                continue;
+            };
+
+            let Some(message) = CoapMessage::decode(&buf[..len]) else {
+                debug!("Malformed CoAP message from {from:?} ignored");
+                continue;
+            };
+
+            if from == *peer {
+                match message.kind {
+                    CoapType::Reset if message.message_id == request.message_id => {
+                        warn!("CoAP request reset by {peer:?}");
+
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                    }
+                    CoapType::Acknowledgement if message.message_id == request.message_id => {
+                        if message.code == CoapCode::EMPTY {
+                            // A separate response follows
+                            acknowledged = true;
+                            deadline = now() + self.response_timeout;
+
+                            continue;
+                        }
+
+                        if message.token == request.token {
+                            return Ok(message);
+                        }
+                    }
+                    CoapType::Confirmable | CoapType::NonConfirmable
+                        if message.code.is_response() && message.token == request.token =>
+                    {
+                        if message.kind == CoapType::Confirmable {
+                            self.send_empty(peer, CoapType::Acknowledgement, message.message_id)?;
+                        }
+
+                        return Ok(message);
+                    }
+                    _ => (),
+                }
+            }
+
+            self.handle(&from, message)?;
+        }
+    }
+
+    /// Handle a message which is not the response to a pending request
+    fn handle(&mut self, peer: &T::Address, message: CoapMessage) -> Result<(), EspError> {
+        if message.code.is_request() {
+            if matches!(
+                message.kind,
+                CoapType::Confirmable | CoapType::NonConfirmable
+            ) {
+                self.serve(peer, message)?;
+            }
+        } else if message.code.is_response() {
+            if let Some(index) = self
+                .observations
+                .iter()
+                .position(|o| o.peer == *peer && o.token == message.token)
+            {
+                if message.kind == CoapType::Confirmable {
+                    self.send_empty(peer, CoapType::Acknowledgement, message.message_id)?;
+                }
+
+                // A notification without the `Observe` option ends the observation
+                if !message.code.is_success() || message.option(option::OBSERVE).is_none() {
+                    self.observations.remove(index);
+                }
+
+                if message
+                    .block(option::BLOCK2)
+                    .is_some_and(|block| block.more)
+                {
+                    warn!("CoAP notification truncated to its first block");
+                }
+
+                self.notifications.push_back((
+                    CoapObservation(message.token.clone()),
+                    CoapResponse::from_message(message),
+                ));
+            } else if matches!(
+                message.kind,
+                CoapType::Confirmable | CoapType::NonConfirmable
+            ) {
+                // E.g. a notification of a forgotten observation
+                self.send_empty(peer, CoapType::Reset, message.message_id)?;
+            }
+        } else if message.code == CoapCode::EMPTY {
+            match message.kind {
+                // A CoAP ping
+                CoapType::Confirmable => {
+                    self.send_empty(peer, CoapType::Reset, message.message_id)?
+                }
+                // The rejection of a notification
+                CoapType::Reset => self.observers.retain(|observer| {
+                    observer.peer != *peer || observer.message_id != message.message_id
+                }),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a request, or replay the response to a duplicate confirmable request
+    fn serve(&mut self, peer: &T::Address, request: CoapMessage) -> Result<(), EspError> {
+        let confirmable = request.kind == CoapType::Confirmable;
+
+        if confirmable {
+            if let Some(cached) = self
+                .responses
+                .iter()
+                .find(|cached| cached.peer == *peer && cached.message_id == request.message_id)
+            {
+                debug!("Duplicate CoAP request from {peer:?}, replaying the response");
+
+                return self.transport.send_to(&cached.datagram, peer);
+            }
+        }
+
+        let datagram = self.respond(peer, &request).encode();
+
+        self.transport.send_to(&datagram, peer)?;
+
+        if confirmable {
+            if self.responses.len() >= MAX_CACHED_RESPONSES {
+                self.responses.pop_front();
+            }
+
+            self.responses.push_back(CachedResponse {
+                peer: peer.clone(),
+                message_id: request.message_id,
+                datagram,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn respond(&mut self, peer: &T::Address, request: &CoapMessage) -> CoapMessage {
+        let mut response = CoapMessage::new(CoapType::Acknowledgement, CoapCode::EMPTY);
+        response.token = request.token.clone();
+
+        if request.kind == CoapType::Confirmable {
+            response.message_id = request.message_id;
+        } else {
+            response.kind = CoapType::NonConfirmable;
+            response.message_id = self.next_message_id();
+        }
+
+        let path = request.path();
+
+        let Some(method) = CoapMethod::from_code(request.code) else {
+            response.code = CoapCode::METHOD_NOT_ALLOWED;
+            return response;
+        };
+
+        let mut upload = None;
+
+        if let Some(block) = request.block(option::BLOCK1) {
+            match self.upload(peer, &path, block, &request.payload) {
+                Ok(Some(data)) => upload = Some(data),
+                Ok(None) => {
+                    response.code = CoapCode::CONTINUE;
+                    response.set_uint_option(option::BLOCK1, block.encode());
+                    return response;
+                }
+                Err(code) => {
+                    response.code = code;
+                    return response;
+                }
+            }
+
+            response.set_uint_option(option::BLOCK1, block.encode());
+        }
+
+        let Some(resource) = self
+            .resources
+            .iter_mut()
+            .find(|resource| resource.path == path && resource.method == method)
+        else {
+            response.code = if self.resources.iter().any(|resource| resource.path == path) {
+                CoapCode::METHOD_NOT_ALLOWED
+            } else {
+                CoapCode::NOT_FOUND
+            };
+
+            return response;
+        };
+
+        let result = (resource.handler)(&CoapRequest {
+            message: request,
+            payload: upload.as_deref().unwrap_or(&request.payload),
+        });
+
+        response.code = result.code;
+
+        if method == CoapMethod::Get {
+            match request.uint_option(option::OBSERVE) {
+                Some(0) if result.code.is_success() => {
+                    self.observers
+                        .retain(|observer| observer.peer != *peer || observer.path != path);
+
+                    self.observers.push(Observer {
+                        peer: peer.clone(),
+                        token: request.token.clone(),
+                        path,
+                        message_id: 0,
+                    });
+
+                    response.set_uint_option(option::OBSERVE, self.observe_seq);
+                }
+                Some(1) => self
+                    .observers
+                    .retain(|observer| observer.peer != *peer || observer.token != request.token),
+                _ => (),
+            }
+        }
+
+        self.set_body(&mut response, &result, request.block(option::BLOCK2));
+
+        response
+    }
+
+    /// Reassemble a block-wise upload, and return its body once complete
+    fn upload(
+        &mut self,
+        peer: &T::Address,
+        path: &str,
+        block: Block,
+        payload: &[u8],
+    ) -> Result<Option<Vec<u8>>, CoapCode> {
+        let index = self
+            .uploads
+            .iter()
+            .position(|upload| upload.peer == *peer && upload.path == path);
+
+        let index = match index {
+            Some(index) => index,
+            None if block.num == 0 => {
+                if self.uploads.len() >= MAX_UPLOADS {
+                    self.uploads.remove(0);
+                }
+
+                self.uploads.push(Upload {
+                    peer: peer.clone(),
+                    path: path.into(),
+                    data: Vec::new(),
+                });
+
+                self.uploads.len() - 1
+            }
+            None => return Err(CoapCode::REQUEST_ENTITY_INCOMPLETE),
+        };
+
+        let upload = &mut self.uploads[index];
+
+        if block.num == 0 {
+            upload.data.clear();
+        }
+
+        if upload.data.len() != block.num as usize * block.size() {
+            self.uploads.remove(index);
+            return Err(CoapCode::REQUEST_ENTITY_INCOMPLETE);
+        }
+
+        if upload.data.len() + payload.len() > self.max_body_size {
+            self.uploads.remove(index);
+            return Err(CoapCode::REQUEST_ENTITY_TOO_LARGE);
+        }
+
+        upload.data.extend_from_slice(payload);
+
+        if block.more {
+            Ok(None)
+        } else {
+            Ok(Some(self.uploads.remove(index).data))
+        }
+    }
+
+    /// Set the body of a response message, or the requested block of it
+    fn set_body(&self, message: &mut CoapMessage, response: &CoapResponse, block: Option<Block>) {
+        if let Some(content_format) = response.content_format {
+            message.set_uint_option(option::CONTENT_FORMAT, content_format as _);
+        }
+
+        let szx = block
+            .map(|block| block.szx.min(self.szx))
+            .unwrap_or(self.szx);
+
+        if block.is_none() && response.payload.len() <= block_size(szx) {
+            message.payload = response.payload.clone();
+            return;
+        }
+
+        let num = block.map(|block| block.num).unwrap_or(0);
+        let size = block_size(szx);
+        let start = num as usize * size;
+
+        if start >= response.payload.len() && num > 0 {
+            message.code = CoapCode::BAD_OPTION;
+            return;
+        }
+
+        let end = (start + size).min(response.payload.len());
+
+        message.set_uint_option(
+            option::BLOCK2,
+            Block {
+                num,
+                more: end < response.payload.len(),
+                szx,
+            }
+            .encode(),
+        );
+
+        if num == 0 {
+            message.set_uint_option(option::SIZE2, response.payload.len() as _);
+        }
+
+        message.payload = response.payload[start..end].to_vec();
+    }
+
+    fn send_empty(
+        &mut self,
+        peer: &T::Address,
+        kind: CoapType,
+        message_id: u16,
+    ) -> Result<(), EspError> {
+        let mut message = CoapMessage::new(kind, CoapCode::EMPTY);
+        message.message_id = message_id;
+
+        self.transport.send_to(&message.encode(), peer)
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        self.next_message_id
+    }
+}
+
+fn normalize(path: &str) -> String {
+    let mut normalized = String::new();
+
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if !normalized.is_empty() {
+            normalized.push('/');
+        }
+
+        normalized.push_str(segment);
+    }
+
+    normalized
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transport which replays the queued datagrams, and records the sent ones
+    #[derive(Default)]
+    struct Loopback {
+        incoming: VecDeque<Vec<u8>>,
+        outgoing: Vec<CoapMessage>,
+    }
+
+    impl CoapTransport for Loopback {
+        type Address = u8;
+
+        fn send_to(&mut self, datagram: &[u8], _peer: &u8) -> Result<(), EspError> {
+            self.outgoing.push(CoapMessage::decode(datagram).unwrap());
+            Ok(())
+        }
+
+        fn recv_from(
+            &mut self,
+            buf: &mut [u8],
+            _timeout: Duration,
+        ) -> Result<Option<(usize, u8)>, EspError> {
+            Ok(self.incoming.pop_front().map(|datagram| {
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                (datagram.len(), 1)
+            }))
+        }
+    }
+
+    fn get(message_id: u16, path: &str, block2: Option<u32>) -> Vec<u8> {
+        let mut request = CoapMessage::new(CoapType::Confirmable, CoapMethod::Get.code());
+        request.message_id = message_id;
+        request.token = vec![1, 2];
+        request.set_path(path);
+
+        if let Some(block2) = block2 {
+            request.set_uint_option(option::BLOCK2, block2);
+        }
+
+        request.encode()
+    }
+
+    #[test]
+    fn codec() {
+        let mut message = CoapMessage::new(CoapType::Confirmable, CoapCode::CONTENT);
+        message.message_id = 0x1234;
+        message.token = vec![0xaa, 0xbb];
+        message.set_path("a/bb/ccc?x=1");
+        message.set_uint_option(option::CONTENT_FORMAT, content_format::JSON as _);
+        message.set_uint_option(option::SIZE1, 70000);
+        message.add_option(option::PROXY_URI, &[b'p'; 300]);
+        message.payload = b"{}".to_vec();
+
+        let decoded = CoapMessage::decode(&message.encode()).unwrap();
+
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.path(), "a/bb/ccc");
+        assert_eq!(decoded.uint_option(option::SIZE1), Some(70000));
+        assert_eq!(decoded.code.to_string(), "2.05");
+
+        assert_eq!(CoapMessage::decode(&[0x40, 0x01, 0x00, 0x01, 0xff]), None);
+    }
+
+    #[test]
+    fn server() {
+        let mut coap = EspCoap::new(
+            Loopback::default(),
+            &CoapConfiguration {
+                block_size: 16,
+                ..Default::default()
+            },
+        );
+
+        coap.fn_handler("/data", CoapMethod::Get, |_| {
+            Ok::<_, EspError>(CoapResponse::content(content_format::OCTET_STREAM, [7; 40]))
+        })
+        .unwrap();
+
+        coap.transport.incoming.extend([
+            get(1, "data", None),
+            get(2, "data", Some(0x20)),
+            get(2, "data", Some(0x20)),
+            get(3, "missing", None),
+        ]);
+
+        while coap.poll(Duration::ZERO).unwrap() {}
+
+        let responses = &coap.transport.outgoing;
+
+        assert_eq!(responses.len(), 4);
+
+        assert_eq!(responses[0].kind, CoapType::Acknowledgement);
+        assert_eq!(responses[0].message_id, 1);
+        assert_eq!(responses[0].payload.len(), 16);
+        assert_eq!(
+            responses[0].block(option::BLOCK2),
+            Some(Block {
+                num: 0,
+                more: true,
+                szx: 0
+            })
+        );
+        assert_eq!(responses[0].uint_option(option::SIZE2), Some(40));
+
+        assert_eq!(responses[1].payload.len(), 8);
+        assert_eq!(
+            responses[1].block(option::BLOCK2),
+            Some(Block {
+                num: 2,
+                more: false,
+                szx: 0
+            })
+        );
+
+        // The duplicate is answered with the same response
+        assert_eq!(responses[2], responses[1]);
+
+        assert_eq!(responses[3].code, CoapCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,14 @@
+//! DNS services
+//!
+//! - `captive` - a minimal DNS responder answering all (or a configured set of)
+//!   queries with the address of a netif; useful for SoftAP provisioning portals
+//! - `resolver` - a DNS client with custom or per-netif servers, a timeout,
+//!   an IPv4/IPv6 preference, a TTL cache and an async variant
+
+#[cfg(feature = "std")]
+pub mod captive;
+#[cfg(feature = "std")]
+pub mod resolver;
+
+#[cfg(feature = "std")]
+mod wire;
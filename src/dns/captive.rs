@@ -0,0 +1,384 @@
+//! Captive portal DNS responder
+//!
+//! Phones and laptops detect a captive portal by resolving a well-known
+//! host name and requesting a well-known URL on it (e.g. `/generate_204`
+//! on Android or `/hotspot-detect.html` on iOS/macOS). If the DNS responder
+//! answers these queries with the address of the SoftAP, and the HTTP server
+//! on the SoftAP redirects the connectivity-check URLs to the portal page,
+//! the OS pops up the portal automatically.
+//!
+//! Typical usage:
+//! ```ignore
+//! let dns = EspCaptiveDns::new(wifi.ap_netif(), &Configuration::default())?;
+//!
+//! let mut server = EspHttpServer::new(&Default::default())?;
+//! server.fn_handler("/", Method::Get, |req| { /* The portal page */ })?;
+//! register_connectivity_check_handlers(&mut server, "http://192.168.71.1/")?;
+//! ```
+
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use std::net::UdpSocket;
+use std::thread::JoinHandle;
+
+use ::log::{debug, info, warn};
+
+use crate::netif::EspNetif;
+use crate::private::common::to_esp_error;
+use crate::sys::*;
+
+use super::wire::*;
+
+#[derive(Clone, Debug)]
+pub struct Configuration<'a> {
+    /// The domains to answer; the domains and all of their sub-domains are answered
+    ///
+    /// When empty (the default), all queries are answered with the address of the
+    /// netif, which is what a captive portal typically needs. Queries for other
+    /// domains are refused.
+    pub domains: &'a [&'a str],
+    /// The TTL of the answers
+    pub ttl: Duration,
+    /// The UDP port to listen on
+    pub port: u16,
+    /// The stack size of the responder thread
+    pub stack_size: usize,
+}
+
+impl Configuration<'_> {
+    pub const fn new() -> Self {
+        Self {
+            domains: &[],
+            ttl: Duration::from_secs(60),
+            port: DNS_PORT,
+            stack_size: 4096,
+        }
+    }
+}
+
+impl Default for Configuration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A DNS responder answering A queries with the IPv4 address of a netif
+///
+/// The responder runs in its own thread, and is stopped when dropped.
+pub struct EspCaptiveDns {
+    quit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    addr: Ipv4Addr,
+}
+
+impl EspCaptiveDns {
+    /// Start a responder bound to the IPv4 address of the provided netif,
+    /// answering the queries with that same address
+    ///
+    /// The netif should already have its IP configured, which is the
+    /// case for router (SoftAP) netifs as soon as they are created.
+    pub fn new(netif: &EspNetif, conf: &Configuration) -> Result<Self, EspError> {
+        let addr = netif.get_ip_info()?.ip;
+
+        Self::new_with_addr(addr, conf)
+    }
+
+    /// Start a responder bound to the provided address, answering the queries
+    /// with that same address
+    pub fn new_with_addr(addr: Ipv4Addr, conf: &Configuration) -> Result<Self, EspError> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(addr, conf.port)).map_err(to_esp_error)?;
+
+        // So that the thread can notice the quit request in a timely manner
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .map_err(to_esp_error)?;
+
+        let quit = Arc::new(AtomicBool::new(false));
+
+        let responder = Responder {
+            socket,
+            addr,
+            ttl: conf.ttl.as_secs().min(u32::MAX as _) as u32,
+            domains: conf
+                .domains
+                .iter()
+                .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+        };
+
+        let thread = {
+            let quit = quit.clone();
+
+            std::thread::Builder::new()
+                .name("captive-dns".to_string())
+                .stack_size(conf.stack_size)
+                .spawn(move || responder.run(&quit))
+                .map_err(to_esp_error)?
+        };
+
+        info!("Captive DNS responder started on {addr}:{}", conf.port);
+
+        Ok(Self {
+            quit,
+            thread: Some(thread),
+            addr,
+        })
+    }
+
+    /// Return the address the responder answers with
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+}
+
+impl Drop for EspCaptiveDns {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        info!("Captive DNS responder stopped");
+    }
+}
+
+struct Responder {
+    socket: UdpSocket,
+    addr: Ipv4Addr,
+    ttl: u32,
+    domains: Vec<String>,
+}
+
+impl Responder {
+    fn run(&self, quit: &AtomicBool) {
+        let mut query = [0; MAX_PACKET_LEN];
+        let mut reply = [0; MAX_PACKET_LEN];
+
+        while !quit.load(Ordering::SeqCst) {
+            let (len, peer) = match self.socket.recv_from(&mut query) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    warn!("Captive DNS receive error: {e}");
+                    continue;
+                }
+            };
+
+            if let Some(reply_len) = self.reply(&query[..len], &mut reply) {
+                if let Err(e) = self.socket.send_to(&reply[..reply_len], peer) {
+                    warn!("Captive DNS send error to {peer}: {e}");
+                }
+            }
+        }
+    }
+
+    fn reply(&self, query: &[u8], reply: &mut [u8]) -> Option<usize> {
+        reply_to(query, reply, self.addr, self.ttl, &self.domains)
+    }
+}
+
+/// Compose the reply to a DNS query
+///
+/// Returns `None` if the packet should be silently dropped (e.g. it is not a query).
+fn reply_to(
+    query: &[u8],
+    reply: &mut [u8],
+    addr: Ipv4Addr,
+    ttl: u32,
+    domains: &[String],
+) -> Option<usize> {
+    let header = Header::parse(query)?;
+
+    // Responses are never answered
+    if header.response || reply.len() < query.len() {
+        return None;
+    }
+
+    let question = (header.opcode == 0 && header.qdcount == 1)
+        .then(|| parse_question(&query[HEADER_LEN..]))
+        .flatten();
+
+    let Some((name, qtype, qclass, question_len)) = question else {
+        let rcode = if header.opcode != 0 {
+            RCODE_NOT_IMPLEMENTED
+        } else {
+            RCODE_FORMAT_ERROR
+        };
+
+        return Some(write_header(query, reply, rcode, 0, 0));
+    };
+
+    let matched = domains.is_empty()
+        || domains.iter().any(|domain| {
+            name == *domain
+                || name
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        });
+
+    if !matched {
+        debug!("Refusing DNS query for {name}");
+        return Some(write_header(query, reply, RCODE_REFUSED, 0, 0));
+    }
+
+    let answer = qclass == CLASS_IN && (qtype == TYPE_A || qtype == TYPE_ANY);
+
+    let mut len = write_header(query, reply, RCODE_NO_ERROR, 1, answer as u16);
+
+    reply[len..len + question_len].copy_from_slice(&query[HEADER_LEN..HEADER_LEN + question_len]);
+    len += question_len;
+
+    if answer {
+        const ANSWER_LEN: usize = 16;
+
+        if reply.len() < len + ANSWER_LEN {
+            return None;
+        }
+
+        // Name: a pointer to the name in the question
+        reply[len..len + 2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        reply[len + 2..len + 4].copy_from_slice(&TYPE_A.to_be_bytes());
+        reply[len + 4..len + 6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        reply[len + 6..len + 10].copy_from_slice(&ttl.to_be_bytes());
+        reply[len + 10..len + 12].copy_from_slice(&4_u16.to_be_bytes());
+        reply[len + 12..len + 16].copy_from_slice(&addr.octets());
+
+        len += ANSWER_LEN;
+
+        debug!("Answering DNS query for {name} with {addr}");
+    }
+
+    Some(len)
+}
+
+/// Write the header of the reply and return its length
+fn write_header(query: &[u8], reply: &mut [u8], rcode: u8, qdcount: u16, ancount: u16) -> usize {
+    // ID
+    reply[0..2].copy_from_slice(&query[0..2]);
+    // QR, the opcode and RD of the query, AA
+    reply[2] = 0x80 | (query[2] & 0x79) | 0x04;
+    // RA = 0, Z = 0, RCODE
+    reply[3] = rcode & 0x0f;
+    reply[4..6].copy_from_slice(&qdcount.to_be_bytes());
+    reply[6..8].copy_from_slice(&ancount.to_be_bytes());
+    // NSCOUNT, ARCOUNT
+    reply[8..12].fill(0);
+
+    HEADER_LEN
+}
+
+/// URLs which the popular operating systems and browsers request to detect captive portals
+pub const CONNECTIVITY_CHECK_URIS: &[&str] = &[
+    // Android, Chrome OS
+    "/generate_204",
+    "/gen_204",
+    // iOS, macOS
+    "/hotspot-detect.html",
+    "/library/test/success.html",
+    // Windows
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/redirect",
+    // Firefox
+    "/canonical.html",
+    "/success.txt",
+];
+
+/// Register handlers on the HTTP server which redirect all
+/// `CONNECTIVITY_CHECK_URIS` to the provided portal URL
+///
+/// The portal URL is typically the root page of the same HTTP server,
+/// addressed by the IP of the SoftAP, e.g. `http://192.168.71.1/`.
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+pub fn register_connectivity_check_handlers(
+    server: &mut crate::http::server::EspHttpServer<'_>,
+    portal_url: &str,
+) -> Result<(), EspError> {
+    use embedded_svc::http::Method;
+
+    for uri in CONNECTIVITY_CHECK_URIS {
+        let portal_url = portal_url.to_string();
+
+        server.fn_handler(uri, Method::Get, move |request| {
+            request
+                .into_response(302, Some("Found"), &[("Location", portal_url.as_str())])
+                .map(|_| ())
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        packet
+    }
+
+    #[test]
+    fn answers_a_query() {
+        let query = query("connectivitycheck.gstatic.com", TYPE_A);
+        let mut reply = [0; MAX_PACKET_LEN];
+
+        let len = reply_to(&query, &mut reply, Ipv4Addr::new(192, 168, 71, 1), 60, &[]).unwrap();
+
+        assert_eq!(len, query.len() + 16);
+        assert_eq!(&reply[0..2], &[0x12, 0x34]);
+        assert_eq!(reply[3] & 0x0f, RCODE_NO_ERROR);
+        assert_eq!(&reply[6..8], &[0, 1]);
+        assert_eq!(&reply[len - 4..len], &[192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn no_answer_for_aaaa() {
+        let query = query("example.com", 28);
+        let mut reply = [0; MAX_PACKET_LEN];
+
+        let len = reply_to(&query, &mut reply, Ipv4Addr::new(192, 168, 71, 1), 60, &[]).unwrap();
+
+        assert_eq!(len, query.len());
+        assert_eq!(&reply[6..8], &[0, 0]);
+    }
+
+    #[test]
+    fn refuses_unknown_domains() {
+        let domains = ["portal.local".to_string()];
+        let mut reply = [0; MAX_PACKET_LEN];
+
+        let matching = query("www.portal.local", TYPE_A);
+        reply_to(&matching, &mut reply, Ipv4Addr::LOCALHOST, 60, &domains).unwrap();
+        assert_eq!(reply[3] & 0x0f, RCODE_NO_ERROR);
+
+        let other = query("notportal.local", TYPE_A);
+        reply_to(&other, &mut reply, Ipv4Addr::LOCALHOST, 60, &domains).unwrap();
+        assert_eq!(reply[3] & 0x0f, RCODE_REFUSED);
+    }
+}
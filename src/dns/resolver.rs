@@ -0,0 +1,614 @@
+//! DNS resolver client
+//!
+//! Unlike `std::net::ToSocketAddrs` (which goes through the lwIP resolver and
+//! its global set of DNS servers), `EspDnsResolver` talks to an explicitly
+//! selected set of DNS servers - either custom ones, or the ones configured on
+//! a specific netif - and supports a timeout, an IPv4/IPv6 preference and a
+//! small cache honoring the TTLs of the answers.
+//!
+//! `EspAsyncDnsResolver` offers the same functionality without blocking
+//! the executor, by running the queries in a dedicated task.
+
+use core::ffi::CStr;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::time::Instant;
+
+use ::log::{debug, warn};
+
+use crate::netif::EspNetif;
+use crate::private::common::to_esp_error;
+use crate::private::mutex::Mutex;
+use crate::private::unblocker::Unblocker;
+use crate::private::zerocopy::Channel;
+use crate::sys::*;
+
+use super::wire::*;
+
+/// Which address families to resolve, and in what order to return them
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum IpPreference {
+    /// Resolve only A records
+    Ipv4Only,
+    /// Resolve only AAAA records
+    Ipv6Only,
+    /// Resolve both A and AAAA records, returning the IPv4 addresses first
+    #[default]
+    Ipv4First,
+    /// Resolve both A and AAAA records, returning the IPv6 addresses first
+    Ipv6First,
+}
+
+impl IpPreference {
+    fn query_types(&self) -> &'static [u16] {
+        match self {
+            Self::Ipv4Only => &[TYPE_A],
+            Self::Ipv6Only => &[TYPE_AAAA],
+            Self::Ipv4First => &[TYPE_A, TYPE_AAAA],
+            Self::Ipv6First => &[TYPE_AAAA, TYPE_A],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The DNS servers to query, in order
+    ///
+    /// Ignored by `EspDnsResolver::new_with_netif`, which uses the DNS servers of the netif.
+    pub servers: Vec<SocketAddr>,
+    /// The timeout of a single query sent to a single server
+    pub timeout: Duration,
+    /// How many times each server is tried before moving to the next one
+    pub attempts: u8,
+    /// Which address families to resolve
+    pub preference: IpPreference,
+    /// The maximum number of host names kept in the cache; 0 disables the cache
+    pub cache_size: usize,
+    /// Upper bound on the TTL of the cached entries
+    pub max_ttl: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            timeout: Duration::from_secs(3),
+            attempts: 2,
+            preference: IpPreference::default(),
+            cache_size: 8,
+            max_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+struct CacheEntry {
+    host: String,
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+/// A DNS client resolving host names via UDP queries to a selected set of servers
+pub struct EspDnsResolver {
+    servers: Vec<SocketAddr>,
+    netif: Option<heapless::String<6>>,
+    timeout: Duration,
+    attempts: u8,
+    preference: IpPreference,
+    cache_size: usize,
+    max_ttl: Duration,
+    cache: Mutex<Vec<CacheEntry>>,
+}
+
+impl EspDnsResolver {
+    /// Create a resolver querying the servers from the configuration
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        if conf.servers.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self::new_with_servers(conf.servers.clone(), None, conf))
+    }
+
+    /// Create a resolver querying the main and the backup DNS servers of the netif
+    ///
+    /// The servers are read once, at construction time. The queries are sent
+    /// and received only via the netif, which has to outlive the resolver.
+    pub fn new_with_netif(netif: &EspNetif, conf: &Configuration) -> Result<Self, EspError> {
+        let servers: Vec<_> = [netif.get_dns_addr()?, netif.get_secondary_dns_addr()?]
+            .into_iter()
+            .filter(|addr| !addr.is_unspecified())
+            .map(|addr| SocketAddr::new(addr, DNS_PORT))
+            .collect();
+
+        if servers.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        Ok(Self::new_with_servers(
+            servers,
+            Some(netif.get_name()),
+            conf,
+        ))
+    }
+
+    fn new_with_servers(
+        servers: Vec<SocketAddr>,
+        netif: Option<heapless::String<6>>,
+        conf: &Configuration,
+    ) -> Self {
+        Self {
+            servers,
+            netif,
+            timeout: conf.timeout,
+            attempts: conf.attempts.max(1),
+            preference: conf.preference,
+            cache_size: conf.cache_size,
+            max_ttl: conf.max_ttl,
+            cache: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Return the DNS servers this resolver queries
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// Resolve the host name into a list of addresses, ordered according to the
+    /// configured `IpPreference`
+    ///
+    /// IP address literals are returned as-is, without a query.
+    ///
+    /// With both address families configured, the addresses of one family are returned
+    /// even if the query for the other one failed.
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if the host name does not exist or has no addresses
+    /// of the requested families, and `ESP_ERR_TIMEOUT` if none of the servers replied.
+    pub fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, EspError> {
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return Ok(alloc::vec![addr]);
+        }
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        if host.is_empty() || host.len() > 253 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if let Some(addrs) = self.cached(&host) {
+            debug!("Resolved {host} from cache: {addrs:?}");
+            return Ok(addrs);
+        }
+
+        let mut addrs = Vec::new();
+        let mut ttl = u32::MAX;
+        let mut answered = false;
+        let mut last_err = None;
+
+        for qtype in self.preference.query_types() {
+            match self.query(&host, *qtype) {
+                Ok((answers, answers_ttl)) => {
+                    if !answers.is_empty() {
+                        ttl = ttl.min(answers_ttl);
+                    }
+
+                    answered = true;
+                    addrs.extend(answers);
+                }
+                Err(e) if e.code() == ESP_ERR_NOT_FOUND => answered = true,
+                Err(e) => {
+                    debug!("Resolving {host} (type {qtype}) failed: {e}");
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            // Only fail with the error of a query if no query type succeeded
+            return Err(match last_err {
+                Some(e) if !answered => e,
+                _ => EspError::from_infallible::<ESP_ERR_NOT_FOUND>(),
+            });
+        }
+
+        debug!("Resolved {host}: {addrs:?}, TTL {ttl}s");
+
+        // Do not cache partial results, so that the failed query is retried next time
+        if last_err.is_none() {
+            self.cache(host, &addrs, ttl);
+        }
+
+        Ok(addrs)
+    }
+
+    /// Resolve the host name and return the first address
+    pub fn resolve_first(&self, host: &str) -> Result<IpAddr, EspError> {
+        Ok(self.resolve(host)?[0])
+    }
+
+    /// Remove all entries from the cache
+    pub fn clear_cache(&self) {
+        self.cache.lock().clear();
+    }
+
+    fn query(&self, host: &str, qtype: u16) -> Result<(Vec<IpAddr>, u32), EspError> {
+        let mut packet = [0; MAX_PACKET_LEN];
+        let mut reply = [0; MAX_PACKET_LEN];
+
+        let mut last_err = EspError::from_infallible::<ESP_ERR_TIMEOUT>();
+
+        for server in &self.servers {
+            for _ in 0..self.attempts {
+                let id = unsafe { esp_random() } as u16;
+
+                let len = build_query(id, host, qtype, &mut packet)
+                    .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+                match self.exchange(*server, &packet[..len], &mut reply, id) {
+                    Ok(reply_len) => match parse_reply(id, qtype, &reply[..reply_len]) {
+                        Ok(answers) => return Ok(answers),
+                        Err(rcode) if rcode == RCODE_NAME_ERROR => {
+                            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+                        }
+                        Err(rcode) => {
+                            warn!("DNS server {server} replied with RCODE {rcode} for {host}");
+                            last_err = EspError::from_infallible::<ESP_FAIL>();
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        debug!("DNS query for {host} to {server} failed: {e}");
+                        last_err = e;
+                    }
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn exchange(
+        &self,
+        server: SocketAddr,
+        packet: &[u8],
+        reply: &mut [u8],
+        id: u16,
+    ) -> Result<usize, EspError> {
+        let local: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local).map_err(to_esp_error)?;
+
+        if let Some(netif) = self.netif.as_deref() {
+            crate::socket::bind(socket.as_raw_fd(), Some(netif))?;
+        }
+
+        socket.connect(server).map_err(to_esp_error)?;
+        socket.send(packet).map_err(to_esp_error)?;
+
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+            }
+
+            socket
+                .set_read_timeout(Some(remaining))
+                .map_err(to_esp_error)?;
+
+            let len = socket.recv(reply).map_err(to_esp_error)?;
+
+            // Ignore stray replies to earlier queries
+            if Header::parse(&reply[..len]).is_some_and(|header| header.id == id) {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock();
+        let now = Instant::now();
+
+        cache.retain(|entry| entry.expires_at > now);
+
+        cache
+            .iter()
+            .find(|entry| entry.host == host)
+            .map(|entry| entry.addrs.clone())
+    }
+
+    fn cache(&self, host: String, addrs: &[IpAddr], ttl: u32) {
+        if self.cache_size == 0 || ttl == 0 {
+            return;
+        }
+
+        let ttl = Duration::from_secs(ttl as _).min(self.max_ttl);
+
+        let mut cache = self.cache.lock();
+
+        cache.retain(|entry| entry.host != host);
+
+        if cache.len() >= self.cache_size {
+            // Evict the entry expiring first
+            if let Some(index) = cache
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(index, _)| index)
+            {
+                cache.swap_remove(index);
+            }
+        }
+
+        cache.push(CacheEntry {
+            host,
+            addrs: addrs.to_vec(),
+            expires_at: Instant::now() + ttl,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct AsyncWork {
+    host: String,
+    result: Result<Vec<IpAddr>, EspError>,
+}
+
+/// An async wrapper around `EspDnsResolver`
+///
+/// The queries are executed in a dedicated task, so awaiting them does not block the executor.
+pub struct EspAsyncDnsResolver {
+    unblocker: Unblocker<AsyncWork>,
+    resolver: Arc<EspDnsResolver>,
+}
+
+impl EspAsyncDnsResolver {
+    /// Create an async resolver querying the servers from the configuration
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        Self::wrap(EspDnsResolver::new(conf)?)
+    }
+
+    /// Create an async resolver querying the main and the backup DNS servers of the netif
+    pub fn new_with_netif(netif: &EspNetif, conf: &Configuration) -> Result<Self, EspError> {
+        Self::wrap(EspDnsResolver::new_with_netif(netif, conf)?)
+    }
+
+    /// Wrap an existing resolver with an async interface
+    pub fn wrap(resolver: EspDnsResolver) -> Result<Self, EspError> {
+        let resolver = Arc::new(resolver);
+
+        let unblocker = {
+            let resolver = resolver.clone();
+
+            Unblocker::new(
+                CStr::from_bytes_until_nul(b"DNS resolver task\0").unwrap(),
+                4096,
+                None,
+                None,
+                move |channel| Self::work(channel, resolver),
+            )?
+        };
+
+        Ok(Self {
+            unblocker,
+            resolver,
+        })
+    }
+
+    /// Return the wrapped resolver, e.g. for synchronous use or to clear its cache
+    pub fn resolver(&self) -> &EspDnsResolver {
+        &self.resolver
+    }
+
+    /// Resolve the host name into a list of addresses
+    ///
+    /// See `EspDnsResolver::resolve`.
+    pub async fn resolve(&mut self, host: &str) -> Result<Vec<IpAddr>, EspError> {
+        // Answer IP literals and cached entries without a round-trip to the task
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return Ok(alloc::vec![addr]);
+        }
+
+        if let Some(addrs) = self
+            .resolver
+            .cached(&host.trim_end_matches('.').to_ascii_lowercase())
+        {
+            return Ok(addrs);
+        }
+
+        let work = self.unblocker.exec_in_out().await.unwrap();
+
+        work.host.clear();
+        work.host.push_str(host);
+
+        self.unblocker.do_exec().await;
+
+        let work = self.unblocker.exec_in_out().await.unwrap();
+
+        core::mem::replace(&mut work.result, Ok(Vec::new()))
+    }
+
+    /// Resolve the host name and return the first address
+    pub async fn resolve_first(&mut self, host: &str) -> Result<IpAddr, EspError> {
+        Ok(self.resolve(host).await?[0])
+    }
+
+    fn work(channel: Arc<Channel<AsyncWork>>, resolver: Arc<EspDnsResolver>) {
+        // Placeholder work item. This will be replaced by the first actual work item.
+        let mut work = AsyncWork {
+            host: String::new(),
+            result: Ok(Vec::new()),
+        };
+
+        while channel.share(&mut work) {
+            if !work.host.is_empty() {
+                work.result = resolver.resolve(&work.host);
+            }
+        }
+    }
+}
+
+/// Compose a recursive query for the host name and return its length
+fn build_query(id: u16, host: &str, qtype: u16, packet: &mut [u8]) -> Option<usize> {
+    packet.get_mut(..HEADER_LEN)?.fill(0);
+
+    packet[0..2].copy_from_slice(&id.to_be_bytes());
+    // RD
+    packet[2] = 0x01;
+    // QDCOUNT
+    packet[5] = 1;
+
+    let mut len = HEADER_LEN;
+
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+
+        *packet.get_mut(len)? = label.len() as u8;
+        len += 1;
+
+        packet
+            .get_mut(len..len + label.len())?
+            .copy_from_slice(label.as_bytes());
+        len += label.len();
+    }
+
+    let fixed = packet.get_mut(len..len + 5)?;
+    fixed[0] = 0;
+    fixed[1..3].copy_from_slice(&qtype.to_be_bytes());
+    fixed[3..5].copy_from_slice(&CLASS_IN.to_be_bytes());
+
+    Some(len + 5)
+}
+
+/// Parse the reply to a query of type `qtype` and return the addresses
+/// together with the smallest TTL among them
+///
+/// CNAME records are skipped, as recursive servers return the records
+/// of the canonical name in the same reply. On failure, the RCODE is returned.
+fn parse_reply(id: u16, qtype: u16, reply: &[u8]) -> Result<(Vec<IpAddr>, u32), u8> {
+    const MALFORMED: u8 = 0xff;
+
+    let header = Header::parse(reply)
+        .filter(|header| header.id == id && header.response)
+        .ok_or(MALFORMED)?;
+
+    if header.rcode != RCODE_NO_ERROR {
+        return Err(header.rcode);
+    }
+
+    let mut offset = HEADER_LEN;
+
+    for _ in 0..header.qdcount {
+        offset = skip_name(reply, offset).ok_or(MALFORMED)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for _ in 0..header.ancount {
+        offset = skip_name(reply, offset).ok_or(MALFORMED)?;
+
+        let fixed = reply.get(offset..offset + 10).ok_or(MALFORMED)?;
+
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let rttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdlen = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+
+        offset += 10;
+
+        let rdata = reply.get(offset..offset + rdlen).ok_or(MALFORMED)?;
+        offset += rdlen;
+
+        if rclass != CLASS_IN || rtype != qtype || rtype == TYPE_CNAME {
+            continue;
+        }
+
+        let addr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(MALFORMED),
+        };
+
+        addrs.push(addr);
+        ttl = ttl.min(rttl);
+    }
+
+    Ok((addrs, ttl))
+}
+
+impl core::fmt::Debug for EspDnsResolver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EspDnsResolver")
+            .field("servers", &self.servers)
+            .field("timeout", &self.timeout)
+            .field("preference", &self.preference)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_query() {
+        let mut packet = [0; MAX_PACKET_LEN];
+
+        let len = build_query(0x1234, "example.com", TYPE_AAAA, &mut packet).unwrap();
+
+        assert_eq!(&packet[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&packet[4..6], &[0, 1]);
+        assert_eq!(&packet[HEADER_LEN..len - 4], b"\x07example\x03com\x00");
+        assert_eq!(&packet[len - 4..len], &[0, 28, 0, 1]);
+
+        assert!(build_query(0, "example..com", TYPE_A, &mut packet).is_none());
+    }
+
+    #[test]
+    fn parses_reply_with_cname() {
+        let mut packet = [0; MAX_PACKET_LEN];
+        let len = build_query(0x4242, "www.example.com", TYPE_A, &mut packet).unwrap();
+
+        let mut reply = packet[..len].to_vec();
+        // QR, RD, RA, ANCOUNT = 2
+        reply[2] = 0x81;
+        reply[3] = 0x80;
+        reply[7] = 2;
+
+        // CNAME www.example.com -> example.com (pointer into the question)
+        reply.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+        // A example.com -> 93.184.216.34
+        reply.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 34]);
+
+        let (addrs, ttl) = parse_reply(0x4242, TYPE_A, &reply).unwrap();
+
+        assert_eq!(addrs, [IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]);
+        assert_eq!(ttl, 30);
+
+        assert_eq!(parse_reply(0x4343, TYPE_A, &reply), Err(0xff));
+
+        reply[3] = 0x80 | RCODE_NAME_ERROR;
+        assert_eq!(parse_reply(0x4242, TYPE_A, &reply), Err(RCODE_NAME_ERROR));
+    }
+}
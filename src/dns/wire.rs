@@ -0,0 +1,101 @@
+//! DNS wire format (RFC 1035) shared by the captive responder and the resolver
+
+use alloc::string::String;
+
+pub const DNS_PORT: u16 = 53;
+
+pub const HEADER_LEN: usize = 12;
+pub const MAX_PACKET_LEN: usize = 512;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+pub const RCODE_NAME_ERROR: u8 = 3;
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// The fields of the header of a DNS packet which are of interest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub id: u16,
+    /// QR: whether the packet is a response
+    pub response: bool,
+    pub opcode: u8,
+    pub rcode: u8,
+    pub qdcount: u16,
+    pub ancount: u16,
+}
+
+impl Header {
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let header = packet.get(..HEADER_LEN)?;
+
+        Some(Self {
+            id: u16::from_be_bytes([header[0], header[1]]),
+            response: header[2] & 0x80 != 0,
+            opcode: (header[2] >> 3) & 0x0f,
+            rcode: header[3] & 0x0f,
+            qdcount: u16::from_be_bytes([header[4], header[5]]),
+            ancount: u16::from_be_bytes([header[6], header[7]]),
+        })
+    }
+}
+
+/// Parse the question section and return the (lowercased) name, QTYPE,
+/// QCLASS and the length of the question section
+pub fn parse_question(data: &[u8]) -> Option<(String, u16, u16, usize)> {
+    let mut name = String::new();
+    let mut offset = 0;
+
+    loop {
+        let label_len = *data.get(offset)? as usize;
+        offset += 1;
+
+        if label_len == 0 {
+            break;
+        }
+
+        // Compression pointers are not expected in the question of a query
+        if label_len & 0xc0 != 0 {
+            return None;
+        }
+
+        let label = data.get(offset..offset + label_len)?;
+        offset += label_len;
+
+        if !name.is_empty() {
+            name.push('.');
+        }
+
+        name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+    }
+
+    let fixed = data.get(offset..offset + 4)?;
+
+    let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+
+    Some((name, qtype, qclass, offset + 4))
+}
+
+/// Skip a (possibly compressed) name and return the offset right after it
+pub fn skip_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let label_len = *data.get(offset)? as usize;
+
+        match label_len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => {
+                data.get(offset + 1)?;
+                return Some(offset + 2);
+            }
+            len if len & 0xc0 == 0 => offset += 1 + len,
+            _ => return None,
+        }
+    }
+}
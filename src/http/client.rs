@@ -13,6 +13,8 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use ::log::*;
 
@@ -26,12 +28,15 @@ use crate::handle::RawHandle;
 use crate::io::EspIOError;
 use crate::private::common::Newtype;
 use crate::private::cstr::*;
+use crate::private::mutex::Mutex;
 use crate::tls::X509;
 
 pub use embedded_svc::http::client::{Connection, Request, Response};
 
 pub use super::*;
 
+pub mod multipart;
+
 impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     fn from(method: Method) -> Self {
         Self((
@@ -68,6 +73,28 @@ pub enum FollowRedirectsPolicy {
     FollowAll,
 }
 
+/// The HTTP authentication scheme used by the client
+#[derive(Default, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum AuthType {
+    #[default]
+    None,
+    /// Basic authentication; the credentials are sent with every request
+    Basic,
+    /// Digest authentication; the credentials are sent after the server challenges
+    /// the request with a 401 response
+    Digest,
+}
+
+impl From<AuthType> for Newtype<esp_http_client_auth_type_t> {
+    fn from(auth_type: AuthType) -> Self {
+        Self(match auth_type {
+            AuthType::None => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_NONE,
+            AuthType::Basic => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_BASIC,
+            AuthType::Digest => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_DIGEST,
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Configuration {
     pub buffer_size: Option<usize>,
@@ -84,6 +111,10 @@ pub struct Configuration {
     pub keep_alive_idle: Option<usize>,
     pub keep_alive_interval: Option<usize>,
     pub keep_alive_count: Option<usize>,
+    /// The authentication scheme; requires `username` and `password`
+    pub auth_type: AuthType,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -93,6 +124,13 @@ enum State {
     Response,
 }
 
+/// The reason for re-sending a request
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Hop {
+    Redirect,
+    Authenticate,
+}
+
 #[allow(clippy::type_complexity)]
 pub struct EspHttpConnection {
     raw_client: esp_http_client_handle_t,
@@ -104,10 +142,20 @@ pub struct EspHttpConnection {
     follow_redirects: bool,
     headers: BTreeMap<Uncased<'static>, String>,
     content_len_header: UnsafeCell<Option<Option<String>>>,
+    default_headers: BTreeMap<Uncased<'static>, String>,
+    cookie_jar: Option<Arc<CookieJar>>,
+    set_cookies: Vec<String>,
+    auth_configured: bool,
+    pooled: bool,
+    reused: bool,
 }
 
 impl EspHttpConnection {
     pub fn new(configuration: &Configuration) -> Result<Self, EspError> {
+        Self::new_raw(configuration, false)
+    }
+
+    fn new_raw(configuration: &Configuration, is_async: bool) -> Result<Self, EspError> {
         let event_handler = Box::new(None);
 
         #[allow(clippy::manual_c_str_literals)]
@@ -121,6 +169,7 @@ impl EspHttpConnection {
             use_global_ca_store: configuration.use_global_ca_store,
             crt_bundle_attach: configuration.crt_bundle_attach,
             keep_alive_enable: configuration.keep_alive_enable,
+            is_async,
 
             ..Default::default()
         };
@@ -133,7 +182,11 @@ impl EspHttpConnection {
             native_config.buffer_size_tx = buffer_size_tx as _;
         }
 
-        if let Some(timeout) = configuration.timeout {
+        if is_async {
+            // The timeout is enforced by `EspAsyncHttpConnection` instead, so only poll the socket.
+            // Note that 0 would mean "no timeout" when connecting
+            native_config.timeout_ms = 1;
+        } else if let Some(timeout) = configuration.timeout {
             native_config.timeout_ms = timeout.as_millis() as _;
         }
 
@@ -168,6 +221,21 @@ impl EspHttpConnection {
             native_config.client_key_len = private_key.as_esp_idf_raw_len();
         }
 
+        // The native client copies the credentials
+        let username = configuration.username.map(to_cstring_arg).transpose()?;
+        let password = configuration.password.map(to_cstring_arg).transpose()?;
+
+        if let Some(username) = username.as_ref() {
+            native_config.username = username.as_ptr() as _;
+        }
+
+        if let Some(password) = password.as_ref() {
+            native_config.password = password.as_ptr() as _;
+        }
+
+        native_config.auth_type =
+            Newtype::<esp_http_client_auth_type_t>::from(configuration.auth_type).0;
+
         if configuration.keep_alive_enable {
             if let Some(keep_alive_idle) = configuration.keep_alive_idle {
                 native_config.keep_alive_idle = keep_alive_idle as _;
@@ -194,10 +262,99 @@ impl EspHttpConnection {
                 follow_redirects: false,
                 headers: BTreeMap::new(),
                 content_len_header: UnsafeCell::new(None),
+                default_headers: BTreeMap::new(),
+                cookie_jar: None,
+                set_cookies: Vec::new(),
+                auth_configured: configuration.auth_type != AuthType::None,
+                pooled: false,
+                reused: false,
             })
         }
     }
 
+    /// Set the credentials used to authenticate the subsequent requests
+    ///
+    /// With `AuthType::None`, the credentials are cleared.
+    pub fn set_auth(
+        &mut self,
+        auth_type: AuthType,
+        username: &str,
+        password: &str,
+    ) -> Result<(), EspError> {
+        self.assert_initial();
+
+        if auth_type == AuthType::None {
+            // Basic auth installs the header once, on the next request after it is configured
+            let _ = unsafe {
+                esp_http_client_delete_header(self.raw_client, c"Authorization".as_ptr())
+            };
+        } else {
+            let c_username = to_cstring_arg(username)?;
+            let c_password = to_cstring_arg(password)?;
+
+            esp!(unsafe {
+                esp_http_client_set_username(self.raw_client, c_username.as_ptr() as _)
+            })?;
+            esp!(unsafe {
+                esp_http_client_set_password(self.raw_client, c_password.as_ptr() as _)
+            })?;
+        }
+
+        esp!(unsafe {
+            esp_http_client_set_authtype(
+                self.raw_client,
+                Newtype::<esp_http_client_auth_type_t>::from(auth_type).0,
+            )
+        })?;
+
+        self.auth_configured = auth_type != AuthType::None;
+
+        Ok(())
+    }
+
+    /// Send an `Authorization: Bearer <token>` header with the subsequent requests,
+    /// or stop sending it if `token` is `None`
+    pub fn set_bearer_token(&mut self, token: Option<&str>) {
+        if let Some(token) = token {
+            self.set_default_header("Authorization", &alloc::format!("Bearer {token}"));
+        } else {
+            self.remove_default_header("Authorization");
+        }
+    }
+
+    /// Set a header sent with all subsequent requests
+    ///
+    /// A header with the same name passed to `initiate_request` takes precedence.
+    pub fn set_default_header(&mut self, name: &str, value: &str) {
+        self.default_headers
+            .insert(Uncased::from(name.to_string()), value.to_string());
+    }
+
+    /// Stop sending a header set with `set_default_header`
+    pub fn remove_default_header(&mut self, name: &str) -> Option<String> {
+        self.default_headers.remove(UncasedStr::new(name))
+    }
+
+    /// Return the headers set with `set_default_header`
+    pub fn default_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.default_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Attach a cookie jar to the connection, or detach it if `cookie_jar` is `None`
+    ///
+    /// The `Set-Cookie` headers of the responses are stored in the jar, and the
+    /// matching cookies from the jar are sent with the requests. The same jar can be
+    /// shared by several connections (e.g. the ones of an `EspHttpConnectionPool`).
+    pub fn set_cookie_jar(&mut self, cookie_jar: Option<Arc<CookieJar>>) {
+        self.cookie_jar = cookie_jar;
+    }
+
+    pub fn cookie_jar(&self) -> Option<&Arc<CookieJar>> {
+        self.cookie_jar.as_ref()
+    }
+
     pub fn status(&self) -> u16 {
         self.assert_response();
         unsafe { esp_http_client_get_status_code(self.raw_client) as _ }
@@ -244,55 +401,16 @@ impl EspHttpConnection {
             self.flush_response()?;
         }
 
-        self.assert_initial();
-
-        let c_uri = to_cstring_arg(uri)?;
-
-        esp!(unsafe { esp_http_client_set_url(self.raw_client, c_uri.as_ptr() as _) })?;
-        esp!(unsafe {
-            esp_http_client_set_method(
-                self.raw_client,
-                Newtype::<(esp_http_client_method_t, ())>::from(method).0 .0,
-            )
-        })?;
-
-        let mut content_len = None;
-
-        for (name, value) in headers {
-            if name.eq_ignore_ascii_case("Content-Length") {
-                if let Ok(len) = value.parse::<i64>() {
-                    content_len = Some(len);
-                }
-            } else {
-                let c_name = to_cstring_arg(name)?;
-
-                // TODO: Replace with a proper conversion from UTF8 to ISO-8859-1
-                let c_value = to_cstring_arg(value)?;
+        self.prepare_request(method, uri, headers)?;
 
-                esp!(unsafe {
-                    esp_http_client_set_header(
-                        self.raw_client,
-                        c_name.as_ptr() as _,
-                        c_value.as_ptr() as _,
-                    )
-                })?;
+        if let Err(err) =
+            esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as i32) })
+        {
+            if !self.reopen_stale()? {
+                return Err(err);
             }
         }
 
-        self.follow_redirects = match self.follow_redirects_policy {
-            FollowRedirectsPolicy::FollowAll => true,
-            FollowRedirectsPolicy::FollowGetHead => method == Method::Get || method == Method::Head,
-            _ => false,
-        };
-
-        // No Content-Length for POST requests means chunked encoding
-        // This is indicated to the ESP IDF client by setting the
-        // content length param of `esp_http_client_open` to -1
-        self.request_content_len =
-            content_len.unwrap_or(if method == Method::Post { -1 } else { 0 });
-
-        esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as i32) })?;
-
         self.state = State::Request;
 
         Ok(())
@@ -355,7 +473,7 @@ impl EspHttpConnection {
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
         if buf.is_empty() {
             Ok(0)
-        } else if !self.raw_request_body && self.request_content_len == -1 {
+        } else if self.is_chunked() {
             // Assume chunked encoding and send the buffer as the next chunk
             let mut len_buf = heapless::String::<8>::new();
             write!(&mut len_buf, "{:x}", buf.len()).unwrap();
@@ -382,8 +500,158 @@ impl EspHttpConnection {
         Ok(())
     }
 
+    /// Configure the native client for a new request, without sending anything yet
+    fn prepare_request(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EspError> {
+        self.assert_initial();
+
+        // Only the first request on a connection taken from a pool may find it closed
+        // by the server; whatever happened to the previous request is irrelevant
+        self.reused = core::mem::take(&mut self.pooled);
+
+        let c_uri = to_cstring_arg(uri)?;
+
+        esp!(unsafe { esp_http_client_set_url(self.raw_client, c_uri.as_ptr() as _) })?;
+        esp!(unsafe {
+            esp_http_client_set_method(
+                self.raw_client,
+                Newtype::<(esp_http_client_method_t, ())>::from(method).0 .0,
+            )
+        })?;
+
+        let mut content_len = None;
+
+        let default_headers = self
+            .default_headers
+            .iter()
+            .filter(|(name, _)| {
+                !headers
+                    .iter()
+                    .any(|(header, _)| name.as_str().eq_ignore_ascii_case(header))
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+
+        for (name, value) in headers.iter().copied().chain(default_headers) {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                if let Ok(len) = value.parse::<i64>() {
+                    content_len = Some(len);
+                }
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding")
+                && value.eq_ignore_ascii_case("chunked")
+            {
+                // The ESP IDF client adds the header by itself for chunked requests
+                content_len = Some(-1);
+            } else {
+                Self::set_header(self.raw_client, name, value)?;
+            }
+        }
+
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+        {
+            self.set_cookie_header(uri)?;
+        }
+
+        self.follow_redirects = match self.follow_redirects_policy {
+            FollowRedirectsPolicy::FollowAll => true,
+            FollowRedirectsPolicy::FollowGetHead => method == Method::Get || method == Method::Head,
+            _ => false,
+        };
+
+        // No Content-Length for POST requests (or an explicit `Transfer-Encoding: chunked`
+        // header for any method) means chunked encoding
+        // This is indicated to the ESP IDF client by setting the
+        // content length param of `esp_http_client_open` to -1
+        self.request_content_len =
+            content_len.unwrap_or(if method == Method::Post { -1 } else { 0 });
+
+        Ok(())
+    }
+
+    fn set_header(
+        raw_client: esp_http_client_handle_t,
+        name: &str,
+        value: &str,
+    ) -> Result<(), EspError> {
+        let c_name = to_cstring_arg(name)?;
+
+        // TODO: Replace with a proper conversion from UTF8 to ISO-8859-1
+        let c_value = to_cstring_arg(value)?;
+
+        esp!(unsafe {
+            esp_http_client_set_header(raw_client, c_name.as_ptr() as _, c_value.as_ptr() as _)
+        })
+    }
+
+    /// Set (or remove) the `Cookie` header with the cookies of the jar matching the URL
+    fn set_cookie_header(&mut self, url: &str) -> Result<(), EspError> {
+        let Some(cookie_jar) = self.cookie_jar.as_ref() else {
+            return Ok(());
+        };
+
+        let cookies = cookie_jar.cookie_header(url);
+
+        if cookies.is_empty() {
+            // The native client keeps the headers of the previous requests
+            let _ = unsafe { esp_http_client_delete_header(self.raw_client, c"Cookie".as_ptr()) };
+
+            Ok(())
+        } else {
+            Self::set_header(self.raw_client, "Cookie", &cookies)
+        }
+    }
+
+    /// Store the `Set-Cookie` headers of the last response into the cookie jar
+    fn store_cookies(&mut self) -> Result<(), EspError> {
+        let Some(cookie_jar) = self.cookie_jar.as_ref() else {
+            return Ok(());
+        };
+
+        if self.set_cookies.is_empty() {
+            return Ok(());
+        }
+
+        let url = self.url()?;
+
+        for set_cookie in self.set_cookies.drain(..) {
+            cookie_jar.store(&url, &set_cookie);
+        }
+
+        Ok(())
+    }
+
+    /// Return the current URL of the native client, which changes when following redirects
+    fn url(&self) -> Result<String, EspError> {
+        let mut buf = alloc::vec![0_u8; 256];
+
+        loop {
+            esp!(unsafe {
+                esp_http_client_get_url(self.raw_client, buf.as_mut_ptr() as _, buf.len() as _)
+            })?;
+
+            let url = from_cstr(&buf);
+
+            // The native client silently truncates the URL to the size of the buffer
+            if url.len() < buf.len() - 1 {
+                break Ok(url.to_string());
+            }
+
+            buf.resize(buf.len() * 2, 0);
+        }
+    }
+
+    /// Whether the request body is sent with chunked encoding by `write`
+    fn is_chunked(&self) -> bool {
+        !self.raw_request_body && self.request_content_len == -1
+    }
+
     fn flush(&mut self) -> Result<(), EspError> {
-        if !self.raw_request_body && self.request_content_len == -1 {
+        if self.is_chunked() {
             // Finish the chunked-encoded stream
             self.raw_write_all(b"0\r\n\r\n")
         } else {
@@ -441,67 +709,154 @@ impl EspHttpConnection {
     }
 
     fn fetch_headers(&mut self) -> Result<(), EspError> {
-        self.headers.clear();
-        *self.content_len_header.get_mut() = None;
+        self.clear_headers();
+
+        let mut challenged = false;
 
         loop {
-            // TODO: Implement a mechanism where the client can declare in which header it is interested
-            let headers_ptr = &mut self.headers as *mut BTreeMap<Uncased, String>;
-
-            let handler = move |event: &esp_http_client_event_t| {
-                if event.event_id == esp_http_client_event_id_t_HTTP_EVENT_ON_HEADER {
-                    unsafe {
-                        // TODO: Replace with a proper conversion from ISO-8859-1 to UTF8
-
-                        headers_ptr.as_mut().unwrap().insert(
-                            Uncased::from(from_cstr_ptr(event.header_key).to_string()),
-                            from_cstr_ptr(event.header_value).to_string(),
-                        );
-                    }
-                }
+            let result = self.with_header_handler(|raw_client| unsafe {
+                esp_http_client_fetch_headers(raw_client)
+            });
+
+            // Requests with a body cannot be re-sent, as the body is already consumed
+            if result < 0 && self.request_content_len == 0 && self.reopen_stale()? {
+                continue;
+            }
+
+            Self::check(result as _)?;
+
+            self.reused = false;
 
-                ESP_OK as esp_err_t
+            let Some(hop) = self.next_hop(&mut challenged)? else {
+                break;
             };
 
-            self.register_handler(handler);
+            let mut len = 0_i32;
+            esp!(unsafe { esp_http_client_flush_response(self.raw_client, &mut len) })?;
 
-            let result = unsafe { esp_http_client_fetch_headers(self.raw_client) };
+            let content_len = self.prepare_hop(hop)?;
 
-            self.deregister_handler();
+            esp!(unsafe { esp_http_client_open(self.raw_client, content_len) })?;
 
-            Self::check(result as _)?;
+            self.headers.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Re-open the request on a new connection if the native client reused a kept-alive
+    /// connection, which the server might have closed while the connection was idle
+    ///
+    /// Return `false` if the connection was not reused.
+    fn reopen_stale(&mut self) -> Result<bool, EspError> {
+        if !core::mem::take(&mut self.reused) {
+            return Ok(false);
+        }
+
+        debug!("Request on a kept-alive connection failed, re-sending it on a new connection");
+
+        esp!(unsafe { esp_http_client_close(self.raw_client) })?;
+        esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as i32) })?;
 
-            trace!("Fetched headers: {:?}", self.headers);
+        Ok(true)
+    }
+
+    fn clear_headers(&mut self) {
+        self.headers.clear();
+        self.set_cookies.clear();
+        *self.content_len_header.get_mut() = None;
+    }
 
-            if self.follow_redirects {
-                let status = unsafe { esp_http_client_get_status_code(self.raw_client) as u16 };
+    /// Call `f` with a handler collecting the response headers installed
+    fn with_header_handler<R>(&mut self, f: impl FnOnce(esp_http_client_handle_t) -> R) -> R {
+        // TODO: Implement a mechanism where the client can declare in which header it is interested
+        let headers_ptr = &mut self.headers as *mut BTreeMap<Uncased, String>;
+        let set_cookies_ptr = &mut self.set_cookies as *mut Vec<String>;
 
-                if status::REDIRECT.contains(&status) && status != 304 {
-                    info!("Got response {status}, about to follow redirect");
+        let handler = move |event: &esp_http_client_event_t| {
+            if event.event_id == esp_http_client_event_id_t_HTTP_EVENT_ON_HEADER {
+                unsafe {
+                    // TODO: Replace with a proper conversion from ISO-8859-1 to UTF8
 
-                    let mut len = 0_i32;
-                    esp!(unsafe { esp_http_client_flush_response(self.raw_client, &mut len) })?;
-                    esp!(unsafe {
-                        esp_http_client_set_method(
-                            self.raw_client,
-                            esp_http_client_method_t_HTTP_METHOD_GET,
-                        )
-                    })?;
-                    esp!(unsafe { esp_http_client_set_redirection(self.raw_client) })?;
-                    esp!(unsafe {
-                        esp_http_client_open(self.raw_client, self.request_content_len as i32)
-                    })?;
+                    let key = from_cstr_ptr(event.header_key);
+                    let value = from_cstr_ptr(event.header_value);
 
-                    self.headers.clear();
+                    // Multiple `Set-Cookie` headers cannot be folded into one
+                    if key.eq_ignore_ascii_case("Set-Cookie") {
+                        set_cookies_ptr.as_mut().unwrap().push(value.to_string());
+                    }
 
-                    continue;
+                    headers_ptr
+                        .as_mut()
+                        .unwrap()
+                        .insert(Uncased::from(key.to_string()), value.to_string());
                 }
             }
 
-            break;
+            ESP_OK as esp_err_t
+        };
+
+        self.register_handler(handler);
+
+        let result = f(self.raw_client);
+
+        self.deregister_handler();
+
+        result
+    }
+
+    /// Process the fetched response headers and return whether the request has
+    /// to be re-sent, because of a redirect or an authentication challenge
+    fn next_hop(&mut self, challenged: &mut bool) -> Result<Option<Hop>, EspError> {
+        trace!("Fetched headers: {:?}", self.headers);
+
+        self.store_cookies()?;
+        self.set_cookies.clear();
+
+        let status = unsafe { esp_http_client_get_status_code(self.raw_client) as u16 };
+
+        if self.follow_redirects && status::REDIRECT.contains(&status) && status != 304 {
+            info!("Got response {status}, about to follow redirect");
+
+            return Ok(Some(Hop::Redirect));
         }
 
-        Ok(())
+        // Requests with a body cannot be re-sent, as the body is already consumed
+        if status == 401 && self.auth_configured && !*challenged && self.request_content_len == 0 {
+            info!("Got response 401, about to answer the authentication challenge");
+
+            *challenged = true;
+
+            return Ok(Some(Hop::Authenticate));
+        }
+
+        Ok(None)
+    }
+
+    /// Prepare the native client for re-sending the request once the previous
+    /// response is consumed, and return the content length to open it with
+    fn prepare_hop(&mut self, hop: Hop) -> Result<i32, EspError> {
+        match hop {
+            Hop::Redirect => {
+                esp!(unsafe {
+                    esp_http_client_set_method(
+                        self.raw_client,
+                        esp_http_client_method_t_HTTP_METHOD_GET,
+                    )
+                })?;
+                esp!(unsafe { esp_http_client_set_redirection(self.raw_client) })?;
+
+                let url = self.url()?;
+                self.set_cookie_header(&url)?;
+
+                Ok(self.request_content_len as i32)
+            }
+            Hop::Authenticate => {
+                esp!(unsafe { esp_http_client_add_auth(self.raw_client) })?;
+
+                Ok(0)
+            }
+        }
     }
 
     fn register_handler(
@@ -629,3 +984,1134 @@ impl embedded_svc::http::client::Connection for EspHttpConnection {
         Err(EspError::from_infallible::<ESP_FAIL>().into())
     }
 }
+
+#[cfg(all(not(esp_idf_version_major = "4"), esp_idf_comp_esp_timer_enabled))]
+pub use asynch::*;
+
+#[cfg(all(not(esp_idf_version_major = "4"), esp_idf_comp_esp_timer_enabled))]
+mod asynch {
+    use core::fmt::Write as _;
+    use core::time::Duration;
+
+    use crate::sys::*;
+
+    use crate::io::EspIOError;
+    use crate::timer::{EspAsyncTimer, EspTaskTimerService};
+
+    use super::{EspHttpConnection, Method, State};
+
+    /// The timeout of the native client, which is used when `Configuration::timeout` is not set
+    const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+    /// How long to wait before retrying an operation of the native client which would block
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Whether a result of the native client is `-ESP_ERR_HTTP_EAGAIN`, i.e. the call would block
+    fn would_block(result: i64) -> bool {
+        result == -(ESP_ERR_HTTP_EAGAIN as i64)
+    }
+
+    /// An async HTTP client connection
+    ///
+    /// `EspAsyncHttpConnection` runs the native ESP-IDF HTTP client in its non-blocking
+    /// mode (`is_async`): connecting, sending and receiving return `ESP_ERR_HTTP_EAGAIN`
+    /// (or `ESP_ERR_HTTP_CONNECTING`) instead of waiting for the socket, in which case the
+    /// operation is retried after a short asynchronous delay. Awaiting a request, a read or
+    /// a write therefore does not block the executor, and the connection can be used
+    /// alongside e.g. `AsyncWifi` and `EspAsyncMqttClient`.
+    ///
+    /// `Configuration::timeout` is the time an operation may wait for the socket before
+    /// failing with `ESP_ERR_TIMEOUT`.
+    ///
+    /// Note that the host name resolution done by the native client when connecting
+    /// is still blocking.
+    pub struct EspAsyncHttpConnection {
+        connection: EspHttpConnection,
+        timer: EspAsyncTimer,
+        timeout: Duration,
+    }
+
+    impl EspAsyncHttpConnection {
+        /// Create a new async HTTP connection with the given configuration.
+        ///
+        /// The delays between the retries of the operations which would block
+        /// are timed with a timer of `timer_service`.
+        pub fn new(
+            configuration: &super::Configuration,
+            timer_service: &EspTaskTimerService,
+        ) -> Result<Self, EspError> {
+            Ok(Self {
+                connection: EspHttpConnection::new_raw(configuration, true)?,
+                timer: timer_service.timer_async()?,
+                timeout: configuration.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            })
+        }
+
+        /// Return the underlying connection, e.g. for setting default headers or a cookie jar
+        ///
+        /// The blocking request and response methods of the returned connection must not be used.
+        pub fn connection(&mut self) -> &mut EspHttpConnection {
+            &mut self.connection
+        }
+
+        pub fn status(&self) -> u16 {
+            self.connection.status()
+        }
+
+        pub fn status_message(&self) -> Option<&str> {
+            self.connection.status_message()
+        }
+
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.connection.header(name)
+        }
+
+        pub async fn initiate_request(
+            &mut self,
+            method: Method,
+            uri: &str,
+            headers: &[(&str, &str)],
+        ) -> Result<(), EspError> {
+            // If response data from the previous request remains, subsequent requests may fail
+            if self.is_response_initiated() {
+                self.drain().await?;
+            }
+
+            self.connection.prepare_request(method, uri, headers)?;
+
+            let content_len = self.connection.request_content_len as i32;
+            self.open(content_len).await?;
+
+            self.connection.state = State::Request;
+
+            Ok(())
+        }
+
+        pub fn is_request_initiated(&self) -> bool {
+            self.connection.is_request_initiated()
+        }
+
+        pub async fn initiate_response(&mut self) -> Result<(), EspError> {
+            self.connection.assert_request();
+
+            if self.connection.is_chunked() {
+                // Finish the chunked-encoded stream
+                self.raw_write_all(b"0\r\n\r\n").await?;
+            }
+
+            self.fetch_headers().await?;
+
+            self.connection.state = State::Response;
+
+            Ok(())
+        }
+
+        pub fn is_response_initiated(&self) -> bool {
+            self.connection.is_response_initiated()
+        }
+
+        pub fn split(&mut self) -> (&EspAsyncHttpConnection, &mut Self) {
+            self.connection.assert_response();
+
+            let headers_ptr: *const EspAsyncHttpConnection = self as *const _;
+
+            let headers = unsafe { headers_ptr.as_ref().unwrap() };
+
+            (headers, self)
+        }
+
+        pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+            self.connection.assert_response();
+
+            self.raw_read(buf).await
+        }
+
+        pub async fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+            self.connection.assert_request();
+
+            if buf.is_empty() {
+                Ok(0)
+            } else if self.connection.is_chunked() {
+                // Assume chunked encoding and send the buffer as the next chunk
+                let mut len_buf = heapless::String::<8>::new();
+                write!(&mut len_buf, "{:x}", buf.len()).unwrap();
+
+                self.raw_write_all(len_buf.as_bytes()).await?;
+                self.raw_write_all(b"\r\n").await?;
+
+                self.raw_write_all(buf).await?;
+                self.raw_write_all(b"\r\n").await?;
+
+                Ok(buf.len())
+            } else {
+                self.raw_write(buf).await
+            }
+        }
+
+        pub async fn write_all(&mut self, data: &[u8]) -> Result<(), EspError> {
+            let mut offset = 0;
+
+            while offset < data.len() {
+                offset += self.write(&data[offset..]).await?;
+            }
+
+            Ok(())
+        }
+
+        async fn open(&mut self, content_len: i32) -> Result<(), EspError> {
+            self.poll(|connection| {
+                match unsafe { esp_http_client_open(connection.raw_client, content_len) } {
+                    ESP_ERR_HTTP_CONNECTING | ESP_ERR_HTTP_EAGAIN => Ok(None),
+                    err => esp!(err).map(Some),
+                }
+            })
+            .await
+        }
+
+        async fn fetch_headers(&mut self) -> Result<(), EspError> {
+            self.connection.clear_headers();
+
+            let mut challenged = false;
+
+            loop {
+                self.poll(|connection| {
+                    let result = connection.with_header_handler(|raw_client| unsafe {
+                        esp_http_client_fetch_headers(raw_client)
+                    });
+
+                    if would_block(result) {
+                        Ok(None)
+                    } else {
+                        EspHttpConnection::check(result as _).map(Some)
+                    }
+                })
+                .await?;
+
+                let Some(hop) = self.connection.next_hop(&mut challenged)? else {
+                    break;
+                };
+
+                self.drain().await?;
+
+                let content_len = self.connection.prepare_hop(hop)?;
+                self.open(content_len).await?;
+
+                self.connection.headers.clear();
+            }
+
+            Ok(())
+        }
+
+        /// Read and discard the rest of the current response
+        async fn drain(&mut self) -> Result<(), EspError> {
+            let mut buf = [0_u8; 64];
+
+            while self.raw_read(&mut buf).await? > 0 {}
+
+            Ok(())
+        }
+
+        async fn raw_read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+            self.poll(|connection| {
+                let result = unsafe {
+                    esp_http_client_read(
+                        connection.raw_client,
+                        buf.as_mut_ptr() as _,
+                        buf.len() as _,
+                    )
+                };
+
+                if would_block(result as _) {
+                    Ok(None)
+                } else {
+                    EspHttpConnection::check(result).map(Some)
+                }
+            })
+            .await
+        }
+
+        async fn raw_write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+            // In the non-blocking mode, the native client returns 0 when the socket is not writable
+            self.poll(|connection| {
+                let result = unsafe {
+                    esp_http_client_write(connection.raw_client, buf.as_ptr() as _, buf.len() as _)
+                };
+
+                EspHttpConnection::check(result).map(|len| (len > 0).then_some(len))
+            })
+            .await
+        }
+
+        async fn raw_write_all(&mut self, data: &[u8]) -> Result<(), EspError> {
+            let mut offset = 0;
+
+            while offset < data.len() {
+                offset += self.raw_write(&data[offset..]).await?;
+            }
+
+            Ok(())
+        }
+
+        /// Call `f` until it no longer returns `None` (i.e. "would block"), waiting
+        /// asynchronously between the attempts
+        ///
+        /// Fails with `ESP_ERR_TIMEOUT` if `f` does not complete within the configured timeout.
+        async fn poll<R>(
+            &mut self,
+            mut f: impl FnMut(&mut EspHttpConnection) -> Result<Option<R>, EspError>,
+        ) -> Result<R, EspError> {
+            let started = super::now();
+
+            loop {
+                if let Some(result) = f(&mut self.connection)? {
+                    break Ok(result);
+                }
+
+                if super::now() - started >= self.timeout {
+                    break Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                }
+
+                self.timer.after(POLL_INTERVAL).await?;
+            }
+        }
+    }
+
+    impl embedded_svc::http::Status for EspAsyncHttpConnection {
+        fn status(&self) -> u16 {
+            EspAsyncHttpConnection::status(self)
+        }
+
+        fn status_message(&self) -> Option<&str> {
+            EspAsyncHttpConnection::status_message(self)
+        }
+    }
+
+    impl embedded_svc::http::Headers for EspAsyncHttpConnection {
+        fn header(&self, name: &str) -> Option<&str> {
+            EspAsyncHttpConnection::header(self, name)
+        }
+    }
+
+    impl embedded_svc::io::ErrorType for EspAsyncHttpConnection {
+        type Error = EspIOError;
+    }
+
+    impl embedded_svc::io::asynch::Read for EspAsyncHttpConnection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let size = EspAsyncHttpConnection::read(self, buf).await?;
+
+            Ok(size)
+        }
+    }
+
+    impl embedded_svc::io::asynch::Write for EspAsyncHttpConnection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let size = EspAsyncHttpConnection::write(self, buf).await?;
+
+            Ok(size)
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.connection.assert_request();
+
+            Ok(())
+        }
+    }
+
+    impl embedded_svc::http::client::asynch::Connection for EspAsyncHttpConnection {
+        type Headers = Self;
+
+        type Read = Self;
+
+        type RawConnectionError = EspIOError;
+
+        type RawConnection = Self;
+
+        async fn initiate_request<'a>(
+            &'a mut self,
+            method: Method,
+            uri: &'a str,
+            headers: &'a [(&'a str, &'a str)],
+        ) -> Result<(), Self::Error> {
+            EspAsyncHttpConnection::initiate_request(self, method, uri, headers)
+                .await
+                .map_err(EspIOError)
+        }
+
+        fn is_request_initiated(&self) -> bool {
+            EspAsyncHttpConnection::is_request_initiated(self)
+        }
+
+        async fn initiate_response(&mut self) -> Result<(), Self::Error> {
+            EspAsyncHttpConnection::initiate_response(self)
+                .await
+                .map_err(EspIOError)
+        }
+
+        fn is_response_initiated(&self) -> bool {
+            EspAsyncHttpConnection::is_response_initiated(self)
+        }
+
+        fn split(&mut self) -> (&Self::Headers, &mut Self::Read) {
+            EspAsyncHttpConnection::split(self)
+        }
+
+        fn raw_connection(&mut self) -> Result<&mut Self::RawConnection, Self::Error> {
+            Err(EspError::from_infallible::<ESP_FAIL>().into())
+        }
+    }
+}
+
+/// The key of the connections in an `EspHttpConnectionPool`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl PoolKey {
+    /// Extract the scheme, host and port of an absolute `http` or `https` URI
+    ///
+    /// The default port of the scheme is used if the URI does not specify one.
+    pub fn from_uri(uri: &str) -> Result<Self, EspError> {
+        let (scheme, rest) = uri
+            .split_once("://")
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let scheme = scheme.to_ascii_lowercase();
+
+        let default_port = match scheme.as_str() {
+            "http" => 80,
+            "https" => 443,
+            _ => return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+        };
+
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+
+        // Strip the user info, if any
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            // IPv6 literal
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+            (host, rest.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+            None => default_port,
+        };
+
+        Ok(Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PoolConfiguration {
+    /// The maximum number of idle connections kept per scheme, host and port
+    pub max_idle_per_host: usize,
+    /// The maximum number of idle connections kept in total
+    pub max_idle: usize,
+    /// Idle connections older than this are closed instead of being reused
+    pub idle_timeout: core::time::Duration,
+}
+
+impl Default for PoolConfiguration {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 2,
+            max_idle: 4,
+            idle_timeout: core::time::Duration::from_secs(60),
+        }
+    }
+}
+
+struct IdleConnection {
+    key: PoolKey,
+    connection: EspHttpConnection,
+    idle_since: core::time::Duration,
+}
+
+/// A pool of keep-alive HTTP connections, keyed by scheme, host and port
+///
+/// The ESP-IDF HTTP client reuses its underlying (TLS) session as long as consecutive
+/// requests go to the same host and the previous response was fully consumed.
+/// The pool keeps up to `PoolConfiguration::max_idle_per_host` idle `EspHttpConnection`s
+/// per endpoint around, so that applications talking to several endpoints avoid a new
+/// TCP connection and TLS handshake per request.
+///
+/// The server might close an idle connection at any time. As the native client does not
+/// expose its socket for probing it, a request failing on a reused connection before any
+/// response was received is re-sent once on a new connection, provided it has no body.
+///
+/// Connections are returned to the pool when the `PooledHttpConnection` handed out by
+/// `EspHttpConnectionPool::get` is dropped, provided that they pass a health check:
+/// the request must have been completed, the rest of the response is drained, and
+/// the server must not have asked for the connection to be closed. A connection used
+/// for a request to another endpoint (or redirected to one) is pooled under the key
+/// of that endpoint.
+pub struct EspHttpConnectionPool {
+    configuration: Configuration,
+    pool_configuration: PoolConfiguration,
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+impl EspHttpConnectionPool {
+    /// Create a new pool creating its connections with the given configuration
+    ///
+    /// Keep-alive is always enabled for the pooled connections.
+    pub fn new(configuration: &Configuration, pool_configuration: &PoolConfiguration) -> Self {
+        Self {
+            configuration: Configuration {
+                keep_alive_enable: true,
+                ..*configuration
+            },
+            pool_configuration: *pool_configuration,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Get a connection for the scheme, host and port of the URI
+    ///
+    /// An idle connection to the same endpoint is reused, if available.
+    /// Otherwise, a new connection is created.
+    pub fn get(&self, uri: &str) -> Result<PooledHttpConnection<'_>, EspError> {
+        let key = PoolKey::from_uri(uri)?;
+
+        let idle = {
+            let mut idle = self.idle.lock();
+
+            self.evict_expired(&mut idle);
+
+            idle.iter()
+                .rposition(|idle| idle.key == key)
+                .map(|index| idle.remove(index))
+        };
+
+        let connection = if let Some(idle) = idle {
+            debug!(
+                "Reusing pooled connection to {}://{}:{}",
+                key.scheme, key.host, key.port
+            );
+
+            let mut connection = idle.connection;
+            connection.pooled = true;
+
+            connection
+        } else {
+            debug!(
+                "Creating a new connection to {}://{}:{}",
+                key.scheme, key.host, key.port
+            );
+
+            EspHttpConnection::new(&self.configuration)?
+        };
+
+        Ok(PooledHttpConnection {
+            pool: self,
+            key,
+            connection: Some(connection),
+        })
+    }
+
+    /// Return the number of idle connections in the pool
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    /// Close the idle connections which exceeded the idle timeout
+    pub fn evict_idle(&self) {
+        self.evict_expired(&mut self.idle.lock());
+    }
+
+    /// Close all idle connections
+    pub fn clear(&self) {
+        self.idle.lock().clear();
+    }
+
+    fn release(&self, key: PoolKey, mut connection: EspHttpConnection) {
+        connection.pooled = false;
+
+        if !Self::is_reusable(&mut connection) {
+            debug!(
+                "Closing connection to {}://{}:{}",
+                key.scheme, key.host, key.port
+            );
+
+            return;
+        }
+
+        // The connection might have been used for (or redirected to) another endpoint
+        let Some(key) = Self::endpoint(&connection) else {
+            debug!(
+                "Closing connection to {}://{}:{}",
+                key.scheme, key.host, key.port
+            );
+
+            return;
+        };
+
+        let mut idle = self.idle.lock();
+
+        self.evict_expired(&mut idle);
+
+        if idle.iter().filter(|idle| idle.key == key).count()
+            >= self.pool_configuration.max_idle_per_host
+        {
+            return;
+        }
+
+        if idle.len() >= self.pool_configuration.max_idle {
+            if self.pool_configuration.max_idle == 0 {
+                return;
+            }
+
+            // Make room by closing the connection idle for the longest time
+            idle.remove(0);
+        }
+
+        idle.push(IdleConnection {
+            key,
+            connection,
+            idle_since: now(),
+        });
+    }
+
+    fn is_reusable(connection: &mut EspHttpConnection) -> bool {
+        match connection.state {
+            State::New => true,
+            State::Request => false,
+            State::Response => {
+                let close = connection
+                    .header("Connection")
+                    .map(|value| value.eq_ignore_ascii_case("close"))
+                    .unwrap_or(false);
+
+                !close && connection.flush_response().is_ok()
+            }
+        }
+    }
+
+    /// Return the endpoint the connection is connected to, as per the URI of its last request
+    fn endpoint(connection: &EspHttpConnection) -> Option<PoolKey> {
+        let mut uri = [0_u8; 512];
+
+        esp!(unsafe {
+            esp_http_client_get_url(connection.raw_client, uri.as_mut_ptr() as _, uri.len() as _)
+        })
+        .ok()?;
+
+        PoolKey::from_uri(from_cstr(&uri)).ok()
+    }
+
+    fn evict_expired(&self, idle: &mut Vec<IdleConnection>) {
+        let now = now();
+
+        idle.retain(|idle| now - idle.idle_since < self.pool_configuration.idle_timeout);
+    }
+}
+
+/// A connection borrowed from an `EspHttpConnectionPool`
+///
+/// Dereferences to `EspHttpConnection`, so it can be used directly, or wrapped in an
+/// `embedded_svc::http::client::Client` via `Client::wrap(&mut *connection)`.
+/// The connection is returned to the pool when dropped.
+pub struct PooledHttpConnection<'a> {
+    pool: &'a EspHttpConnectionPool,
+    key: PoolKey,
+    connection: Option<EspHttpConnection>,
+}
+
+impl PooledHttpConnection<'_> {
+    /// Return the scheme, host and port this connection was taken from the pool for
+    pub fn key(&self) -> &PoolKey {
+        &self.key
+    }
+
+    /// Close the connection instead of returning it to the pool
+    pub fn discard(mut self) {
+        self.connection = None;
+    }
+}
+
+impl core::ops::Deref for PooledHttpConnection<'_> {
+    type Target = EspHttpConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl core::ops::DerefMut for PooledHttpConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledHttpConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.release(self.key.clone(), connection);
+        }
+    }
+}
+
+/// A cookie stored in a `CookieJar`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// The domain the cookie is sent to
+    pub domain: String,
+    /// If `true`, the cookie is sent only to `domain`, and not to its sub-domains
+    pub host_only: bool,
+    pub path: String,
+    /// If `true`, the cookie is sent only over HTTPS
+    pub secure: bool,
+    pub http_only: bool,
+    /// The expiry time, as a monotonic time (see `esp_timer_get_time`);
+    /// `None` for session cookies
+    pub expires_at: Option<core::time::Duration>,
+}
+
+/// An in-memory cookie jar
+///
+/// Stores the cookies of `Set-Cookie` response headers and returns the cookies matching
+/// a request URL by domain, path and scheme, as per RFC 6265. The `Max-Age` and `Expires`
+/// attributes are honored; the latter only if the system time is set (e.g. via SNTP).
+/// Otherwise, cookies with an `Expires` attribute are kept as session cookies, except
+/// for expiry dates before 2020, which are commonly used to delete a cookie.
+///
+/// Public suffixes are not checked, so a server could set cookies for e.g. `.com`.
+/// This is acceptable for talking to a known set of servers, but not for general browsing.
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+    max_cookies: usize,
+}
+
+impl CookieJar {
+    /// Create an empty jar keeping at most 32 cookies
+    pub fn new() -> Self {
+        Self::new_with_capacity(32)
+    }
+
+    /// Create an empty jar keeping at most `max_cookies` cookies
+    ///
+    /// When full, the cookie stored first is evicted.
+    pub fn new_with_capacity(max_cookies: usize) -> Self {
+        Self {
+            cookies: Mutex::new(Vec::new()),
+            max_cookies,
+        }
+    }
+
+    /// Store the cookie of a `Set-Cookie` header value received in the response to `url`
+    ///
+    /// Returns `false` if the header is malformed or rejected (e.g. its `Domain` attribute
+    /// does not match the URL). Cookies which already expired are removed from the jar.
+    pub fn store(&self, url: &str, set_cookie: &str) -> bool {
+        let Some((scheme, host, path)) = split_url(url) else {
+            return false;
+        };
+
+        let mut attributes = set_cookie.split(';');
+
+        let Some((name, value)) = attributes.next().and_then(|pair| pair.split_once('=')) else {
+            return false;
+        };
+
+        let name = name.trim();
+        if name.is_empty() {
+            return false;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().trim_matches('"').to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_cookie_path(&path).to_string(),
+            secure: false,
+            http_only: false,
+            expires_at: None,
+        };
+
+        let mut max_age = None;
+        let mut expires = None;
+
+        for attribute in attributes {
+            let (key, value) = attribute
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .unwrap_or((attribute.trim(), ""));
+
+            if key.eq_ignore_ascii_case("Domain") {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+
+                if !domain.is_empty() {
+                    if !domain_matches(&host, &domain) {
+                        return false;
+                    }
+
+                    cookie.host_only = false;
+                    cookie.domain = domain;
+                }
+            } else if key.eq_ignore_ascii_case("Path") {
+                if value.starts_with('/') {
+                    cookie.path = value.to_string();
+                }
+            } else if key.eq_ignore_ascii_case("Secure") {
+                cookie.secure = true;
+            } else if key.eq_ignore_ascii_case("HttpOnly") {
+                cookie.http_only = true;
+            } else if key.eq_ignore_ascii_case("Max-Age") {
+                max_age = value.parse::<i64>().ok();
+            } else if key.eq_ignore_ascii_case("Expires") {
+                expires = parse_http_date(value);
+            }
+        }
+
+        if cookie.secure && scheme != "https" {
+            return false;
+        }
+
+        // Max-Age takes precedence over Expires
+        let lifetime = max_age.or_else(|| expires.and_then(expires_lifetime));
+
+        let expired = lifetime.is_some_and(|lifetime| lifetime <= 0);
+
+        cookie.expires_at = lifetime
+            .filter(|lifetime| *lifetime > 0)
+            .map(|lifetime| now() + core::time::Duration::from_secs(lifetime as _));
+
+        let mut cookies = self.cookies.lock();
+
+        cookies.retain(|other| {
+            !(other.name == cookie.name
+                && other.domain == cookie.domain
+                && other.path == cookie.path)
+        });
+
+        if expired {
+            return true;
+        }
+
+        Self::evict_expired(&mut cookies);
+
+        if cookies.len() >= self.max_cookies {
+            if self.max_cookies == 0 {
+                return false;
+            }
+
+            cookies.remove(0);
+        }
+
+        cookies.push(cookie);
+
+        true
+    }
+
+    /// Return the value of the `Cookie` header for a request to `url`,
+    /// or an empty string if no cookies match
+    pub fn cookie_header(&self, url: &str) -> String {
+        let mut header = String::new();
+
+        let Some((scheme, host, path)) = split_url(url) else {
+            return header;
+        };
+
+        let mut cookies = self.cookies.lock();
+
+        Self::evict_expired(&mut cookies);
+
+        let mut matching = cookies
+            .iter()
+            .filter(|cookie| {
+                let domain_matched = if cookie.host_only {
+                    cookie.domain == host
+                } else {
+                    domain_matches(&host, &cookie.domain)
+                };
+
+                domain_matched
+                    && path_matches(&path, &cookie.path)
+                    && (!cookie.secure || scheme == "https")
+            })
+            .collect::<Vec<_>>();
+
+        // Cookies with longer paths are listed first
+        matching.sort_by_key(|cookie| core::cmp::Reverse(cookie.path.len()));
+
+        for cookie in matching {
+            if !header.is_empty() {
+                header.push_str("; ");
+            }
+
+            header.push_str(&cookie.name);
+            header.push('=');
+            header.push_str(&cookie.value);
+        }
+
+        header
+    }
+
+    /// Return a snapshot of the cookies in the jar
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let mut cookies = self.cookies.lock();
+
+        Self::evict_expired(&mut cookies);
+
+        cookies.clone()
+    }
+
+    /// Remove the cookies with the given name set for the given domain
+    pub fn remove(&self, domain: &str, name: &str) {
+        self.cookies
+            .lock()
+            .retain(|cookie| !(cookie.name == name && cookie.domain.eq_ignore_ascii_case(domain)));
+    }
+
+    /// Remove all cookies
+    pub fn clear(&self) {
+        self.cookies.lock().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn evict_expired(cookies: &mut Vec<Cookie>) {
+        let now = now();
+
+        cookies.retain(|cookie| cookie.expires_at.is_none_or(|expires_at| expires_at > now));
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for CookieJar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CookieJar")
+            .field("cookies", &*self.cookies.lock())
+            .finish()
+    }
+}
+
+fn now() -> core::time::Duration {
+    core::time::Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+/// The lifetime in seconds of a cookie expiring at `expires` (seconds since the UNIX epoch),
+/// or `None` if it cannot be determined because the system time is not set
+fn expires_lifetime(expires: i64) -> Option<i64> {
+    // 2020-01-01T00:00:00Z; an earlier system time means that it was never set
+    const TIME_SET_THRESHOLD: i64 = 1_577_836_800;
+
+    let now = unsafe { time(core::ptr::null_mut()) } as i64;
+
+    if now >= TIME_SET_THRESHOLD {
+        Some(expires - now)
+    } else if expires < TIME_SET_THRESHOLD {
+        // Surely in the past
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// Split an absolute URL into its (lowercased) scheme and host, and its path
+fn split_url(url: &str) -> Option<(String, String, String)> {
+    let key = PoolKey::from_uri(url).ok()?;
+
+    let (_, rest) = url.split_once("://")?;
+
+    let path = rest
+        .find(['/', '?', '#'])
+        .map(|index| &rest[index..])
+        .unwrap_or_default()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default();
+
+    let path = if path.is_empty() { "/" } else { path };
+
+    Some((key.scheme, key.host, path.to_string()))
+}
+
+/// The default cookie path, as per RFC 6265 section 5.1.4
+fn default_cookie_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.parse::<core::net::IpAddr>().is_err()
+            && host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.')))
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || path
+            .strip_prefix(cookie_path)
+            .is_some_and(|rest| cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+/// Parse an HTTP date (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, or with dashes
+/// between the day, month and year) into seconds since the UNIX epoch
+fn parse_http_date(date: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let date = date.split_once(',').map(|(_, date)| date).unwrap_or(date);
+
+    let mut parts = date.split([' ', '-']).filter(|part| !part.is_empty());
+
+    let day: i64 = parts.next()?.parse().ok()?;
+
+    let month = parts.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? as i64 + 1;
+
+    let mut year: i64 = parts.next()?.parse().ok()?;
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<i64>().ok());
+
+    let hours = time.next()??;
+    let minutes = time.next()??;
+    let seconds = time.next()??;
+
+    // Days since the epoch of a proleptic Gregorian date
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_domains() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(domain_matches("www.example.com", "example.com"));
+        assert!(!domain_matches("wwwexample.com", "example.com"));
+        assert!(!domain_matches("example.com", "www.example.com"));
+        assert!(!domain_matches("1.2.3.4", "2.3.4"));
+    }
+
+    #[test]
+    fn matches_paths() {
+        assert!(path_matches("/", "/"));
+        assert!(path_matches("/a/b", "/a"));
+        assert!(path_matches("/a/b", "/a/"));
+        assert!(!path_matches("/ab", "/a"));
+        assert!(!path_matches("/a", "/a/b"));
+
+        assert_eq!(default_cookie_path("/"), "/");
+        assert_eq!(default_cookie_path("/a"), "/");
+        assert_eq!(default_cookie_path("/a/b"), "/a");
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2028 23:59:59 GMT"),
+            Some(1835481599)
+        );
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994"), None);
+        assert_eq!(parse_http_date(""), None);
+    }
+
+    #[test]
+    fn matches_cookies() {
+        let jar = CookieJar::new();
+
+        assert!(jar.store("http://example.com/a/b", "host=1"));
+        assert!(jar.store("http://example.com/", "domain=2; Domain=.example.com"));
+        assert!(jar.store("http://example.com/", "path=3; Path=/c"));
+        assert!(jar.store("https://example.com/", "secure=4; Secure"));
+        assert!(!jar.store("http://example.com/", "insecure=5; Secure"));
+        assert!(!jar.store("http://example.com/", "other=6; Domain=other.com"));
+
+        assert_eq!(
+            jar.cookie_header("http://example.com/a/x"),
+            "host=1; domain=2"
+        );
+        assert_eq!(jar.cookie_header("http://www.example.com/a/x"), "domain=2");
+        assert_eq!(
+            jar.cookie_header("http://example.com/c/d"),
+            "path=3; domain=2"
+        );
+        assert_eq!(
+            jar.cookie_header("https://example.com/"),
+            "domain=2; secure=4"
+        );
+        assert_eq!(jar.cookie_header("http://other.com/"), "");
+
+        // Deleting a cookie
+        assert!(jar.store(
+            "http://example.com/",
+            "domain=; Domain=example.com; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        ));
+        assert!(jar.store("http://example.com/a/b", "host=; Max-Age=0"));
+        assert_eq!(jar.cookie_header("http://example.com/a/x"), "");
+    }
+}
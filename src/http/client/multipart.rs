@@ -0,0 +1,418 @@
+//! Streaming `multipart/form-data` and chunked request bodies
+//!
+//! `Multipart` composes a `multipart/form-data` body out of text fields, byte slices
+//! and `Read` implementations (e.g. a file on flash), and streams it to a connection
+//! without buffering it in memory. If the lengths of all parts are known up front,
+//! the request carries a `Content-Length` header; otherwise it is sent with the
+//! chunked transfer encoding.
+//!
+//! Typical usage:
+//! ```ignore
+//! let mut file = std::fs::File::open("/spiffs/image.jpg")?;
+//! let len = file.metadata()?.len();
+//! let mut file = embedded_io_adapters::std::FromStd::new(file);
+//!
+//! let mut multipart = Multipart::new();
+//! multipart
+//!     .text("device", "camera-1")
+//!     .reader("image", Some("image.jpg"), Some("image/jpeg"), &mut file, Some(len));
+//!
+//! multipart.submit(&mut connection, Method::Post, "http://example.com/upload", &[])?;
+//! connection.initiate_response()?;
+//! ```
+
+use core::fmt::{self, Write as _};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use embedded_svc::http::client::Connection;
+use embedded_svc::http::Method;
+use embedded_svc::io::{Error, ErrorKind, Read, Write};
+
+use crate::sys::esp_random;
+
+const CHUNK_SIZE: usize = 512;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MultipartError<E> {
+    /// Writing to the connection failed
+    Write(E),
+    /// Reading from one of the `Read` parts failed
+    Read(ErrorKind),
+    /// A `Read` part returned a different number of bytes than its declared length
+    LengthMismatch,
+}
+
+impl<E> fmt::Display for MultipartError<E>
+where
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Write(e) => write!(f, "Write error: {e:?}"),
+            Self::Read(kind) => write!(f, "Read error: {kind:?}"),
+            Self::LengthMismatch => write!(f, "Body length does not match the declared length"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for MultipartError<E> where E: fmt::Debug {}
+
+impl<E> Error for MultipartError<E>
+where
+    E: Error,
+{
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Write(e) => e.kind(),
+            Self::Read(kind) => *kind,
+            Self::LengthMismatch => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// `Read` with the error type erased, so that readers of different types can be mixed
+trait ErasedRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind>;
+}
+
+impl<R> ErasedRead for R
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        Read::read(self, buf).map_err(|e| e.kind())
+    }
+}
+
+enum Body<'a> {
+    Bytes(&'a [u8]),
+    Reader(&'a mut dyn ErasedRead, Option<u64>),
+}
+
+impl Body<'_> {
+    fn len(&self) -> Option<u64> {
+        match self {
+            Self::Bytes(data) => Some(data.len() as _),
+            Self::Reader(_, len) => *len,
+        }
+    }
+
+    fn write<W>(&mut self, write: &mut W) -> Result<(), MultipartError<W::Error>>
+    where
+        W: Write,
+    {
+        match self {
+            Self::Bytes(data) => write.write_all(data).map_err(MultipartError::Write),
+            Self::Reader(read, len) => {
+                let written = copy(&mut **read, write)?;
+
+                if len.is_some_and(|len| len != written) {
+                    Err(MultipartError::LengthMismatch)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+struct Part<'a> {
+    /// The boundary delimiter followed by the part headers and the empty line
+    headers: String,
+    body: Body<'a>,
+}
+
+/// A streaming `multipart/form-data` request body
+pub struct Multipart<'a> {
+    boundary: String,
+    parts: Vec<Part<'a>>,
+}
+
+impl<'a> Multipart<'a> {
+    /// Create an empty body with a random boundary
+    pub fn new() -> Self {
+        let mut boundary = String::new();
+
+        write!(
+            &mut boundary,
+            "----EspIdfSvcBoundary{:08x}{:08x}",
+            unsafe { esp_random() },
+            unsafe { esp_random() }
+        )
+        .unwrap();
+
+        Self::new_with_boundary(&boundary)
+    }
+
+    /// Create an empty body with the given boundary
+    ///
+    /// The boundary must not occur in any of the parts.
+    pub fn new_with_boundary(boundary: &str) -> Self {
+        Self {
+            boundary: boundary.into(),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// Return the value of the `Content-Type` header of the request
+    pub fn content_type(&self) -> String {
+        let mut content_type = String::new();
+
+        write!(
+            &mut content_type,
+            "multipart/form-data; boundary={}",
+            self.boundary
+        )
+        .unwrap();
+
+        content_type
+    }
+
+    /// Add a text field
+    pub fn text(&mut self, name: &str, value: &'a str) -> &mut Self {
+        self.bytes(name, None, None, value.as_bytes())
+    }
+
+    /// Add a part with the contents of a byte slice
+    ///
+    /// With a `filename`, the part is a file upload.
+    pub fn bytes(
+        &mut self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        data: &'a [u8],
+    ) -> &mut Self {
+        self.add(name, filename, content_type, Body::Bytes(data))
+    }
+
+    /// Add a part with the contents of a reader, read until its end
+    ///
+    /// If `len` is `None`, the total length of the body cannot be computed,
+    /// and the request is sent with the chunked transfer encoding.
+    pub fn reader<R>(
+        &mut self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        reader: &'a mut R,
+        len: Option<u64>,
+    ) -> &mut Self
+    where
+        R: Read + 'a,
+    {
+        self.add(name, filename, content_type, Body::Reader(reader, len))
+    }
+
+    /// Return the total length of the body, if the lengths of all parts are known
+    pub fn content_length(&self) -> Option<u64> {
+        self.parts.iter().try_fold(self.trailer_len(), |len, part| {
+            Some(len + part.headers.len() as u64 + part.body.len()? + 2)
+        })
+    }
+
+    /// Initiate a request with the `Content-Type` and either the `Content-Length`
+    /// or the `Transfer-Encoding: chunked` headers set, and write the body
+    ///
+    /// The response should then be initiated by the caller.
+    pub fn submit<C>(
+        &mut self,
+        connection: &mut C,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), MultipartError<C::Error>>
+    where
+        C: Connection,
+    {
+        let content_type = self.content_type();
+
+        let mut content_len = String::new();
+
+        let length_header = if let Some(len) = self.content_length() {
+            write!(&mut content_len, "{len}").unwrap();
+
+            ("Content-Length", content_len.as_str())
+        } else {
+            ("Transfer-Encoding", "chunked")
+        };
+
+        let mut all_headers = Vec::with_capacity(headers.len() + 2);
+
+        all_headers.extend(headers.iter().copied().filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Type")
+                && !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+        }));
+        all_headers.push(("Content-Type", content_type.as_str()));
+        all_headers.push(length_header);
+
+        connection
+            .initiate_request(method, uri, &all_headers)
+            .map_err(MultipartError::Write)?;
+
+        self.write_body(connection)
+    }
+
+    /// Write the body
+    ///
+    /// The parts added with `reader` are consumed, so the body can be written only once.
+    pub fn write_body<W>(&mut self, write: &mut W) -> Result<(), MultipartError<W::Error>>
+    where
+        W: Write,
+    {
+        for part in &mut self.parts {
+            write
+                .write_all(part.headers.as_bytes())
+                .map_err(MultipartError::Write)?;
+
+            part.body.write(write)?;
+
+            write.write_all(b"\r\n").map_err(MultipartError::Write)?;
+        }
+
+        for chunk in ["--", self.boundary.as_str(), "--\r\n"] {
+            write
+                .write_all(chunk.as_bytes())
+                .map_err(MultipartError::Write)?;
+        }
+
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        body: Body<'a>,
+    ) -> &mut Self {
+        let mut headers = String::new();
+
+        write!(
+            &mut headers,
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+            self.boundary,
+            Escaped(name)
+        )
+        .unwrap();
+
+        if let Some(filename) = filename {
+            write!(&mut headers, "; filename=\"{}\"", Escaped(filename)).unwrap();
+        }
+
+        headers.push_str("\r\n");
+
+        if let Some(content_type) = content_type {
+            write!(&mut headers, "Content-Type: {content_type}\r\n").unwrap();
+        } else if filename.is_some() {
+            headers.push_str("Content-Type: application/octet-stream\r\n");
+        }
+
+        headers.push_str("\r\n");
+
+        self.parts.push(Part { headers, body });
+
+        self
+    }
+
+    fn trailer_len(&self) -> u64 {
+        (self.boundary.len() + 6) as _
+    }
+}
+
+impl Default for Multipart<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Initiate a request with a body streamed from a reader, and write the body
+///
+/// If `len` is `None`, the body is sent with the chunked transfer encoding, otherwise
+/// it must match the number of bytes the reader returns until its end.
+/// The response should then be initiated by the caller.
+pub fn upload<C, R>(
+    connection: &mut C,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: &mut R,
+    len: Option<u64>,
+) -> Result<(), MultipartError<C::Error>>
+where
+    C: Connection,
+    R: Read,
+{
+    let mut content_len = String::new();
+
+    let length_header = if let Some(len) = len {
+        write!(&mut content_len, "{len}").unwrap();
+
+        ("Content-Length", content_len.as_str())
+    } else {
+        ("Transfer-Encoding", "chunked")
+    };
+
+    let mut all_headers = Vec::with_capacity(headers.len() + 1);
+
+    all_headers.extend(headers.iter().copied().filter(|(name, _)| {
+        !name.eq_ignore_ascii_case("Content-Length")
+            && !name.eq_ignore_ascii_case("Transfer-Encoding")
+    }));
+    all_headers.push(length_header);
+
+    connection
+        .initiate_request(method, uri, &all_headers)
+        .map_err(MultipartError::Write)?;
+
+    Body::Reader(body, len).write(connection)
+}
+
+/// Copy the reader into the writer until the end of the reader, and return the number of bytes copied
+fn copy<W>(read: &mut dyn ErasedRead, write: &mut W) -> Result<u64, MultipartError<W::Error>>
+where
+    W: Write,
+{
+    let mut buf = [0; CHUNK_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let len = read.read(&mut buf).map_err(MultipartError::Read)?;
+        if len == 0 {
+            break Ok(copied);
+        }
+
+        write
+            .write_all(&buf[..len])
+            .map_err(MultipartError::Write)?;
+
+        copied += len as u64;
+    }
+}
+
+/// Escape the quotes and line breaks of a `Content-Disposition` parameter, as browsers do
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("%22")?,
+                '\r' => f.write_str("%0D")?,
+                '\n' => f.write_str("%0A")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
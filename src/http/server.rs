@@ -79,6 +79,14 @@ pub use embedded_svc::utils::http::server::registration::*;
 
 pub use super::*;
 
+pub mod files;
+pub mod middleware;
+pub mod router;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod sse;
+#[cfg(feature = "std")]
+pub mod tunnel;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct KeepAlive {
     pub idle_secs: u32,
@@ -933,6 +941,9 @@ pub struct EspHttpConnection<'a> {
     request: EspHttpRawConnection<'a>,
     headers: Option<UnsafeCell<EspHttpHeaders>>,
     response_headers: Option<Vec<CString>>,
+    path_params: Vec<(String, String)>,
+    extra_response_headers: Vec<CString>,
+    detached: bool,
 }
 
 /// Represents the two-way connection between an HTTP request and its response.
@@ -942,6 +953,9 @@ impl<'a> EspHttpConnection<'a> {
             request: EspHttpRawConnection(raw_req),
             headers: Some(UnsafeCell::new(EspHttpHeaders::new())),
             response_headers: None,
+            path_params: Vec::new(),
+            extra_response_headers: Vec::new(),
+            detached: false,
         }
     }
 
@@ -961,6 +975,67 @@ impl<'a> EspHttpConnection<'a> {
         Method::from(Newtype(self.request.0.method as u32))
     }
 
+    /// Returns the query string of the request URI (without the leading `?`), if any.
+    pub fn query_string(&self) -> Option<&str> {
+        let uri = self.uri();
+        let uri = uri.split_once('#').map(|(uri, _)| uri).unwrap_or(uri);
+
+        uri.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Returns the percent-decoded name-value pairs of the query string, in order.
+    ///
+    /// Pairs which cannot be decoded are skipped.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query_string().map(parse_query).unwrap_or_default()
+    }
+
+    /// Returns the percent-decoded value of the first query parameter with the given name.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of the path parameter with the given name, as matched by a `Router`.
+    pub fn path_param(&self, name: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns all path parameters matched by a `Router`, in the order of the route pattern.
+    pub fn path_params(&self) -> &[(String, String)] {
+        &self.path_params
+    }
+
+    /// Adds a header to the response, before the response is initiated.
+    ///
+    /// This is useful for middleware, which needs to add headers (e.g. CORS ones) to
+    /// a response initiated later by the wrapped handler.
+    /// Note that the number of response headers is limited by `Configuration::max_resp_headers`.
+    pub fn add_response_header(&mut self, name: &str, value: &str) -> Result<(), EspError> {
+        self.assert_request();
+
+        let name = to_cstring_arg(name)?;
+        let value = to_cstring_arg(value)?;
+
+        esp!(unsafe {
+            httpd_resp_set_hdr(
+                self.request.0,
+                name.as_c_str().as_ptr() as _,
+                value.as_c_str().as_ptr() as _,
+            )
+        })?;
+
+        self.extra_response_headers.push(name);
+        self.extra_response_headers.push(value);
+
+        Ok(())
+    }
+
     // Searches for the header of the given name in the HTTP request's headers.
     pub fn header(&self, name: &str) -> Option<&str> {
         self.assert_request();
@@ -1153,6 +1228,11 @@ impl<'a> EspHttpConnection<'a> {
     }
 
     fn complete(&mut self) -> Result<(), EspError> {
+        if self.detached {
+            // The response is continued outside of the handler (e.g. a Server-Sent Events stream)
+            return Ok(());
+        }
+
         let buf = &[];
 
         if self.response_headers.is_some() {
@@ -1221,6 +1301,80 @@ impl<'a> EspHttpConnection<'a> {
     }
 }
 
+/// The name of a method, as sent on the wire (e.g. `GET`)
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Delete => "DELETE",
+        Method::Head => "HEAD",
+        Method::Put => "PUT",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Copy => "COPY",
+        Method::Lock => "LOCK",
+        Method::MkCol => "MKCOL",
+        Method::Move => "MOVE",
+        Method::Propfind => "PROPFIND",
+        Method::Proppatch => "PROPPATCH",
+        Method::Search => "SEARCH",
+        Method::Unlock => "UNLOCK",
+        Method::Bind => "BIND",
+        Method::Rebind => "REBIND",
+        Method::Unbind => "UNBIND",
+        Method::Acl => "ACL",
+        Method::Report => "REPORT",
+        Method::MkActivity => "MKACTIVITY",
+        Method::Checkout => "CHECKOUT",
+        Method::Merge => "MERGE",
+        Method::MSearch => "M-SEARCH",
+        Method::Notify => "NOTIFY",
+        Method::Subscribe => "SUBSCRIBE",
+        Method::Unsubscribe => "UNSUBSCRIBE",
+        Method::Patch => "PATCH",
+        Method::Purge => "PURGE",
+        Method::MkCalendar => "MKCALENDAR",
+        Method::Link => "LINK",
+        Method::Unlink => "UNLINK",
+    }
+}
+
+/// Percent-decode a URI component; returns `None` for malformed escapes or non-UTF-8 results
+fn percent_decode(uri: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(uri.len());
+    let mut bytes = uri.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = core::str::from_utf8(&hex).ok()?;
+
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Parse an `application/x-www-form-urlencoded` string, e.g. a query string
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            Some((
+                percent_decode(&name.replace('+', " "))?,
+                percent_decode(&value.replace('+', " "))?,
+            ))
+        })
+        .collect()
+}
+
 impl RawHandle for EspHttpConnection<'_> {
     type Handle = *mut httpd_req_t;
 
@@ -1302,11 +1456,15 @@ impl<'b> Connection for EspHttpConnection<'b> {
 pub mod ws {
     use core::ffi;
     use core::fmt::Debug;
+    use core::future::poll_fn;
     use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::{Poll, Waker};
 
     extern crate alloc;
     use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
     use alloc::sync::Arc;
+    use alloc::vec::Vec;
 
     use ::log::*;
 
@@ -1324,6 +1482,8 @@ pub mod ws {
     use super::OPEN_SESSIONS;
     use super::{CloseHandler, NativeHandler};
 
+    pub mod hub;
+
     /// A Websocket connection between this server and a client.
     pub enum EspHttpWsConnection {
         New(httpd_handle_t, *mut httpd_req_t),
@@ -1730,379 +1890,443 @@ pub mod ws {
         }
     }
 
-    // TODO: Consider if it makes sense at all to put a complex async layer on top of the ESP-IDF WS API,
-    // which is very far from being async
-    // TODO: Port all of the code below to `zerocopy`, thus simplifying it and providing blocking
-    // sender/receiver/acceptor implementations as well
-
-    // enum ReceiverData {
-    //     None,
-    //     Metadata((FrameType, usize)),
-    //     Data(*mut u8),
-    //     DataCopied,
-    //     Closed,
-    // }
-
-    // unsafe impl Send for ReceiverData {}
-
-    // struct SharedReceiverState {
-    //     waker: Option<Waker>,
-    //     data: ReceiverData,
-    // }
-
-    // struct ConnectionState {
-    //     session: ffi::c_int,
-    //     receiver_state: Arc<Mutex<SharedReceiverState>>,
-    // }
-
-    // pub struct SharedAcceptorState {
-    //     waker: Option<Waker>,
-    //     data: Option<Option<(Arc<Mutex<SharedReceiverState>>, EspHttpWsDetachedSender)>>,
-    // }
-
-    // pub struct EspHttpWsAsyncSender<U> {
-    //     unblocker: U,
-    //     sender: EspHttpWsDetachedSender,
-    // }
-
-    // impl<U> EspHttpWsAsyncSender<U>
-    // where
-    //     U: Unblocker,
-    // {
-    //     pub async fn send(
-    //         &mut self,
-    //         frame_type: FrameType,
-    //         frame_data: &[u8],
-    //     ) -> Result<(), EspError> {
-    //         #[cfg(not(feature = "std"))]
-    //         use alloc::borrow::ToOwned;
-
-    //         debug!(
-    //             "Sending data (frame_type={:?}, frame_len={}) to WS connection {:?}",
-    //             frame_type,
-    //             frame_data.len(),
-    //             self.sender.session()
-    //         );
-
-    //         let mut sender = self.sender.clone();
-    //         let frame_data: alloc::vec::Vec<u8> = frame_data.to_owned();
-
-    //         self.unblocker
-    //             .unblock(move || sender.send(frame_type, &frame_data))
-    //             .await
-    //     }
-    // }
-
-    // impl<U> ErrorType for EspHttpWsAsyncSender<U> {
-    //     type Error = EspError;
-    // }
-
-    // impl<U> asynch::Sender for EspHttpWsAsyncSender<U>
-    // where
-    //     U: Unblocker,
-    // {
-    //     async fn send(
-    //         &mut self,
-    //         frame_type: FrameType,
-    //         frame_data: &[u8],
-    //     ) -> Result<(), Self::Error> {
-    //         EspHttpWsAsyncSender::send(self, frame_type, frame_data).await
-    //     }
-    // }
-
-    // pub struct EspHttpWsAsyncReceiver {
-    //     shared: Arc<Mutex<SharedReceiverState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl EspHttpWsAsyncReceiver {
-    //     pub async fn recv(
-    //         &mut self,
-    //         frame_data_buf: &mut [u8],
-    //     ) -> Result<(FrameType, usize), EspError> {
-    //         AsyncReceiverFuture {
-    //             receiver: self,
-    //             frame_data_buf,
-    //         }
-    //         .await
-    //     }
-    // }
-
-    // struct AsyncReceiverFuture<'a> {
-    //     receiver: &'a mut EspHttpWsAsyncReceiver,
-    //     frame_data_buf: &'a mut [u8],
-    // }
-
-    // impl<'a> Future for AsyncReceiverFuture<'a> {
-    //     type Output = Result<(FrameType, usize), EspError>;
-
-    //     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    //         let frame_data_buf_ptr = self.frame_data_buf.as_mut_ptr();
-    //         let mut shared = self.receiver.shared.lock();
-
-    //         if let ReceiverData::Metadata((frame_type, size)) = shared.data {
-    //             if self.frame_data_buf.len() >= size {
-    //                 shared.data = ReceiverData::Data(frame_data_buf_ptr);
-
-    //                 self.receiver.condvar.notify_all();
-
-    //                 while !matches!(shared.data, ReceiverData::DataCopied) {
-    //                     shared = self.receiver.condvar.wait(shared);
-    //                 }
-
-    //                 shared.data = ReceiverData::None;
-    //                 self.receiver.condvar.notify_all();
-    //             }
-
-    //             Poll::Ready(Ok((frame_type, size)))
-    //         } else if let ReceiverData::Closed = shared.data {
-    //             Poll::Ready(Ok((FrameType::Close, 0)))
-    //         } else {
-    //             shared.waker = Some(cx.waker().clone());
-    //             Poll::Pending
-    //         }
-    //     }
-    // }
-
-    // impl ErrorType for EspHttpWsAsyncReceiver {
-    //     type Error = EspError;
-    // }
-
-    // impl asynch::Receiver for EspHttpWsAsyncReceiver {
-    //     async fn recv(
-    //         &mut self,
-    //         frame_data_buf: &mut [u8],
-    //     ) -> Result<(FrameType, usize), Self::Error> {
-    //         EspHttpWsAsyncReceiver::recv(self, frame_data_buf).await
-    //     }
-    // }
-
-    // pub struct EspHttpWsAsyncAcceptor<U> {
-    //     unblocker: U,
-    //     accept: Arc<Mutex<SharedAcceptorState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl<U> EspHttpWsAsyncAcceptor<U> {
-    //     pub fn accept(&self) -> &EspHttpWsAsyncAcceptor<U> {
-    //         self
-    //     }
-    // }
-
-    // impl<'a, U> Future for &'a EspHttpWsAsyncAcceptor<U>
-    // where
-    //     U: Clone,
-    // {
-    //     type Output = Result<(EspHttpWsAsyncSender<U>, EspHttpWsAsyncReceiver), EspError>;
-
-    //     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    //         let mut accept = self.accept.lock();
-
-    //         match accept.data.take() {
-    //             Some(Some((shared, sender))) => {
-    //                 let sender = EspHttpWsAsyncSender {
-    //                     unblocker: self.unblocker.clone(),
-    //                     sender,
-    //                 };
-
-    //                 let receiver = EspHttpWsAsyncReceiver {
-    //                     shared,
-    //                     condvar: self.condvar.clone(),
-    //                 };
-
-    //                 self.condvar.notify_all();
-
-    //                 Poll::Ready(Ok((sender, receiver)))
-    //             }
-    //             Some(None) => {
-    //                 accept.data = Some(None);
-    //                 Poll::Pending
-    //             }
-    //             None => {
-    //                 accept.waker = Some(cx.waker().clone());
-    //                 Poll::Pending
-    //             }
-    //         }
-    //     }
-    // }
-
-    // impl<U> ErrorType for EspHttpWsAsyncAcceptor<U> {
-    //     type Error = EspError;
-    // }
-
-    // impl<U> asynch::server::Acceptor for EspHttpWsAsyncAcceptor<U>
-    // where
-    //     U: Unblocker + Clone + Send,
-    // {
-    //     type Sender<'a> = EspHttpWsAsyncSender<U> where U: 'a;
-    //     type Receiver<'a> = EspHttpWsAsyncReceiver where U: 'a;
-
-    //     async fn accept(&self) -> Result<(Self::Sender<'_>, Self::Receiver<'_>), Self::Error> {
-    //         self.await
-    //     }
-    // }
-
-    // #[allow(clippy::type_complexity)]
-    // pub struct EspHttpWsProcessor<const N: usize> {
-    //     connections: alloc::vec::Vec<ConnectionState>,
-    //     frame_data_buf: [u8; N],
-    //     accept: Arc<Mutex<SharedAcceptorState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl<const N: usize> EspHttpWsProcessor<N> {
-    //     pub fn new<U>(unblocker: U) -> (Self, EspHttpWsAsyncAcceptor<U>) {
-    //         let this = Self {
-    //             connections: alloc::vec::Vec::new(),
-    //             frame_data_buf: [0_u8; N],
-    //             accept: Arc::new(Mutex::new(SharedAcceptorState {
-    //                 waker: None,
-    //                 data: None,
-    //             })),
-    //             condvar: Arc::new(Condvar::new()),
-    //         };
-
-    //         let acceptor = EspHttpWsAsyncAcceptor {
-    //             unblocker,
-    //             accept: this.accept.clone(),
-    //             condvar: this.condvar.clone(),
-    //         };
-
-    //         (this, acceptor)
-    //     }
-
-    //     pub fn process(&mut self, connection: &mut EspHttpWsConnection) -> Result<(), EspError> {
-    //         if connection.is_new() {
-    //             let session = connection.session();
-
-    //             info!("New WS connection {:?}", session);
-
-    //             self.process_accept(session, connection)?;
-    //         } else if connection.is_closed() {
-    //             let session = connection.session();
-
-    //             if let Some(index) = self
-    //                 .connections
-    //                 .iter()
-    //                 .enumerate()
-    //                 .find_map(|(index, conn)| (conn.session == session).then_some(index))
-    //             {
-    //                 let conn = self.connections.swap_remove(index);
-
-    //                 Self::process_receive_close(&conn.receiver_state);
-    //                 info!("Closed WS connection {:?}", session);
-    //             }
-    //         } else {
-    //             let session = connection.session();
-    //             let (frame_type, len) = connection.recv(&mut self.frame_data_buf)?;
-
-    //             debug!(
-    //                 "Incoming data (frame_type={:?}, frame_len={}) from WS connection {:?}",
-    //                 frame_type, len, session
-    //             );
-
-    //             if let Some(connection) = self
-    //                 .connections
-    //                 .iter()
-    //                 .find(|connection| connection.session == session)
-    //             {
-    //                 self.process_receive(&connection.receiver_state, frame_type, len)
-    //             }
-    //         }
-
-    //         Ok(())
-    //     }
-
-    //     fn process_accept(
-    //         &mut self,
-    //         session: ffi::c_int,
-    //         sender: &EspHttpWsConnection,
-    //     ) -> Result<(), EspError> {
-    //         let receiver_state = Arc::new(Mutex::new(SharedReceiverState {
-    //             waker: None,
-    //             data: ReceiverData::None,
-    //         }));
-
-    //         let state = ConnectionState {
-    //             session,
-    //             receiver_state: receiver_state.clone(),
-    //         };
-
-    //         self.connections.push(state);
-
-    //         let sender = sender.create_detached_sender()?;
-
-    //         let mut accept = self.accept.lock();
-
-    //         accept.data = Some(Some((receiver_state, sender)));
-
-    //         if let Some(waker) = accept.waker.take() {
-    //             waker.wake();
-    //         }
-
-    //         while accept.data.is_some() {
-    //             accept = self.condvar.wait(accept);
-    //         }
-
-    //         Ok(())
-    //     }
-
-    //     fn process_receive(
-    //         &self,
-    //         state: &Mutex<SharedReceiverState>,
-    //         frame_type: FrameType,
-    //         len: usize,
-    //     ) {
-    //         let mut shared = state.lock();
-
-    //         shared.data = ReceiverData::Metadata((frame_type, len));
-
-    //         if let Some(waker) = shared.waker.take() {
-    //             waker.wake();
-    //         }
-
-    //         loop {
-    //             if let ReceiverData::Data(buf) = &shared.data {
-    //                 unsafe { slice::from_raw_parts_mut(*buf, len) }
-    //                     .copy_from_slice(&self.frame_data_buf[..len]);
-    //                 shared.data = ReceiverData::DataCopied;
-    //                 self.condvar.notify_all();
-
-    //                 break;
-    //             }
-
-    //             shared = self.condvar.wait(shared);
-    //         }
-
-    //         while !matches!(shared.data, ReceiverData::None) {
-    //             shared = self.condvar.wait(shared);
-    //         }
-    //     }
-
-    //     fn process_accept_close(&mut self) {
-    //         let mut accept = self.accept.lock();
-
-    //         accept.data = Some(None);
-
-    //         if let Some(waker) = accept.waker.take() {
-    //             waker.wake();
-    //         }
-    //     }
-
-    //     fn process_receive_close(state: &Mutex<SharedReceiverState>) {
-    //         let mut shared = state.lock();
-
-    //         shared.data = ReceiverData::Closed;
-
-    //         if let Some(waker) = shared.waker.take() {
-    //             waker.wake();
-    //         }
-    //     }
-    // }
-
-    // impl<const N: usize> Drop for EspHttpWsProcessor<N> {
-    //     fn drop(&mut self) {
-    //         self.process_accept_close();
-    //     }
-    // }
+    impl<'a> EspHttpServer<'a> {
+        /// Registers an async Websockets handler for a URI and returns the acceptor of its sessions.
+        ///
+        /// Every new Websockets session is handed over as a pair of `EspHttpWsAsyncSender` and
+        /// `EspHttpWsAsyncReceiver` by `EspHttpWsAsyncAcceptor::accept`.
+        ///
+        /// The incoming frames of each session are received by the `httpd` task and queued,
+        /// up to `queue_size` frames per session. The `httpd` task never waits for the receivers,
+        /// so when the queue of a session is full, the frame is dropped and the session is closed.
+        ///
+        /// Frames larger than `max_frame_size` bytes are not received at all: the session
+        /// is closed with the status 1009 (message too big) instead.
+        pub fn ws_handler_async(
+            &mut self,
+            uri: &str,
+            subprotocol_list: Option<&str>,
+            queue_size: usize,
+            max_frame_size: usize,
+        ) -> Result<EspHttpWsAsyncAcceptor, EspError> {
+            let shared = Arc::new(AsyncShared {
+                queue_size: queue_size.max(1),
+                max_frame_size,
+                acceptor: Mutex::new(AcceptorState {
+                    pending: VecDeque::new(),
+                    waker: None,
+                    dropped: false,
+                }),
+                sessions: Mutex::new(Vec::new()),
+            });
+
+            let processor = shared.clone();
+
+            self.ws_handler(uri, subprotocol_list, move |connection| {
+                processor.process(connection)
+            })?;
+
+            Ok(EspHttpWsAsyncAcceptor { shared })
+        }
+    }
+
+    struct AcceptorState {
+        pending: VecDeque<(EspHttpWsDetachedSender, Arc<AsyncSession>)>,
+        waker: Option<Waker>,
+        dropped: bool,
+    }
+
+    struct SessionState {
+        frames: VecDeque<(FrameType, Vec<u8>)>,
+        waker: Option<Waker>,
+        closed: bool,
+        receiver_dropped: bool,
+    }
+
+    struct AsyncSession {
+        session: ffi::c_int,
+        state: Mutex<SessionState>,
+    }
+
+    struct AsyncShared {
+        queue_size: usize,
+        max_frame_size: usize,
+        acceptor: Mutex<AcceptorState>,
+        sessions: Mutex<Vec<Arc<AsyncSession>>>,
+    }
+
+    impl AsyncShared {
+        fn process(&self, connection: &mut EspHttpWsConnection) -> Result<(), EspError> {
+            let session = connection.session();
+
+            if connection.is_new() {
+                let mut acceptor = self.acceptor.lock();
+
+                if acceptor.dropped {
+                    warn!("No acceptor for new WS connection {session}, rejecting");
+
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+
+                let async_session = Arc::new(AsyncSession {
+                    session,
+                    state: Mutex::new(SessionState {
+                        frames: VecDeque::new(),
+                        waker: None,
+                        closed: false,
+                        receiver_dropped: false,
+                    }),
+                });
+
+                let sender = connection.create_detached_sender()?;
+
+                self.sessions.lock().push(async_session.clone());
+
+                acceptor.pending.push_back((sender, async_session));
+
+                if let Some(waker) = acceptor.waker.take() {
+                    waker.wake();
+                }
+
+                info!("New WS connection {session}");
+            } else if connection.is_closed() {
+                let mut sessions = self.sessions.lock();
+
+                if let Some(index) = sessions.iter().position(|s| s.session == session) {
+                    let async_session = sessions.swap_remove(index);
+
+                    let mut state = async_session.state.lock();
+
+                    state.closed = true;
+
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+
+                    info!("Closed WS connection {session}");
+                }
+            } else {
+                let (frame_type, len) = connection.recv(&mut [])?;
+
+                if len > self.max_frame_size {
+                    warn!("Frame of {len} bytes from WS connection {session} is too big, closing");
+
+                    // Status 1009: message too big
+                    connection.send(FrameType::Close, &1009_u16.to_be_bytes())?;
+
+                    if let EspHttpWsConnection::Receiving(sd, _, _) = connection {
+                        unsafe {
+                            httpd_sess_trigger_close(*sd, session);
+                        }
+                    }
+
+                    return Ok(());
+                }
+
+                let mut frame_data = alloc::vec![0; len];
+                connection.recv(&mut frame_data)?;
+
+                debug!("Incoming data (frame_type={frame_type:?}, frame_len={len}) from WS connection {session}");
+
+                let async_session = self
+                    .sessions
+                    .lock()
+                    .iter()
+                    .find(|s| s.session == session)
+                    .cloned();
+
+                if let Some(async_session) = async_session {
+                    let mut state = async_session.state.lock();
+
+                    if state.receiver_dropped || state.closed {
+                        return Ok(());
+                    }
+
+                    if state.frames.len() >= self.queue_size {
+                        warn!("WS connection {session} is not received fast enough, closing");
+
+                        if let EspHttpWsConnection::Receiving(sd, _, _) = connection {
+                            unsafe {
+                                httpd_sess_trigger_close(*sd, session);
+                            }
+                        }
+                    } else {
+                        state.frames.push_back((frame_type, frame_data));
+
+                        if let Some(waker) = state.waker.take() {
+                            waker.wake();
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// The acceptor of the sessions of an async Websockets handler, as registered with
+    /// `EspHttpServer::ws_handler_async`
+    ///
+    /// New sessions are rejected once the acceptor is dropped.
+    pub struct EspHttpWsAsyncAcceptor {
+        shared: Arc<AsyncShared>,
+    }
+
+    impl EspHttpWsAsyncAcceptor {
+        /// Waits for a new Websockets session and returns its sender and receiver.
+        pub async fn accept(
+            &self,
+        ) -> Result<(EspHttpWsAsyncSender, EspHttpWsAsyncReceiver), EspError> {
+            poll_fn(|cx| {
+                let mut acceptor = self.shared.acceptor.lock();
+
+                if let Some((sender, session)) = acceptor.pending.pop_front() {
+                    Poll::Ready(Ok((
+                        EspHttpWsAsyncSender(sender),
+                        EspHttpWsAsyncReceiver(session),
+                    )))
+                } else {
+                    acceptor.waker = Some(cx.waker().clone());
+
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+    }
+
+    impl Drop for EspHttpWsAsyncAcceptor {
+        fn drop(&mut self) {
+            let mut acceptor = self.shared.acceptor.lock();
+
+            acceptor.dropped = true;
+            acceptor.waker = None;
+
+            // Close the sessions which were never accepted, as nobody is going to serve them
+            let pending = core::mem::take(&mut acceptor.pending);
+
+            drop(acceptor);
+
+            let mut sessions = self.shared.sessions.lock();
+
+            for (sender, async_session) in pending {
+                {
+                    let mut state = async_session.state.lock();
+
+                    state.receiver_dropped = true;
+                    state.closed = true;
+                    state.frames.clear();
+                    state.waker = None;
+                }
+
+                sessions.retain(|s| !Arc::ptr_eq(s, &async_session));
+
+                unsafe {
+                    httpd_sess_trigger_close(sender.sd, async_session.session);
+                }
+            }
+        }
+    }
+
+    struct AsyncSendState {
+        result: Option<esp_err_t>,
+        waker: Option<Waker>,
+    }
+
+    struct AsyncSendRequest {
+        sender: EspHttpWsDetachedSender,
+        frame_type: FrameType,
+        frame_data: Vec<u8>,
+        state: Arc<Mutex<AsyncSendState>>,
+    }
+
+    /// The sending half of a session accepted by `EspHttpWsAsyncAcceptor`
+    ///
+    /// The frames are sent by the `httpd` task, without blocking the calling task.
+    #[derive(Clone)]
+    pub struct EspHttpWsAsyncSender(EspHttpWsDetachedSender);
+
+    impl EspHttpWsAsyncSender {
+        pub fn session(&self) -> i32 {
+            self.0.session()
+        }
+
+        pub fn is_closed(&self) -> bool {
+            self.0.is_closed()
+        }
+
+        /// Returns a blocking sender for the same session.
+        pub fn detached(&self) -> EspHttpWsDetachedSender {
+            self.0.clone()
+        }
+
+        pub async fn send(
+            &mut self,
+            frame_type: FrameType,
+            frame_data: &[u8],
+        ) -> Result<(), EspError> {
+            if self.0.is_closed() {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+
+            debug!(
+                "Sending data (frame_type={:?}, frame_len={}) to WS connection {}",
+                frame_type,
+                frame_data.len(),
+                self.0.session()
+            );
+
+            let state = Arc::new(Mutex::new(AsyncSendState {
+                result: None,
+                waker: None,
+            }));
+
+            self.queue(frame_type, frame_data, state.clone())?;
+
+            poll_fn(|cx| {
+                let mut state = state.lock();
+
+                if let Some(result) = state.result {
+                    Poll::Ready(esp!(result))
+                } else {
+                    state.waker = Some(cx.waker().clone());
+
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+
+        fn queue(
+            &self,
+            frame_type: FrameType,
+            frame_data: &[u8],
+            state: Arc<Mutex<AsyncSendState>>,
+        ) -> Result<(), EspError> {
+            let request = Box::into_raw(Box::new(AsyncSendRequest {
+                sender: self.0.clone(),
+                frame_type,
+                frame_data: frame_data.to_vec(),
+                state,
+            }));
+
+            let result = esp!(unsafe {
+                httpd_queue_work(self.0.sd, Some(Self::enqueue), request as *mut _)
+            });
+
+            if result.is_err() {
+                // The request was not queued, so it has to be reclaimed here
+                drop(unsafe { Box::from_raw(request) });
+            }
+
+            result
+        }
+
+        extern "C" fn enqueue(arg: *mut ffi::c_void) {
+            let request = unsafe { Box::from_raw(arg as *mut AsyncSendRequest) };
+
+            let ret = if !request.sender.is_closed() {
+                let raw_frame =
+                    EspHttpWsConnection::create_raw_frame(request.frame_type, &request.frame_data);
+
+                unsafe {
+                    httpd_ws_send_frame_async(
+                        request.sender.sd,
+                        request.sender.fd,
+                        &raw_frame as *const _ as *mut _,
+                    )
+                }
+            } else {
+                ESP_FAIL
+            };
+
+            let mut state = request.state.lock();
+
+            state.result = Some(ret);
+
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl ErrorType for EspHttpWsAsyncSender {
+        type Error = EspError;
+    }
+
+    impl asynch::Sender for EspHttpWsAsyncSender {
+        async fn send(
+            &mut self,
+            frame_type: FrameType,
+            frame_data: &[u8],
+        ) -> Result<(), Self::Error> {
+            EspHttpWsAsyncSender::send(self, frame_type, frame_data).await
+        }
+    }
+
+    /// The receiving half of a session accepted by `EspHttpWsAsyncAcceptor`
+    pub struct EspHttpWsAsyncReceiver(Arc<AsyncSession>);
+
+    impl EspHttpWsAsyncReceiver {
+        pub fn session(&self) -> i32 {
+            self.0.session
+        }
+
+        /// Receives a frame from the client.
+        ///
+        /// As with `EspHttpWsConnection::recv`, if the buffer is too small for the frame,
+        /// only the frame type and length are returned and the frame is not consumed.
+        /// Once the session is closed, `FrameType::SocketClose` is returned.
+        pub async fn recv(
+            &mut self,
+            frame_data_buf: &mut [u8],
+        ) -> Result<(FrameType, usize), EspError> {
+            poll_fn(|cx| {
+                let mut state = self.0.state.lock();
+
+                if let Some((frame_type, frame_data)) = state.frames.front() {
+                    let frame_type = *frame_type;
+                    let len = frame_data.len();
+
+                    if frame_data_buf.len() >= len {
+                        frame_data_buf[..len].copy_from_slice(frame_data);
+
+                        state.frames.pop_front();
+                    }
+
+                    Poll::Ready(Ok((frame_type, len)))
+                } else if state.closed {
+                    Poll::Ready(Ok((FrameType::SocketClose, 0)))
+                } else {
+                    state.waker = Some(cx.waker().clone());
+
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+    }
+
+    impl Drop for EspHttpWsAsyncReceiver {
+        fn drop(&mut self) {
+            let mut state = self.0.state.lock();
+
+            state.receiver_dropped = true;
+            state.frames.clear();
+            state.waker = None;
+        }
+    }
+
+    impl ErrorType for EspHttpWsAsyncReceiver {
+        type Error = EspError;
+    }
+
+    impl asynch::Receiver for EspHttpWsAsyncReceiver {
+        async fn recv(
+            &mut self,
+            frame_data_buf: &mut [u8],
+        ) -> Result<(FrameType, usize), Self::Error> {
+            EspHttpWsAsyncReceiver::recv(self, frame_data_buf).await
+        }
+    }
 }
@@ -0,0 +1,540 @@
+//! Static file serving
+//!
+//! `StaticFiles` is a `Handler` serving files either from a directory of the VFS
+//! (e.g. a mounted LittleFS, FAT or SPIFFS partition) or from a table of assets
+//! embedded in the firmware with `include_bytes!`.
+//!
+//! It supports:
+//! - guessing the `Content-Type` from the file extension
+//! - serving a pre-compressed `<file>.gz` variant with `Content-Encoding: gzip`
+//!   when the client accepts it
+//! - `ETag` and `If-None-Match` (304 responses)
+//! - single `Range` requests (206 and 416 responses)
+//! - an SPA fallback, serving the index file for unknown paths without an extension
+//!
+//! Typical usage:
+//! ```ignore
+//! static ASSETS: &[EmbeddedAsset] = &[
+//!     EmbeddedAsset::new("index.html", include_bytes!("../web/index.html")),
+//!     EmbeddedAsset::new("app.js.gz", include_bytes!("../web/app.js.gz")),
+//! ];
+//!
+//! let mut server = EspHttpServer::new(&Configuration {
+//!     uri_match_wildcard: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! server.serve_static("/", StaticFiles::new_embedded(ASSETS, &files::Configuration::default()))?;
+//! ```
+
+use core::fmt::Write as _;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::debug;
+
+use embedded_svc::http::Method;
+
+use crate::sys::*;
+
+use super::{percent_decode, EspHttpConnection, EspHttpServer, Handler};
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    /// The file served for directory paths, and as the SPA fallback
+    pub index: &'static str,
+    /// Serve the index file for paths which do not exist and have no file extension,
+    /// so that client-side routing of single-page applications works on reload
+    pub spa_fallback: bool,
+    /// Serve `<file>.gz` with `Content-Encoding: gzip` instead of `<file>`
+    /// if it exists and the client accepts gzip
+    pub gzip: bool,
+    /// The value of the `Cache-Control` header, if any
+    pub cache_control: Option<&'static str>,
+    /// The size of the chunks the files are read and sent in
+    pub chunk_size: usize,
+}
+
+impl Configuration {
+    pub const fn new() -> Self {
+        Self {
+            index: "index.html",
+            spa_fallback: false,
+            gzip: true,
+            cache_control: Some("no-cache"),
+            chunk_size: 1024,
+        }
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An asset embedded in the firmware
+#[derive(Copy, Clone, Debug)]
+pub struct EmbeddedAsset {
+    /// The path of the asset, relative to the URI prefix the files are served under
+    /// (e.g. `index.html` or `css/app.css.gz`)
+    pub path: &'static str,
+    pub data: &'static [u8],
+}
+
+impl EmbeddedAsset {
+    pub const fn new(path: &'static str, data: &'static [u8]) -> Self {
+        Self { path, data }
+    }
+}
+
+struct Embedded {
+    asset: EmbeddedAsset,
+    etag: String,
+}
+
+enum Source {
+    Embedded(Vec<Embedded>),
+    #[cfg(feature = "std")]
+    Dir(std::path::PathBuf),
+}
+
+enum File<'a> {
+    Embedded(&'a [u8]),
+    #[cfg(feature = "std")]
+    Fs(std::fs::File),
+}
+
+struct Found<'a> {
+    file: File<'a>,
+    len: u64,
+    etag: String,
+    /// The path of the requested file, used to guess the content type
+    path: String,
+    gzipped: bool,
+}
+
+/// A `Handler` serving static files
+///
+/// Cloning is cheap, as the configuration and the asset table are shared.
+#[derive(Clone)]
+pub struct StaticFiles(Arc<(Source, Configuration)>);
+
+impl StaticFiles {
+    /// Serve the files of a directory of the VFS, e.g. `/littlefs/www`
+    #[cfg(feature = "std")]
+    pub fn new_dir<P>(path: P, conf: &Configuration) -> Self
+    where
+        P: Into<std::path::PathBuf>,
+    {
+        Self(Arc::new((Source::Dir(path.into()), conf.clone())))
+    }
+
+    /// Serve assets embedded in the firmware
+    pub fn new_embedded(assets: &'static [EmbeddedAsset], conf: &Configuration) -> Self {
+        let assets = assets
+            .iter()
+            .map(|asset| {
+                let mut etag = String::new();
+                write!(&mut etag, "\"{:016x}\"", fnv1a(asset.data)).unwrap();
+
+                Embedded {
+                    asset: EmbeddedAsset {
+                        path: asset.path.trim_start_matches('/'),
+                        data: asset.data,
+                    },
+                    etag,
+                }
+            })
+            .collect();
+
+        Self(Arc::new((Source::Embedded(assets), conf.clone())))
+    }
+
+    /// Serve the request, with its URI interpreted relative to `uri_prefix`
+    pub fn handle_prefixed(
+        &self,
+        connection: &mut EspHttpConnection<'_>,
+        uri_prefix: &str,
+    ) -> Result<(), EspError> {
+        let (_, conf) = &*self.0;
+
+        let uri = connection.uri();
+        let uri = uri.split(['?', '#']).next().unwrap_or_default();
+
+        let Some(path) = percent_decode(uri) else {
+            return respond(connection, 400, "Bad Request");
+        };
+
+        let path = path
+            .strip_prefix(uri_prefix.trim_end_matches('/'))
+            .unwrap_or(&path)
+            .trim_start_matches('/');
+
+        if path
+            .split(['/', '\\'])
+            .any(|segment| segment == ".." || segment == ".")
+        {
+            return respond(connection, 400, "Bad Request");
+        }
+
+        let accepts_gzip = conf.gzip
+            && connection.header("Accept-Encoding").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|coding| coding.trim().split(';').next() == Some("gzip"))
+            });
+
+        let mut found = self.find(path, accepts_gzip);
+
+        if found.is_none() && conf.spa_fallback {
+            let last = path.rsplit('/').next().unwrap_or_default();
+
+            if !last.contains('.') {
+                found = self.find(conf.index, accepts_gzip);
+            }
+        }
+
+        let Some(found) = found else {
+            debug!("Static file not found: {uri}");
+            return respond(connection, 404, "Not Found");
+        };
+
+        self.serve(connection, found)
+    }
+
+    fn serve(
+        &self,
+        connection: &mut EspHttpConnection<'_>,
+        mut found: Found<'_>,
+    ) -> Result<(), EspError> {
+        let (_, conf) = &*self.0;
+
+        let mut headers: Vec<(&str, &str)> = Vec::with_capacity(8);
+
+        headers.push(("Content-Type", content_type(&found.path)));
+        headers.push(("ETag", &found.etag));
+        headers.push(("Accept-Ranges", "bytes"));
+
+        if let Some(cache_control) = conf.cache_control {
+            headers.push(("Cache-Control", cache_control));
+        }
+
+        if conf.gzip {
+            headers.push(("Vary", "Accept-Encoding"));
+        }
+
+        if found.gzipped {
+            headers.push(("Content-Encoding", "gzip"));
+        }
+
+        if connection
+            .header("If-None-Match")
+            .is_some_and(|value| etag_matches(value, &found.etag))
+        {
+            return connection.initiate_response(304, Some("Not Modified"), &headers);
+        }
+
+        let mut range = connection
+            .header("Range")
+            .and_then(|value| parse_range(value, found.len));
+
+        // A range request is honored only if the resource did not change
+        if connection
+            .header("If-Range")
+            .is_some_and(|value| value.trim() != found.etag)
+        {
+            range = None;
+        }
+
+        let mut content_range = String::new();
+
+        let (start, end) = match range {
+            Some(Some((start, end))) => {
+                write!(&mut content_range, "bytes {start}-{end}/{}", found.len).unwrap();
+
+                headers.push(("Content-Range", &content_range));
+                connection.initiate_response(206, Some("Partial Content"), &headers)?;
+
+                (start, end + 1)
+            }
+            Some(None) => {
+                write!(&mut content_range, "bytes */{}", found.len).unwrap();
+
+                headers.push(("Content-Range", &content_range));
+                connection.initiate_response(416, Some("Range Not Satisfiable"), &headers)?;
+
+                return Ok(());
+            }
+            None => {
+                connection.initiate_response(200, Some("OK"), &headers)?;
+
+                (0, found.len)
+            }
+        };
+
+        if connection.method() == Method::Head {
+            return Ok(());
+        }
+
+        match &mut found.file {
+            File::Embedded(data) => {
+                for chunk in data[start as usize..end as usize].chunks(conf.chunk_size.max(1)) {
+                    connection.write_all(chunk)?;
+                }
+            }
+            #[cfg(feature = "std")]
+            File::Fs(file) => {
+                use std::io::{Read, Seek, SeekFrom};
+
+                use crate::private::common::to_esp_error;
+
+                file.seek(SeekFrom::Start(start)).map_err(to_esp_error)?;
+
+                let mut buf = alloc::vec![0; conf.chunk_size.max(1)];
+                let mut remaining = end - start;
+
+                while remaining > 0 {
+                    let len = (buf.len() as u64).min(remaining) as usize;
+
+                    let len = file.read(&mut buf[..len]).map_err(to_esp_error)?;
+                    if len == 0 {
+                        // The file was truncated in the meantime
+                        break;
+                    }
+
+                    connection.write_all(&buf[..len])?;
+
+                    remaining -= len as u64;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, path: &str, accepts_gzip: bool) -> Option<Found<'_>> {
+        let (source, conf) = &*self.0;
+
+        let path = if path.is_empty() || path.ends_with('/') {
+            let mut index = String::from(path);
+            index.push_str(conf.index);
+            index
+        } else {
+            path.into()
+        };
+
+        if accepts_gzip {
+            let mut gz_path = path.clone();
+            gz_path.push_str(".gz");
+
+            if let Some(mut found) = Self::find_in(source, &gz_path) {
+                found.path = path;
+                found.gzipped = true;
+
+                return Some(found);
+            }
+        }
+
+        if let Some(found) = Self::find_in(source, &path) {
+            return Some(found);
+        }
+
+        // A directory without a trailing slash
+        #[cfg(feature = "std")]
+        if let Source::Dir(dir) = source {
+            if dir.join(&path).is_dir() {
+                let mut index = path;
+                index.push('/');
+
+                return self.find(&index, accepts_gzip);
+            }
+        }
+
+        None
+    }
+
+    fn find_in<'a>(source: &'a Source, path: &str) -> Option<Found<'a>> {
+        match source {
+            Source::Embedded(assets) => assets
+                .iter()
+                .find(|embedded| embedded.asset.path == path)
+                .map(|embedded| Found {
+                    file: File::Embedded(embedded.asset.data),
+                    len: embedded.asset.data.len() as _,
+                    etag: embedded.etag.clone(),
+                    path: path.into(),
+                    gzipped: false,
+                }),
+            #[cfg(feature = "std")]
+            Source::Dir(dir) => {
+                let file = std::fs::File::open(dir.join(path)).ok()?;
+                let metadata = file.metadata().ok()?;
+
+                if !metadata.is_file() {
+                    return None;
+                }
+
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs())
+                    .unwrap_or(0);
+
+                let mut etag = String::new();
+                write!(&mut etag, "\"{:x}-{:x}\"", metadata.len(), modified).unwrap();
+
+                Some(Found {
+                    file: File::Fs(file),
+                    len: metadata.len(),
+                    etag,
+                    path: path.into(),
+                    gzipped: false,
+                })
+            }
+        }
+    }
+}
+
+impl<'r> Handler<EspHttpConnection<'r>> for StaticFiles {
+    type Error = EspError;
+
+    /// Serve the request, with its full URI used as the path of the file
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        self.handle_prefixed(connection, "")
+    }
+}
+
+impl EspHttpServer<'_> {
+    /// Register `files` for the GET and HEAD requests of all URIs under `uri_prefix`
+    ///
+    /// The prefix is stripped from the request URIs before looking up the files.
+    /// Requires `Configuration::uri_match_wildcard` to be enabled.
+    pub fn serve_static(
+        &mut self,
+        uri_prefix: &str,
+        files: StaticFiles,
+    ) -> Result<&mut Self, EspError> {
+        let uri_prefix = String::from(uri_prefix.trim_end_matches('/'));
+
+        let mut uri = uri_prefix.clone();
+        uri.push_str("/*");
+
+        for method in [Method::Get, Method::Head] {
+            let files = files.clone();
+            let uri_prefix = uri_prefix.clone();
+
+            self.fn_handler(&uri, method, move |mut request| {
+                files.handle_prefixed(request.connection(), &uri_prefix)
+            })?;
+        }
+
+        Ok(self)
+    }
+}
+
+fn respond(
+    connection: &mut EspHttpConnection<'_>,
+    status: u16,
+    message: &str,
+) -> Result<(), EspError> {
+    connection.initiate_response(status, Some(message), &[("Content-Type", "text/plain")])?;
+
+    if connection.method() != Method::Head {
+        connection.write_all(message.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Guess the content type from the extension of the path
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "bin" => "application/octet-stream",
+        "webmanifest" => "application/manifest+json",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+
+        candidate == "*" || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+/// Parse a `Range` header with a single byte range
+///
+/// Returns `None` if the header should be ignored (unsupported or multiple ranges),
+/// `Some(None)` if the range is not satisfiable, and `Some(Some((start, end)))` -
+/// with `end` being inclusive - otherwise.
+fn parse_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // The last N bytes
+        let suffix: u64 = end.parse().ok()?;
+
+        (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+    } else {
+        let start: u64 = start.parse().ok()?;
+
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<u64>().ok()?.min(len.saturating_sub(1))
+        };
+
+        (start < len && start <= end).then_some((start, end))
+    };
+
+    Some(range)
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
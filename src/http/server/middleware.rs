@@ -0,0 +1,873 @@
+//! Reusable middleware for `EspHttpServer` handlers
+//!
+//! - `BasicAuth` and `DigestAuth` - HTTP authentication with a credential callback
+//!   (`DigestAuth` only up to ESP-IDF 5)
+//! - `BearerAuth` - bearer token checking with a token callback
+//! - `Cors` - CORS response headers and preflight `OPTIONS` handling
+//! - `RateLimit` - per-client (source IP) request rate limiting
+//!
+//! All of them implement `Middleware`, so they can be composed with a handler
+//! and registered directly or as part of a handler chain:
+//! ```ignore
+//! let cors = Cors::new(CorsConfiguration {
+//!     allowed_origins: vec!["https://example.com".into()],
+//!     ..Default::default()
+//! });
+//!
+//! let auth = BasicAuth::new("device", |user, password| user == "admin" && password == "secret");
+//!
+//! let handler = cors.compose(auth.compose(FnHandler::new(|request| {
+//!     request.into_ok_response()?.write_all(b"Hello")
+//! })));
+//!
+//! server.handler("/api/status", Method::Get, handler)?;
+//! ```
+//!
+//! Note that `Cors` needs to be the outermost middleware so that it can answer
+//! preflight requests without them being rejected by the authentication, and that
+//! the composed handler also needs to be registered for `Method::Options` so that
+//! preflight requests reach it.
+
+use core::fmt::{self, Debug, Write as _};
+use core::net::IpAddr;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::warn;
+
+use embedded_svc::http::Method;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::{method_name, EspHttpConnection, Handler, Middleware};
+
+/// The error returned by the middleware: either the error of the wrapped handler,
+/// or an error sending the response generated by the middleware itself
+#[derive(Debug)]
+pub enum MiddlewareError<E> {
+    Handler(E),
+    Response(EspError),
+}
+
+impl<E> fmt::Display for MiddlewareError<E>
+where
+    E: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handler(e) => write!(f, "Handler error: {e:?}"),
+            Self::Response(e) => write!(f, "Response error: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for MiddlewareError<E> where E: Debug {}
+
+impl<E> From<EspError> for MiddlewareError<E> {
+    fn from(e: EspError) -> Self {
+        Self::Response(e)
+    }
+}
+
+/// HTTP basic authentication
+///
+/// The callback receives the user name and the password of the request and returns
+/// whether they are valid. As the credentials are sent in clear text, basic authentication
+/// should only be used over HTTPS.
+pub struct BasicAuth<F> {
+    challenge: String,
+    credentials: F,
+}
+
+impl<F> BasicAuth<F>
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+{
+    pub fn new(realm: &str, credentials: F) -> Self {
+        Self {
+            challenge: alloc::format!("Basic realm=\"{}\", charset=\"UTF-8\"", Quoted(realm)),
+            credentials,
+        }
+    }
+
+    fn authorized(&self, connection: &EspHttpConnection<'_>) -> bool {
+        let Some(encoded) = connection
+            .header("Authorization")
+            .and_then(|value| strip_scheme(value, "Basic"))
+        else {
+            return false;
+        };
+
+        let Some(decoded) =
+            base64_decode(encoded).and_then(|decoded| String::from_utf8(decoded).ok())
+        else {
+            return false;
+        };
+
+        decoded
+            .split_once(':')
+            .is_some_and(|(user, password)| (self.credentials)(user, password))
+    }
+}
+
+impl<'r, F, H> Middleware<EspHttpConnection<'r>, H> for BasicAuth<F>
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = MiddlewareError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        if self.authorized(connection) {
+            handler.handle(connection).map_err(MiddlewareError::Handler)
+        } else {
+            unauthorized(connection, &self.challenge)
+        }
+    }
+}
+
+/// HTTP digest authentication (RFC 7616), with the `MD5` algorithm and the `auth` quality of protection
+///
+/// The callback receives a user name and returns its password, or `None` if the user is unknown.
+///
+/// The nonces are stateless: they carry their creation time and are signed with a secret
+/// generated when the middleware is created, so they stay valid for `nonce_lifetime`.
+/// As a consequence, nonce counts are not tracked and replays within the nonce lifetime
+/// are not detected.
+///
+/// Not available on ESP-IDF 6 and later, as Mbed TLS 4 no longer offers the MD5 API.
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+pub struct DigestAuth<F> {
+    realm: String,
+    secret: [u32; 4],
+    nonce_lifetime: Duration,
+    credentials: F,
+}
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+impl<F> DigestAuth<F>
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+{
+    pub fn new(realm: &str, credentials: F) -> Self {
+        Self::new_with_nonce_lifetime(realm, Duration::from_secs(300), credentials)
+    }
+
+    pub fn new_with_nonce_lifetime(realm: &str, nonce_lifetime: Duration, credentials: F) -> Self {
+        Self {
+            realm: realm.to_string(),
+            secret: core::array::from_fn(|_| unsafe { esp_random() }),
+            nonce_lifetime,
+            credentials,
+        }
+    }
+
+    fn nonce(&self, timestamp: u64) -> String {
+        let mut nonce = String::new();
+
+        write!(
+            &mut nonce,
+            "{timestamp:016x}{}",
+            Hex(&self.signature(timestamp))
+        )
+        .unwrap();
+
+        nonce
+    }
+
+    fn signature(&self, timestamp: u64) -> [u8; 16] {
+        let mut data = Vec::with_capacity(24);
+
+        data.extend_from_slice(&timestamp.to_be_bytes());
+
+        for word in self.secret {
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+
+        md5(&data)
+    }
+
+    /// Check the `Authorization` header; returns `Ok(())` if authorized, or
+    /// `Err(stale)` otherwise, where `stale` tells that only the nonce has expired
+    fn check(&self, connection: &EspHttpConnection<'_>) -> Result<(), bool> {
+        let params = connection
+            .header("Authorization")
+            .and_then(|value| strip_scheme(value, "Digest"))
+            .map(parse_auth_params)
+            .ok_or(false)?;
+
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(param, _)| param.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+
+        let (Some(username), Some(nonce), Some(uri), Some(response)) = (
+            param("username"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) else {
+            return Err(false);
+        };
+
+        if param("realm") != Some(self.realm.as_str())
+            || param("algorithm").is_some_and(|algorithm| !algorithm.eq_ignore_ascii_case("MD5"))
+            || param("qop") != Some("auth")
+            || uri != connection.uri()
+        {
+            return Err(false);
+        }
+
+        let (Some(nc), Some(cnonce)) = (param("nc"), param("cnonce")) else {
+            return Err(false);
+        };
+
+        let timestamp = nonce
+            .get(..16)
+            .and_then(|timestamp| u64::from_str_radix(timestamp, 16).ok())
+            .ok_or(false)?;
+
+        if !constant_time_eq(nonce.as_bytes(), self.nonce(timestamp).as_bytes()) {
+            return Err(false);
+        }
+
+        let password = (self.credentials)(username).ok_or(false)?;
+
+        let ha1 = md5_hex(&[username, &self.realm, &password]);
+        let ha2 = md5_hex(&[method_name(connection.method()), uri]);
+        let expected = md5_hex(&[&ha1, nonce, nc, cnonce, "auth", &ha2]);
+
+        if !constant_time_eq(
+            response.to_ascii_lowercase().as_bytes(),
+            expected.as_bytes(),
+        ) {
+            return Err(false);
+        }
+
+        if now().saturating_sub(Duration::from_micros(timestamp)) > self.nonce_lifetime {
+            return Err(true);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+impl<'r, F, H> Middleware<EspHttpConnection<'r>, H> for DigestAuth<F>
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = MiddlewareError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        match self.check(connection) {
+            Ok(()) => handler.handle(connection).map_err(MiddlewareError::Handler),
+            Err(stale) => {
+                let challenge = alloc::format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                    Quoted(&self.realm),
+                    self.nonce(now().as_micros() as _),
+                    if stale { ", stale=true" } else { "" }
+                );
+
+                unauthorized(connection, &challenge)
+            }
+        }
+    }
+}
+
+/// Bearer token authentication (RFC 6750)
+///
+/// The callback receives the token of the request and returns whether it is valid.
+pub struct BearerAuth<F> {
+    challenge: String,
+    token: F,
+}
+
+impl<F> BearerAuth<F>
+where
+    F: Fn(&str) -> bool + Send + Sync,
+{
+    pub fn new(realm: &str, token: F) -> Self {
+        Self {
+            challenge: alloc::format!("Bearer realm=\"{}\"", Quoted(realm)),
+            token,
+        }
+    }
+}
+
+impl<'r, F, H> Middleware<EspHttpConnection<'r>, H> for BearerAuth<F>
+where
+    F: Fn(&str) -> bool + Send + Sync,
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = MiddlewareError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        let authorized = connection
+            .header("Authorization")
+            .and_then(|value| strip_scheme(value, "Bearer"))
+            .is_some_and(|token| (self.token)(token));
+
+        if authorized {
+            handler.handle(connection).map_err(MiddlewareError::Handler)
+        } else {
+            unauthorized(connection, &self.challenge)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorsConfiguration {
+    /// The allowed origins (e.g. `https://example.com`); an empty list allows any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    /// The request headers allowed in preflight requests; an empty list allows the requested ones
+    pub allowed_headers: Vec<String>,
+    /// The response headers exposed to the scripts of the origin
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long the browsers may cache the result of a preflight request
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CorsConfiguration {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: alloc::vec![Method::Get, Method::Head, Method::Post],
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+/// Cross-origin resource sharing
+///
+/// Adds the CORS headers to the responses of requests with an allowed `Origin`, and answers
+/// preflight `OPTIONS` requests itself, without calling the wrapped handler.
+pub struct Cors {
+    conf: CorsConfiguration,
+    methods: String,
+}
+
+impl Cors {
+    pub fn new(conf: CorsConfiguration) -> Self {
+        let mut methods = String::new();
+
+        for method in &conf.allowed_methods {
+            if !methods.is_empty() {
+                methods.push_str(", ");
+            }
+
+            methods.push_str(method_name(*method));
+        }
+
+        Self { conf, methods }
+    }
+
+    fn allowed_origin<'o>(&self, origin: &'o str) -> Option<&'o str> {
+        if self.conf.allowed_origins.is_empty() {
+            Some(if self.conf.allow_credentials {
+                origin
+            } else {
+                "*"
+            })
+        } else {
+            self.conf
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+                .then_some(origin)
+        }
+    }
+
+    fn add_origin_headers(
+        &self,
+        connection: &mut EspHttpConnection<'_>,
+        origin: &str,
+    ) -> Result<(), EspError> {
+        connection.add_response_header("Access-Control-Allow-Origin", origin)?;
+
+        if origin != "*" {
+            connection.add_response_header("Vary", "Origin")?;
+        }
+
+        if self.conf.allow_credentials {
+            connection.add_response_header("Access-Control-Allow-Credentials", "true")?;
+        }
+
+        Ok(())
+    }
+
+    fn preflight(
+        &self,
+        connection: &mut EspHttpConnection<'_>,
+        origin: &str,
+    ) -> Result<(), EspError> {
+        let Some(origin) = self.allowed_origin(origin) else {
+            connection.initiate_response(403, Some("Forbidden"), &[])?;
+
+            return Ok(());
+        };
+
+        let origin = origin.to_string();
+        let requested_headers = connection
+            .header("Access-Control-Request-Headers")
+            .map(ToString::to_string);
+
+        self.add_origin_headers(connection, &origin)?;
+
+        connection.add_response_header("Access-Control-Allow-Methods", &self.methods)?;
+
+        if !self.conf.allowed_headers.is_empty() {
+            connection.add_response_header(
+                "Access-Control-Allow-Headers",
+                &self.conf.allowed_headers.join(", "),
+            )?;
+        } else if let Some(requested_headers) = requested_headers {
+            connection.add_response_header("Access-Control-Allow-Headers", &requested_headers)?;
+        }
+
+        if let Some(max_age) = self.conf.max_age {
+            connection
+                .add_response_header("Access-Control-Max-Age", &max_age.as_secs().to_string())?;
+        }
+
+        connection.initiate_response(204, Some("No Content"), &[])
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for Cors
+where
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = MiddlewareError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        let Some(origin) = connection.header("Origin").map(ToString::to_string) else {
+            return handler.handle(connection).map_err(MiddlewareError::Handler);
+        };
+
+        if connection.method() == Method::Options
+            && connection.header("Access-Control-Request-Method").is_some()
+        {
+            self.preflight(connection, &origin)?;
+
+            return Ok(());
+        }
+
+        if let Some(origin) = self.allowed_origin(&origin) {
+            let origin = origin.to_string();
+
+            self.add_origin_headers(connection, &origin)?;
+
+            if !self.conf.exposed_headers.is_empty() {
+                connection.add_response_header(
+                    "Access-Control-Expose-Headers",
+                    &self.conf.exposed_headers.join(", "),
+                )?;
+            }
+        }
+
+        handler.handle(connection).map_err(MiddlewareError::Handler)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitConfiguration {
+    /// The number of requests a client can make per `period`, which is also the size of its burst
+    pub requests: u32,
+    pub period: Duration,
+    /// The maximum number of clients tracked; when exceeded, the least recently seen client is forgotten
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        Self {
+            requests: 10,
+            period: Duration::from_secs(1),
+            max_clients: 16,
+        }
+    }
+}
+
+struct Bucket {
+    client: IpAddr,
+    tokens: u32,
+    refilled: Duration,
+    seen: Duration,
+}
+
+/// Per-client request rate limiting, with a token bucket per source IP address
+///
+/// Requests exceeding the rate are answered with 429 and a `Retry-After` header.
+/// Requests whose source address cannot be retrieved are not limited.
+/// Requests tunnelled by `tunnel::EspHttpTunnel` all come from the loopback address,
+/// so their remote clients share a single bucket.
+pub struct RateLimit {
+    conf: RateLimitConfiguration,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(conf: &RateLimitConfiguration) -> Self {
+        Self {
+            conf: *conf,
+            buckets: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take a token from the bucket of the client; returns the time until the next token
+    /// is available if the bucket is empty
+    fn acquire(&self, client: IpAddr, now: Duration) -> Result<(), Duration> {
+        let requests = self.conf.requests.max(1);
+        let interval = self.conf.period / requests;
+
+        let mut buckets = self.buckets.lock();
+
+        let index = if let Some(index) = buckets.iter().position(|bucket| bucket.client == client) {
+            index
+        } else {
+            if buckets.len() >= self.conf.max_clients.max(1) {
+                let oldest = buckets
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, bucket)| bucket.seen)
+                    .map(|(index, _)| index)
+                    .unwrap();
+
+                buckets.swap_remove(oldest);
+            }
+
+            buckets.push(Bucket {
+                client,
+                tokens: requests,
+                refilled: now,
+                seen: now,
+            });
+
+            buckets.len() - 1
+        };
+
+        let bucket = &mut buckets[index];
+
+        bucket.seen = now;
+
+        if !interval.is_zero() {
+            let elapsed = now.saturating_sub(bucket.refilled);
+            let refill = (elapsed.as_micros() / interval.as_micros().max(1)) as u32;
+
+            if refill > 0 {
+                bucket.tokens = bucket.tokens.saturating_add(refill).min(requests);
+                bucket.refilled += interval * refill;
+            }
+
+            if bucket.tokens == requests {
+                bucket.refilled = now;
+            }
+        } else {
+            bucket.tokens = requests;
+        }
+
+        if bucket.tokens > 0 {
+            bucket.tokens -= 1;
+
+            Ok(())
+        } else {
+            Err((bucket.refilled + interval).saturating_sub(now))
+        }
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for RateLimit
+where
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = MiddlewareError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        if let Some(client) = source_ip(connection) {
+            if let Err(retry) = self.acquire(client, now()) {
+                warn!("Rate limit exceeded for {client}");
+
+                let retry = retry.as_secs() + u64::from(retry.subsec_nanos() > 0);
+
+                connection.initiate_response(
+                    429,
+                    Some("Too Many Requests"),
+                    &[
+                        ("Retry-After", &retry.max(1).to_string()),
+                        ("Content-Type", "text/plain"),
+                    ],
+                )?;
+
+                connection.write_all(b"Too Many Requests")?;
+
+                return Ok(());
+            }
+        }
+
+        handler.handle(connection).map_err(MiddlewareError::Handler)
+    }
+}
+
+/// Retrieve the source IP address of the request; IPv4-mapped IPv6 addresses are converted to IPv4
+#[allow(unused_variables)]
+fn source_ip(connection: &mut EspHttpConnection<'_>) -> Option<IpAddr> {
+    let raw = connection.raw_connection().ok()?;
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    if let Ok(addr) = raw.source_ipv6() {
+        return Some(
+            addr.to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(addr)),
+        );
+    }
+
+    #[cfg(esp_idf_lwip_ipv4)]
+    if let Ok(addr) = raw.source_ipv4() {
+        return Some(IpAddr::V4(addr));
+    }
+
+    None
+}
+
+fn unauthorized<E>(
+    connection: &mut EspHttpConnection<'_>,
+    challenge: &str,
+) -> Result<(), MiddlewareError<E>> {
+    connection.initiate_response(
+        401,
+        Some("Unauthorized"),
+        &[
+            ("WWW-Authenticate", challenge),
+            ("Content-Type", "text/plain"),
+        ],
+    )?;
+
+    connection.write_all(b"Unauthorized")?;
+
+    Ok(())
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+/// Strip the (case-insensitive) authentication scheme from an `Authorization` header value
+fn strip_scheme<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    let (name, rest) = value.trim().split_once(' ')?;
+
+    name.eq_ignore_ascii_case(scheme).then(|| rest.trim())
+}
+
+/// Parse the comma-separated `name=value` or `name="quoted value"` parameters of a digest header
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+fn parse_auth_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = params;
+
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+
+        let Some((name, value)) = rest.split_once('=') else {
+            break;
+        };
+
+        let name = name.trim();
+        let value = value.trim_start();
+
+        let mut parsed_value = String::new();
+
+        if let Some(quoted) = value.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+
+            while let Some((index, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, c)) = chars.next() {
+                            parsed_value.push(c);
+                        }
+                    }
+                    '"' => {
+                        end = index + 1;
+                        break;
+                    }
+                    c => parsed_value.push(c),
+                }
+            }
+
+            rest = &quoted[end..];
+        } else {
+            let end = value.find(',').unwrap_or(value.len());
+
+            parsed_value.push_str(value[..end].trim());
+            rest = &value[end..];
+        }
+
+        parsed.push((name.to_string(), parsed_value));
+    }
+
+    parsed
+}
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![0; encoded.len() / 4 * 3 + 3];
+    let mut len = 0;
+
+    let result = unsafe {
+        mbedtls_base64_decode(
+            decoded.as_mut_ptr(),
+            decoded.len(),
+            &mut len,
+            encoded.as_ptr(),
+            encoded.len(),
+        )
+    };
+
+    if result != 0 {
+        return None;
+    }
+
+    decoded.truncate(len);
+
+    Some(decoded)
+}
+
+/// The hex-encoded MD5 digest of the parts joined with `:`
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+fn md5_hex(parts: &[&str]) -> String {
+    let data = parts.join(":");
+
+    let mut hex = String::with_capacity(32);
+    write!(&mut hex, "{}", Hex(&md5(data.as_bytes()))).unwrap();
+
+    hex
+}
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut digest = [0; 16];
+
+    #[cfg(esp_idf_version_major = "4")]
+    let result = unsafe { mbedtls_md5_ret(data.as_ptr(), data.len(), digest.as_mut_ptr()) };
+    #[cfg(not(esp_idf_version_major = "4"))]
+    let result = unsafe { mbedtls_md5(data.as_ptr(), data.len(), digest.as_mut_ptr()) };
+
+    // MD5 over an in-memory buffer cannot fail unless offloaded to a failing accelerator
+    assert_eq!(result, 0);
+
+    digest
+}
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+struct Hex<'a>(&'a [u8]);
+
+#[cfg(not(esp_idf_version_at_least_6_0_0))]
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Escape the quotes and backslashes of a quoted header parameter
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if matches!(c, '"' | '\\') {
+                f.write_char('\\')?;
+            }
+
+            f.write_char(c)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(esp_idf_version_at_least_6_0_0))]
+    fn digest_response() {
+        // RFC 2617, section 3.5
+        let params = parse_auth_params(
+            r#"username="Mufasa", realm="testrealm@host.com", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", uri="/dir/index.html", qop=auth, nc=00000001, cnonce="0a4f113b", response="6629fae49393a05397450978507c4ef1""#,
+        );
+
+        assert_eq!(params.len(), 8);
+        assert_eq!(params[4], ("qop".into(), "auth".into()));
+
+        let ha1 = md5_hex(&["Mufasa", "testrealm@host.com", "Circle Of Life"]);
+        let ha2 = md5_hex(&["GET", "/dir/index.html"]);
+
+        assert_eq!(
+            md5_hex(&[
+                &ha1,
+                "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+                "00000001",
+                "0a4f113b",
+                "auth",
+                &ha2
+            ]),
+            params[7].1
+        );
+    }
+
+    #[test]
+    fn basic_credentials() {
+        assert_eq!(
+            base64_decode("QWxhZGRpbjpvcGVuIHNlc2FtZQ==").unwrap(),
+            b"Aladdin:open sesame"
+        );
+        assert_eq!(strip_scheme("basic  abc ", "Basic"), Some("abc"));
+        assert_eq!(base64_decode("a*"), None);
+    }
+}
@@ -0,0 +1,407 @@
+//! Request routing with path parameters
+//!
+//! The native HTTP server matches handlers on fixed URIs or on wildcard globs only.
+//! `Router` adds a routing layer on top of it, with route patterns like
+//! `/api/devices/{id}/config`:
+//! - `{name}` matches a single path segment
+//! - `{*name}` - allowed only as the last segment - matches the rest of the path
+//!
+//! The matched path parameters are available via `EspHttpConnection::path_param`,
+//! and the query string via `EspHttpConnection::query_param`/`query_pairs`.
+//!
+//! Requests matching a route pattern but not its method are answered with 405
+//! (with an `Allow` header), and requests matching no route with 404, unless a
+//! custom not-found handler is set.
+//!
+//! Typical usage:
+//! ```ignore
+//! let mut router = Router::new("/api");
+//!
+//! router.route("/devices/{id}/config", Method::Get, |mut request| {
+//!     let id = request.connection().path_param("id").unwrap().to_owned();
+//!     let verbose = request.connection().query_param("verbose").is_some();
+//!
+//!     request.into_ok_response()?.write_all(id.as_bytes())
+//! })?;
+//!
+//! let mut server = EspHttpServer::new(&Configuration {
+//!     uri_match_wildcard: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! server.mount(router)?;
+//! ```
+
+use core::fmt::Debug;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::debug;
+
+use embedded_svc::http::Method;
+
+use crate::sys::*;
+
+use super::{method_name, percent_decode, EspHttpConnection, EspHttpServer, Handler, Request};
+
+/// The error type of the route handlers, once erased
+pub type RouteError = Box<dyn Debug + Send>;
+
+type RouteHandler = Box<
+    dyn for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), RouteError> + Send + Sync,
+>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Route {
+    pattern: String,
+    segments: Vec<Segment>,
+    method: Method,
+    handler: RouteHandler,
+}
+
+/// A router dispatching requests by path pattern and method
+///
+/// The router is mounted on an `EspHttpServer` with `EspHttpServer::mount`.
+pub struct Router {
+    prefix: String,
+    routes: Vec<Route>,
+    not_found: Option<RouteHandler>,
+}
+
+impl Router {
+    /// Create a router for the URIs under `prefix` (e.g. `/api`, or an empty string for all URIs)
+    ///
+    /// The route patterns are relative to the prefix.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            routes: Vec::new(),
+            not_found: None,
+        }
+    }
+
+    /// Return the prefix of the router
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Add a route
+    ///
+    /// Routes are matched in the order they are added.
+    /// Returns `ESP_ERR_INVALID_ARG` if the pattern is malformed.
+    pub fn route<E, F>(
+        &mut self,
+        pattern: &str,
+        method: Method,
+        f: F,
+    ) -> Result<&mut Self, EspError>
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug + Send + 'static,
+    {
+        let segments =
+            parse_pattern(pattern).ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            segments,
+            method,
+            handler: Box::new(move |request| f(request).map_err(|e| Box::new(e) as RouteError)),
+        });
+
+        Ok(self)
+    }
+
+    /// Set the handler of the requests which match no route
+    ///
+    /// By default, such requests are answered with a plain 404 response.
+    pub fn not_found<E, F>(&mut self, f: F) -> &mut Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.not_found = Some(Box::new(move |request| {
+            f(request).map_err(|e| Box::new(e) as RouteError)
+        }));
+
+        self
+    }
+
+    /// Return the distinct methods of all routes
+    pub fn methods(&self) -> Vec<Method> {
+        let mut methods = Vec::new();
+
+        for route in &self.routes {
+            if !methods.contains(&route.method) {
+                methods.push(route.method);
+            }
+        }
+
+        methods
+    }
+
+    /// Dispatch the request to the matching route
+    pub fn dispatch(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), RouteError> {
+        let uri = connection.uri();
+        let path = uri.split(['?', '#']).next().unwrap_or_default();
+
+        let Some(path) = path.strip_prefix(self.prefix.as_str()) else {
+            return self.respond_not_found(connection);
+        };
+
+        let path = path.to_string();
+        let method = connection.method();
+
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let Some(params) = match_path(&route.segments, &path) else {
+                continue;
+            };
+
+            if route.method == method {
+                debug!("Request {method:?} {path} matched route {}", route.pattern);
+
+                connection.path_params = params;
+
+                return (route.handler)(Request::wrap(connection));
+            }
+
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if allowed.is_empty() {
+            return self.respond_not_found(connection);
+        }
+
+        let mut allow = String::new();
+
+        for method in allowed {
+            if !allow.is_empty() {
+                allow.push_str(", ");
+            }
+
+            allow.push_str(method_name(method));
+        }
+
+        connection
+            .initiate_response(
+                405,
+                Some("Method Not Allowed"),
+                &[("Allow", &allow), ("Content-Type", "text/plain")],
+            )
+            .map_err(|e| Box::new(e) as RouteError)?;
+
+        connection
+            .write_all(b"Method Not Allowed")
+            .map_err(|e| Box::new(e) as RouteError)
+    }
+
+    fn respond_not_found(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), RouteError> {
+        if let Some(not_found) = self.not_found.as_ref() {
+            return not_found(Request::wrap(connection));
+        }
+
+        connection
+            .initiate_response(404, Some("Not Found"), &[("Content-Type", "text/plain")])
+            .map_err(|e| Box::new(e) as RouteError)?;
+
+        connection
+            .write_all(b"Not Found")
+            .map_err(|e| Box::new(e) as RouteError)
+    }
+}
+
+impl<'r> Handler<EspHttpConnection<'r>> for Router {
+    type Error = RouteError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        self.dispatch(connection)
+    }
+}
+
+struct SharedRouter(Arc<Router>);
+
+impl<'r> Handler<EspHttpConnection<'r>> for SharedRouter {
+    type Error = RouteError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        self.0.dispatch(connection)
+    }
+}
+
+impl EspHttpServer<'_> {
+    /// Register the router for the URIs under its prefix, for all methods used by its routes
+    ///
+    /// Requires `Configuration::uri_match_wildcard` to be enabled.
+    ///
+    /// Note that requests with a method which is not used by any of the routes are answered
+    /// by the native server itself with a 405 response (without an `Allow` header).
+    pub fn mount(&mut self, router: Router) -> Result<&mut Self, EspError> {
+        let methods = router.methods();
+        let router = Arc::new(router);
+
+        let mut wildcard = router.prefix.clone();
+        wildcard.push_str("/*");
+
+        for method in methods {
+            if !router.prefix.is_empty() {
+                self.handler(&router.prefix, method, SharedRouter(router.clone()))?;
+            }
+
+            self.handler(&wildcard, method, SharedRouter(router.clone()))?;
+        }
+
+        Ok(self)
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Option<Vec<Segment>> {
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+    let mut segments = Vec::new();
+
+    let mut split = pattern.split('/').peekable();
+
+    while let Some(segment) = split.next() {
+        let last = split.peek().is_none();
+
+        let segment = if let Some(name) = segment
+            .strip_prefix('{')
+            .and_then(|segment| segment.strip_suffix('}'))
+        {
+            if let Some(name) = name.strip_prefix('*') {
+                if !last || name.is_empty() {
+                    return None;
+                }
+
+                Segment::Rest(name.to_string())
+            } else if name.is_empty() {
+                return None;
+            } else {
+                Segment::Param(name.to_string())
+            }
+        } else if segment.contains(['{', '}']) {
+            return None;
+        } else {
+            Segment::Literal(segment.to_string())
+        };
+
+        segments.push(segment);
+    }
+
+    Some(segments)
+}
+
+fn match_path(segments: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+
+    let mut params = Vec::new();
+    let mut rest = Some(path);
+
+    for segment in segments {
+        if let Segment::Rest(name) = segment {
+            params.push((name.clone(), percent_decode(rest.unwrap_or_default())?));
+            return Some(params);
+        }
+
+        let (part, remaining) = match rest?.split_once('/') {
+            Some((part, remaining)) => (part, Some(remaining)),
+            None => (rest?, None),
+        };
+
+        rest = remaining;
+
+        match segment {
+            Segment::Literal(literal) => {
+                if literal != part {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                if part.is_empty() {
+                    return None;
+                }
+
+                params.push((name.clone(), percent_decode(part)?));
+            }
+            Segment::Rest(_) => unreachable!(),
+        }
+    }
+
+    // A trailing slash is tolerated
+    rest.is_none_or(|rest| rest.is_empty()).then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        match_path(&parse_pattern(pattern).unwrap(), path)
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert_eq!(
+            parse_pattern("/users/{id}/files/{*path}"),
+            Some(vec![
+                Segment::Literal("users".into()),
+                Segment::Param("id".into()),
+                Segment::Literal("files".into()),
+                Segment::Rest("path".into()),
+            ])
+        );
+        assert_eq!(parse_pattern("/"), Some(vec![Segment::Literal("".into())]));
+
+        assert_eq!(parse_pattern("/{*path}/x"), None);
+        assert_eq!(parse_pattern("/{}"), None);
+        assert_eq!(parse_pattern("/{*}"), None);
+        assert_eq!(parse_pattern("/a{id}"), None);
+    }
+
+    #[test]
+    fn matches_paths() {
+        assert_eq!(params("/", "/"), Some(vec![]));
+        assert_eq!(params("/status", "/status"), Some(vec![]));
+        assert_eq!(params("/status", "/status/"), Some(vec![]));
+        assert_eq!(params("/status", "/status/x"), None);
+        assert_eq!(params("/status", "/stat"), None);
+
+        assert_eq!(
+            params("/users/{id}", "/users/a%20b"),
+            Some(vec![("id".into(), "a b".into())])
+        );
+        assert_eq!(params("/users/{id}", "/users/"), None);
+        assert_eq!(params("/users/{id}", "/users"), None);
+        assert_eq!(params("/users/{id}", "/users/%zz"), None);
+
+        assert_eq!(
+            params("/files/{*path}", "/files/a/b.txt"),
+            Some(vec![("path".into(), "a/b.txt".into())])
+        );
+        assert_eq!(
+            params("/files/{*path}", "/files"),
+            Some(vec![("path".into(), "".into())])
+        );
+    }
+
+    #[test]
+    fn names_methods() {
+        assert_eq!(method_name(Method::Get), "GET");
+        assert_eq!(method_name(Method::MkCol), "MKCOL");
+        assert_eq!(method_name(Method::MSearch), "M-SEARCH");
+    }
+}
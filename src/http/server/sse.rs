@@ -0,0 +1,478 @@
+//! Server-Sent Events (SSE)
+//!
+//! A handler turns its response into a `text/event-stream` with
+//! `EspHttpConnection::initiate_sse_response`, and returns. The `httpd` session
+//! is kept open, and the events are sent later - typically from other tasks - with
+//! the returned `EspHttpSseSender`, which is the SSE analogue of `EspHttpWsDetachedSender`.
+//!
+//! The stream is sent with the chunked transfer encoding. It ends when the client
+//! disconnects (see `EspHttpSseSender::is_closed`), when `EspHttpSseSender::close`
+//! is called, or when the last clone of the sender is dropped.
+//!
+//! Typical usage:
+//! ```ignore
+//! let senders = Arc::new(Mutex::new(Vec::new()));
+//!
+//! server.fn_handler("/events", Method::Get, {
+//!     let senders = senders.clone();
+//!
+//!     move |mut request| {
+//!         let sender = request
+//!             .connection()
+//!             .initiate_sse_response(&Configuration::default(), &[])?;
+//!
+//!         senders.lock().unwrap().push(sender);
+//!
+//!         Ok::<_, EspError>(())
+//!     }
+//! })?;
+//!
+//! // In another task
+//! senders.lock().unwrap().retain_mut(|sender| {
+//!     sender
+//!         .send(&SseEvent {
+//!             event: Some("temperature"),
+//!             data: "21.5",
+//!             ..Default::default()
+//!         })
+//!         .is_ok()
+//! });
+//! ```
+
+use core::ffi;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::debug;
+
+use crate::private::mutex::{Condvar, Mutex};
+use crate::sys::*;
+use crate::timer::{EspTaskTimerService, EspTimer};
+
+use super::{EspHttpConnection, OPEN_SESSIONS};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Configuration {
+    /// The interval of the heartbeat comments sent to keep the connection alive
+    /// through proxies and to detect disconnected clients; `None` disables them
+    pub heartbeat: Option<Duration>,
+    /// The reconnection time sent to the client (the `retry` field)
+    pub retry: Option<Duration>,
+    /// How long sending an event waits for the `httpd` task to hand it over to the
+    /// socket; the stream is closed when this elapses, e.g. because the server was stopped
+    pub send_timeout: Duration,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            heartbeat: Some(Duration::from_secs(15)),
+            retry: None,
+            send_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// An event of the stream
+///
+/// Multi-line `data` is sent as multiple `data` fields. The `id` and `event` fields
+/// must not contain line breaks; sending an event with such fields fails with
+/// `ESP_ERR_INVALID_ARG`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SseEvent<'a> {
+    pub id: Option<&'a str>,
+    pub event: Option<&'a str>,
+    pub data: &'a str,
+    pub retry: Option<Duration>,
+}
+
+impl SseEvent<'_> {
+    fn write(&self, buf: &mut String) -> Result<(), EspError> {
+        // A line break would end the field, and let the rest of the value inject other fields
+        if [self.id, self.event]
+            .into_iter()
+            .flatten()
+            .any(|field| field.contains(['\r', '\n']))
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if let Some(id) = self.id {
+            writeln!(buf, "id: {id}").unwrap();
+        }
+
+        if let Some(event) = self.event {
+            writeln!(buf, "event: {event}").unwrap();
+        }
+
+        if let Some(retry) = self.retry {
+            writeln!(buf, "retry: {}", retry.as_millis()).unwrap();
+        }
+
+        for line in self.data.split('\n') {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+
+            writeln!(buf, "data: {line}").unwrap();
+        }
+
+        buf.push('\n');
+
+        Ok(())
+    }
+}
+
+impl EspHttpConnection<'_> {
+    /// Sends the response headers of a Server-Sent Events stream and detaches the
+    /// response from the handler, returning a sender for the events of the stream.
+    ///
+    /// The handler should return after calling this method; the stream continues
+    /// until the client disconnects or the sender is closed or dropped.
+    pub fn initiate_sse_response(
+        &mut self,
+        conf: &Configuration,
+        headers: &[(&str, &str)],
+    ) -> Result<EspHttpSseSender, EspError> {
+        let mut all_headers = Vec::with_capacity(headers.len() + 2);
+
+        all_headers.extend(headers.iter().copied().filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Type")
+                && !name.eq_ignore_ascii_case("Cache-Control")
+        }));
+        all_headers.push(("Content-Type", "text/event-stream"));
+        all_headers.push(("Cache-Control", "no-cache"));
+
+        self.initiate_response(200, Some("OK"), &all_headers)?;
+
+        // Send a first event stream chunk, so that the headers are flushed
+        let mut preamble = String::new();
+
+        if let Some(retry) = conf.retry {
+            writeln!(&mut preamble, "retry: {}\n", retry.as_millis()).unwrap();
+        } else {
+            preamble.push_str(": connected\n\n");
+        }
+
+        self.write_all(preamble.as_bytes())?;
+
+        let sd = self.request.0.handle;
+        let fd = unsafe { httpd_req_to_sockfd(self.request.0) };
+
+        let closed = OPEN_SESSIONS
+            .lock()
+            .entry((sd as u32, fd))
+            .or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone();
+
+        let stream = Arc::new(SseStream {
+            session: SseSession { sd, fd, closed },
+            heartbeat: Mutex::new(None),
+            send_timeout: conf.send_timeout,
+        });
+
+        if let Some(interval) = conf.heartbeat {
+            let session = stream.session.clone();
+
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                if session.closed.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                // Fire and forget, as the timer task should not block
+                let _ = session.queue(chunk(b": heartbeat\n\n"), None);
+            })?;
+
+            timer.every(interval)?;
+
+            *stream.heartbeat.lock() = Some(timer);
+        }
+
+        self.detached = true;
+
+        debug!("SSE stream started on session {fd}");
+
+        Ok(EspHttpSseSender(stream))
+    }
+}
+
+/// A sender of the events of a Server-Sent Events stream
+///
+/// The sender can be cloned and sent to other tasks. Note that sending blocks until the
+/// event is handed over to the socket by the `httpd` task (or until
+/// `Configuration::send_timeout` elapses), so it must not be used from within a
+/// request handler of the same server.
+#[derive(Clone)]
+pub struct EspHttpSseSender(Arc<SseStream>);
+
+impl EspHttpSseSender {
+    /// Returns the internal file descriptor of the socket of the stream.
+    pub fn session(&self) -> i32 {
+        self.0.session.fd
+    }
+
+    /// Returns `true` when the client has disconnected or the stream has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.0.session.closed.load(Ordering::SeqCst)
+    }
+
+    /// Sends an event.
+    ///
+    /// Fails with `ESP_ERR_INVALID_ARG` if the `id` or the `event` of the event
+    /// contains a line break.
+    pub fn send(&mut self, event: &SseEvent<'_>) -> Result<(), EspError> {
+        let mut buf = String::new();
+        event.write(&mut buf)?;
+
+        self.send_raw(buf.as_bytes())
+    }
+
+    /// Sends an event of the default (`message`) type, with the given data.
+    pub fn send_data(&mut self, data: &str) -> Result<(), EspError> {
+        self.send(&SseEvent {
+            data,
+            ..Default::default()
+        })
+    }
+
+    /// Sends a comment, which is ignored by the client.
+    pub fn send_comment(&mut self, comment: &str) -> Result<(), EspError> {
+        let mut buf = String::new();
+
+        for line in comment.split('\n') {
+            writeln!(&mut buf, ": {}", line.strip_suffix('\r').unwrap_or(line)).unwrap();
+        }
+
+        buf.push('\n');
+
+        self.send_raw(buf.as_bytes())
+    }
+
+    /// Ends the stream and closes the session.
+    pub fn close(&mut self) -> Result<(), EspError> {
+        self.0.session.end()
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<(), EspError> {
+        let session = &self.0.session;
+
+        if session.closed.load(Ordering::SeqCst) {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let completion = Arc::new(Completion {
+            result: Mutex::new(None),
+            condvar: Condvar::new(),
+        });
+
+        session.queue(chunk(data), Some(completion.clone()))?;
+
+        let mut result = completion.result.lock();
+
+        while result.is_none() {
+            let (guard, timed_out) = completion.condvar.wait_timeout(result, self.0.send_timeout);
+            result = guard;
+
+            if timed_out && result.is_none() {
+                // The `httpd` task is stuck or gone, so give up on the stream
+                session.closed.store(true, Ordering::SeqCst);
+
+                unsafe {
+                    httpd_sess_trigger_close(session.sd, session.fd);
+                }
+
+                return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+            }
+        }
+
+        esp!((*result).unwrap())
+    }
+}
+
+unsafe impl Send for EspHttpSseSender {}
+unsafe impl Sync for EspHttpSseSender {}
+
+struct SseStream {
+    session: SseSession,
+    heartbeat: Mutex<Option<EspTimer<'static>>>,
+    send_timeout: Duration,
+}
+
+impl Drop for SseStream {
+    fn drop(&mut self) {
+        // Stop the heartbeats before ending the stream
+        *self.heartbeat.lock() = None;
+
+        let _ = self.session.end();
+    }
+}
+
+#[derive(Clone)]
+struct SseSession {
+    sd: httpd_handle_t,
+    fd: ffi::c_int,
+    closed: Arc<AtomicBool>,
+}
+
+unsafe impl Send for SseSession {}
+
+impl SseSession {
+    /// Send the last chunk of the response and close the session
+    fn end(&self) -> Result<(), EspError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        debug!("SSE stream on session {} ended", self.fd);
+
+        let work = Box::new(SendWork {
+            session: self.clone(),
+            data: b"0\r\n\r\n".to_vec(),
+            completion: None,
+            close: true,
+        });
+
+        Self::queue_work(self.sd, work)
+    }
+
+    fn queue(&self, data: Vec<u8>, completion: Option<Arc<Completion>>) -> Result<(), EspError> {
+        let work = Box::new(SendWork {
+            session: self.clone(),
+            data,
+            completion,
+            close: false,
+        });
+
+        Self::queue_work(self.sd, work)
+    }
+
+    fn queue_work(sd: httpd_handle_t, work: Box<SendWork>) -> Result<(), EspError> {
+        let work = Box::into_raw(work);
+
+        let result = esp!(unsafe { httpd_queue_work(sd, Some(Self::work), work as *mut _) });
+
+        if result.is_err() {
+            // The work item was not queued, so it has to be reclaimed here
+            drop(unsafe { Box::from_raw(work) });
+        }
+
+        result
+    }
+
+    extern "C" fn work(arg: *mut ffi::c_void) {
+        let work = unsafe { Box::from_raw(arg as *mut SendWork) };
+        let session = &work.session;
+
+        // Data of a stream which was ended in the meantime is dropped,
+        // except for the last chunk which is sent by `end`
+        let result = if session.closed.load(Ordering::SeqCst) && !work.close {
+            ESP_FAIL
+        } else {
+            session.send_all(&work.data)
+        };
+
+        if result != ESP_OK || work.close {
+            session.closed.store(true, Ordering::SeqCst);
+
+            unsafe {
+                httpd_sess_trigger_close(session.sd, session.fd);
+            }
+        }
+
+        if let Some(completion) = work.completion.as_ref() {
+            *completion.result.lock() = Some(result);
+            completion.condvar.notify_all();
+        }
+    }
+
+    fn send_all(&self, mut data: &[u8]) -> esp_err_t {
+        while !data.is_empty() {
+            let sent = unsafe {
+                httpd_socket_send(self.sd, self.fd, data.as_ptr() as *const _, data.len(), 0)
+            };
+
+            if sent <= 0 {
+                return ESP_FAIL;
+            }
+
+            data = &data[sent as usize..];
+        }
+
+        ESP_OK
+    }
+}
+
+struct SendWork {
+    session: SseSession,
+    data: Vec<u8>,
+    completion: Option<Arc<Completion>>,
+    /// Close the session after sending the data
+    close: bool,
+}
+
+struct Completion {
+    result: Mutex<Option<esp_err_t>>,
+    condvar: Condvar,
+}
+
+/// Frame the data as a chunk of the chunked transfer encoding
+fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+
+    let mut len = String::new();
+    write!(&mut len, "{:x}\r\n", data.len()).unwrap();
+
+    chunk.extend_from_slice(len.as_bytes());
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event() {
+        let mut buf = String::new();
+
+        SseEvent {
+            id: Some("7"),
+            event: Some("temperature"),
+            data: "21.5\r\n22.0",
+            retry: None,
+        }
+        .write(&mut buf)
+        .unwrap();
+
+        assert_eq!(buf, "id: 7\nevent: temperature\ndata: 21.5\ndata: 22.0\n\n");
+    }
+
+    #[test]
+    fn line_break_in_fields() {
+        for (id, event) in [
+            (Some("1\ndata: injected"), None),
+            (None, Some("message\r\nid: 2")),
+            (None, Some("message\r")),
+        ] {
+            let mut buf = String::new();
+
+            let result = SseEvent {
+                id,
+                event,
+                data: "x",
+                retry: None,
+            }
+            .write(&mut buf);
+
+            assert_eq!(result.map_err(|e| e.code()), Err(ESP_ERR_INVALID_ARG));
+            assert!(buf.is_empty());
+        }
+    }
+}
@@ -0,0 +1,1568 @@
+//! HTTP reverse tunnel
+//!
+//! Devices behind NAT cannot be reached directly. `EspHttpTunnel` keeps an outbound
+//! connection to a relay server instead - a WebSocket (`EspWebSocketTunnel`) or any byte
+//! stream like a TLS connection (`EspStreamTunnel`) - and serves the HTTP requests which
+//! the relay tunnels over it with the handlers of the local `EspHttpServer`. This gives
+//! remote access to e.g. the device UI without any port forwarding.
+//!
+//! The requests are forwarded to the local server over the loopback interface, so every
+//! handler registered with the server works through the tunnel unchanged. They are
+//! served one at a time, in the order in which the relay sends them; long-lived
+//! responses (Server-Sent Events, WebSocket upgrades) are therefore not supported.
+//! While a request is served, up to 16 frames of the other streams are kept for
+//! later; the streams whose frames do not fit are reset.
+//!
+//! Since the local server sees every tunnelled request coming from `127.0.0.1`,
+//! middleware keyed by the source address of the requests, like
+//! `middleware::RateLimit`, does not apply to the remote clients - they all share
+//! the bucket of the loopback address. Likewise, handlers which trust requests from
+//! the loopback interface are exposed to anyone the relay lets through. The address
+//! of the remote client, as supplied by the relay, is passed in the `X-Forwarded-For`
+//! and `Forwarded` headers instead, which replace any sent by the client itself.
+//!
+//! ## Protocol
+//!
+//! The tunnel carries frames - one WebSocket binary message per frame, or frames prefixed
+//! with their length as a big-endian `u32` over a byte stream. Each frame starts with
+//! a 6-byte header: the frame type (`u8`), flags (`u8`, bit 0 = end of stream) and
+//! the stream ID (`u32`, big-endian), which the relay allocates for each request.
+//! All integers are big-endian, strings are prefixed with their length as a `u16`,
+//! and header lists are prefixed with their count as a `u16`.
+//!
+//! | Type | Frame      | Direction      | Payload                                    |
+//! |------|------------|----------------|--------------------------------------------|
+//! | 1    | `HELLO`    | device → relay | device ID, token (stream ID 0)             |
+//! | 2    | `REQUEST`  | relay → device | method, URI, headers, client address       |
+//! | 3    | `RESPONSE` | device → relay | status (`u16`), headers                    |
+//! | 4    | `DATA`     | both           | a chunk of the request or response body    |
+//! | 5    | `RESET`    | both           | none; aborts the stream                    |
+//! | 6    | `PING`     | relay → device | arbitrary data (stream ID 0)               |
+//! | 7    | `PONG`     | device → relay | the data of the `PING` (stream ID 0)       |
+//!
+//! A request without a body has the end of stream flag set on its `REQUEST` frame,
+//! otherwise the body follows in `DATA` frames, the last of which has the flag set.
+//! The client address is the IP address of the remote client, or empty if unknown.
+//! Responses are sent back the same way. The tunnelled headers exclude the hop-by-hop
+//! ones (`Connection`, `Transfer-Encoding`, ...), and response bodies are always
+//! sent de-chunked.
+//!
+//! Typical usage:
+//! ```ignore
+//! let server = EspHttpServer::new(&Default::default())?;
+//! // ... register the handlers of the device UI
+//!
+//! loop {
+//!     let transport = EspWebSocketTunnel::new(
+//!         "wss://relay.example.com/tunnel",
+//!         &EspWebSocketClientConfig {
+//!             max_message_size: Some(8192),
+//!             ..Default::default()
+//!         },
+//!         Duration::from_secs(10),
+//!     )?;
+//!
+//!     let mut tunnel = EspHttpTunnel::new(
+//!         transport,
+//!         &TunnelConfiguration {
+//!             device_id: "device-1",
+//!             token: Some("secret"),
+//!             ..Default::default()
+//!         },
+//!     );
+//!
+//!     if let Err(e) = tunnel.run() {
+//!         warn!("Tunnel failed: {e}");
+//!     }
+//!
+//!     std::thread::sleep(Duration::from_secs(5));
+//! }
+//! ```
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use std::io::{BufRead, BufReader, Read as _, Write as _};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+
+use ::log::{debug, info, warn};
+
+use crate::io::{ErrorType, EspIOError, Read, Write};
+use crate::private::common::to_esp_error;
+use crate::sys::*;
+
+const HELLO: u8 = 1;
+const REQUEST: u8 = 2;
+const RESPONSE: u8 = 3;
+const DATA: u8 = 4;
+const RESET: u8 = 5;
+const PING: u8 = 6;
+const PONG: u8 = 7;
+
+const FLAG_END: u8 = 0x01;
+
+const MAX_FRAME_SIZE: usize = 65536;
+const MAX_HEAD_SIZE: usize = 4096;
+
+/// The maximum number of frames queued while a request is served
+const MAX_PENDING_FRAMES: usize = 16;
+
+const FORWARDED_HEADERS: &[&str] = &["forwarded", "x-forwarded-for"];
+
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A transport of frames to the relay
+pub trait TunnelTransport {
+    /// Send a frame to the relay
+    fn send(&mut self, frame: &[u8]) -> Result<(), EspError>;
+
+    /// Wait for the next frame from the relay, or return `None` if the relay
+    /// closed the connection
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, EspError>;
+
+    /// Return the ID of a stream some frames of which were dropped because they
+    /// did not fit in the buffer of the transport, so that the stream gets reset
+    fn dropped_stream(&mut self) -> Option<u32> {
+        None
+    }
+}
+
+impl<T> TunnelTransport for &mut T
+where
+    T: TunnelTransport,
+{
+    fn send(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        (**self).send(frame)
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+        (**self).recv()
+    }
+
+    fn dropped_stream(&mut self) -> Option<u32> {
+        (**self).dropped_stream()
+    }
+}
+
+/// A transport of length-prefixed frames over a byte stream, like `tls::EspTls`
+pub struct EspStreamTunnel<T>(T);
+
+impl<T> EspStreamTunnel<T>
+where
+    T: Read + Write + ErrorType<Error = EspIOError>,
+{
+    /// Create a transport over a stream connected to the relay
+    pub const fn new(stream: T) -> Self {
+        Self(stream)
+    }
+
+    /// Return the stream
+    pub fn release(self) -> T {
+        self.0
+    }
+
+    /// Read exactly `buf.len()` bytes, or return `false` if the stream ends before the first one
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<bool, EspError> {
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let len = self.0.read(&mut buf[offset..]).map_err(|e| e.0)?;
+
+            if len == 0 {
+                if offset == 0 {
+                    return Ok(false);
+                }
+
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+            }
+
+            offset += len;
+        }
+
+        Ok(true)
+    }
+}
+
+impl<T> TunnelTransport for EspStreamTunnel<T>
+where
+    T: Read + Write + ErrorType<Error = EspIOError>,
+{
+    fn send(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        self.0
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .map_err(|e| e.0)?;
+        self.0.write_all(frame).map_err(|e| e.0)?;
+        self.0.flush().map_err(|e| e.0)
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+        let mut len = [0; 4];
+
+        if !self.read_exact(&mut len)? {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut frame = vec![0; len];
+        if !self.read_exact(&mut frame)? && len > 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+        }
+
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(all(
+    esp_idf_comp_tcp_transport_enabled,
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_comp_espressif__esp_websocket_client_enabled
+))]
+pub use ws::*;
+
+#[cfg(all(
+    esp_idf_comp_tcp_transport_enabled,
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_comp_espressif__esp_websocket_client_enabled
+))]
+mod ws {
+    use core::time::Duration;
+
+    extern crate alloc;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use crate::io::EspIOError;
+    use crate::private::mutex::{Condvar, Mutex};
+    use crate::sys::*;
+    use crate::ws::client::{EspWebSocketClient, EspWebSocketClientConfig, WebSocketEventType};
+    use crate::ws::FrameType;
+
+    use ::log::warn;
+
+    use super::{Frame, TunnelTransport, MAX_PENDING_FRAMES};
+
+    #[derive(Default)]
+    struct Inbox {
+        frames: VecDeque<Vec<u8>>,
+        dropped: VecDeque<u32>,
+        connected: bool,
+        disconnected: bool,
+    }
+
+    impl Inbox {
+        fn push(&mut self, data: &[u8]) {
+            if self.frames.len() < MAX_PENDING_FRAMES {
+                self.frames.push_back(data.to_vec());
+                return;
+            }
+
+            // The frames of stream 0 (`PING`) can be dropped without further ado
+            let Some(stream) = Frame::stream(data).filter(|stream| *stream != 0) else {
+                return;
+            };
+
+            if self.dropped.contains(&stream) {
+                return;
+            }
+
+            if self.dropped.len() < MAX_PENDING_FRAMES {
+                warn!("Stream {stream}: too many frames queued, dropping");
+                self.dropped.push_back(stream);
+            } else {
+                // The relay keeps flooding the device; give up on the connection
+                warn!("Too many frames queued, dropping the tunnel");
+
+                self.frames.clear();
+                self.dropped.clear();
+                self.disconnected = true;
+            }
+        }
+    }
+
+    struct Shared {
+        inbox: Mutex<Inbox>,
+        changed: Condvar,
+    }
+
+    /// A transport of frames as the binary messages of a WebSocket connection to the relay
+    ///
+    /// `recv` returns `None` when the connection is lost. Unless disabled in the
+    /// configuration, the client reconnects by itself, so the tunnel can be `run` again.
+    ///
+    /// The received frames are queued until `recv` is called, at most 16 of them;
+    /// the streams of the frames which do not fit are reported by `dropped_stream`.
+    /// `recv` also returns `None` if the relay keeps sending frames that do not fit.
+    pub struct EspWebSocketTunnel {
+        client: EspWebSocketClient<'static>,
+        shared: Arc<Shared>,
+        timeout: Duration,
+    }
+
+    impl EspWebSocketTunnel {
+        /// Connect to the relay at `uri`
+        ///
+        /// The configuration must have `max_message_size` set, so that each frame is
+        /// received as a single message.
+        pub fn new(
+            uri: &str,
+            config: &EspWebSocketClientConfig,
+            timeout: Duration,
+        ) -> Result<Self, EspIOError> {
+            if config.max_message_size.is_none() {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>().into());
+            }
+
+            let shared = Arc::new(Shared {
+                inbox: Mutex::new(Inbox::default()),
+                changed: Condvar::new(),
+            });
+
+            let client = EspWebSocketClient::new(uri, config, timeout, {
+                let shared = shared.clone();
+
+                move |event| {
+                    let mut inbox = shared.inbox.lock();
+
+                    match event.as_ref().map(|event| &event.event_type) {
+                        Ok(WebSocketEventType::Connected) => inbox.connected = true,
+                        Ok(WebSocketEventType::Binary(data)) => inbox.push(data),
+                        Ok(WebSocketEventType::Disconnected | WebSocketEventType::Closed) => {
+                            if inbox.connected {
+                                inbox.connected = false;
+                                inbox.disconnected = true;
+                            }
+                        }
+                        _ => return,
+                    }
+
+                    shared.changed.notify_all();
+                }
+            })?;
+
+            Ok(Self {
+                client,
+                shared,
+                timeout,
+            })
+        }
+
+        /// Return the WebSocket client
+        pub fn client(&mut self) -> &mut EspWebSocketClient<'static> {
+            &mut self.client
+        }
+    }
+
+    impl TunnelTransport for EspWebSocketTunnel {
+        fn send(&mut self, frame: &[u8]) -> Result<(), EspError> {
+            {
+                let mut inbox = self.shared.inbox.lock();
+
+                // Wait for the (re)connection to the relay
+                while !inbox.connected {
+                    let (guard, timed_out) = self.shared.changed.wait_timeout(inbox, self.timeout);
+                    inbox = guard;
+
+                    if timed_out && !inbox.connected {
+                        return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                    }
+                }
+            }
+
+            self.client.send(FrameType::Binary(false), frame)
+        }
+
+        fn recv(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+            let mut inbox = self.shared.inbox.lock();
+
+            loop {
+                if let Some(frame) = inbox.frames.pop_front() {
+                    return Ok(Some(frame));
+                }
+
+                if inbox.disconnected {
+                    inbox.disconnected = false;
+                    return Ok(None);
+                }
+
+                inbox = self.shared.changed.wait(inbox);
+            }
+        }
+
+        fn dropped_stream(&mut self) -> Option<u32> {
+            self.shared.inbox.lock().dropped.pop_front()
+        }
+    }
+}
+
+/// Configuration of `EspHttpTunnel`
+#[derive(Clone, Debug)]
+pub struct TunnelConfiguration<'a> {
+    /// The ID with which the device introduces itself to the relay
+    pub device_id: &'a str,
+    /// The secret with which the device authenticates with the relay, if any
+    pub token: Option<&'a str>,
+    /// The port of the local `EspHttpServer`
+    pub local_port: u16,
+    /// The timeout of the connections to the local server
+    pub timeout: Duration,
+    /// The maximum size of the request bodies which have to be buffered because
+    /// the relay did not supply their `Content-Length`
+    pub max_request_size: usize,
+    /// The maximum size of the response body chunks sent to the relay
+    pub chunk_size: usize,
+}
+
+impl Default for TunnelConfiguration<'_> {
+    fn default() -> Self {
+        Self {
+            device_id: "",
+            token: None,
+            local_port: 80,
+            timeout: Duration::from_secs(10),
+            max_request_size: 16384,
+            chunk_size: 4096,
+        }
+    }
+}
+
+/// A remote-access agent, serving the HTTP requests tunnelled by a relay with
+/// the handlers of the local `EspHttpServer`
+pub struct EspHttpTunnel<T> {
+    transport: T,
+    device_id: String,
+    token: Option<String>,
+    local: SocketAddr,
+    timeout: Duration,
+    max_request_size: usize,
+    chunk_size: usize,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl<T> EspHttpTunnel<T>
+where
+    T: TunnelTransport,
+{
+    /// Create the agent over a transport connected to the relay
+    pub fn new(transport: T, conf: &TunnelConfiguration) -> Self {
+        Self {
+            transport,
+            device_id: conf.device_id.into(),
+            token: conf.token.map(Into::into),
+            local: SocketAddr::from((Ipv4Addr::LOCALHOST, conf.local_port)),
+            timeout: conf.timeout,
+            max_request_size: conf.max_request_size,
+            chunk_size: conf.chunk_size.clamp(1, MAX_FRAME_SIZE - 6),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Introduce the device to the relay, then serve the requests it tunnels
+    /// until it closes the connection
+    pub fn run(&mut self) -> Result<(), EspError> {
+        self.pending.clear();
+
+        let mut hello = Vec::new();
+        Frame::Hello {
+            device_id: &self.device_id,
+            token: self.token.as_deref().unwrap_or(""),
+        }
+        .encode(&mut hello);
+
+        self.transport.send(&hello)?;
+
+        info!("Tunnel to the relay established");
+
+        while let Some(data) = self.next_frame()? {
+            match Frame::decode(&data) {
+                Some(Frame::Request {
+                    stream,
+                    end,
+                    method,
+                    uri,
+                    headers,
+                    client,
+                }) => {
+                    if !self.serve(stream, end, method, uri, &headers, client)? {
+                        break;
+                    }
+                }
+                Some(Frame::Ping { data }) => self.send(&Frame::Pong { data })?,
+                Some(frame) => debug!("Ignoring frame {frame:?}"),
+                None => warn!("Ignoring malformed frame"),
+            }
+        }
+
+        info!("Tunnel closed by the relay");
+
+        Ok(())
+    }
+
+    /// Return the transport
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    /// Serve the request of `stream`, and return `false` if the relay closed the connection
+    fn serve(
+        &mut self,
+        stream: u32,
+        end: bool,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        client: &str,
+    ) -> Result<bool, EspError> {
+        debug!("Stream {stream}: {method} {uri}");
+
+        let mut responded = false;
+
+        match self.proxy(stream, end, method, uri, headers, client, &mut responded) {
+            Ok(()) => (),
+            Err(ProxyError::Tunnel(e)) => Err(e)?,
+            Err(ProxyError::Closed) => return Ok(false),
+            Err(ProxyError::Reset) => debug!("Stream {stream}: reset by the relay"),
+            Err(ProxyError::Dropped) => self.reset(stream)?,
+            Err(ProxyError::Local(status)) if !responded => {
+                warn!("Stream {stream}: failed with status {status}");
+
+                self.send(&Frame::Response {
+                    stream,
+                    end: true,
+                    status,
+                    headers: Vec::new(),
+                })?;
+            }
+            Err(ProxyError::Local(_)) => {
+                warn!("Stream {stream}: local server failed mid-response");
+
+                self.send(&Frame::Reset { stream })?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn proxy(
+        &mut self,
+        stream: u32,
+        end: bool,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        client: &str,
+        responded: &mut bool,
+    ) -> Result<(), ProxyError> {
+        // The request head is written as is to the local server, so none of its parts may
+        // smuggle additional lines into it
+        let valid = is_token(method)
+            && !uri.contains([' ', '\r', '\n', '\0'])
+            && headers
+                .iter()
+                .all(|(name, value)| is_token(name) && !value.contains(['\r', '\n', '\0']));
+
+        if !valid {
+            warn!("Stream {stream}: malformed request");
+
+            if !end {
+                self.discard_body(stream)?;
+            }
+
+            return Err(ProxyError::Local(400));
+        }
+
+        let content_len: Option<usize> =
+            header(headers, "content-length").and_then(|len| len.parse().ok());
+
+        // Without a `Content-Length`, the body has to be buffered to compute it
+        let buffered = if !end && content_len.is_none() {
+            let mut body = Vec::new();
+
+            while let Some(data) = self.next_body(stream)? {
+                if body.len() + data.len() > self.max_request_size {
+                    self.discard_body(stream)?;
+                    return Err(ProxyError::Local(413));
+                }
+
+                body.extend_from_slice(&data);
+            }
+
+            Some(body)
+        } else {
+            None
+        };
+
+        let mut local = TcpStream::connect_timeout(&self.local, self.timeout)
+            .and_then(|local| {
+                local.set_read_timeout(Some(self.timeout))?;
+                local.set_write_timeout(Some(self.timeout))?;
+
+                Ok(local)
+            })
+            .map_err(|e| {
+                warn!("Cannot connect to the local server: {e}");
+                ProxyError::Local(502)
+            })?;
+
+        let mut head = String::new();
+
+        head.push_str(method);
+        head.push(' ');
+        head.push_str(uri);
+        head.push_str(" HTTP/1.1\r\n");
+
+        for (name, value) in headers {
+            // A buffered body gets its `Content-Length` computed below, and the
+            // forwarding headers sent by the client cannot be trusted
+            let skip = is_hop_by_hop(name)
+                || is_forwarding(name)
+                || buffered.is_some() && name.eq_ignore_ascii_case("content-length");
+
+            if !skip {
+                head.push_str(name);
+                head.push_str(": ");
+                head.push_str(value);
+                head.push_str("\r\n");
+            }
+        }
+
+        if header(headers, "host").is_none() {
+            head.push_str("Host: localhost\r\n");
+        }
+
+        if let Ok(client) = client.parse::<IpAddr>() {
+            head.push_str(&format!("X-Forwarded-For: {client}\r\n"));
+
+            if client.is_ipv6() {
+                head.push_str(&format!("Forwarded: for=\"[{client}]\"\r\n"));
+            } else {
+                head.push_str(&format!("Forwarded: for={client}\r\n"));
+            }
+        }
+
+        if let Some(body) = &buffered {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+
+        head.push_str("Connection: close\r\n\r\n");
+
+        local.write_all(head.as_bytes()).map_err(local_err)?;
+
+        if let Some(body) = &buffered {
+            local.write_all(body).map_err(local_err)?;
+        } else if !end {
+            while let Some(data) = self.next_body(stream)? {
+                local.write_all(&data).map_err(local_err)?;
+            }
+        }
+
+        let mut reader = BufReader::new(&local);
+
+        let (status, headers) = read_head(&mut reader).map_err(local_err)?;
+
+        let chunked = header_values(&headers, "transfer-encoding")
+            .any(|value| value.to_ascii_lowercase().contains("chunked"));
+        let content_len: Option<usize> =
+            header(&headers, "content-length").and_then(|len| len.parse().ok());
+
+        let bodyless = method.eq_ignore_ascii_case("HEAD")
+            || (100..200).contains(&status)
+            || status == 204
+            || status == 304
+            || content_len == Some(0) && !chunked;
+
+        self.send(&Frame::Response {
+            stream,
+            end: bodyless,
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .filter(|(name, _)| !is_hop_by_hop(name))
+                .collect(),
+        })?;
+
+        *responded = true;
+
+        if bodyless {
+            return Ok(());
+        }
+
+        let mut buf = vec![0; self.chunk_size];
+
+        if chunked {
+            loop {
+                let mut line = String::new();
+                read_line(&mut reader, &mut line).map_err(local_err)?;
+
+                let size = line.split(';').next().unwrap_or("").trim();
+                let mut remaining = usize::from_str_radix(size, 16).map_err(|_| local_err(()))?;
+
+                if remaining == 0 {
+                    // Skip the trailers
+                    while !line.is_empty() {
+                        read_line(&mut reader, &mut line).map_err(local_err)?;
+                    }
+
+                    break;
+                }
+
+                while remaining > 0 {
+                    let len = remaining.min(buf.len());
+
+                    reader.read_exact(&mut buf[..len]).map_err(local_err)?;
+                    self.send_data(stream, &buf[..len], false)?;
+
+                    remaining -= len;
+                }
+
+                read_line(&mut reader, &mut line).map_err(local_err)?;
+            }
+        } else {
+            let mut remaining = content_len;
+
+            while remaining != Some(0) {
+                let max = remaining.map(|r| r.min(buf.len())).unwrap_or(buf.len());
+
+                let len = reader.read(&mut buf[..max]).map_err(local_err)?;
+                if len == 0 {
+                    if remaining.is_some() {
+                        return Err(local_err(()));
+                    }
+
+                    break;
+                }
+
+                self.send_data(stream, &buf[..len], false)?;
+
+                remaining = remaining.map(|r| r - len);
+            }
+        }
+
+        self.send_data(stream, &[], true)?;
+
+        Ok(())
+    }
+
+    /// Return the next chunk of the body of the request of `stream`, or `None` at its end
+    fn next_body(&mut self, stream: u32) -> Result<Option<Vec<u8>>, ProxyError> {
+        let data = self.next_stream_frame(stream)?;
+
+        match Frame::decode(&data) {
+            Some(Frame::Data { end, data, .. }) => {
+                if end && data.is_empty() {
+                    Ok(None)
+                } else {
+                    let data = data.to_vec();
+
+                    if end {
+                        // Mark the end of the body for the next call
+                        let mut frame = Vec::new();
+                        Frame::Data {
+                            stream,
+                            end: true,
+                            data: &[],
+                        }
+                        .encode(&mut frame);
+
+                        self.pending.push_front(frame);
+                    }
+
+                    Ok(Some(data))
+                }
+            }
+            Some(Frame::Reset { .. }) => Err(ProxyError::Reset),
+            _ => {
+                warn!("Stream {stream}: unexpected frame");
+                Err(ProxyError::Local(400))
+            }
+        }
+    }
+
+    fn discard_body(&mut self, stream: u32) -> Result<(), ProxyError> {
+        while self.next_body(stream)?.is_some() {}
+
+        Ok(())
+    }
+
+    /// Return the next frame of `stream`, keeping the frames of the other streams for later
+    fn next_stream_frame(&mut self, stream: u32) -> Result<Vec<u8>, ProxyError> {
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|data| Frame::stream(data) == Some(stream))
+        {
+            return Ok(self.pending.remove(index).unwrap());
+        }
+
+        loop {
+            let received = self.transport.recv()?;
+
+            if self.reset_dropped(Some(stream))? {
+                return Err(ProxyError::Dropped);
+            }
+
+            let Some(data) = received else {
+                return Err(ProxyError::Closed);
+            };
+
+            match Frame::stream(&data) {
+                Some(id) if id == stream => return Ok(data),
+                Some(0) => match Frame::decode(&data) {
+                    Some(Frame::Ping { data }) => self.send(&Frame::Pong { data })?,
+                    frame => debug!("Ignoring frame {frame:?}"),
+                },
+                Some(id) => self.defer(id, data)?,
+                None => warn!("Ignoring malformed frame"),
+            }
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+        if let Some(data) = self.pending.pop_front() {
+            return Ok(Some(data));
+        }
+
+        let data = self.transport.recv()?;
+        self.reset_dropped(None)?;
+
+        Ok(data)
+    }
+
+    /// Keep a frame of another stream for later, or reset that stream if too many
+    /// frames are already kept
+    fn defer(&mut self, stream: u32, data: Vec<u8>) -> Result<(), EspError> {
+        if self.pending.len() < MAX_PENDING_FRAMES {
+            self.pending.push_back(data);
+        } else if data.first() == Some(&RESET) {
+            self.pending
+                .retain(|pending| Frame::stream(pending) != Some(stream));
+        } else {
+            self.reset(stream)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reset the streams with frames dropped by the transport, and return `true`
+    /// if `current` is one of them
+    fn reset_dropped(&mut self, current: Option<u32>) -> Result<bool, EspError> {
+        let mut dropped = false;
+
+        while let Some(stream) = self.transport.dropped_stream() {
+            if Some(stream) == current {
+                dropped = true;
+            } else {
+                self.reset(stream)?;
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    /// Reset `stream`, forgetting its kept frames
+    fn reset(&mut self, stream: u32) -> Result<(), EspError> {
+        warn!("Stream {stream}: too many frames queued, resetting");
+
+        self.pending
+            .retain(|pending| Frame::stream(pending) != Some(stream));
+
+        self.send(&Frame::Reset { stream })
+    }
+
+    fn send_data(&mut self, stream: u32, data: &[u8], end: bool) -> Result<(), EspError> {
+        self.send(&Frame::Data { stream, end, data })
+    }
+
+    fn send(&mut self, frame: &Frame) -> Result<(), EspError> {
+        let mut data = Vec::new();
+        frame.encode(&mut data);
+
+        self.transport.send(&data)
+    }
+}
+
+enum ProxyError {
+    /// The transport to the relay failed
+    Tunnel(EspError),
+    /// The relay reset the stream
+    Reset,
+    /// The relay closed the connection
+    Closed,
+    /// Frames of the stream were dropped because too many were queued
+    Dropped,
+    /// The request could not be served; the status to respond with, if still possible
+    Local(u16),
+}
+
+impl From<EspError> for ProxyError {
+    fn from(e: EspError) -> Self {
+        Self::Tunnel(e)
+    }
+}
+
+fn local_err<E: core::fmt::Debug>(e: E) -> ProxyError {
+    warn!("Local server error: {e:?}");
+
+    ProxyError::Local(502)
+}
+
+/// Return `true` if `s` is a token (RFC 7230, section 3.2.6), as HTTP methods and
+/// header names are
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn is_forwarding(name: &str) -> bool {
+    FORWARDED_HEADERS
+        .iter()
+        .any(|forwarded| name.eq_ignore_ascii_case(forwarded))
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|hop| name.eq_ignore_ascii_case(hop))
+}
+
+fn header<'a, N, V>(headers: &'a [(N, V)], name: &str) -> Option<&'a str>
+where
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    headers
+        .iter()
+        .find(|(n, _)| n.as_ref().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_ref())
+}
+
+fn header_values<'a, N, V>(
+    headers: &'a [(N, V)],
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a
+where
+    N: AsRef<str>,
+    V: AsRef<str>,
+{
+    headers
+        .iter()
+        .filter(move |(n, _)| n.as_ref().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_ref())
+}
+
+/// Read a line, without its terminating CRLF
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<(), EspError> {
+    line.clear();
+
+    let mut buf = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_HEAD_SIZE as u64)
+        .read_until(b'\n', &mut buf)
+        .map_err(to_esp_error)?;
+
+    if !buf.ends_with(b"\n") {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+    }
+
+    line.push_str(
+        core::str::from_utf8(&buf)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?
+            .trim_end_matches(['\r', '\n']),
+    );
+
+    Ok(())
+}
+
+/// Read the status line and the headers of a response
+#[allow(clippy::type_complexity)]
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(u16, Vec<(String, String)>), EspError> {
+    let mut line = String::new();
+    let mut size = 0;
+
+    // Skip the interim responses, like `100 Continue`
+    let status = loop {
+        read_line(reader, &mut line)?;
+
+        let status = line
+            .strip_prefix("HTTP/1.")
+            .and_then(|rest| rest.get(2..5))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?;
+
+        if (100..200).contains(&status) {
+            while !line.is_empty() {
+                read_line(reader, &mut line)?;
+            }
+        } else {
+            break status;
+        }
+    };
+
+    let mut headers = Vec::new();
+
+    loop {
+        read_line(reader, &mut line)?;
+
+        size += line.len();
+        if size > MAX_HEAD_SIZE {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().into(), value.trim().into()));
+        }
+    }
+
+    Ok((status, headers))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame<'a> {
+    Hello {
+        device_id: &'a str,
+        token: &'a str,
+    },
+    Request {
+        stream: u32,
+        end: bool,
+        method: &'a str,
+        uri: &'a str,
+        headers: Vec<(&'a str, &'a str)>,
+        client: &'a str,
+    },
+    Response {
+        stream: u32,
+        end: bool,
+        status: u16,
+        headers: Vec<(&'a str, &'a str)>,
+    },
+    Data {
+        stream: u32,
+        end: bool,
+        data: &'a [u8],
+    },
+    Reset {
+        stream: u32,
+    },
+    Ping {
+        data: &'a [u8],
+    },
+    Pong {
+        data: &'a [u8],
+    },
+}
+
+impl<'a> Frame<'a> {
+    /// Return the stream ID of an encoded frame
+    fn stream(data: &[u8]) -> Option<u32> {
+        Some(u32::from_be_bytes(data.get(2..6)?.try_into().unwrap()))
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let (frame_type, stream, end) = match self {
+            Self::Hello { .. } => (HELLO, 0, false),
+            Self::Request { stream, end, .. } => (REQUEST, *stream, *end),
+            Self::Response { stream, end, .. } => (RESPONSE, *stream, *end),
+            Self::Data { stream, end, .. } => (DATA, *stream, *end),
+            Self::Reset { stream } => (RESET, *stream, true),
+            Self::Ping { .. } => (PING, 0, false),
+            Self::Pong { .. } => (PONG, 0, false),
+        };
+
+        buf.push(frame_type);
+        buf.push(if end { FLAG_END } else { 0 });
+        buf.extend_from_slice(&stream.to_be_bytes());
+
+        match self {
+            Self::Hello { device_id, token } => {
+                encode_str(buf, device_id);
+                encode_str(buf, token);
+            }
+            Self::Request {
+                method,
+                uri,
+                headers,
+                client,
+                ..
+            } => {
+                encode_str(buf, method);
+                encode_str(buf, uri);
+                encode_headers(buf, headers);
+                encode_str(buf, client);
+            }
+            Self::Response {
+                status, headers, ..
+            } => {
+                buf.extend_from_slice(&status.to_be_bytes());
+                encode_headers(buf, headers);
+            }
+            Self::Data { data, .. } | Self::Ping { data } | Self::Pong { data } => {
+                buf.extend_from_slice(data)
+            }
+            Self::Reset { .. } => (),
+        }
+    }
+
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        let frame_type = *data.first()?;
+        let end = *data.get(1)? & FLAG_END != 0;
+        let stream = Self::stream(data)?;
+
+        let mut data = &data[6..];
+
+        let frame = match frame_type {
+            HELLO => Self::Hello {
+                device_id: decode_str(&mut data)?,
+                token: decode_str(&mut data)?,
+            },
+            REQUEST => Self::Request {
+                stream,
+                end,
+                method: decode_str(&mut data)?,
+                uri: decode_str(&mut data)?,
+                headers: decode_headers(&mut data)?,
+                client: decode_str(&mut data)?,
+            },
+            RESPONSE => Self::Response {
+                stream,
+                end,
+                status: u16::from_be_bytes(decode_bytes(&mut data, 2)?.try_into().unwrap()),
+                headers: decode_headers(&mut data)?,
+            },
+            DATA => {
+                return Some(Self::Data { stream, end, data });
+            }
+            RESET => Self::Reset { stream },
+            PING => return Some(Self::Ping { data }),
+            PONG => return Some(Self::Pong { data }),
+            _ => return None,
+        };
+
+        data.is_empty().then_some(frame)
+    }
+}
+
+fn encode_str(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len().min(u16::MAX as usize);
+
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+fn encode_headers(buf: &mut Vec<u8>, headers: &[(&str, &str)]) {
+    buf.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+
+    for (name, value) in headers {
+        encode_str(buf, name);
+        encode_str(buf, value);
+    }
+}
+
+fn decode_bytes<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+
+    Some(bytes)
+}
+
+fn decode_str<'a>(data: &mut &'a [u8]) -> Option<&'a str> {
+    let len = u16::from_be_bytes(decode_bytes(data, 2)?.try_into().unwrap()) as usize;
+
+    core::str::from_utf8(decode_bytes(data, len)?).ok()
+}
+
+fn decode_headers<'a>(data: &mut &'a [u8]) -> Option<Vec<(&'a str, &'a str)>> {
+    let count = u16::from_be_bytes(decode_bytes(data, 2)?.try_into().unwrap());
+
+    (0..count)
+        .map(|_| Some((decode_str(data)?, decode_str(data)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    /// A relay stand-in, sending scripted frames and collecting the responses of the agent
+    struct Relay {
+        incoming: VecDeque<Vec<u8>>,
+        outgoing: Vec<Vec<u8>>,
+    }
+
+    impl Relay {
+        fn new(frames: &[Frame]) -> Self {
+            Self {
+                incoming: frames
+                    .iter()
+                    .map(|frame| {
+                        let mut buf = Vec::new();
+                        frame.encode(&mut buf);
+                        buf
+                    })
+                    .collect(),
+                outgoing: Vec::new(),
+            }
+        }
+    }
+
+    impl TunnelTransport for Relay {
+        fn send(&mut self, frame: &[u8]) -> Result<(), EspError> {
+            self.outgoing.push(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+            Ok(self.incoming.pop_front())
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let frames = [
+            Frame::Hello {
+                device_id: "device",
+                token: "secret",
+            },
+            Frame::Request {
+                stream: 7,
+                end: false,
+                method: "POST",
+                uri: "/api/config?x=1",
+                headers: vec![("Content-Type", "application/json")],
+                client: "203.0.113.7",
+            },
+            Frame::Response {
+                stream: 7,
+                end: true,
+                status: 404,
+                headers: vec![],
+            },
+            Frame::Data {
+                stream: 7,
+                end: true,
+                data: b"{}",
+            },
+            Frame::Reset { stream: 8 },
+            Frame::Ping { data: b"42" },
+        ];
+
+        for frame in frames {
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+
+            assert_eq!(Frame::decode(&buf), Some(frame));
+        }
+
+        assert_eq!(Frame::decode(&[REQUEST, 0, 0, 0, 0, 1, 0]), None);
+    }
+
+    #[test]
+    fn tunnel() {
+        // A stand-in for the local `EspHttpServer`
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+                "HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+            ] {
+                let (local, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&local);
+
+                let mut request = String::new();
+                loop {
+                    let len = reader.read_line(&mut request).unwrap();
+                    if len <= 2 {
+                        break;
+                    }
+                }
+
+                if let Some(len) = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                {
+                    let mut body = vec![0; len.parse().unwrap()];
+                    reader.read_exact(&mut body).unwrap();
+                    request.push_str(core::str::from_utf8(&body).unwrap());
+                }
+
+                (&local).write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+
+            requests
+        });
+
+        let mut relay = Relay::new(&[
+            Frame::Ping { data: b"1" },
+            Frame::Request {
+                stream: 1,
+                end: true,
+                method: "GET",
+                uri: "/",
+                headers: vec![
+                    ("Host", "device.example.com"),
+                    ("Connection", "keep-alive"),
+                    ("X-Forwarded-For", "127.0.0.1"),
+                ],
+                client: "203.0.113.7",
+            },
+            Frame::Request {
+                stream: 2,
+                end: false,
+                method: "POST",
+                uri: "/api",
+                headers: vec![],
+                client: "2001:db8::1",
+            },
+            Frame::Data {
+                stream: 2,
+                end: false,
+                data: b"{\"a\"",
+            },
+            Frame::Data {
+                stream: 2,
+                end: true,
+                data: b":1}",
+            },
+        ]);
+
+        EspHttpTunnel::new(
+            &mut relay,
+            &TunnelConfiguration {
+                device_id: "device",
+                local_port: port,
+                chunk_size: 4,
+                ..Default::default()
+            },
+        )
+        .run()
+        .unwrap();
+
+        let requests = server.join().unwrap();
+
+        assert_eq!(
+            requests[0],
+            "GET / HTTP/1.1\r\nHost: device.example.com\r\nX-Forwarded-For: 203.0.113.7\r\nForwarded: for=203.0.113.7\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            requests[1],
+            "POST /api HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 2001:db8::1\r\nForwarded: for=\"[2001:db8::1]\"\r\nContent-Length: 7\r\nConnection: close\r\n\r\n{\"a\":1}"
+        );
+
+        let frames = relay
+            .outgoing
+            .iter()
+            .map(|data| Frame::decode(data).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            frames,
+            [
+                Frame::Hello {
+                    device_id: "device",
+                    token: "",
+                },
+                Frame::Pong { data: b"1" },
+                Frame::Response {
+                    stream: 1,
+                    end: false,
+                    status: 200,
+                    headers: vec![("Content-Type", "text/plain")],
+                },
+                Frame::Data {
+                    stream: 1,
+                    end: false,
+                    data: b"hell",
+                },
+                Frame::Data {
+                    stream: 1,
+                    end: false,
+                    data: b"o",
+                },
+                Frame::Data {
+                    stream: 1,
+                    end: false,
+                    data: b" wor",
+                },
+                Frame::Data {
+                    stream: 1,
+                    end: false,
+                    data: b"ld",
+                },
+                Frame::Data {
+                    stream: 1,
+                    end: true,
+                    data: b"",
+                },
+                Frame::Response {
+                    stream: 2,
+                    end: false,
+                    status: 201,
+                    headers: vec![("Content-Length", "2")],
+                },
+                Frame::Data {
+                    stream: 2,
+                    end: false,
+                    data: b"ok",
+                },
+                Frame::Data {
+                    stream: 2,
+                    end: true,
+                    data: b"",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let mut relay = Relay::new(&[
+            Frame::Request {
+                stream: 1,
+                end: true,
+                method: "GET",
+                uri: "/",
+                headers: vec![("X-Custom", "x\r\nX-Forwarded-For: 127.0.0.1")],
+                client: "203.0.113.7",
+            },
+            Frame::Request {
+                stream: 2,
+                end: false,
+                method: "POST /admin HTTP/1.1\r\nX:",
+                uri: "/",
+                headers: vec![],
+                client: "203.0.113.7",
+            },
+            Frame::Data {
+                stream: 2,
+                end: true,
+                data: b"{}",
+            },
+            Frame::Request {
+                stream: 3,
+                end: true,
+                method: "GET",
+                uri: "/\0",
+                headers: vec![],
+                client: "203.0.113.7",
+            },
+        ]);
+
+        // Nothing listens on the local port, as the requests are rejected before connecting
+        EspHttpTunnel::new(
+            &mut relay,
+            &TunnelConfiguration {
+                device_id: "device",
+                local_port: 1,
+                ..Default::default()
+            },
+        )
+        .run()
+        .unwrap();
+
+        let frames = relay
+            .outgoing
+            .iter()
+            .skip(1)
+            .map(|data| Frame::decode(data).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            frames,
+            (1..=3)
+                .map(|stream| Frame::Response {
+                    stream,
+                    end: true,
+                    status: 400,
+                    headers: vec![],
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn pending() {
+        let mut frames = vec![
+            Frame::Request {
+                stream: 1,
+                end: false,
+                method: "POST",
+                uri: "/upload",
+                headers: vec![],
+                client: "",
+            },
+            Frame::Ping { data: b"1" },
+        ];
+
+        frames.extend(
+            (2..=MAX_PENDING_FRAMES as u32 + 2).map(|stream| Frame::Request {
+                stream,
+                end: true,
+                method: "GET",
+                uri: "/",
+                headers: vec![],
+                client: "",
+            }),
+        );
+
+        let mut relay = Relay::new(&frames);
+
+        EspHttpTunnel::new(&mut relay, &Default::default())
+            .run()
+            .unwrap();
+
+        let frames = relay
+            .outgoing
+            .iter()
+            .map(|data| Frame::decode(data).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            frames,
+            [
+                Frame::Hello {
+                    device_id: "",
+                    token: "",
+                },
+                Frame::Pong { data: b"1" },
+                Frame::Reset {
+                    stream: MAX_PENDING_FRAMES as u32 + 2,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! A registry of the live sessions of a Websockets handler, with broadcasting
+//!
+//! `EspHttpServer::ws_hub` registers a Websockets handler for a URI, like `ws_handler`,
+//! and returns an `EspHttpWsHub` which tracks the sessions of that URI as they are opened
+//! and closed. The hub can then send frames to all sessions, or to a subset of them, from
+//! any task.
+//!
+//! Sending never blocks the caller: each session has its own queue of outgoing frames,
+//! drained by the `httpd` task one frame at a time, round-robin across the sessions.
+//! When the queue of a slow session is full, the `HubOverflow` policy decides whether
+//! frames get dropped or the session gets closed, so one slow client cannot block the others.
+//!
+//! Typical usage:
+//! ```ignore
+//! let hub = server.ws_hub("/ws", None, &HubConfiguration::default(), |connection| {
+//!     if !connection.is_new() && !connection.is_closed() {
+//!         let mut buf = [0; 256];
+//!         connection.recv(&mut buf)?;
+//!     }
+//!
+//!     Ok::<_, EspError>(())
+//! })?;
+//!
+//! // In another task
+//! hub.broadcast(FrameType::Text(false), b"{\"temperature\":21.5}");
+//! ```
+
+use core::ffi;
+use core::fmt::Debug;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::{debug, info, warn};
+
+use embedded_svc::ws::FrameType;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::super::EspHttpServer;
+use super::{EspHttpWsConnection, EspHttpWsDetachedSender};
+
+/// What to do with a frame sent to a session whose queue is full
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HubOverflow {
+    /// Drop the oldest queued frame to make room for the new one
+    #[default]
+    DropOldest,
+    /// Drop the new frame
+    DropNewest,
+    /// Close the session
+    Close,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HubConfiguration {
+    /// The maximum number of frames queued per session
+    pub max_pending: usize,
+    pub overflow: HubOverflow,
+}
+
+impl Default for HubConfiguration {
+    fn default() -> Self {
+        Self {
+            max_pending: 8,
+            overflow: HubOverflow::DropOldest,
+        }
+    }
+}
+
+type Frame = Arc<(FrameType, Vec<u8>)>;
+
+struct HubSession {
+    sender: EspHttpWsDetachedSender,
+    queue: VecDeque<Frame>,
+    sending: bool,
+    dropped: usize,
+}
+
+struct HubShared {
+    conf: HubConfiguration,
+    sessions: Mutex<BTreeMap<ffi::c_int, HubSession>>,
+}
+
+/// The registry of the live sessions of a Websockets URI, as returned by `EspHttpServer::ws_hub`
+#[derive(Clone)]
+pub struct EspHttpWsHub(Arc<HubShared>);
+
+impl EspHttpWsHub {
+    fn new(conf: &HubConfiguration) -> Self {
+        Self(Arc::new(HubShared {
+            conf: HubConfiguration {
+                max_pending: conf.max_pending.max(1),
+                overflow: conf.overflow,
+            },
+            sessions: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Returns the live sessions.
+    pub fn sessions(&self) -> Vec<i32> {
+        self.prune();
+
+        self.0.sessions.lock().keys().copied().collect()
+    }
+
+    /// Returns the number of live sessions.
+    pub fn len(&self) -> usize {
+        self.prune();
+
+        self.0.sessions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of frames queued for the session, or `None` if the session is not live.
+    pub fn pending(&self, session: i32) -> Option<usize> {
+        self.0
+            .sessions
+            .lock()
+            .get(&session)
+            .map(|hub_session| hub_session.queue.len())
+    }
+
+    /// Returns the number of frames dropped for the session because its queue was full,
+    /// or `None` if the session is not live.
+    pub fn dropped(&self, session: i32) -> Option<usize> {
+        self.0
+            .sessions
+            .lock()
+            .get(&session)
+            .map(|hub_session| hub_session.dropped)
+    }
+
+    /// Queues the frame for all live sessions and returns the number of sessions it was queued for.
+    pub fn broadcast(&self, frame_type: FrameType, frame_data: &[u8]) -> usize {
+        self.send_filtered(frame_type, frame_data, |_| true)
+    }
+
+    /// Queues the frame for the given sessions and returns the number of sessions it was queued for.
+    pub fn send_to(&self, sessions: &[i32], frame_type: FrameType, frame_data: &[u8]) -> usize {
+        self.send_filtered(frame_type, frame_data, |session| {
+            sessions.contains(&session)
+        })
+    }
+
+    /// Queues the frame for the live sessions matching the filter and returns the number
+    /// of sessions it was queued for.
+    pub fn send_filtered<F>(&self, frame_type: FrameType, frame_data: &[u8], filter: F) -> usize
+    where
+        F: Fn(i32) -> bool,
+    {
+        self.prune();
+
+        let frame: Frame = Arc::new((frame_type, frame_data.to_vec()));
+
+        let mut to_close = Vec::new();
+        let mut queued = 0;
+
+        {
+            let mut sessions = self.0.sessions.lock();
+
+            for (session, hub_session) in sessions.iter_mut() {
+                if !filter(*session) {
+                    continue;
+                }
+
+                if hub_session.queue.len() >= self.0.conf.max_pending {
+                    hub_session.dropped += 1;
+
+                    match self.0.conf.overflow {
+                        HubOverflow::DropOldest => {
+                            hub_session.queue.pop_front();
+                        }
+                        HubOverflow::DropNewest => continue,
+                        HubOverflow::Close => {
+                            to_close.push((hub_session.sender.sd, *session));
+                            continue;
+                        }
+                    }
+                }
+
+                hub_session.queue.push_back(frame.clone());
+                queued += 1;
+
+                if !hub_session.sending {
+                    hub_session.sending = true;
+
+                    if self.schedule(hub_session.sender.sd, *session).is_err() {
+                        hub_session.sending = false;
+                    }
+                }
+            }
+
+            for (_, session) in &to_close {
+                sessions.remove(session);
+            }
+        }
+
+        for (sd, session) in to_close {
+            warn!("WS session {session} is too slow, closing");
+
+            unsafe {
+                httpd_sess_trigger_close(sd, session);
+            }
+        }
+
+        queued
+    }
+
+    /// Queues the frame for a single session.
+    ///
+    /// Returns an error if the session is not live or if the frame was dropped because
+    /// the queue of the session was full.
+    pub fn send(
+        &self,
+        session: i32,
+        frame_type: FrameType,
+        frame_data: &[u8],
+    ) -> Result<(), EspError> {
+        if self.send_to(&[session], frame_type, frame_data) > 0 {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        }
+    }
+
+    /// Closes the session.
+    pub fn close(&self, session: i32) -> Result<(), EspError> {
+        let hub_session = self
+            .0
+            .sessions
+            .lock()
+            .remove(&session)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        esp!(unsafe { httpd_sess_trigger_close(hub_session.sender.sd, session) })
+    }
+
+    fn track(&self, connection: &EspHttpWsConnection) -> Result<(), EspError> {
+        let session = connection.session();
+
+        if connection.is_new() {
+            let sender = connection.create_detached_sender()?;
+
+            self.0.sessions.lock().insert(
+                session,
+                HubSession {
+                    sender,
+                    queue: VecDeque::new(),
+                    sending: false,
+                    dropped: 0,
+                },
+            );
+
+            info!("WS session {session} added to the hub");
+        } else if connection.is_closed() && self.0.sessions.lock().remove(&session).is_some() {
+            info!("WS session {session} removed from the hub");
+        }
+
+        Ok(())
+    }
+
+    /// Remove the sessions closed in the meantime, in case the close handler was not called yet
+    fn prune(&self) {
+        self.0
+            .sessions
+            .lock()
+            .retain(|_, hub_session| !hub_session.sender.is_closed());
+    }
+
+    fn schedule(&self, sd: httpd_handle_t, session: ffi::c_int) -> Result<(), EspError> {
+        let work = Box::into_raw(Box::new(HubWork {
+            hub: self.clone(),
+            session,
+        }));
+
+        let result = esp!(unsafe { httpd_queue_work(sd, Some(Self::work), work as *mut _) });
+
+        if result.is_err() {
+            // The work item was not queued, so it has to be reclaimed here
+            drop(unsafe { Box::from_raw(work) });
+        }
+
+        result
+    }
+
+    /// Send the next frame of a session from the `httpd` task, and re-schedule if more
+    /// frames are queued, so that the sessions are served round-robin
+    extern "C" fn work(arg: *mut ffi::c_void) {
+        let work = unsafe { Box::from_raw(arg as *mut HubWork) };
+        let hub = &work.hub;
+
+        let Some((sd, frame)) = ({
+            let mut sessions = hub.0.sessions.lock();
+
+            sessions.get_mut(&work.session).and_then(|hub_session| {
+                let frame = hub_session.queue.pop_front();
+
+                if frame.is_none() {
+                    hub_session.sending = false;
+                }
+
+                frame.map(|frame| (hub_session.sender.sd, frame))
+            })
+        }) else {
+            return;
+        };
+
+        let raw_frame = EspHttpWsConnection::create_raw_frame(frame.0, &frame.1);
+
+        let ret = unsafe {
+            httpd_ws_send_frame_async(sd, work.session, &raw_frame as *const _ as *mut _)
+        };
+
+        if ret != ESP_OK {
+            warn!(
+                "Sending to WS session {} failed with {ret}, closing",
+                work.session
+            );
+
+            hub.0.sessions.lock().remove(&work.session);
+
+            unsafe {
+                httpd_sess_trigger_close(sd, work.session);
+            }
+
+            return;
+        }
+
+        debug!(
+            "Sent data (frame_type={:?}, frame_len={}) to WS session {}",
+            frame.0,
+            frame.1.len(),
+            work.session
+        );
+
+        let mut sessions = hub.0.sessions.lock();
+
+        if let Some(hub_session) = sessions.get_mut(&work.session) {
+            if hub_session.queue.is_empty() || hub.schedule(sd, work.session).is_err() {
+                hub_session.sending = false;
+            }
+        }
+    }
+}
+
+struct HubWork {
+    hub: EspHttpWsHub,
+    session: ffi::c_int,
+}
+
+impl<'a> EspHttpServer<'a> {
+    /// Registers a Websockets handler for a URI, like `ws_handler`, and returns a hub
+    /// tracking the live sessions of that URI.
+    ///
+    /// The handler is called exactly as with `ws_handler`, and should in particular
+    /// receive the incoming frames.
+    pub fn ws_hub<H, E>(
+        &mut self,
+        uri: &str,
+        subprotocol_list: Option<&str>,
+        conf: &HubConfiguration,
+        handler: H,
+    ) -> Result<EspHttpWsHub, EspError>
+    where
+        H: for<'r> Fn(&'r mut EspHttpWsConnection) -> Result<(), E> + Send + Sync + 'a,
+        E: Debug,
+    {
+        let hub = EspHttpWsHub::new(conf);
+
+        let tracker = hub.clone();
+
+        self.ws_handler(uri, subprotocol_list, move |connection| {
+            if let Err(e) = tracker.track(connection) {
+                warn!("Tracking WS session {} failed: {e}", connection.session());
+            }
+
+            handler(connection)
+        })?;
+
+        Ok(hub)
+    }
+}
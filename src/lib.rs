@@ -40,6 +40,10 @@ pub mod ble;
     feature = "alloc",
 ))]
 pub mod bt;
+#[cfg(feature = "alloc")]
+pub mod coap;
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
+pub mod dns;
 #[cfg(all(
     feature = "alloc",
     any(
@@ -108,6 +112,8 @@ pub mod partition;
 pub mod ping;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
 pub mod sntp;
+#[cfg(all(feature = "std", esp_idf_comp_esp_netif_enabled))]
+pub mod socket;
 pub mod sys;
 pub mod systime;
 #[cfg(all(
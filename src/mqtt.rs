@@ -3,3 +3,8 @@
 //! MQTT is a lightweight publish/subscribe messaging protocol.
 
 pub mod client;
+#[cfg(feature = "use_serde")]
+pub mod cloud;
+pub mod dispatch;
+pub mod outbox;
+pub mod sn;
@@ -70,6 +70,61 @@ pub struct Mqtt5ConnectionPropertyConfig {
     pub payload_format_indicator: Option<bool>,
 }
 
+/// MQTT 5.0 PUBLISH properties, as used by `EspMqttClient::publish_with_properties`.
+/// `None` on an optional field leaves the property unset; `payload_format_indicator`
+/// defaults to `false`, which is also the protocol default.
+#[cfg(esp_idf_mqtt_protocol_5)]
+#[derive(Debug, Clone, Default)]
+pub struct Mqtt5PublishProperties<'a> {
+    /// Payload Format Indicator: UTF-8 (`true`) or bytes (`false`) (MQTT5 §3.3.2.3.2).
+    pub payload_format_indicator: bool,
+    /// Message Expiry Interval, seconds (MQTT5 §3.3.2.3.3).
+    pub message_expiry_interval: Option<u32>,
+    /// Topic Alias, up to the Topic Alias Maximum of the broker (MQTT5 §3.3.2.3.4).
+    pub topic_alias: Option<u16>,
+    /// Response Topic of the request/response pattern (MQTT5 §3.3.2.3.5).
+    pub response_topic: Option<&'a str>,
+    /// Correlation Data of the request/response pattern (MQTT5 §3.3.2.3.6).
+    pub correlation_data: Option<&'a [u8]>,
+    /// Content Type, e.g. a MIME type (MQTT5 §3.3.2.3.9).
+    pub content_type: Option<&'a str>,
+    /// User Properties, as key/value pairs (MQTT5 §3.3.2.3.7).
+    pub user_properties: &'a [(&'a str, &'a str)],
+}
+
+/// MQTT 5.0 Retain Handling subscription option (MQTT5 §3.8.3.1)
+#[cfg(esp_idf_mqtt_protocol_5)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Mqtt5RetainHandling {
+    /// Send the retained messages when subscribing
+    #[default]
+    SendOnSubscribe = 0,
+    /// Send the retained messages only if the subscription does not exist yet
+    SendOnNewSubscription = 1,
+    /// Do not send the retained messages
+    DoNotSend = 2,
+}
+
+/// MQTT 5.0 SUBSCRIBE properties and subscription options, as used by
+/// `EspMqttClient::subscribe_with_properties`.
+#[cfg(esp_idf_mqtt_protocol_5)]
+#[derive(Debug, Clone, Default)]
+pub struct Mqtt5SubscribeProperties<'a> {
+    /// Subscription Identifier, reported back with the matching messages (MQTT5 §3.8.2.1.2).
+    pub subscription_identifier: Option<u16>,
+    /// No Local option: do not receive the own publications (MQTT5 §3.8.3.1).
+    pub no_local: bool,
+    /// Retain As Published option: keep the retain flag of forwarded messages (MQTT5 §3.8.3.1).
+    pub retain_as_published: bool,
+    /// Retain Handling option (MQTT5 §3.8.3.1).
+    pub retain_handling: Mqtt5RetainHandling,
+    /// Subscribe as a shared subscription of this group, i.e. `$share/{share_name}/{topic}`
+    /// (MQTT5 §4.8.2).
+    pub share_name: Option<&'a str>,
+    /// User Properties, as key/value pairs (MQTT5 §3.8.2.1.3).
+    pub user_properties: &'a [(&'a str, &'a str)],
+}
+
 #[derive(Debug)]
 pub struct LwtConfiguration<'a> {
     pub topic: &'a str,
@@ -739,6 +794,148 @@ impl<'a> EspMqttClient<'a> {
     }
 }
 
+#[cfg(esp_idf_mqtt_protocol_5)]
+impl EspMqttClient<'_> {
+    /// Publishes a message with MQTT 5.0 PUBLISH properties.
+    /// Requires `protocol_version: Some(MqttProtocolVersion::V5)`.
+    pub fn publish_with_properties(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+        properties: &Mqtt5PublishProperties<'_>,
+    ) -> Result<MessageId, EspError> {
+        let topic = to_cstring_arg(topic)?;
+
+        let mut cstrs = RawCstrs::new();
+        let user_properties = Mqtt5UserProperties::new(properties.user_properties)?;
+
+        let correlation_data = properties.correlation_data.unwrap_or_default();
+        if correlation_data.len() > u16::MAX as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let c_props = esp_mqtt5_publish_property_config_t {
+            payload_format_indicator: properties.payload_format_indicator,
+            message_expiry_interval: properties.message_expiry_interval.unwrap_or(0) as _,
+            topic_alias: properties.topic_alias.unwrap_or(0),
+            response_topic: cstrs.as_nptr(properties.response_topic)?,
+            correlation_data: if correlation_data.is_empty() {
+                core::ptr::null()
+            } else {
+                correlation_data.as_ptr() as _
+            },
+            correlation_data_len: correlation_data.len() as _,
+            content_type: cstrs.as_nptr(properties.content_type)?,
+            user_property: user_properties.0,
+        };
+
+        // Only the pointers are copied, so `cstrs`, `user_properties` and `correlation_data`
+        // have to stay alive until the properties are reset below
+        esp!(unsafe { esp_mqtt5_client_set_publish_property(self.raw_client, &c_props) })?;
+
+        let result = self.publish_cstr(topic.as_c_str(), qos, retain, payload);
+
+        // Do not apply the properties - and their soon to be dangling pointers - to
+        // subsequent publications
+        esp!(unsafe {
+            esp_mqtt5_client_set_publish_property(self.raw_client, &Default::default())
+        })?;
+
+        result
+    }
+
+    /// Subscribes to a topic with MQTT 5.0 SUBSCRIBE properties and subscription options.
+    /// Requires `protocol_version: Some(MqttProtocolVersion::V5)`.
+    pub fn subscribe_with_properties(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        properties: &Mqtt5SubscribeProperties<'_>,
+    ) -> Result<MessageId, EspError> {
+        let topic = to_cstring_arg(topic)?;
+
+        let mut cstrs = RawCstrs::new();
+        let user_properties = Mqtt5UserProperties::new(properties.user_properties)?;
+
+        let c_props = esp_mqtt5_subscribe_property_config_t {
+            subscribe_id: properties.subscription_identifier.unwrap_or(0),
+            no_local_flag: properties.no_local,
+            retain_as_published_flag: properties.retain_as_published,
+            retain_handle: properties.retain_handling as _,
+            is_share_subscribe: properties.share_name.is_some(),
+            share_name: cstrs.as_nptr(properties.share_name)?,
+            user_property: user_properties.0,
+        };
+
+        // Only the pointers are copied, so `cstrs` and `user_properties` have to stay alive
+        // until the properties are reset below
+        esp!(unsafe { esp_mqtt5_client_set_subscribe_property(self.raw_client, &c_props) })?;
+
+        let result = self.subscribe_cstr(topic.as_c_str(), qos);
+
+        // Do not apply the properties - and their soon to be dangling pointers - to
+        // subsequent subscriptions
+        esp!(unsafe {
+            esp_mqtt5_client_set_subscribe_property(self.raw_client, &Default::default())
+        })?;
+
+        result
+    }
+}
+
+/// A native list of MQTT 5.0 User Properties, deleted on drop
+///
+/// The client copies the property configuration by value, i.e. it keeps the pointer to the
+/// list (as well as to the strings and data of the other properties) and only reads them
+/// when the next message is published or the next subscription is made. Hence the list has
+/// to outlive that call, and the properties have to be reset before the list is dropped.
+#[cfg(esp_idf_mqtt_protocol_5)]
+struct Mqtt5UserProperties(mqtt5_user_property_handle_t);
+
+#[cfg(esp_idf_mqtt_protocol_5)]
+impl Mqtt5UserProperties {
+    fn new(properties: &[(&str, &str)]) -> Result<Self, EspError> {
+        let mut list = Self(core::ptr::null_mut());
+
+        if properties.is_empty() {
+            return Ok(list);
+        }
+
+        if properties.len() > u8::MAX as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut cstrs = RawCstrs::new();
+        let mut items = alloc::vec::Vec::with_capacity(properties.len());
+
+        for (key, value) in properties {
+            items.push(esp_mqtt5_user_property_item_t {
+                key: cstrs.as_ptr(key)?,
+                value: cstrs.as_ptr(value)?,
+            });
+        }
+
+        esp!(unsafe {
+            esp_mqtt5_client_set_user_property(&mut list.0, items.as_mut_ptr(), items.len() as _)
+        })?;
+
+        Ok(list)
+    }
+}
+
+#[cfg(esp_idf_mqtt_protocol_5)]
+impl Drop for Mqtt5UserProperties {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe {
+                esp_mqtt5_client_delete_user_property(self.0);
+            }
+        }
+    }
+}
+
 impl Drop for EspMqttClient<'_> {
     fn drop(&mut self) {
         unsafe {
@@ -1162,6 +1359,131 @@ impl<'a> EspMqttEvent<'a> {
     }
 }
 
+impl EspMqttEvent<'_> {
+    /// Returns the return codes of the SUBACK packet for `Subscribed` events (the MQTT 5.0
+    /// reason codes for MQTT 5.0 sessions), one per topic filter.
+    ///
+    /// Empty for the other events, or if the ESP-IDF version does not report them.
+    #[allow(non_upper_case_globals)]
+    pub fn reason_codes(&self) -> &[u8] {
+        match self.0.event_id {
+            esp_mqtt_event_id_t_MQTT_EVENT_SUBSCRIBED
+                if !self.0.data.is_null() && self.0.data_len > 0 =>
+            unsafe { slice::from_raw_parts(self.0.data as *const u8, self.0.data_len as _) },
+            _ => &[],
+        }
+    }
+
+    /// Returns the return code of the CONNACK packet (the MQTT 5.0 reason code for MQTT 5.0
+    /// sessions) for `Error` events reporting a refused connection.
+    #[allow(non_upper_case_globals)]
+    pub fn connect_return_code(&self) -> Option<u8> {
+        let error = unsafe { self.0.error_handle.as_ref() }?;
+
+        (self.0.event_id == esp_mqtt_event_id_t_MQTT_EVENT_ERROR
+            && error.error_type == esp_mqtt_error_type_t_MQTT_ERROR_TYPE_CONNECTION_REFUSED)
+            .then_some(error.connect_return_code as _)
+    }
+
+    /// Returns the MQTT 5.0 properties of the event, if any.
+    ///
+    /// For `Received` events, these are the PUBLISH properties of the message.
+    #[cfg(esp_idf_mqtt_protocol_5)]
+    pub fn mqtt5_properties(&self) -> Option<Mqtt5EventProperties<'_>> {
+        let property = unsafe { self.0.property.as_ref() }?;
+
+        let bytes = |ptr: *const core::ffi::c_char, len: usize| {
+            (!ptr.is_null() && len > 0)
+                .then(|| unsafe { slice::from_raw_parts(ptr as *const u8, len) })
+        };
+
+        Some(Mqtt5EventProperties {
+            payload_format_indicator: property.payload_format_indicator,
+            response_topic: bytes(property.response_topic, property.response_topic_len as _)
+                .and_then(|topic| core::str::from_utf8(topic).ok()),
+            correlation_data: bytes(
+                property.correlation_data,
+                property.correlation_data_len as _,
+            ),
+            content_type: bytes(property.content_type, property.content_type_len as _)
+                .and_then(|content_type| core::str::from_utf8(content_type).ok()),
+            subscription_identifier: (property.subscribe_id != 0)
+                .then_some(property.subscribe_id as _),
+            user_property: property.user_property,
+        })
+    }
+}
+
+/// The MQTT 5.0 properties of an `EspMqttEvent`
+#[cfg(esp_idf_mqtt_protocol_5)]
+#[derive(Debug, Clone)]
+pub struct Mqtt5EventProperties<'a> {
+    /// Payload Format Indicator: UTF-8 (`true`) or bytes (`false`)
+    pub payload_format_indicator: bool,
+    /// Response Topic of the request/response pattern
+    pub response_topic: Option<&'a str>,
+    /// Correlation Data of the request/response pattern
+    pub correlation_data: Option<&'a [u8]>,
+    /// Content Type
+    pub content_type: Option<&'a str>,
+    /// Subscription Identifier of the subscription matching the message
+    pub subscription_identifier: Option<u16>,
+    user_property: mqtt5_user_property_handle_t,
+}
+
+#[cfg(esp_idf_mqtt_protocol_5)]
+impl Mqtt5EventProperties<'_> {
+    /// Returns the User Properties, as key/value pairs.
+    pub fn user_properties(
+        &self,
+    ) -> Result<alloc::vec::Vec<(alloc::string::String, alloc::string::String)>, EspError> {
+        let mut properties = alloc::vec::Vec::new();
+
+        if self.user_property.is_null() {
+            return Ok(properties);
+        }
+
+        let mut count = unsafe { esp_mqtt5_client_get_user_property_count(self.user_property) };
+        if count == 0 {
+            return Ok(properties);
+        }
+
+        let mut items = alloc::vec::Vec::with_capacity(count as _);
+        items.resize_with(count as _, || esp_mqtt5_user_property_item_t {
+            key: core::ptr::null(),
+            value: core::ptr::null(),
+        });
+
+        esp!(unsafe {
+            esp_mqtt5_client_get_user_property(self.user_property, items.as_mut_ptr(), &mut count)
+        })?;
+
+        for item in items.iter().take(count as _) {
+            let string = |ptr: *const core::ffi::c_char| {
+                if ptr.is_null() {
+                    alloc::string::String::new()
+                } else {
+                    unsafe { core::ffi::CStr::from_ptr(ptr) }
+                        .to_string_lossy()
+                        .into_owned()
+                }
+            };
+
+            properties.push((string(item.key), string(item.value)));
+        }
+
+        // The keys and values are copies owned by the caller
+        for item in &items {
+            unsafe {
+                free(item.key as *mut _);
+                free(item.value as *mut _);
+            }
+        }
+
+        Ok(properties)
+    }
+}
+
 /// SAFETY: EspMqttEvent contains no thread-specific data.
 unsafe impl Send for EspMqttEvent<'_> {}
 
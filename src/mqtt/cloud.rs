@@ -0,0 +1,728 @@
+//! Device shadow and jobs flows of the IoT cloud services
+//!
+//! Cloud IoT services like AWS IoT expose a device "shadow" (a.k.a. "twin") - a JSON document
+//! with the desired and the reported state of the device - and a "jobs" queue of remote
+//! operations, both as conventions on top of MQTT topics and JSON payloads:
+//! - `DeviceShadow` implements the get, update and delta flows of a (classic or named) shadow
+//! - `DeviceJobs` implements the notify, start and update flows of the jobs
+//!
+//! Both follow the AWS IoT topic layout under a configurable topic prefix (`$aws/things`
+//! by default), so that a local broker can stand in for the cloud service, e.g. for testing.
+//! The requests are correlated with their responses by client token, and fail with
+//! `CloudError::Timeout` if no response arrives in time.
+//!
+//! The responses are received through an `MqttDispatcher` which subscribes the response
+//! topics and reassembles the large documents, so the events of the client must be handled
+//! by another thread. The requests are therefore split in two steps: publishing the request
+//! returns a `CloudRequest`, whose `wait` blocks until the response is received. The client
+//! needs to be borrowed only while publishing, and must not be locked while waiting, as
+//! the thread handling the events needs it as well.
+//!
+//! Typical usage:
+//! ```ignore
+//! let (mut client, mut connection) = EspMqttClient::new(url, &conf)?;
+//! let client = Arc::new(Mutex::new(client));
+//!
+//! let conf = CloudConfiguration {
+//!     thing_name: "sensor-1",
+//!     ..Default::default()
+//! };
+//!
+//! let shadow = DeviceShadow::new(&conf, None);
+//!
+//! let mut dispatcher = MqttDispatcher::new(4096);
+//! shadow.route(&mut dispatcher)?;
+//!
+//! let events_client = client.clone();
+//! std::thread::spawn(move || {
+//!     while let Ok(event) = connection.next() {
+//!         dispatcher.handle(&mut *events_client.lock().unwrap(), &event).unwrap();
+//!     }
+//! });
+//!
+//! // The client is only locked while publishing the request
+//! let request = shadow.report(&mut *client.lock().unwrap(), &json!({ "temperature": 21.5 }))?;
+//! let document = request.wait()?;
+//!
+//! if let Some(delta) = shadow.next_delta(Duration::from_secs(60)) {
+//!     info!("Desired state changed: {}", delta.state);
+//! }
+//! ```
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+
+use ::log::{debug, warn};
+
+use embedded_svc::mqtt::client::{Publish, QoS};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::private::mutex::{Condvar, Mutex};
+use crate::sys::*;
+
+use super::dispatch::MqttDispatcher;
+
+/// The maximum number of queued deltas or job notifications; the oldest are dropped
+const MAX_NOTIFICATIONS: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct CloudConfiguration<'a> {
+    /// The prefix of the topics of the things: `$aws/things` for AWS IoT
+    pub topic_prefix: &'a str,
+    pub thing_name: &'a str,
+    pub qos: QoS,
+    /// The time to wait for the response to a request
+    pub timeout: Duration,
+}
+
+impl Default for CloudConfiguration<'_> {
+    fn default() -> Self {
+        Self {
+            topic_prefix: "$aws/things",
+            thing_name: "",
+            qos: QoS::AtLeastOnce,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CloudError<E> {
+    /// Publishing the request failed
+    Client(E),
+    /// The service rejected the request
+    Rejected { code: u32, message: String },
+    /// No response arrived in time
+    Timeout,
+    /// The request could not be serialized, or the response could not be parsed
+    Json(serde_json::Error),
+}
+
+impl<E> fmt::Display for CloudError<E>
+where
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Client(e) => write!(f, "Client error: {e:?}"),
+            Self::Rejected { code, message } => write!(f, "Rejected ({code}): {message}"),
+            Self::Timeout => write!(f, "No response"),
+            Self::Json(e) => write!(f, "JSON error: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for CloudError<E> where E: fmt::Debug {}
+
+/// The state sections of a shadow document
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ShadowState {
+    #[serde(default)]
+    pub desired: Option<Value>,
+    #[serde(default)]
+    pub reported: Option<Value>,
+    /// The differences between the desired and the reported state
+    #[serde(default)]
+    pub delta: Option<Value>,
+}
+
+/// A shadow document, as returned by `DeviceShadow::get` and `DeviceShadow::update`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ShadowDocument {
+    #[serde(default)]
+    pub state: ShadowState,
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// A change of the desired state which differs from the reported state
+#[derive(Clone, Debug, Deserialize)]
+pub struct ShadowDelta {
+    pub state: Value,
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// The device shadow of a thing
+pub struct DeviceShadow {
+    topic: String,
+    qos: QoS,
+    timeout: Duration,
+    requests: Arc<Requests>,
+}
+
+impl DeviceShadow {
+    /// Create the classic shadow of the thing, or the named shadow `shadow_name`
+    pub fn new(conf: &CloudConfiguration<'_>, shadow_name: Option<&str>) -> Self {
+        let topic = if let Some(shadow_name) = shadow_name {
+            format!(
+                "{}/{}/shadow/name/{shadow_name}",
+                conf.topic_prefix, conf.thing_name
+            )
+        } else {
+            format!("{}/{}/shadow", conf.topic_prefix, conf.thing_name)
+        };
+
+        Self {
+            topic,
+            qos: conf.qos,
+            timeout: conf.timeout,
+            requests: Arc::new(Requests::new()),
+        }
+    }
+
+    /// Add the routes of the responses and the deltas to the dispatcher
+    pub fn route(&self, dispatcher: &mut MqttDispatcher<'_>) -> Result<(), EspError> {
+        for operation in ["get", "update"] {
+            let requests = self.requests.clone();
+
+            dispatcher.route(
+                &format!("{}/{operation}/+", self.topic),
+                self.qos,
+                move |topic, payload| match topic.rsplit('/').next() {
+                    Some("accepted") => requests.respond(payload, true),
+                    Some("rejected") => requests.respond(payload, false),
+                    Some("delta") => requests.notify(payload),
+                    _ => (),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Request the shadow document
+    pub fn get<C>(
+        &self,
+        client: &mut C,
+    ) -> Result<CloudRequest<ShadowDocument, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        self.request(client, "get", json!({}))
+    }
+
+    /// Update the shadow with a `state` object of `desired` and/or `reported` sections
+    pub fn update<C, S>(
+        &self,
+        client: &mut C,
+        state: &S,
+    ) -> Result<CloudRequest<ShadowDocument, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+        S: Serialize + ?Sized,
+    {
+        let state = serde_json::to_value(state).map_err(CloudError::Json)?;
+
+        self.request(client, "update", json!({ "state": state }))
+    }
+
+    /// Update the reported state of the shadow
+    pub fn report<C, S>(
+        &self,
+        client: &mut C,
+        reported: &S,
+    ) -> Result<CloudRequest<ShadowDocument, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+        S: Serialize + ?Sized,
+    {
+        let reported = serde_json::to_value(reported).map_err(CloudError::Json)?;
+
+        self.update(client, &json!({ "reported": reported }))
+    }
+
+    /// Wait up to `timeout` for the next delta
+    pub fn next_delta(&self, timeout: Duration) -> Option<ShadowDelta> {
+        let delta = self.requests.next_notification(timeout)?;
+
+        serde_json::from_value(delta)
+            .inspect_err(|e| warn!("Malformed shadow delta ignored: {e}"))
+            .ok()
+    }
+
+    fn request<C>(
+        &self,
+        client: &mut C,
+        operation: &str,
+        payload: Value,
+    ) -> Result<CloudRequest<ShadowDocument, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        Requests::send(
+            &self.requests,
+            client,
+            &format!("{}/{operation}", self.topic),
+            self.qos,
+            payload,
+            self.timeout,
+            serde_json::from_value,
+        )
+    }
+}
+
+/// The status of a job execution
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobStatus {
+    Queued,
+    InProgress,
+    Succeeded,
+    Failed,
+    TimedOut,
+    Rejected,
+    Removed,
+    Canceled,
+}
+
+/// The execution of a job by the thing
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobExecution {
+    pub job_id: String,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub status_details: BTreeMap<String, String>,
+    /// The job document, describing the operation
+    #[serde(default)]
+    pub job_document: Value,
+    #[serde(default)]
+    pub version_number: u64,
+    #[serde(default)]
+    pub execution_number: u64,
+}
+
+/// A request answered with the next pending job execution, if any
+pub type JobExecutionRequest<E> = CloudRequest<Option<JobExecution>, E>;
+
+#[derive(Deserialize)]
+struct JobExecutionResponse {
+    #[serde(default)]
+    execution: Option<JobExecution>,
+}
+
+/// The jobs of a thing
+pub struct DeviceJobs {
+    topic: String,
+    qos: QoS,
+    timeout: Duration,
+    requests: Arc<Requests>,
+}
+
+impl DeviceJobs {
+    pub fn new(conf: &CloudConfiguration<'_>) -> Self {
+        Self {
+            topic: format!("{}/{}/jobs", conf.topic_prefix, conf.thing_name),
+            qos: conf.qos,
+            timeout: conf.timeout,
+            requests: Arc::new(Requests::new()),
+        }
+    }
+
+    /// Add the routes of the responses and the notifications to the dispatcher
+    pub fn route(&self, dispatcher: &mut MqttDispatcher<'_>) -> Result<(), EspError> {
+        let requests = self.requests.clone();
+
+        dispatcher.route(
+            &format!("{}/notify-next", self.topic),
+            self.qos,
+            move |_, payload| requests.notify(payload),
+        )?;
+
+        for filter in ["+/get/+", "start-next/+", "+/update/+"] {
+            let requests = self.requests.clone();
+
+            dispatcher.route(
+                &format!("{}/{filter}", self.topic),
+                self.qos,
+                move |topic, payload| match topic.rsplit('/').next() {
+                    Some("accepted") => requests.respond(payload, true),
+                    Some("rejected") => requests.respond(payload, false),
+                    _ => (),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Request the next pending job execution, if any
+    pub fn next_pending<C>(
+        &self,
+        client: &mut C,
+    ) -> Result<JobExecutionRequest<C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        self.request(client, "$next/get", json!({}), Self::execution)
+    }
+
+    /// Request the next pending job execution, if any, and mark it as in progress
+    pub fn start_next<C>(
+        &self,
+        client: &mut C,
+        status_details: &[(&str, &str)],
+    ) -> Result<JobExecutionRequest<C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        self.request(
+            client,
+            "start-next",
+            json!({ "statusDetails": Self::details(status_details) }),
+            Self::execution,
+        )
+    }
+
+    /// Update the status of a job execution
+    pub fn update<C>(
+        &self,
+        client: &mut C,
+        job_id: &str,
+        status: JobStatus,
+        status_details: &[(&str, &str)],
+    ) -> Result<CloudRequest<(), C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        self.request(
+            client,
+            &format!("{job_id}/update"),
+            json!({
+                "status": status,
+                "statusDetails": Self::details(status_details),
+            }),
+            |_| Ok(()),
+        )
+    }
+
+    /// Wait up to `timeout` for the notification of the next pending job execution
+    pub fn next_notification(&self, timeout: Duration) -> Option<JobExecution> {
+        let notification = self.requests.next_notification(timeout)?;
+
+        serde_json::from_value::<JobExecutionResponse>(notification)
+            .inspect_err(|e| warn!("Malformed job notification ignored: {e}"))
+            .ok()?
+            .execution
+    }
+
+    fn request<C, T>(
+        &self,
+        client: &mut C,
+        operation: &str,
+        payload: Value,
+        parse: fn(Value) -> serde_json::Result<T>,
+    ) -> Result<CloudRequest<T, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        Requests::send(
+            &self.requests,
+            client,
+            &format!("{}/{operation}", self.topic),
+            self.qos,
+            payload,
+            self.timeout,
+            parse,
+        )
+    }
+
+    fn execution(response: Value) -> serde_json::Result<Option<JobExecution>> {
+        serde_json::from_value::<JobExecutionResponse>(response).map(|response| response.execution)
+    }
+
+    fn details(status_details: &[(&str, &str)]) -> Value {
+        Value::Object(
+            status_details
+                .iter()
+                .map(|(name, value)| (name.to_string(), Value::String(value.to_string())))
+                .collect(),
+        )
+    }
+}
+
+/// A published request, awaiting its response
+///
+/// The response is no longer awaited once the request is dropped.
+pub struct CloudRequest<T, E> {
+    requests: Arc<Requests>,
+    token: String,
+    timeout: Duration,
+    parse: fn(Value) -> serde_json::Result<T>,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<T, E> CloudRequest<T, E> {
+    /// Wait for the response, up to the timeout of the configuration
+    ///
+    /// The client must not be locked while waiting, as its events need to be handled.
+    pub fn wait(self) -> Result<T, CloudError<E>> {
+        match self
+            .requests
+            .wait(&self.token, self.timeout)
+            .ok_or(CloudError::Timeout)?
+        {
+            Response::Accepted(response) => (self.parse)(response).map_err(CloudError::Json),
+            Response::Rejected { code, message } => Err(CloudError::Rejected { code, message }),
+        }
+    }
+}
+
+impl<T, E> Drop for CloudRequest<T, E> {
+    fn drop(&mut self) {
+        self.requests.state.lock().pending.remove(&self.token);
+    }
+}
+
+enum Response {
+    Accepted(Value),
+    Rejected { code: u32, message: String },
+}
+
+#[derive(Default)]
+struct RequestsState {
+    next_id: u32,
+    pending: BTreeMap<String, Option<Response>>,
+    notifications: VecDeque<Value>,
+}
+
+/// The requests awaiting a response, correlated by client token, and the notifications
+struct Requests {
+    token_prefix: String,
+    state: Mutex<RequestsState>,
+    cvar: Condvar,
+}
+
+impl Requests {
+    fn new() -> Self {
+        Self {
+            // Other clients of the same thing receive the responses too
+            token_prefix: format!("{:08x}", unsafe { esp_random() }),
+            state: Mutex::new(RequestsState::default()),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn send<C, T>(
+        this: &Arc<Self>,
+        client: &mut C,
+        topic: &str,
+        qos: QoS,
+        mut payload: Value,
+        timeout: Duration,
+        parse: fn(Value) -> serde_json::Result<T>,
+    ) -> Result<CloudRequest<T, C::Error>, CloudError<C::Error>>
+    where
+        C: Publish,
+    {
+        let token = {
+            let mut state = this.state.lock();
+
+            state.next_id = state.next_id.wrapping_add(1);
+
+            let token = format!("{}-{}", this.token_prefix, state.next_id);
+            state.pending.insert(token.clone(), None);
+
+            token
+        };
+
+        payload["clientToken"] = Value::String(token.clone());
+
+        // Dropping the request on error stops awaiting the response
+        let request = CloudRequest {
+            requests: this.clone(),
+            token,
+            timeout,
+            parse,
+            _error: PhantomData,
+        };
+
+        let data = serde_json::to_vec(&payload).map_err(CloudError::Json)?;
+
+        debug!("Requesting {topic} ({})", request.token);
+
+        client
+            .publish(topic, qos, false, &data)
+            .map_err(CloudError::Client)?;
+
+        Ok(request)
+    }
+
+    fn respond(&self, payload: &[u8], accepted: bool) {
+        let Ok(response) = serde_json::from_slice::<Value>(payload) else {
+            warn!("Malformed response ignored");
+            return;
+        };
+
+        let Some(token) = response.get("clientToken").and_then(Value::as_str) else {
+            return;
+        };
+
+        let mut state = self.state.lock();
+
+        // Responses to the requests of other clients are ignored
+        if let Some(slot) = state.pending.get_mut(token) {
+            *slot = Some(if accepted {
+                Response::Accepted(response)
+            } else {
+                Response::Rejected {
+                    code: response.get("code").and_then(Value::as_u64).unwrap_or(0) as _,
+                    message: response
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                }
+            });
+
+            self.cvar.notify_all();
+        }
+    }
+
+    fn notify(&self, payload: &[u8]) {
+        let Ok(notification) = serde_json::from_slice::<Value>(payload) else {
+            warn!("Malformed notification ignored");
+            return;
+        };
+
+        let mut state = self.state.lock();
+
+        if state.notifications.len() >= MAX_NOTIFICATIONS {
+            warn!("Notification queue full, dropping the oldest notification");
+            state.notifications.pop_front();
+        }
+
+        state.notifications.push_back(notification);
+
+        self.cvar.notify_all();
+    }
+
+    fn wait(&self, token: &str, timeout: Duration) -> Option<Response> {
+        let deadline = now() + timeout;
+        let mut state = self.state.lock();
+
+        loop {
+            if let Some(response) = state.pending.get_mut(token).and_then(Option::take) {
+                return Some(response);
+            }
+
+            let remaining = deadline.saturating_sub(now());
+            if remaining.is_zero() {
+                return None;
+            }
+
+            state = self.cvar.wait_timeout(state, remaining).0;
+        }
+    }
+
+    fn next_notification(&self, timeout: Duration) -> Option<Value> {
+        let deadline = now() + timeout;
+        let mut state = self.state.lock();
+
+        loop {
+            if let Some(notification) = state.notifications.pop_front() {
+                return Some(notification);
+            }
+
+            let remaining = deadline.saturating_sub(now());
+            if remaining.is_zero() {
+                return None;
+            }
+
+            state = self.cvar.wait_timeout(state, remaining).0;
+        }
+    }
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Loopback<'a>(&'a Requests, bool);
+
+    impl embedded_svc::mqtt::client::ErrorType for Loopback<'_> {
+        type Error = EspError;
+    }
+
+    impl Publish for Loopback<'_> {
+        fn publish(
+            &mut self,
+            _topic: &str,
+            _qos: QoS,
+            _retain: bool,
+            payload: &[u8],
+        ) -> Result<u32, EspError> {
+            let request: Value = serde_json::from_slice(payload).unwrap();
+
+            let response = json!({
+                "clientToken": request["clientToken"],
+                "code": 404,
+                "message": "No shadow exists",
+                "execution": { "jobId": "job-1", "status": "IN_PROGRESS" },
+            });
+
+            self.0
+                .respond(&serde_json::to_vec(&response).unwrap(), self.1);
+
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn correlation() {
+        let requests = Arc::new(Requests::new());
+
+        let execution = Requests::send(
+            &requests,
+            &mut Loopback(&requests, true),
+            "jobs/start-next",
+            QoS::AtLeastOnce,
+            json!({}),
+            Duration::from_millis(100),
+            DeviceJobs::execution,
+        )
+        .unwrap()
+        .wait()
+        .unwrap()
+        .unwrap();
+        assert_eq!(execution.job_id, "job-1");
+        assert_eq!(execution.status, JobStatus::InProgress);
+
+        let request = Requests::send(
+            &requests,
+            &mut Loopback(&requests, false),
+            "shadow/get",
+            QoS::AtLeastOnce,
+            json!({}),
+            Duration::from_millis(100),
+            serde_json::from_value::<ShadowDocument>,
+        )
+        .unwrap();
+        assert!(matches!(
+            request.wait(),
+            Err(CloudError::Rejected { code: 404, .. })
+        ));
+
+        requests.respond(br#"{"clientToken": "other-1"}"#, true);
+        assert!(requests.state.lock().pending.is_empty());
+    }
+}
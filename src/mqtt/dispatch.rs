@@ -0,0 +1,495 @@
+//! Routing of the received MQTT messages by topic filter
+//!
+//! `MqttDispatcher` registers handlers - or async streams - per topic filter, and routes
+//! the messages received by a client to all routes whose filter matches the topic of the
+//! message, following the wildcard rules of the MQTT specification (`+` and `#`).
+//!
+//! The dispatcher works on top of any client implementing the `embedded-svc` MQTT traits,
+//! like `EspMqttClient` or `EspAsyncMqttClient`:
+//! - the payloads delivered in chunks (see `Details::InitialChunk`) are reassembled,
+//!   up to a maximum size
+//! - all filters are subscribed again after the client reconnects, unless the broker
+//!   resumed the previous session
+//!
+//! Typical usage:
+//! ```ignore
+//! let (mut client, mut connection) = EspMqttClient::new(url, &conf)?;
+//!
+//! let mut dispatcher = MqttDispatcher::new(4096);
+//!
+//! dispatcher.route("devices/+/config", QoS::AtLeastOnce, |topic, payload| {
+//!     info!("Configuration on {topic}: {} bytes", payload.len());
+//! })?;
+//!
+//! let mut alerts = dispatcher.stream("alerts/#", QoS::AtMostOnce, 8)?;
+//!
+//! // `client` is typically shared with other tasks, e.g. in an `Arc<Mutex<_>>`
+//! while let Ok(event) = connection.next() {
+//!     dispatcher.handle(&mut client, &event)?;
+//! }
+//! ```
+
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::{debug, warn};
+
+use embedded_svc::mqtt::client::{asynch, Client, Details, Event, EventPayload, QoS};
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+type RouteHandler<'a> = Box<dyn FnMut(&str, &[u8]) + Send + 'a>;
+
+struct Route<'a> {
+    filter: String,
+    qos: QoS,
+    handler: RouteHandler<'a>,
+}
+
+struct Chunked {
+    topic: String,
+    data: Vec<u8>,
+    overflow: bool,
+}
+
+/// A dispatcher of the received MQTT messages to handlers and streams, by topic filter
+pub struct MqttDispatcher<'a> {
+    routes: Vec<Route<'a>>,
+    max_payload_size: usize,
+    chunked: Option<Chunked>,
+}
+
+impl<'a> MqttDispatcher<'a> {
+    /// Create a dispatcher reassembling chunked payloads of up to `max_payload_size` bytes
+    ///
+    /// Larger chunked payloads are discarded.
+    pub const fn new(max_payload_size: usize) -> Self {
+        Self {
+            routes: Vec::new(),
+            max_payload_size,
+            chunked: None,
+        }
+    }
+
+    /// Add a route calling `handler` with the topic and the payload of the messages matching `filter`
+    ///
+    /// The filter is subscribed with the given QoS by `subscribe_all` (or `handle`).
+    /// A message matching several routes is passed to all of them.
+    /// Returns `ESP_ERR_INVALID_ARG` if the filter is malformed.
+    pub fn route<F>(&mut self, filter: &str, qos: QoS, handler: F) -> Result<&mut Self, EspError>
+    where
+        F: FnMut(&str, &[u8]) + Send + 'a,
+    {
+        if !is_valid_filter(filter) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        self.routes.push(Route {
+            filter: filter.to_string(),
+            qos,
+            handler: Box::new(handler),
+        });
+
+        Ok(self)
+    }
+
+    /// Add a route queueing the messages matching `filter` into the returned stream
+    ///
+    /// Up to `capacity` messages are queued; when the stream is full, the oldest message is dropped.
+    /// Returns `ESP_ERR_INVALID_ARG` if the filter is malformed.
+    pub fn stream(
+        &mut self,
+        filter: &str,
+        qos: QoS,
+        capacity: usize,
+    ) -> Result<MqttTopicStream, EspError> {
+        let shared = Arc::new(Mutex::new(StreamState {
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            dropped: 0,
+            waker: None,
+            closed: false,
+        }));
+
+        let sender = StreamSender(shared.clone());
+
+        self.route(filter, qos, move |topic, payload| {
+            sender.push(topic, payload)
+        })?;
+
+        Ok(MqttTopicStream(shared))
+    }
+
+    /// Return the distinct filters of all routes, with the highest QoS requested for each
+    pub fn filters(&self) -> Vec<(&str, QoS)> {
+        let mut filters: Vec<(&str, QoS)> = Vec::new();
+
+        for route in &self.routes {
+            if let Some((_, qos)) = filters
+                .iter_mut()
+                .find(|(filter, _)| *filter == route.filter)
+            {
+                if route.qos as u8 > *qos as u8 {
+                    *qos = route.qos;
+                }
+            } else {
+                filters.push((route.filter.as_str(), route.qos));
+            }
+        }
+
+        filters
+    }
+
+    /// Subscribe all filters
+    pub fn subscribe_all<C>(&self, client: &mut C) -> Result<(), C::Error>
+    where
+        C: Client,
+    {
+        for (filter, qos) in self.filters() {
+            debug!("Subscribing to {filter}");
+
+            client.subscribe(filter, qos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe all filters
+    pub async fn subscribe_all_async<C>(&self, client: &mut C) -> Result<(), C::Error>
+    where
+        C: asynch::Client,
+    {
+        for (filter, qos) in self.filters() {
+            debug!("Subscribing to {filter}");
+
+            client.subscribe(filter, qos).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle an event of the client: dispatch the received messages, and subscribe all filters
+    /// once connected, unless the broker resumed the previous session
+    pub fn handle<C, E>(&mut self, client: &mut C, event: &E) -> Result<(), C::Error>
+    where
+        C: Client,
+        E: Event,
+    {
+        if self.dispatch(event) {
+            self.subscribe_all(client)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `handle`, for async clients
+    pub async fn handle_async<C, E>(&mut self, client: &mut C, event: &E) -> Result<(), C::Error>
+    where
+        C: asynch::Client,
+        E: Event,
+    {
+        if self.dispatch(event) {
+            self.subscribe_all_async(client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Dispatch the received messages of the event, without subscribing
+    ///
+    /// Return `true` if the event is a connection to the broker which did not resume
+    /// the previous session, i.e. if the filters need to be subscribed.
+    pub fn dispatch<E>(&mut self, event: &E) -> bool
+    where
+        E: Event,
+    {
+        match event.payload() {
+            EventPayload::Connected(session_present) => {
+                self.chunked = None;
+
+                !session_present
+            }
+            EventPayload::Disconnected => {
+                self.chunked = None;
+
+                false
+            }
+            EventPayload::Received {
+                topic,
+                data,
+                details,
+                ..
+            } => {
+                self.receive(topic, data, details);
+
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn receive(&mut self, topic: Option<&str>, data: &[u8], details: Details) {
+        match details {
+            Details::Complete => {
+                if let Some(topic) = topic {
+                    self.deliver(topic, data);
+                }
+            }
+            Details::InitialChunk(initial) => {
+                if self.chunked.is_some() {
+                    warn!("Incomplete chunked MQTT message discarded");
+                }
+
+                let overflow = initial.total_data_size > self.max_payload_size;
+                if overflow {
+                    warn!(
+                        "MQTT message of {} bytes exceeds {} bytes, discarding",
+                        initial.total_data_size, self.max_payload_size
+                    );
+                }
+
+                self.chunked = topic.map(|topic| Chunked {
+                    topic: topic.to_string(),
+                    data: if overflow {
+                        Vec::new()
+                    } else {
+                        let mut buf = Vec::with_capacity(initial.total_data_size);
+                        buf.extend_from_slice(data);
+                        buf
+                    },
+                    overflow,
+                });
+            }
+            Details::SubsequentChunk(subsequent) => {
+                let Some(chunked) = self.chunked.as_mut() else {
+                    // A chunk of a message whose start was missed
+                    return;
+                };
+
+                if !chunked.overflow {
+                    chunked.data.extend_from_slice(data);
+                }
+
+                if subsequent.current_data_offset + data.len() >= subsequent.total_data_size {
+                    let chunked = self.chunked.take().unwrap();
+
+                    if !chunked.overflow {
+                        self.deliver(&chunked.topic, &chunked.data);
+                    }
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, topic: &str, payload: &[u8]) {
+        let mut delivered = false;
+
+        for route in self
+            .routes
+            .iter_mut()
+            .filter(|route| topic_matches(&route.filter, topic))
+        {
+            (route.handler)(topic, payload);
+
+            delivered = true;
+        }
+
+        if !delivered {
+            debug!("No route for MQTT message on {topic}");
+        }
+    }
+}
+
+/// A message queued by an `MqttTopicStream`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+struct StreamState {
+    queue: VecDeque<MqttMessage>,
+    capacity: usize,
+    dropped: usize,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The messages of a topic filter, as returned by `MqttDispatcher::stream`
+pub struct MqttTopicStream(Arc<Mutex<StreamState>>);
+
+impl MqttTopicStream {
+    /// Wait for the next message
+    ///
+    /// Return `None` once the queued messages are consumed and the dispatcher is dropped.
+    pub async fn next(&mut self) -> Option<MqttMessage> {
+        poll_fn(|cx| {
+            let mut state = self.0.lock();
+
+            if let Some(message) = state.queue.pop_front() {
+                Poll::Ready(Some(message))
+            } else if state.closed {
+                Poll::Ready(None)
+            } else {
+                state.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Return the next message, if one is queued
+    pub fn try_next(&mut self) -> Option<MqttMessage> {
+        self.0.lock().queue.pop_front()
+    }
+
+    /// Return the number of messages dropped because the stream was full
+    pub fn dropped(&self) -> usize {
+        self.0.lock().dropped
+    }
+}
+
+struct StreamSender(Arc<Mutex<StreamState>>);
+
+impl StreamSender {
+    fn push(&self, topic: &str, payload: &[u8]) {
+        let mut state = self.0.lock();
+
+        if state.queue.len() >= state.capacity {
+            state.queue.pop_front();
+            state.dropped += 1;
+        }
+
+        state.queue.push_back(MqttMessage {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        });
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+
+        state.closed = true;
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Return `true` if the topic filter is well-formed (MQTT §4.7.1)
+///
+/// Shared subscription filters (`$share/{group}/{filter}`) are supported.
+pub fn is_valid_filter(filter: &str) -> bool {
+    let Some(filter) = strip_share(filter) else {
+        return false;
+    };
+
+    if filter.is_empty() {
+        return false;
+    }
+
+    let mut levels = filter.split('/').peekable();
+
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return false,
+            "#" | "+" => (),
+            level if level.contains(['#', '+']) => return false,
+            _ => (),
+        }
+    }
+
+    true
+}
+
+/// Return `true` if the topic matches the topic filter (MQTT §4.7)
+///
+/// Topics starting with `$` are not matched by filters starting with a wildcard.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let Some(filter) = strip_share(filter) else {
+        return false;
+    };
+
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            // `#` also matches the parent level
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (level, Some(topic_level)) if level == topic_level => (),
+            _ => return false,
+        }
+    }
+
+    topic_levels.next().is_none()
+}
+
+/// Strip the `$share/{group}/` prefix of shared subscription filters
+fn strip_share(filter: &str) -> Option<&str> {
+    match filter.strip_prefix("$share/") {
+        Some(shared) => {
+            let (group, filter) = shared.split_once('/')?;
+
+            (!group.is_empty() && !group.contains(['+', '#'])).then_some(filter)
+        }
+        None => Some(filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_filter, topic_matches};
+
+    #[test]
+    fn filters() {
+        assert!(is_valid_filter("sport/tennis/#"));
+        assert!(is_valid_filter("+/tennis/+"));
+        assert!(is_valid_filter("#"));
+        assert!(is_valid_filter("$share/group/sport/+"));
+        assert!(!is_valid_filter("sport/tennis#"));
+        assert!(!is_valid_filter("sport/#/ranking"));
+        assert!(!is_valid_filter("sport+"));
+        assert!(!is_valid_filter(""));
+        assert!(!is_valid_filter("$share/group"));
+    }
+
+    #[test]
+    fn matching() {
+        assert!(topic_matches("sport/tennis/#", "sport/tennis"));
+        assert!(topic_matches(
+            "sport/tennis/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(topic_matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(topic_matches("sport/+", "sport/"));
+        assert!(topic_matches("+/+", "/finance"));
+        assert!(topic_matches("$share/group/sport/#", "sport/tennis"));
+        assert!(topic_matches("$SYS/#", "$SYS/monitor"));
+        assert!(!topic_matches("sport/+", "sport"));
+        assert!(!topic_matches("sport/+", "sport/tennis/player1"));
+        assert!(!topic_matches("sport/tennis", "sport/tennis/player1"));
+        assert!(!topic_matches("#", "$SYS/monitor"));
+        assert!(!topic_matches("+/monitor", "$SYS/monitor"));
+    }
+}
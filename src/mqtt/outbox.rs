@@ -0,0 +1,1042 @@
+//! A persistent outbox for MQTT publications
+//!
+//! The outbox of the ESP-IDF MQTT client is kept in RAM, so the QoS 1 and 2 messages
+//! queued while the device is offline are lost on reboot. `MqttOutbox` persists the
+//! publications in flash - in a dedicated data partition, or in a file of a mounted
+//! filesystem - and publishes them, in order, once the client is connected.
+//!
+//! A publication is removed from the outbox once it is acknowledged by the broker (i.e. on
+//! the `Published` event of the client for QoS 1 and 2, and once published for QoS 0).
+//! Publications which are not acknowledged before a reboot are published again, so the
+//! delivery is at least once.
+//!
+//! The storage is used as a ring of sectors, erased one at a time, so the flash wear is
+//! spread over the whole partition. When the storage (or `OutboxConfiguration::max_messages`)
+//! is exhausted, the `OutboxOverflow` policy decides whether the oldest or the newest
+//! publications are dropped.
+//!
+//! Typical usage:
+//! ```ignore
+//! let partition = unsafe { EspPartition::new("outbox") }?.unwrap();
+//! let mut outbox = MqttOutbox::new(partition, &OutboxConfiguration::default())?;
+//!
+//! // Publications while offline are persisted, and published once connected
+//! outbox.publish(&mut client, "loggers/1/samples", QoS::AtLeastOnce, false, &sample)?;
+//!
+//! // In the task processing the events of the client
+//! outbox.handle(&mut client, &event)?;
+//! ```
+//!
+//! Note that encrypted partitions are not supported, and are rejected by `MqttOutbox::new`.
+
+use core::fmt;
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ::log::{debug, warn};
+
+use embedded_svc::mqtt::client::{asynch, Event, EventPayload, MessageId, Publish, QoS};
+
+use crate::sys::*;
+
+const SECTOR_MAGIC: u32 = 0x3158_424f;
+const SECTOR_HEADER_LEN: usize = 8;
+
+const RECORD_MAGIC: u8 = 0xa5;
+const RECORD_HEADER_LEN: usize = 16;
+
+const STATE_PENDING: u8 = 0xff;
+const STATE_SENT: u8 = 0x00;
+
+const ERASED: u8 = 0xff;
+
+/// The storage of an `MqttOutbox`, with the semantics of a NOR flash: erasing sets all
+/// bytes to `0xff`, and writing may only clear bits of erased bytes
+pub trait MqttOutboxStorage {
+    /// Return the size of the storage, in bytes
+    fn size(&self) -> usize;
+
+    /// Return the size of the erase unit, in bytes
+    fn sector_size(&self) -> usize;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EspError>;
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError>;
+
+    /// Erase a region aligned on the erase unit
+    fn erase(&mut self, offset: usize, size: usize) -> Result<(), EspError>;
+
+    /// Return `true` if the storage is encrypted
+    ///
+    /// Encrypted storage is not supported, as it does not allow clearing bits of
+    /// already written bytes.
+    fn encrypted(&self) -> bool {
+        false
+    }
+}
+
+impl<T> MqttOutboxStorage for &mut T
+where
+    T: MqttOutboxStorage,
+{
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+        (**self).read(offset, buf)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+        (**self).write(offset, data)
+    }
+
+    fn erase(&mut self, offset: usize, size: usize) -> Result<(), EspError> {
+        (**self).erase(offset, size)
+    }
+
+    fn encrypted(&self) -> bool {
+        (**self).encrypted()
+    }
+}
+
+#[cfg(any(esp_idf_comp_spi_flash_enabled, esp_idf_comp_esp_partition_enabled))]
+impl MqttOutboxStorage for crate::partition::EspPartition {
+    fn size(&self) -> usize {
+        crate::partition::EspPartition::size(self)
+    }
+
+    fn sector_size(&self) -> usize {
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let sector_size = self.erase_size();
+
+        #[cfg(esp_idf_version_major = "4")]
+        let sector_size = 4096;
+
+        sector_size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+        crate::partition::EspPartition::read(self, offset, buf)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+        crate::partition::EspPartition::write(self, offset, data)
+    }
+
+    fn erase(&mut self, offset: usize, size: usize) -> Result<(), EspError> {
+        crate::partition::EspPartition::erase(self, offset, size)
+    }
+
+    fn encrypted(&self) -> bool {
+        crate::partition::EspPartition::encrypted(self)
+    }
+}
+
+/// An `MqttOutboxStorage` in a file of a mounted filesystem (e.g. SPIFFS, LittleFS or FAT)
+#[cfg(feature = "std")]
+pub struct FileOutboxStorage {
+    file: std::fs::File,
+    size: usize,
+    sector_size: usize,
+}
+
+#[cfg(feature = "std")]
+impl FileOutboxStorage {
+    /// Open - or create - the file of the outbox, with the given size and erase unit
+    pub fn new<P>(path: P, size: usize, sector_size: usize) -> Result<Self, EspError>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        if sector_size == 0 || size % sector_size != 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(Self::map_err)?;
+
+        let len = file.metadata().map_err(Self::map_err)?.len() as usize;
+
+        if len < size {
+            // Extend the file with erased sectors
+            use std::io::{Seek, SeekFrom, Write};
+
+            file.seek(SeekFrom::Start(len as _))
+                .map_err(Self::map_err)?;
+            file.write_all(&vec![ERASED; size - len])
+                .map_err(Self::map_err)?;
+        }
+
+        Ok(Self {
+            file,
+            size,
+            sector_size,
+        })
+    }
+
+    fn map_err(e: std::io::Error) -> EspError {
+        warn!("Outbox file error: {e}");
+
+        EspError::from_infallible::<ESP_FAIL>()
+    }
+}
+
+#[cfg(feature = "std")]
+impl MqttOutboxStorage for FileOutboxStorage {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        self.file
+            .seek(SeekFrom::Start(offset as _))
+            .map_err(Self::map_err)?;
+        self.file.read_exact(buf).map_err(Self::map_err)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        self.file
+            .seek(SeekFrom::Start(offset as _))
+            .map_err(Self::map_err)?;
+        self.file.write_all(data).map_err(Self::map_err)?;
+        self.file.flush().map_err(Self::map_err)
+    }
+
+    fn erase(&mut self, offset: usize, size: usize) -> Result<(), EspError> {
+        self.write(offset, &vec![ERASED; size])
+    }
+}
+
+/// What to do with a publication when the outbox is full
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutboxOverflow {
+    /// Drop the oldest publications to make room for the new one
+    ///
+    /// Only publications which are not published yet are dropped: those awaiting
+    /// their acknowledgement are kept, and the new publication is rejected with
+    /// `ESP_ERR_NO_MEM` if dropping them would be the only way to make room.
+    #[default]
+    DropOldest,
+    /// Reject the new publication with `ESP_ERR_NO_MEM`
+    DropNewest,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutboxConfiguration {
+    /// The maximum number of pending publications; `None` for as many as the storage fits
+    pub max_messages: Option<usize>,
+    pub overflow: OutboxOverflow,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MqttOutboxError<E> {
+    /// Accessing the storage of the outbox failed
+    Storage(EspError),
+    /// Publishing with the client failed
+    Client(E),
+}
+
+impl<E> fmt::Display for MqttOutboxError<E>
+where
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(e) => write!(f, "Storage error: {e}"),
+            Self::Client(e) => write!(f, "Client error: {e:?}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for MqttOutboxError<E> where E: fmt::Debug {}
+
+/// A publication read back from the outbox
+struct Record {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Sector {
+    /// The sequence number of the sector, if it holds records
+    seq: Option<u32>,
+    /// The number of pending records of the sector
+    pending: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    sector: usize,
+    offset: usize,
+    qos: QoS,
+    /// The message ID, once published and until acknowledged
+    msg_id: Option<MessageId>,
+}
+
+/// A persistent outbox of MQTT publications
+pub struct MqttOutbox<S> {
+    storage: S,
+    conf: OutboxConfiguration,
+    sector_size: usize,
+    sectors: Vec<Sector>,
+    head: Option<usize>,
+    head_offset: usize,
+    next_seq: u32,
+    entries: VecDeque<Entry>,
+    connected: bool,
+}
+
+impl<S> MqttOutbox<S>
+where
+    S: MqttOutboxStorage,
+{
+    /// Open the outbox in the storage, recovering the pending publications
+    ///
+    /// The storage must have at least two sectors and must not be encrypted. Sectors which
+    /// do not hold outbox data are erased when needed, so a new storage needs no formatting.
+    pub fn new(storage: S, conf: &OutboxConfiguration) -> Result<Self, EspError> {
+        if storage.encrypted() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        let sector_size = storage.sector_size();
+        let sectors = if sector_size > SECTOR_HEADER_LEN + RECORD_HEADER_LEN {
+            storage.size() / sector_size
+        } else {
+            0
+        };
+
+        if sectors < 2 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut outbox = Self {
+            storage,
+            conf: *conf,
+            sector_size,
+            sectors: vec![Sector::default(); sectors],
+            head: None,
+            head_offset: 0,
+            next_seq: 0,
+            entries: VecDeque::new(),
+            connected: false,
+        };
+
+        outbox.recover()?;
+
+        Ok(outbox)
+    }
+
+    /// Return the number of pending publications
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the maximum size of a publication - topic and payload - the outbox accepts
+    pub fn max_message_size(&self) -> usize {
+        self.sector_size - SECTOR_HEADER_LEN - RECORD_HEADER_LEN
+    }
+
+    /// Persist a publication, without publishing it
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if the publication exceeds `max_message_size`, and
+    /// `ESP_ERR_NO_MEM` if the outbox is full and the overflow policy is `DropNewest`, or
+    /// no room can be made without dropping a publication awaiting its acknowledgement.
+    pub fn push(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), EspError> {
+        if topic.len() > u16::MAX as usize || topic.len() + payload.len() > self.max_message_size()
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        if let Some(max_messages) = self.conf.max_messages {
+            while self.entries.len() >= max_messages.max(1) {
+                match self.conf.overflow {
+                    OutboxOverflow::DropOldest => {
+                        // The publications awaiting their acknowledgement must be kept,
+                        // as they are never published again once removed
+                        let Some(index) =
+                            self.entries.iter().position(|entry| entry.msg_id.is_none())
+                        else {
+                            warn!("Outbox full of publications awaiting acknowledgement");
+                            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+                        };
+
+                        warn!("Outbox full, dropping the oldest unpublished publication");
+
+                        self.remove(index)?;
+                    }
+                    OutboxOverflow::DropNewest => {
+                        return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
+                    }
+                }
+            }
+        }
+
+        let record = encode(topic, qos, retain, payload);
+
+        if self.head.is_none() || self.head_offset + record.len() > self.sector_size {
+            self.advance()?;
+        }
+
+        let sector = self.head.unwrap();
+        let offset = self.head_offset;
+
+        self.storage
+            .write(sector * self.sector_size + offset, &record)?;
+
+        self.head_offset += record.len();
+        self.sectors[sector].pending += 1;
+        self.entries.push_back(Entry {
+            sector,
+            offset,
+            qos,
+            msg_id: None,
+        });
+
+        debug!("Publication on {topic} persisted in the outbox");
+
+        Ok(())
+    }
+
+    /// Persist a publication, and publish the pending publications if the client is connected
+    ///
+    /// The connection state is tracked by `handle`, which should be called with all the events
+    /// of the client.
+    pub fn publish<C>(
+        &mut self,
+        client: &mut C,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), MqttOutboxError<C::Error>>
+    where
+        C: Publish,
+    {
+        self.push(topic, qos, retain, payload)
+            .map_err(MqttOutboxError::Storage)?;
+
+        if self.connected {
+            self.flush(client)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `publish`, for async clients
+    pub async fn publish_async<C>(
+        &mut self,
+        client: &mut C,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), MqttOutboxError<C::Error>>
+    where
+        C: asynch::Publish,
+    {
+        self.push(topic, qos, retain, payload)
+            .map_err(MqttOutboxError::Storage)?;
+
+        if self.connected {
+            self.flush_async(client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish the pending publications which are not published yet, in order, and return
+    /// their number
+    pub fn flush<C>(&mut self, client: &mut C) -> Result<usize, MqttOutboxError<C::Error>>
+    where
+        C: Publish,
+    {
+        let mut published = 0;
+        let mut index = 0;
+
+        while let Some((next, record)) = self
+            .next_unpublished(index)
+            .map_err(MqttOutboxError::Storage)?
+        {
+            let msg_id = client
+                .publish(&record.topic, record.qos, record.retain, &record.payload)
+                .map_err(MqttOutboxError::Client)?;
+
+            index = self
+                .published(next, msg_id)
+                .map_err(MqttOutboxError::Storage)?;
+            published += 1;
+        }
+
+        Ok(published)
+    }
+
+    /// Same as `flush`, for async clients
+    pub async fn flush_async<C>(
+        &mut self,
+        client: &mut C,
+    ) -> Result<usize, MqttOutboxError<C::Error>>
+    where
+        C: asynch::Publish,
+    {
+        let mut published = 0;
+        let mut index = 0;
+
+        while let Some((next, record)) = self
+            .next_unpublished(index)
+            .map_err(MqttOutboxError::Storage)?
+        {
+            let msg_id = client
+                .publish(&record.topic, record.qos, record.retain, &record.payload)
+                .await
+                .map_err(MqttOutboxError::Client)?;
+
+            index = self
+                .published(next, msg_id)
+                .map_err(MqttOutboxError::Storage)?;
+            published += 1;
+        }
+
+        Ok(published)
+    }
+
+    /// Handle an event of the client: publish the pending publications once connected,
+    /// and remove the acknowledged ones
+    pub fn handle<C, E>(
+        &mut self,
+        client: &mut C,
+        event: &E,
+    ) -> Result<(), MqttOutboxError<C::Error>>
+    where
+        C: Publish,
+        E: Event,
+    {
+        if self.track(event).map_err(MqttOutboxError::Storage)? {
+            self.flush(client)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `handle`, for async clients
+    pub async fn handle_async<C, E>(
+        &mut self,
+        client: &mut C,
+        event: &E,
+    ) -> Result<(), MqttOutboxError<C::Error>>
+    where
+        C: asynch::Publish,
+        E: Event,
+    {
+        if self.track(event).map_err(MqttOutboxError::Storage)? {
+            self.flush_async(client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop all pending publications and erase the storage
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        self.entries.clear();
+
+        for (index, sector) in self.sectors.iter_mut().enumerate() {
+            if sector.seq.is_some() {
+                self.storage
+                    .erase(index * self.sector_size, self.sector_size)?;
+            }
+
+            *sector = Sector::default();
+        }
+
+        self.head = None;
+        self.head_offset = 0;
+
+        Ok(())
+    }
+
+    /// Release the storage
+    pub fn release(self) -> S {
+        self.storage
+    }
+
+    /// Update the state of the outbox with the event, and return `true` if the pending
+    /// publications should be published
+    fn track<E>(&mut self, event: &E) -> Result<bool, EspError>
+    where
+        E: Event,
+    {
+        match event.payload() {
+            EventPayload::Connected(_) => {
+                self.connected = true;
+
+                Ok(true)
+            }
+            EventPayload::Disconnected => {
+                // The client re-sends its in-flight publications after reconnecting,
+                // so these are not published again
+                self.connected = false;
+
+                Ok(false)
+            }
+            EventPayload::Published(msg_id) => {
+                if let Some(index) = self.position(msg_id) {
+                    self.remove(index)?;
+                }
+
+                Ok(false)
+            }
+            EventPayload::Deleted(msg_id) => {
+                // The client dropped the publication from its own outbox, so publish it again
+                if let Some(index) = self.position(msg_id) {
+                    self.entries[index].msg_id = None;
+                }
+
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn position(&self, msg_id: MessageId) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.msg_id == Some(msg_id))
+    }
+
+    fn next_unpublished(&mut self, from: usize) -> Result<Option<(usize, Record)>, EspError> {
+        let Some(index) =
+            (from..self.entries.len()).find(|index| self.entries[*index].msg_id.is_none())
+        else {
+            return Ok(None);
+        };
+
+        let entry = self.entries[index];
+
+        let record = self.read(entry.sector, entry.offset)?.ok_or_else(|| {
+            warn!("Outbox record corrupted");
+            EspError::from_infallible::<ESP_ERR_INVALID_CRC>()
+        })?;
+
+        Ok(Some((index, record)))
+    }
+
+    /// Record the publication of the entry, and return the index of the next entry
+    fn published(&mut self, index: usize, msg_id: MessageId) -> Result<usize, EspError> {
+        if self.entries[index].qos == QoS::AtMostOnce {
+            // Not acknowledged by the broker
+            self.remove(index)?;
+
+            Ok(index)
+        } else {
+            self.entries[index].msg_id = Some(msg_id);
+
+            Ok(index + 1)
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Result<(), EspError> {
+        let entry = self.entries.remove(index).unwrap();
+
+        self.sectors[entry.sector].pending -= 1;
+
+        self.storage.write(
+            entry.sector * self.sector_size + entry.offset + 1,
+            &[STATE_SENT],
+        )
+    }
+
+    /// Start writing in the next sector of the ring
+    fn advance(&mut self) -> Result<(), EspError> {
+        let next = self
+            .head
+            .map(|head| (head + 1) % self.sectors.len())
+            .unwrap_or(0);
+
+        let pending = self.sectors[next].pending;
+        if pending > 0 {
+            match self.conf.overflow {
+                OutboxOverflow::DropOldest => {
+                    if self
+                        .entries
+                        .iter()
+                        .any(|entry| entry.sector == next && entry.msg_id.is_some())
+                    {
+                        warn!("Outbox full, the oldest publications await acknowledgement");
+                        return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+                    }
+
+                    warn!("Outbox full, dropping the {pending} oldest publication(s)");
+
+                    self.entries.retain(|entry| entry.sector != next);
+                }
+                OutboxOverflow::DropNewest => {
+                    return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
+                }
+            }
+        }
+
+        let offset = next * self.sector_size;
+
+        self.storage.erase(offset, self.sector_size)?;
+
+        let mut header = [0; SECTOR_HEADER_LEN];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&self.next_seq.to_le_bytes());
+
+        self.storage.write(offset, &header)?;
+
+        self.sectors[next] = Sector {
+            seq: Some(self.next_seq),
+            pending: 0,
+        };
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.head = Some(next);
+        self.head_offset = SECTOR_HEADER_LEN;
+
+        Ok(())
+    }
+
+    /// Scan the storage for the sectors and the pending records
+    fn recover(&mut self) -> Result<(), EspError> {
+        let mut order = Vec::new();
+
+        for index in 0..self.sectors.len() {
+            let mut header = [0; SECTOR_HEADER_LEN];
+            self.storage.read(index * self.sector_size, &mut header)?;
+
+            if header[..4] == SECTOR_MAGIC.to_le_bytes() {
+                let seq = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+                self.sectors[index].seq = Some(seq);
+                order.push((seq, index));
+            }
+        }
+
+        order.sort_unstable();
+
+        for (seq, index) in order {
+            let mut offset = SECTOR_HEADER_LEN;
+
+            loop {
+                if offset + RECORD_HEADER_LEN > self.sector_size {
+                    break;
+                }
+
+                let mut header = [0; RECORD_HEADER_LEN];
+                self.storage
+                    .read(index * self.sector_size + offset, &mut header)?;
+
+                if header[0] == ERASED {
+                    break;
+                }
+
+                let Some(len) = record_len(&header)
+                    .filter(|len| offset + len <= self.sector_size)
+                    .filter(|_| header[0] == RECORD_MAGIC)
+                else {
+                    warn!("Outbox sector {index} corrupted after offset {offset}");
+
+                    offset = self.sector_size;
+                    break;
+                };
+
+                match self.read(index, offset)? {
+                    Some(record) => {
+                        if header[1] == STATE_PENDING {
+                            self.sectors[index].pending += 1;
+                            self.entries.push_back(Entry {
+                                sector: index,
+                                offset,
+                                qos: record.qos,
+                                msg_id: None,
+                            });
+                        }
+                    }
+                    None => {
+                        // Likely a write interrupted by a reset
+                        warn!("Outbox sector {index} corrupted after offset {offset}");
+
+                        offset = self.sector_size;
+                        break;
+                    }
+                }
+
+                offset += len;
+            }
+
+            self.head = Some(index);
+            self.head_offset = offset;
+            self.next_seq = seq.wrapping_add(1);
+        }
+
+        debug!(
+            "Outbox recovered with {} pending publication(s)",
+            self.entries.len()
+        );
+
+        Ok(())
+    }
+
+    /// Read the record at the offset of the sector, or `None` if it is corrupted
+    fn read(&mut self, sector: usize, offset: usize) -> Result<Option<Record>, EspError> {
+        let offset = sector * self.sector_size + offset;
+
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.storage.read(offset, &mut header)?;
+
+        let Some(len) = record_len(&header).filter(|len| *len <= self.sector_size) else {
+            return Ok(None);
+        };
+
+        let topic_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let payload_len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+        let mut body = vec![0; topic_len + payload_len];
+        self.storage.read(offset + RECORD_HEADER_LEN, &mut body)?;
+
+        let crc = crc32(crc32(!0, &header[2..12]), &body);
+        if crc.to_le_bytes() != header[12..16] || len < RECORD_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let qos = match header[2] {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => return Ok(None),
+        };
+
+        let payload = body.split_off(topic_len);
+        let Ok(topic) = String::from_utf8(body) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Record {
+            topic,
+            payload,
+            qos,
+            retain: header[3] != 0,
+        }))
+    }
+}
+
+/// Return the length of the record, padded to 4 bytes, from its header
+fn record_len(header: &[u8; RECORD_HEADER_LEN]) -> Option<usize> {
+    let topic_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let payload_len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+    RECORD_HEADER_LEN
+        .checked_add(topic_len)?
+        .checked_add(payload_len)?
+        .checked_next_multiple_of(4)
+}
+
+/// Encode a record, padded to 4 bytes
+///
+/// The header is the magic byte, the state, the QoS, the retain flag, the topic length
+/// (`u16`), two reserved bytes, the payload length (`u32`) and the CRC-32 of the
+/// header fields following the state and of the topic and payload.
+fn encode(topic: &str, qos: QoS, retain: bool, payload: &[u8]) -> Vec<u8> {
+    let len = (RECORD_HEADER_LEN + topic.len() + payload.len()).next_multiple_of(4);
+
+    let mut record = Vec::with_capacity(len);
+
+    record.extend_from_slice(&[RECORD_MAGIC, STATE_PENDING, qos as u8, retain as u8]);
+    record.extend_from_slice(&(topic.len() as u16).to_le_bytes());
+    record.extend_from_slice(&[ERASED, ERASED]);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(topic.as_bytes());
+    record.extend_from_slice(payload);
+
+    let crc = crc32(crc32(!0, &record[2..12]), &record[RECORD_HEADER_LEN..]);
+    record[12..16].copy_from_slice(&crc.to_le_bytes());
+
+    record.resize(len, ERASED);
+
+    record
+}
+
+/// Continue the CRC-32 (IEEE) computation over the data
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RamStorage(Vec<u8>);
+
+    impl MqttOutboxStorage for RamStorage {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+
+        fn sector_size(&self) -> usize {
+            256
+        }
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+            buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+            for (byte, data) in self.0[offset..offset + data.len()].iter_mut().zip(data) {
+                *byte &= *data;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, offset: usize, size: usize) -> Result<(), EspError> {
+            self.0[offset..offset + size].fill(ERASED);
+            Ok(())
+        }
+    }
+
+    fn topics(outbox: &mut MqttOutbox<&mut RamStorage>) -> Vec<String> {
+        let mut topics = Vec::new();
+        let mut index = 0;
+
+        while let Some((next, record)) = outbox.next_unpublished(index).unwrap() {
+            topics.push(record.topic);
+            index = next + 1;
+        }
+
+        topics
+    }
+
+    #[test]
+    fn recovery_and_overflow() {
+        let mut storage = RamStorage(vec![ERASED; 3 * 256]);
+        let conf = OutboxConfiguration::default();
+
+        let mut outbox = MqttOutbox::new(&mut storage, &conf).unwrap();
+
+        for index in 0..13 {
+            let topic = alloc::format!("t/{index}");
+            outbox
+                .push(&topic, QoS::AtLeastOnce, false, &[index as u8; 40])
+                .unwrap();
+        }
+
+        // 4 records of 60 bytes per sector, so the oldest sector was dropped
+        assert_eq!(outbox.len(), 9);
+
+        outbox.remove(0).unwrap();
+
+        let mut outbox = MqttOutbox::new(&mut storage, &conf).unwrap();
+        assert_eq!(outbox.len(), 8);
+        assert_eq!(topics(&mut outbox)[0], "t/5");
+
+        outbox
+            .push("t/13", QoS::ExactlyOnce, true, b"last")
+            .unwrap();
+
+        let mut outbox = MqttOutbox::new(&mut storage, &conf).unwrap();
+        assert_eq!(outbox.len(), 9);
+        assert_eq!(topics(&mut outbox).last().unwrap(), "t/13");
+    }
+
+    #[test]
+    fn overflow_keeps_unacknowledged() {
+        let mut storage = RamStorage(vec![ERASED; 3 * 256]);
+
+        let mut outbox = MqttOutbox::new(
+            &mut storage,
+            &OutboxConfiguration {
+                max_messages: Some(2),
+                overflow: OutboxOverflow::DropOldest,
+            },
+        )
+        .unwrap();
+
+        outbox.push("t/0", QoS::AtLeastOnce, false, b"0").unwrap();
+        outbox.push("t/1", QoS::AtLeastOnce, false, b"1").unwrap();
+        outbox.published(0, 7).unwrap();
+
+        outbox.push("t/2", QoS::AtLeastOnce, false, b"2").unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.position(7), Some(0));
+        assert_eq!(topics(&mut outbox), ["t/2"]);
+
+        outbox.published(1, 8).unwrap();
+
+        assert_eq!(
+            outbox
+                .push("t/3", QoS::AtLeastOnce, false, b"3")
+                .map_err(|e| e.code()),
+            Err(ESP_ERR_NO_MEM)
+        );
+        assert_eq!(outbox.len(), 2);
+
+        // The sector holding an unacknowledged publication cannot be reused either
+        let mut storage = RamStorage(vec![ERASED; 3 * 256]);
+        let mut outbox = MqttOutbox::new(&mut storage, &Default::default()).unwrap();
+
+        for index in 0..12 {
+            let topic = alloc::format!("t/{index}");
+            outbox
+                .push(&topic, QoS::AtLeastOnce, false, &[index as u8; 40])
+                .unwrap();
+        }
+
+        outbox.published(0, 1).unwrap();
+
+        assert_eq!(
+            outbox
+                .push("t/12", QoS::AtLeastOnce, false, &[12; 40])
+                .map_err(|e| e.code()),
+            Err(ESP_ERR_NO_MEM)
+        );
+        assert_eq!(outbox.len(), 12);
+    }
+}
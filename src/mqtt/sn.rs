@@ -0,0 +1,1337 @@
+//! MQTT-SN (MQTT for Sensor Networks) client
+//!
+//! MQTT-SN is a datagram flavour of MQTT for constrained links, where the sensor nodes talk
+//! to an MQTT-SN gateway which relays to an MQTT broker. `EspMqttSnClient` implements
+//! version 1.2 of the protocol on top of any datagram transport implementing
+//! `MqttSnTransport`, like a connected `std::net::UdpSocket`.
+//!
+//! Topics are addressed with 2-byte topic IDs:
+//! - topic names are registered with the gateway as needed, transparently
+//! - predefined topic IDs, agreed with the gateway up front, need no registration
+//! - 2-character topic names are sent as short topic names, which need no registration
+//!
+//! Publishing with QoS -1 (`MqttSnQoS::FireAndForget`) needs no connection at all, but only
+//! works with predefined topic IDs and short topic names.
+//!
+//! The client is synchronous: each request waits for its acknowledgement, and the messages
+//! of the subscriptions are received with `next`, which also sends the keep-alive pings.
+//! Sleeping clients enter the asleep state with `sleep`, then `wake` periodically to
+//! collect the messages buffered by the gateway in the meantime.
+//!
+//! Typical usage:
+//! ```ignore
+//! let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+//! socket.connect("192.168.1.10:1884")?;
+//!
+//! let mut client = EspMqttSnClient::new(
+//!     socket,
+//!     &MqttSnConfiguration {
+//!         client_id: "sensor-1",
+//!         ..Default::default()
+//!     },
+//! );
+//!
+//! client.connect()?;
+//! client.subscribe("commands/sensor-1", QoS::AtLeastOnce)?;
+//! client.publish("sensors/1/temperature", QoS::AtLeastOnce, false, b"21.5")?;
+//!
+//! while let Some(message) = client.next(Duration::from_secs(10))? {
+//!     info!("{:?}: {:?}", message.topic, message.payload);
+//! }
+//! ```
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ::log::{debug, info, warn};
+
+use embedded_svc::mqtt::client::{MessageId, QoS};
+
+use crate::sys::*;
+
+const PROTOCOL_ID: u8 = 0x01;
+
+const MAX_PACKET_SIZE: usize = 1024;
+
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const REGISTER: u8 = 0x0a;
+const REGACK: u8 = 0x0b;
+const PUBLISH: u8 = 0x0c;
+const PUBACK: u8 = 0x0d;
+const PUBCOMP: u8 = 0x0e;
+const PUBREC: u8 = 0x0f;
+const PUBREL: u8 = 0x10;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+
+const TOPIC_ID_NORMAL: u8 = 0x00;
+const TOPIC_ID_PREDEFINED: u8 = 0x01;
+const TOPIC_ID_SHORT: u8 = 0x02;
+
+const RC_ACCEPTED: u8 = 0x00;
+const RC_CONGESTION: u8 = 0x01;
+const RC_INVALID_TOPIC_ID: u8 = 0x02;
+
+/// A datagram transport to the MQTT-SN gateway
+pub trait MqttSnTransport {
+    /// Send a datagram to the gateway
+    fn send(&mut self, datagram: &[u8]) -> Result<(), EspError>;
+
+    /// Wait up to `timeout` for a datagram from the gateway, and return its length,
+    /// or `None` on timeout
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>, EspError>;
+}
+
+impl<T> MqttSnTransport for &mut T
+where
+    T: MqttSnTransport,
+{
+    fn send(&mut self, datagram: &[u8]) -> Result<(), EspError> {
+        (**self).send(datagram)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>, EspError> {
+        (**self).recv(buf, timeout)
+    }
+}
+
+/// A UDP socket connected to the gateway
+#[cfg(feature = "std")]
+impl MqttSnTransport for std::net::UdpSocket {
+    fn send(&mut self, datagram: &[u8]) -> Result<(), EspError> {
+        std::net::UdpSocket::send(self, datagram)
+            .map(|_| ())
+            .map_err(map_io_err)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>, EspError> {
+        self.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .map_err(map_io_err)?;
+
+        match std::net::UdpSocket::recv(self, buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(map_io_err(e)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn map_io_err(e: std::io::Error) -> EspError {
+    warn!("MQTT-SN socket error: {e}");
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
+
+/// The quality of service of an MQTT-SN publication
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MqttSnQoS {
+    /// QoS -1: published without a connection, to a predefined topic ID or a short topic name
+    FireAndForget,
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl MqttSnQoS {
+    const fn flags(&self) -> u8 {
+        match self {
+            Self::FireAndForget => 0x60,
+            Self::AtMostOnce => 0x00,
+            Self::AtLeastOnce => 0x20,
+            Self::ExactlyOnce => 0x40,
+        }
+    }
+
+    const fn from_flags(flags: u8) -> Self {
+        match flags & 0x60 {
+            0x00 => Self::AtMostOnce,
+            0x20 => Self::AtLeastOnce,
+            0x40 => Self::ExactlyOnce,
+            _ => Self::FireAndForget,
+        }
+    }
+}
+
+impl From<QoS> for MqttSnQoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => Self::AtMostOnce,
+            QoS::AtLeastOnce => Self::AtLeastOnce,
+            QoS::ExactlyOnce => Self::ExactlyOnce,
+        }
+    }
+}
+
+/// A topic, as addressed by `EspMqttSnClient::publish_topic` and `subscribe_topic`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MqttSnTopic<'a> {
+    /// A topic name (or, for subscriptions, a topic filter), registered with the gateway
+    /// as needed - or sent as a short topic name if it has 2 characters
+    Name(&'a str),
+    /// A topic ID predefined with the gateway
+    Predefined(u16),
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttSnConfiguration<'a> {
+    /// The client ID, of 1 to 23 characters
+    pub client_id: &'a str,
+    /// The keep-alive interval; the client pings the gateway when idle for that long
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    /// The time to wait for an acknowledgement before re-sending a request
+    pub retry_interval: Duration,
+    /// The number of re-sends of a request before giving up with `ESP_ERR_TIMEOUT`
+    pub retries: u8,
+    /// The names of the predefined topic IDs, reported with the received messages
+    pub predefined_topics: &'a [(u16, &'a str)],
+}
+
+impl Default for MqttSnConfiguration<'_> {
+    fn default() -> Self {
+        Self {
+            client_id: "esp-idf",
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            retry_interval: Duration::from_secs(10),
+            retries: 3,
+            predefined_topics: &[],
+        }
+    }
+}
+
+/// The state of an `EspMqttSnClient`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MqttSnState {
+    Disconnected,
+    Active,
+    Asleep,
+}
+
+/// A message received by an `EspMqttSnClient`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttSnMessage {
+    pub topic_id: u16,
+    /// The topic name, if known (i.e. registered, short, or a named predefined topic)
+    pub topic: Option<String>,
+    pub payload: Vec<u8>,
+    pub qos: MqttSnQoS,
+    pub retain: bool,
+}
+
+/// An MQTT-SN client
+pub struct EspMqttSnClient<T> {
+    transport: T,
+    client_id: String,
+    keep_alive: Duration,
+    clean_session: bool,
+    retry_interval: Duration,
+    retries: u8,
+    predefined: BTreeMap<u16, String>,
+    state: MqttSnState,
+    next_msg_id: u16,
+    /// Registered topic names, by name
+    topic_ids: BTreeMap<String, u16>,
+    /// Registered topic names, by ID
+    topic_names: BTreeMap<u16, String>,
+    messages: VecDeque<MqttSnMessage>,
+    /// The IDs of the received QoS 2 messages awaiting a PUBREL
+    pending_release: Vec<u16>,
+    last_sent: Duration,
+}
+
+impl<T> EspMqttSnClient<T>
+where
+    T: MqttSnTransport,
+{
+    /// Create a client on the transport; no packet is sent until `connect` or a QoS -1 publication
+    pub fn new(transport: T, conf: &MqttSnConfiguration<'_>) -> Self {
+        Self {
+            transport,
+            client_id: conf.client_id.to_string(),
+            keep_alive: conf.keep_alive,
+            clean_session: conf.clean_session,
+            retry_interval: conf.retry_interval,
+            retries: conf.retries,
+            predefined: conf
+                .predefined_topics
+                .iter()
+                .map(|(id, name)| (*id, name.to_string()))
+                .collect(),
+            state: MqttSnState::Disconnected,
+            next_msg_id: 0,
+            topic_ids: BTreeMap::new(),
+            topic_names: BTreeMap::new(),
+            messages: VecDeque::new(),
+            pending_release: Vec::new(),
+            last_sent: Duration::ZERO,
+        }
+    }
+
+    pub fn state(&self) -> MqttSnState {
+        self.state
+    }
+
+    /// Connect to the gateway, or - for a sleeping client - return to the active state
+    pub fn connect(&mut self) -> Result<(), EspError> {
+        if self.client_id.is_empty() || self.client_id.len() > 23 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let client_id = self.client_id.clone();
+
+        let return_code = self.request(
+            &Packet::Connect {
+                flags: if self.clean_session {
+                    FLAG_CLEAN_SESSION
+                } else {
+                    0
+                },
+                duration: self.keep_alive.as_secs().min(u16::MAX as _) as _,
+                client_id: &client_id,
+            },
+            |packet| match packet {
+                Packet::ConnAck { return_code } => Some(*return_code),
+                _ => None,
+            },
+        )?;
+
+        check(return_code)?;
+
+        if self.clean_session {
+            self.topic_ids.clear();
+            self.topic_names.clear();
+        }
+
+        self.state = MqttSnState::Active;
+
+        info!("MQTT-SN client {} connected", self.client_id);
+
+        Ok(())
+    }
+
+    /// Register a topic name with the gateway, and return its topic ID
+    ///
+    /// `publish` registers the topic names as needed, so this is only useful to register
+    /// them up front.
+    pub fn register(&mut self, topic: &str) -> Result<u16, EspError> {
+        if let Some(topic_id) = self.topic_ids.get(topic) {
+            return Ok(*topic_id);
+        }
+
+        self.check_active()?;
+
+        let msg_id = self.next_msg_id();
+
+        let (topic_id, return_code) = self.request(
+            &Packet::Register {
+                topic_id: 0,
+                msg_id,
+                topic,
+            },
+            |packet| match packet {
+                Packet::RegAck {
+                    topic_id,
+                    msg_id: id,
+                    return_code,
+                } if *id == msg_id => Some((*topic_id, *return_code)),
+                _ => None,
+            },
+        )?;
+
+        check(return_code)?;
+
+        debug!("Topic {topic} registered with ID {topic_id}");
+
+        self.topic_ids.insert(topic.to_string(), topic_id);
+        self.topic_names.insert(topic_id, topic.to_string());
+
+        Ok(topic_id)
+    }
+
+    /// Publish to a topic name, registering it as needed
+    ///
+    /// Returns the message ID, or 0 for QoS 0.
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<MessageId, EspError> {
+        self.publish_topic(MqttSnTopic::Name(topic), qos.into(), retain, payload)
+    }
+
+    /// Publish to a topic name or a predefined topic ID, with any quality of service
+    /// including QoS -1
+    ///
+    /// Returns the message ID, or 0 for QoS 0 and -1.
+    pub fn publish_topic(
+        &mut self,
+        topic: MqttSnTopic<'_>,
+        qos: MqttSnQoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<MessageId, EspError> {
+        let (id_type, topic_id) = match topic {
+            MqttSnTopic::Predefined(topic_id) => (TOPIC_ID_PREDEFINED, topic_id),
+            MqttSnTopic::Name(name) if name.len() == 2 => {
+                let name = name.as_bytes();
+
+                (TOPIC_ID_SHORT, u16::from_be_bytes([name[0], name[1]]))
+            }
+            MqttSnTopic::Name(_) if qos == MqttSnQoS::FireAndForget => {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            }
+            MqttSnTopic::Name(name) => (TOPIC_ID_NORMAL, self.register(name)?),
+        };
+
+        if qos != MqttSnQoS::FireAndForget {
+            self.check_active()?;
+        }
+
+        let msg_id = match qos {
+            MqttSnQoS::AtLeastOnce | MqttSnQoS::ExactlyOnce => self.next_msg_id(),
+            _ => 0,
+        };
+
+        let publish = Packet::Publish {
+            flags: qos.flags() | if retain { FLAG_RETAIN } else { 0 } | id_type,
+            topic_id,
+            msg_id,
+            data: payload,
+        };
+
+        match qos {
+            MqttSnQoS::FireAndForget | MqttSnQoS::AtMostOnce => self.send(&publish)?,
+            MqttSnQoS::AtLeastOnce => {
+                let return_code = self.request(&publish, |packet| match packet {
+                    Packet::PubAck {
+                        msg_id: id,
+                        return_code,
+                        ..
+                    } if *id == msg_id => Some(*return_code),
+                    _ => None,
+                })?;
+
+                self.check_publish(id_type, topic_id, return_code)?;
+            }
+            MqttSnQoS::ExactlyOnce => {
+                let rejection = self.request(&publish, |packet| match packet {
+                    Packet::PubRec { msg_id: id } if *id == msg_id => Some(None),
+                    Packet::PubAck {
+                        msg_id: id,
+                        return_code,
+                        ..
+                    } if *id == msg_id => Some(Some(*return_code)),
+                    _ => None,
+                })?;
+
+                if let Some(return_code) = rejection {
+                    self.check_publish(id_type, topic_id, return_code)?;
+                }
+
+                self.request(&Packet::PubRel { msg_id }, |packet| match packet {
+                    Packet::PubComp { msg_id: id } if *id == msg_id => Some(()),
+                    _ => None,
+                })?;
+            }
+        }
+
+        Ok(msg_id as _)
+    }
+
+    /// Subscribe to a topic filter, which may contain wildcards
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<MessageId, EspError> {
+        self.subscribe_topic(MqttSnTopic::Name(topic), qos)
+    }
+
+    /// Subscribe to a topic filter or a predefined topic ID
+    pub fn subscribe_topic(
+        &mut self,
+        topic: MqttSnTopic<'_>,
+        qos: QoS,
+    ) -> Result<MessageId, EspError> {
+        self.check_active()?;
+
+        let msg_id = self.next_msg_id();
+
+        let (topic_id, return_code) = self.request(
+            &Packet::Subscribe {
+                flags: MqttSnQoS::from(qos).flags() | topic_flags(&topic),
+                msg_id,
+                topic,
+            },
+            |packet| match packet {
+                Packet::SubAck {
+                    topic_id,
+                    msg_id: id,
+                    return_code,
+                    ..
+                } if *id == msg_id => Some((*topic_id, *return_code)),
+                _ => None,
+            },
+        )?;
+
+        check(return_code)?;
+
+        // The gateway returns the ID of topic names without wildcards
+        if let MqttSnTopic::Name(name) = topic {
+            if topic_id != 0 && name.len() != 2 && !name.contains(['+', '#']) {
+                self.topic_ids.insert(name.to_string(), topic_id);
+                self.topic_names.insert(topic_id, name.to_string());
+            }
+        }
+
+        Ok(msg_id as _)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<MessageId, EspError> {
+        self.unsubscribe_topic(MqttSnTopic::Name(topic))
+    }
+
+    pub fn unsubscribe_topic(&mut self, topic: MqttSnTopic<'_>) -> Result<MessageId, EspError> {
+        self.check_active()?;
+
+        let msg_id = self.next_msg_id();
+
+        self.request(
+            &Packet::Unsubscribe {
+                flags: topic_flags(&topic),
+                msg_id,
+                topic,
+            },
+            |packet| match packet {
+                Packet::UnsubAck { msg_id: id } if *id == msg_id => Some(()),
+                _ => None,
+            },
+        )?;
+
+        Ok(msg_id as _)
+    }
+
+    /// Wait up to `timeout` for a message, pinging the gateway as needed to keep the
+    /// connection alive
+    ///
+    /// Returns `None` on timeout.
+    pub fn next(&mut self, timeout: Duration) -> Result<Option<MqttSnMessage>, EspError> {
+        let deadline = now() + timeout;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return Ok(Some(message));
+            }
+
+            let mut wait = deadline.saturating_sub(now());
+
+            if self.state == MqttSnState::Active && !self.keep_alive.is_zero() {
+                let ping = (self.last_sent + self.keep_alive).saturating_sub(now());
+
+                if ping.is_zero() {
+                    self.ping(None)?;
+                    continue;
+                }
+
+                wait = wait.min(ping);
+            }
+
+            if wait.is_zero() {
+                return Ok(None);
+            }
+
+            if let Some(len) = self.transport.recv(&mut buf, wait)? {
+                match Packet::decode(&buf[..len]) {
+                    Some(packet) => self.handle(&packet)?,
+                    None => warn!("Malformed MQTT-SN packet ignored"),
+                }
+            }
+        }
+    }
+
+    /// Enter the asleep state for `duration`, during which the gateway buffers the messages
+    /// of the subscriptions
+    pub fn sleep(&mut self, duration: Duration) -> Result<(), EspError> {
+        self.check_active()?;
+
+        self.request(
+            &Packet::Disconnect {
+                duration: Some(duration.as_secs().min(u16::MAX as _) as _),
+            },
+            |packet| matches!(packet, Packet::Disconnect { .. }).then_some(()),
+        )?;
+
+        self.state = MqttSnState::Asleep;
+
+        Ok(())
+    }
+
+    /// Collect the messages buffered by the gateway while asleep, which are then returned
+    /// by `next`, and return their number
+    ///
+    /// The client stays in the asleep state; use `connect` to return to the active state.
+    pub fn wake(&mut self) -> Result<usize, EspError> {
+        if self.state != MqttSnState::Asleep {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let queued = self.messages.len();
+        let client_id = self.client_id.clone();
+
+        self.ping(Some(&client_id))?;
+
+        Ok(self.messages.len() - queued)
+    }
+
+    /// Disconnect from the gateway
+    pub fn disconnect(&mut self) -> Result<(), EspError> {
+        if self.state == MqttSnState::Disconnected {
+            return Ok(());
+        }
+
+        let result = self.request(&Packet::Disconnect { duration: None }, |packet| {
+            matches!(packet, Packet::Disconnect { .. }).then_some(())
+        });
+
+        self.state = MqttSnState::Disconnected;
+
+        result
+    }
+
+    /// Release the transport
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    fn ping(&mut self, client_id: Option<&str>) -> Result<(), EspError> {
+        self.request(&Packet::PingReq { client_id }, |packet| {
+            matches!(packet, Packet::PingResp).then_some(())
+        })
+    }
+
+    /// Send the packet, and wait for the response matched by `f`, re-sending the packet
+    /// as configured; the other packets received in the meantime are handled as usual
+    fn request<F, R>(&mut self, packet: &Packet<'_>, mut f: F) -> Result<R, EspError>
+    where
+        F: FnMut(&Packet<'_>) -> Option<R>,
+    {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let mut packet = packet.clone();
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                debug!("Re-sending MQTT-SN packet (attempt {attempt})");
+
+                packet = packet.into_dup();
+            }
+
+            self.send(&packet)?;
+
+            let deadline = now() + self.retry_interval;
+
+            loop {
+                let wait = deadline.saturating_sub(now());
+                if wait.is_zero() {
+                    break;
+                }
+
+                let Some(len) = self.transport.recv(&mut buf, wait)? else {
+                    break;
+                };
+
+                let Some(incoming) = Packet::decode(&buf[..len]) else {
+                    warn!("Malformed MQTT-SN packet ignored");
+                    continue;
+                };
+
+                if let Some(response) = f(&incoming) {
+                    return Ok(response);
+                }
+
+                self.handle(&incoming)?;
+            }
+        }
+
+        warn!("No response from the MQTT-SN gateway");
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    /// Handle a packet which is not a response to a request
+    fn handle(&mut self, packet: &Packet<'_>) -> Result<(), EspError> {
+        match packet {
+            Packet::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                let qos = MqttSnQoS::from_flags(*flags);
+
+                let duplicate =
+                    qos == MqttSnQoS::ExactlyOnce && self.pending_release.contains(msg_id);
+
+                if !duplicate {
+                    self.messages.push_back(MqttSnMessage {
+                        topic_id: *topic_id,
+                        topic: self.topic_name(*flags & 0x03, *topic_id),
+                        payload: data.to_vec(),
+                        qos,
+                        retain: *flags & FLAG_RETAIN != 0,
+                    });
+                }
+
+                match qos {
+                    MqttSnQoS::AtLeastOnce => self.send(&Packet::PubAck {
+                        topic_id: *topic_id,
+                        msg_id: *msg_id,
+                        return_code: RC_ACCEPTED,
+                    })?,
+                    MqttSnQoS::ExactlyOnce => {
+                        if !duplicate {
+                            self.pending_release.push(*msg_id);
+                        }
+
+                        self.send(&Packet::PubRec { msg_id: *msg_id })?;
+                    }
+                    _ => (),
+                }
+            }
+            Packet::PubRel { msg_id } => {
+                self.pending_release.retain(|id| id != msg_id);
+                self.send(&Packet::PubComp { msg_id: *msg_id })?;
+            }
+            Packet::Register {
+                topic_id,
+                msg_id,
+                topic,
+            } => {
+                // Registration of the topics matching a wildcard subscription
+                self.topic_ids.insert(topic.to_string(), *topic_id);
+                self.topic_names.insert(*topic_id, topic.to_string());
+
+                self.send(&Packet::RegAck {
+                    topic_id: *topic_id,
+                    msg_id: *msg_id,
+                    return_code: RC_ACCEPTED,
+                })?;
+            }
+            Packet::PubAck {
+                topic_id,
+                return_code,
+                ..
+            } if *return_code == RC_INVALID_TOPIC_ID => {
+                // A QoS 0 publication to a registration which the gateway lost
+                self.forget(*topic_id);
+            }
+            Packet::PingReq { .. } => self.send(&Packet::PingResp)?,
+            Packet::Disconnect { .. } => {
+                warn!("Disconnected by the MQTT-SN gateway");
+
+                self.state = MqttSnState::Disconnected;
+            }
+            _ => debug!("Unexpected MQTT-SN packet ignored"),
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, packet: &Packet<'_>) -> Result<(), EspError> {
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+
+        if buf.len() > MAX_PACKET_SIZE {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        self.transport.send(&buf)?;
+        self.last_sent = now();
+
+        Ok(())
+    }
+
+    fn check_active(&self) -> Result<(), EspError> {
+        if self.state == MqttSnState::Active {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+        }
+    }
+
+    fn check_publish(
+        &mut self,
+        id_type: u8,
+        topic_id: u16,
+        return_code: u8,
+    ) -> Result<(), EspError> {
+        if id_type == TOPIC_ID_NORMAL && return_code == RC_INVALID_TOPIC_ID {
+            // Register the topic again with the next publication
+            self.forget(topic_id);
+        }
+
+        check(return_code)
+    }
+
+    fn forget(&mut self, topic_id: u16) {
+        if let Some(topic) = self.topic_names.remove(&topic_id) {
+            self.topic_ids.remove(&topic);
+        }
+    }
+
+    fn topic_name(&self, id_type: u8, topic_id: u16) -> Option<String> {
+        match id_type {
+            TOPIC_ID_NORMAL => self.topic_names.get(&topic_id).cloned(),
+            TOPIC_ID_PREDEFINED => self.predefined.get(&topic_id).cloned(),
+            TOPIC_ID_SHORT => String::from_utf8(topic_id.to_be_bytes().to_vec()).ok(),
+            _ => None,
+        }
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        // 0 is not a valid message ID
+        self.next_msg_id = self.next_msg_id.checked_add(1).unwrap_or(1);
+
+        self.next_msg_id
+    }
+}
+
+fn topic_flags(topic: &MqttSnTopic<'_>) -> u8 {
+    match topic {
+        MqttSnTopic::Predefined(_) => TOPIC_ID_PREDEFINED,
+        MqttSnTopic::Name(name) if name.len() == 2 => TOPIC_ID_SHORT,
+        MqttSnTopic::Name(_) => TOPIC_ID_NORMAL,
+    }
+}
+
+fn check(return_code: u8) -> Result<(), EspError> {
+    match return_code {
+        RC_ACCEPTED => Ok(()),
+        RC_CONGESTION => {
+            warn!("Rejected by the MQTT-SN gateway: congestion");
+            Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
+        }
+        RC_INVALID_TOPIC_ID => {
+            warn!("Rejected by the MQTT-SN gateway: invalid topic ID");
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        }
+        _ => {
+            warn!("Rejected by the MQTT-SN gateway: not supported");
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+        }
+    }
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() as _ })
+}
+
+/// An MQTT-SN packet, as used by the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Connect {
+        flags: u8,
+        duration: u16,
+        client_id: &'a str,
+    },
+    ConnAck {
+        return_code: u8,
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic: &'a str,
+    },
+    RegAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Publish {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        data: &'a [u8],
+    },
+    PubAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    PubRec {
+        msg_id: u16,
+    },
+    PubRel {
+        msg_id: u16,
+    },
+    PubComp {
+        msg_id: u16,
+    },
+    Subscribe {
+        flags: u8,
+        msg_id: u16,
+        topic: MqttSnTopic<'a>,
+    },
+    SubAck {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Unsubscribe {
+        flags: u8,
+        msg_id: u16,
+        topic: MqttSnTopic<'a>,
+    },
+    UnsubAck {
+        msg_id: u16,
+    },
+    PingReq {
+        client_id: Option<&'a str>,
+    },
+    PingResp,
+    Disconnect {
+        duration: Option<u16>,
+    },
+}
+
+impl<'a> Packet<'a> {
+    /// Mark the packet as re-sent, if it has a DUP flag
+    fn into_dup(self) -> Self {
+        match self {
+            Self::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } if flags & 0x60 == 0x20 || flags & 0x60 == 0x40 => Self::Publish {
+                flags: flags | FLAG_DUP,
+                topic_id,
+                msg_id,
+                data,
+            },
+            Self::Subscribe {
+                flags,
+                msg_id,
+                topic,
+            } => Self::Subscribe {
+                flags: flags | FLAG_DUP,
+                msg_id,
+                topic,
+            },
+            other => other,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut body = Vec::new();
+
+        let msg_type = match self {
+            Self::Connect {
+                flags,
+                duration,
+                client_id,
+            } => {
+                body.extend_from_slice(&[*flags, PROTOCOL_ID]);
+                body.extend_from_slice(&duration.to_be_bytes());
+                body.extend_from_slice(client_id.as_bytes());
+                CONNECT
+            }
+            Self::ConnAck { return_code } => {
+                body.push(*return_code);
+                CONNACK
+            }
+            Self::Register {
+                topic_id,
+                msg_id,
+                topic,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.extend_from_slice(topic.as_bytes());
+                REGISTER
+            }
+            Self::RegAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code);
+                REGACK
+            }
+            Self::Publish {
+                flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                body.push(*flags);
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.extend_from_slice(data);
+                PUBLISH
+            }
+            Self::PubAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code);
+                PUBACK
+            }
+            Self::PubRec { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBREC
+            }
+            Self::PubRel { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBREL
+            }
+            Self::PubComp { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBCOMP
+            }
+            Self::Subscribe {
+                flags,
+                msg_id,
+                topic,
+            }
+            | Self::Unsubscribe {
+                flags,
+                msg_id,
+                topic,
+            } => {
+                body.push(*flags);
+                body.extend_from_slice(&msg_id.to_be_bytes());
+
+                match topic {
+                    MqttSnTopic::Name(name) => body.extend_from_slice(name.as_bytes()),
+                    MqttSnTopic::Predefined(topic_id) => {
+                        body.extend_from_slice(&topic_id.to_be_bytes())
+                    }
+                }
+
+                if matches!(self, Self::Subscribe { .. }) {
+                    SUBSCRIBE
+                } else {
+                    UNSUBSCRIBE
+                }
+            }
+            Self::SubAck {
+                flags,
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.push(*flags);
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code);
+                SUBACK
+            }
+            Self::UnsubAck { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                UNSUBACK
+            }
+            Self::PingReq { client_id } => {
+                if let Some(client_id) = client_id {
+                    body.extend_from_slice(client_id.as_bytes());
+                }
+                PINGREQ
+            }
+            Self::PingResp => PINGRESP,
+            Self::Disconnect { duration } => {
+                if let Some(duration) = duration {
+                    body.extend_from_slice(&duration.to_be_bytes());
+                }
+                DISCONNECT
+            }
+        };
+
+        // The length includes the length field and the message type
+        let len = body.len() + 2;
+
+        if len <= 255 {
+            buf.push(len as u8);
+        } else {
+            buf.push(0x01);
+            buf.extend_from_slice(&((len + 2) as u16).to_be_bytes());
+        }
+
+        buf.push(msg_type);
+        buf.extend_from_slice(&body);
+    }
+
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        let (len, header_len) = if *data.first()? == 0x01 {
+            (
+                u16::from_be_bytes([*data.get(1)?, *data.get(2)?]) as usize,
+                3,
+            )
+        } else {
+            (data[0] as usize, 1)
+        };
+
+        if len <= header_len || len > data.len() {
+            return None;
+        }
+
+        let msg_type = data[header_len];
+        let body = &data[header_len + 1..len];
+
+        let u16_at = |offset: usize| -> Option<u16> {
+            Some(u16::from_be_bytes([
+                *body.get(offset)?,
+                *body.get(offset + 1)?,
+            ]))
+        };
+
+        let str_at = |offset: usize| core::str::from_utf8(body.get(offset..)?).ok();
+
+        let packet = match msg_type {
+            CONNECT => {
+                if *body.get(1)? != PROTOCOL_ID {
+                    return None;
+                }
+
+                Self::Connect {
+                    flags: body[0],
+                    duration: u16_at(2)?,
+                    client_id: str_at(4)?,
+                }
+            }
+            CONNACK => Self::ConnAck {
+                return_code: *body.first()?,
+            },
+            REGISTER => Self::Register {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                topic: str_at(4)?,
+            },
+            REGACK => Self::RegAck {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                return_code: *body.get(4)?,
+            },
+            PUBLISH => Self::Publish {
+                flags: *body.first()?,
+                topic_id: u16_at(1)?,
+                msg_id: u16_at(3)?,
+                data: &body[5..],
+            },
+            PUBACK => Self::PubAck {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                return_code: *body.get(4)?,
+            },
+            PUBREC => Self::PubRec { msg_id: u16_at(0)? },
+            PUBREL => Self::PubRel { msg_id: u16_at(0)? },
+            PUBCOMP => Self::PubComp { msg_id: u16_at(0)? },
+            SUBSCRIBE | UNSUBSCRIBE => {
+                let flags = *body.first()?;
+                let msg_id = u16_at(1)?;
+
+                let topic = if flags & 0x03 == TOPIC_ID_PREDEFINED {
+                    MqttSnTopic::Predefined(u16_at(3)?)
+                } else {
+                    MqttSnTopic::Name(str_at(3)?)
+                };
+
+                if msg_type == SUBSCRIBE {
+                    Self::Subscribe {
+                        flags,
+                        msg_id,
+                        topic,
+                    }
+                } else {
+                    Self::Unsubscribe {
+                        flags,
+                        msg_id,
+                        topic,
+                    }
+                }
+            }
+            SUBACK => Self::SubAck {
+                flags: *body.first()?,
+                topic_id: u16_at(1)?,
+                msg_id: u16_at(3)?,
+                return_code: *body.get(5)?,
+            },
+            UNSUBACK => Self::UnsubAck { msg_id: u16_at(0)? },
+            PINGREQ => Self::PingReq {
+                client_id: if body.is_empty() {
+                    None
+                } else {
+                    Some(str_at(0)?)
+                },
+            },
+            PINGRESP => Self::PingResp,
+            DISCONNECT => Self::Disconnect {
+                duration: if body.is_empty() {
+                    None
+                } else {
+                    Some(u16_at(0)?)
+                },
+            },
+            _ => return None,
+        };
+
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gateway stand-in, answering the requests of the client with scripted responses
+    struct Gateway {
+        script: VecDeque<(Packet<'static>, Vec<Packet<'static>>)>,
+        outgoing: VecDeque<Vec<u8>>,
+    }
+
+    impl MqttSnTransport for Gateway {
+        fn send(&mut self, datagram: &[u8]) -> Result<(), EspError> {
+            let received = Packet::decode(datagram).unwrap();
+            let (expected, responses) = self.script.pop_front().unwrap();
+
+            assert_eq!(received, expected);
+
+            for response in responses {
+                let mut buf = Vec::new();
+                response.encode(&mut buf);
+                self.outgoing.push_back(buf);
+            }
+
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<Option<usize>, EspError> {
+            Ok(self.outgoing.pop_front().map(|datagram| {
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                datagram.len()
+            }))
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let packets = [
+            Packet::Connect {
+                flags: FLAG_CLEAN_SESSION,
+                duration: 60,
+                client_id: "sensor",
+            },
+            Packet::Publish {
+                flags: 0x20,
+                topic_id: 1,
+                msg_id: 2,
+                data: &[0x55; 300],
+            },
+            Packet::Subscribe {
+                flags: 0x01,
+                msg_id: 3,
+                topic: MqttSnTopic::Predefined(7),
+            },
+            Packet::PingReq { client_id: None },
+            Packet::Disconnect { duration: Some(10) },
+        ];
+
+        for packet in packets {
+            let mut buf = Vec::new();
+            packet.encode(&mut buf);
+
+            assert_eq!(Packet::decode(&buf), Some(packet));
+        }
+    }
+
+    #[test]
+    fn register_and_publish() {
+        let gateway = Gateway {
+            script: VecDeque::from([
+                (
+                    Packet::Connect {
+                        flags: FLAG_CLEAN_SESSION,
+                        duration: 60,
+                        client_id: "esp-idf",
+                    },
+                    vec![Packet::ConnAck { return_code: 0 }],
+                ),
+                (
+                    Packet::Register {
+                        topic_id: 0,
+                        msg_id: 1,
+                        topic: "sensors/t",
+                    },
+                    vec![Packet::RegAck {
+                        topic_id: 5,
+                        msg_id: 1,
+                        return_code: 0,
+                    }],
+                ),
+                (
+                    Packet::Publish {
+                        flags: 0x20,
+                        topic_id: 5,
+                        msg_id: 2,
+                        data: b"21.5",
+                    },
+                    vec![
+                        Packet::Publish {
+                            flags: 0x00,
+                            topic_id: 5,
+                            msg_id: 0,
+                            data: b"echo",
+                        },
+                        Packet::PubAck {
+                            topic_id: 5,
+                            msg_id: 2,
+                            return_code: 0,
+                        },
+                    ],
+                ),
+            ]),
+            outgoing: VecDeque::new(),
+        };
+
+        let mut client = EspMqttSnClient::new(gateway, &Default::default());
+
+        client.connect().unwrap();
+        assert_eq!(
+            client
+                .publish("sensors/t", QoS::AtLeastOnce, false, b"21.5")
+                .unwrap(),
+            2
+        );
+
+        let message = client.messages.pop_front().unwrap();
+        assert_eq!(message.topic.as_deref(), Some("sensors/t"));
+        assert_eq!(message.payload, b"echo");
+    }
+}
@@ -1,3 +1,17 @@
+//! Network Address and Port Translation (NAPT)
+//!
+//! [`EspNapt`] is a thin wrapper around the LwIP port-map table, while
+//! [`EspRouter`] ties an upstream netif (STA, Ethernet, PPP) to a downstream
+//! (typically SoftAP) netif and turns the device into a NAT router.
+//!
+//! NAPT support is disabled by default. To enable it, add this to your
+//! `sdkconfig.defaults` file:
+//!
+//! ```
+//! CONFIG_LWIP_IP_FORWARD=y
+//! CONFIG_LWIP_IPV4_NAPT=y
+//! ```
+
 use embedded_svc::ipv4;
 
 use crate::private::mutex::Mutex;
@@ -6,9 +20,13 @@ use crate::sys::*;
 
 use crate::private::common::*;
 
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
+pub use router::*;
+
 #[derive(Debug)]
 pub struct EspNapt(());
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Protocol {
     UDP,
     TCP,
@@ -23,6 +41,21 @@ impl Protocol {
     }
 }
 
+/// A single entry of the NAPT port-forward table
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortMapping {
+    /// The transport protocol of the mapping
+    pub protocol: Protocol,
+    /// The external (upstream) address on which the port is exposed
+    pub external_ip: ipv4::Ipv4Addr,
+    /// The external (upstream) port
+    pub external_port: u16,
+    /// The address of the host on the downstream network
+    pub internal_ip: ipv4::Ipv4Addr,
+    /// The port of the host on the downstream network
+    pub internal_port: u16,
+}
+
 static TAKEN: Mutex<bool> = Mutex::new(false);
 
 impl EspNapt {
@@ -58,6 +91,39 @@ impl EspNapt {
     pub fn remove_portmap(protocol: Protocol, external_port: u16) -> bool {
         unsafe { ip_portmap_remove(protocol.get_num_proto(), external_port) != 0 }
     }
+
+    /// Add (or replace) a port mapping in the LwIP port-map table
+    ///
+    /// Returns `ESP_ERR_NO_MEM` if the table is full.
+    pub fn add_port_mapping(&mut self, mapping: &PortMapping) -> Result<(), EspError> {
+        if Self::add_portmap(
+            mapping.protocol,
+            mapping.external_ip,
+            mapping.external_port,
+            mapping.internal_ip,
+            mapping.internal_port,
+        ) {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
+        }
+    }
+
+    /// Remove a port mapping from the LwIP port-map table
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if there is no mapping for the provided
+    /// protocol and external port.
+    pub fn remove_port_mapping(
+        &mut self,
+        protocol: Protocol,
+        external_port: u16,
+    ) -> Result<(), EspError> {
+        if Self::remove_portmap(protocol, external_port) {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        }
+    }
 }
 
 impl Drop for EspNapt {
@@ -65,3 +131,289 @@ impl Drop for EspNapt {
         *TAKEN.lock() = false;
     }
 }
+
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
+mod router {
+    use core::borrow::Borrow;
+
+    use alloc::vec::Vec;
+
+    use ::log::debug;
+
+    use embedded_svc::ipv4;
+
+    use crate::handle::RawHandle;
+    use crate::netif::EspNetif;
+    use crate::private::common::*;
+    use crate::sys::*;
+
+    use super::{EspNapt, PortMapping, Protocol};
+
+    /// A NAT router between an upstream and a downstream netif
+    ///
+    /// Creating the router enables NAPT on the downstream netif, so that
+    /// traffic originating from hosts on the downstream network is
+    /// masqueraded behind the address of the upstream netif. The DNS server
+    /// of the upstream netif is offered to the downstream DHCP clients.
+    ///
+    /// Dropping the router removes all port mappings registered through it
+    /// and disables NAPT on the downstream netif.
+    ///
+    /// Example (Wi-Fi range extender):
+    /// ```ignore
+    /// let mut router = EspRouter::new(wifi.sta_netif(), wifi.ap_netif())?;
+    ///
+    /// // Expose the HTTP server of 192.168.71.2 on port 8080 of the STA address
+    /// router.add_port_forward(Protocol::TCP, 8080, Ipv4Addr::new(192, 168, 71, 2), 80)?;
+    /// ```
+    pub struct EspRouter<U, D>
+    where
+        U: Borrow<EspNetif>,
+        D: Borrow<EspNetif>,
+    {
+        napt: EspNapt,
+        upstream: U,
+        downstream: D,
+        port_mappings: Vec<PortMapping>,
+    }
+
+    impl<U, D> EspRouter<U, D>
+    where
+        U: Borrow<EspNetif>,
+        D: Borrow<EspNetif>,
+    {
+        /// Create a new router, enable NAPT on the downstream netif and
+        /// forward the upstream DNS server to the downstream DHCP clients
+        ///
+        /// Only one router (or `EspNapt` instance) can exist at a time;
+        /// `ESP_ERR_INVALID_STATE` is returned otherwise.
+        pub fn new(upstream: U, downstream: D) -> Result<Self, EspError> {
+            let napt = EspNapt::new()?;
+
+            let mut this = Self {
+                napt,
+                upstream,
+                downstream,
+                port_mappings: Vec::new(),
+            };
+
+            this.set_napt_enabled(true)?;
+            this.forward_dns()?;
+
+            Ok(this)
+        }
+
+        /// Return the upstream netif
+        pub fn upstream(&self) -> &EspNetif {
+            self.upstream.borrow()
+        }
+
+        /// Return the downstream netif
+        pub fn downstream(&self) -> &EspNetif {
+            self.downstream.borrow()
+        }
+
+        /// Re-read the DNS server of the upstream netif and offer it to the
+        /// downstream DHCP clients
+        ///
+        /// The upstream DNS server is typically assigned by DHCP, so this
+        /// method should be called again each time the upstream netif gets
+        /// a new IP (i.e. on `IpEvent::DhcpIpAssigned` for the upstream netif).
+        pub fn forward_dns(&mut self) -> Result<(), EspError> {
+            let dns = self.upstream().get_dns();
+
+            if dns == ipv4::Ipv4Addr::UNSPECIFIED {
+                debug!("Upstream netif has no DNS server yet, skipping DNS forwarding");
+                return Ok(());
+            }
+
+            let handle = self.downstream().handle();
+
+            let mut status = esp_netif_dhcp_status_t_ESP_NETIF_DHCP_INIT;
+            esp!(unsafe { esp_netif_dhcps_get_status(handle, &mut status) })?;
+
+            let running = status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED;
+
+            if running {
+                esp!(unsafe { esp_netif_dhcps_stop(handle) })?;
+            }
+
+            let mut dns_info: esp_netif_dns_info_t = Default::default();
+            dns_info.ip.u_addr.ip4 = Newtype::<esp_ip4_addr_t>::from(dns).0;
+
+            esp!(unsafe {
+                esp_netif_set_dns_info(
+                    handle,
+                    esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+                    &mut dns_info,
+                )
+            })?;
+
+            #[cfg(esp_idf_version_major = "4")]
+            let mut dhcps_dns_value: dhcps_offer_t = dhcps_offer_option_OFFER_DNS as _;
+
+            // Strangely dhcps_offer_t and dhcps_offer_option_* are not included in ESP-IDF V5's bindings
+            #[cfg(not(esp_idf_version_major = "4"))]
+            let mut dhcps_dns_value: u8 = 2_u8;
+
+            esp!(unsafe {
+                esp_netif_dhcps_option(
+                    handle,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_DOMAIN_NAME_SERVER,
+                    &mut dhcps_dns_value as *mut _ as *mut _,
+                    core::mem::size_of_val(&dhcps_dns_value) as u32,
+                )
+            })?;
+
+            if running {
+                esp!(unsafe { esp_netif_dhcps_start(handle) })?;
+            }
+
+            debug!("Forwarding upstream DNS server {dns} to the downstream netif");
+
+            Ok(())
+        }
+
+        /// Expose `internal_ip:internal_port` of the downstream network on
+        /// `external_port` of the current upstream IP address
+        ///
+        /// An existing mapping for the same protocol and external port is replaced.
+        pub fn add_port_forward(
+            &mut self,
+            protocol: Protocol,
+            external_port: u16,
+            internal_ip: ipv4::Ipv4Addr,
+            internal_port: u16,
+        ) -> Result<(), EspError> {
+            let external_ip = self.upstream().get_ip_info()?.ip;
+
+            self.add_port_mapping(PortMapping {
+                protocol,
+                external_ip,
+                external_port,
+                internal_ip,
+                internal_port,
+            })
+        }
+
+        /// Add (or replace) a port mapping
+        ///
+        /// Returns `ESP_ERR_NO_MEM` if the LwIP port-map table is full.
+        pub fn add_port_mapping(&mut self, mapping: PortMapping) -> Result<(), EspError> {
+            self.napt.add_port_mapping(&mapping)?;
+
+            self.port_mappings.retain(|existing| {
+                existing.protocol != mapping.protocol
+                    || existing.external_port != mapping.external_port
+            });
+            self.port_mappings.push(mapping);
+
+            Ok(())
+        }
+
+        /// Remove the port mapping for the provided protocol and external port
+        ///
+        /// Returns the removed mapping, or `ESP_ERR_NOT_FOUND` if no such
+        /// mapping was registered through this router.
+        pub fn remove_port_mapping(
+            &mut self,
+            protocol: Protocol,
+            external_port: u16,
+        ) -> Result<PortMapping, EspError> {
+            let index = self
+                .port_mappings
+                .iter()
+                .position(|mapping| {
+                    mapping.protocol == protocol && mapping.external_port == external_port
+                })
+                .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+            self.napt.remove_port_mapping(protocol, external_port)?;
+
+            Ok(self.port_mappings.remove(index))
+        }
+
+        /// Remove all port mappings registered through this router
+        ///
+        /// The mappings which could not be removed are kept, and the first error is returned.
+        pub fn clear_port_mappings(&mut self) -> Result<(), EspError> {
+            let mut result = Ok(());
+
+            self.port_mappings.retain(|mapping| {
+                match self
+                    .napt
+                    .remove_port_mapping(mapping.protocol, mapping.external_port)
+                {
+                    Ok(()) => false,
+                    Err(e) => {
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+
+                        true
+                    }
+                }
+            });
+
+            result
+        }
+
+        /// Return the port mapping for the provided protocol and external port, if any
+        pub fn port_mapping(&self, protocol: Protocol, external_port: u16) -> Option<&PortMapping> {
+            self.port_mappings.iter().find(|mapping| {
+                mapping.protocol == protocol && mapping.external_port == external_port
+            })
+        }
+
+        /// Return all port mappings registered through this router
+        pub fn port_mappings(&self) -> &[PortMapping] {
+            &self.port_mappings
+        }
+
+        /// Re-apply all port mappings using the current upstream IP address
+        ///
+        /// Useful after the upstream netif got a new IP address from DHCP,
+        /// as the mappings are bound to the external address they were
+        /// created with.
+        pub fn refresh_port_mappings(&mut self) -> Result<(), EspError> {
+            let external_ip = self.upstream().get_ip_info()?.ip;
+
+            for mapping in &mut self.port_mappings {
+                mapping.external_ip = external_ip;
+
+                self.napt.add_port_mapping(mapping)?;
+            }
+
+            Ok(())
+        }
+
+        fn set_napt_enabled(&mut self, enable: bool) -> Result<(), EspError> {
+            let index = self.downstream().get_index();
+
+            if index == 0 {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            unsafe { ip_napt_enable_no((index - 1) as u8, if enable { 1 } else { 0 }) };
+
+            Ok(())
+        }
+    }
+
+    impl<U, D> Drop for EspRouter<U, D>
+    where
+        U: Borrow<EspNetif>,
+        D: Borrow<EspNetif>,
+    {
+        fn drop(&mut self) {
+            if let Err(e) = self.clear_port_mappings() {
+                ::log::warn!("Failed to remove port mappings: {e}");
+            }
+
+            let _ = self.set_napt_enabled(false);
+
+            debug!("Router dropped");
+        }
+    }
+}
@@ -165,6 +165,8 @@ pub struct NetifConfiguration {
     pub description: heapless::String<8>,
     pub route_priority: u32,
     pub ip_configuration: Option<ipv4::Configuration>,
+    pub ipv6_configuration: Option<Ipv6Configuration>,
+    pub dhcp_server_configuration: Option<DhcpServerConfiguration>,
     pub stack: NetifStack,
     pub custom_mac: Option<[u8; 6]>,
 }
@@ -180,6 +182,8 @@ impl NetifConfiguration {
             description: "eth".try_into().unwrap(),
             route_priority: 60,
             ip_configuration: Some(ipv4::Configuration::Client(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Eth,
             custom_mac: None,
         }
@@ -194,6 +198,8 @@ impl NetifConfiguration {
             description: "ethrt".try_into().unwrap(),
             route_priority: 50,
             ip_configuration: Some(ipv4::Configuration::Router(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Eth,
             custom_mac: None,
         }
@@ -209,6 +215,8 @@ impl NetifConfiguration {
             description: "sta".try_into().unwrap(),
             route_priority: 100,
             ip_configuration: Some(ipv4::Configuration::Client(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Sta,
             custom_mac: None,
         }
@@ -224,6 +232,8 @@ impl NetifConfiguration {
             description: "ap".try_into().unwrap(),
             route_priority: 10,
             ip_configuration: Some(ipv4::Configuration::Router(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Ap,
             custom_mac: None,
         }
@@ -239,6 +249,8 @@ impl NetifConfiguration {
             description: "ppp".try_into().unwrap(),
             route_priority: 30,
             ip_configuration: Some(ipv4::Configuration::Client(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Ppp,
             custom_mac: None,
         }
@@ -254,6 +266,8 @@ impl NetifConfiguration {
             description: "ppprt".try_into().unwrap(),
             route_priority: 20,
             ip_configuration: Some(ipv4::Configuration::Router(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Ppp,
             custom_mac: None,
         }
@@ -269,6 +283,8 @@ impl NetifConfiguration {
             description: "slip".try_into().unwrap(),
             route_priority: 35,
             ip_configuration: Some(ipv4::Configuration::Client(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Slip,
             custom_mac: None,
         }
@@ -284,6 +300,8 @@ impl NetifConfiguration {
             description: "sliprt".try_into().unwrap(),
             route_priority: 25,
             ip_configuration: Some(ipv4::Configuration::Router(Default::default())),
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Slip,
             custom_mac: None,
         }
@@ -299,12 +317,123 @@ impl NetifConfiguration {
             description: "thread".try_into().unwrap(),
             route_priority: 15,
             ip_configuration: None,
+            ipv6_configuration: None,
+            dhcp_server_configuration: None,
             stack: NetifStack::Thread,
             custom_mac: None,
         }
     }
 }
 
+/// The maximum number of IPv6 addresses an `EspNetif` can have
+///
+/// Upper bound of `CONFIG_LWIP_IPV6_NUM_ADDRESSES`.
+pub const MAX_IPV6_ADDRESSES: usize = 8;
+
+/// IPv6 configuration of a netif
+///
+/// With `link_local` set or `static_addresses` not empty, the netif registers
+/// a handler on the default event loop to re-apply the configuration each time it
+/// gets an IP address, so the loop has to exist - i.e. `EspSystemEventLoop::take`
+/// has to be called - before the netif is created. Otherwise, creating the netif
+/// fails with `ESP_ERR_INVALID_STATE`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ipv6Configuration {
+    /// Create an IPv6 link-local address for the netif
+    pub link_local: bool,
+    /// Static IPv6 addresses to be added to the netif
+    pub static_addresses: heapless::Vec<core::net::Ipv6Addr, MAX_IPV6_ADDRESSES>,
+    /// The main IPv6 DNS server
+    ///
+    /// ESP-IDF has a single main and a single backup DNS slot per netif, so
+    /// this setting takes precedence over the IPv4 DNS server of the
+    /// `ipv4::Configuration`.
+    pub dns: Option<core::net::Ipv6Addr>,
+    /// The backup IPv6 DNS server
+    ///
+    /// Takes precedence over the IPv4 secondary DNS server of the
+    /// `ipv4::Configuration`.
+    pub secondary_dns: Option<core::net::Ipv6Addr>,
+}
+
+/// The scope of an IPv6 address, as classified by ESP-NETIF
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ipv6AddrScope {
+    Unknown,
+    Global,
+    LinkLocal,
+    SiteLocal,
+    UniqueLocal,
+    Ipv4Mapped,
+}
+
+/// The state of an IPv6 address assigned to a netif
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ipv6AddrState {
+    /// The address is valid and preferred, i.e. usable for new connections
+    Preferred,
+    /// The address is assigned, but not (yet or anymore) preferred,
+    /// e.g. it is still tentative (duplicate address detection is running)
+    /// or it is deprecated
+    NotPreferred,
+    /// The state of the address cannot be determined with this ESP-IDF version
+    Unknown,
+}
+
+/// An IPv6 address assigned to a netif
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv6AddrInfo {
+    pub addr: core::net::Ipv6Addr,
+    pub zone: u8,
+    pub scope: Ipv6AddrScope,
+    pub state: Ipv6AddrState,
+}
+
+/// DHCP server configuration of a router netif
+///
+/// Only relevant for netifs with an `ipv4::Configuration::Router`
+/// configuration which has `dhcp_enabled` set.
+///
+/// Note that static lease reservations (a fixed IP per MAC) are not supported,
+/// as the ESP-IDF DHCP server has no API for them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DhcpServerConfiguration {
+    /// The range of addresses (inclusive) which the DHCP server leases to the clients
+    ///
+    /// Both addresses should be in the subnet of the netif, and the range
+    /// should not contain more than 100 addresses.
+    pub lease_range: Option<(ipv4::Ipv4Addr, ipv4::Ipv4Addr)>,
+    /// The lease time offered to the clients
+    ///
+    /// ESP-IDF expresses the lease time in units of `CONFIG_LWIP_DHCPS_LEASE_UNIT`
+    /// (60 seconds by default), so the duration is rounded down to that granularity.
+    pub lease_time: Option<core::time::Duration>,
+    /// The captive portal URI (DHCP option 114, RFC 8910) offered to the clients
+    #[cfg(esp_idf_version_at_least_5_4_0)]
+    pub captive_portal_uri: Option<heapless::String<128>>,
+}
+
+/// The ESP-IDF DHCP server expresses lease times in this unit
+const DHCPS_LEASE_UNIT_SECS: u64 = CONFIG_LWIP_DHCPS_LEASE_UNIT as u64;
+
+fn dhcps_lease_time(handle: *mut esp_netif_t) -> Result<core::time::Duration, EspError> {
+    let mut lease_time: u32 = 0;
+
+    esp!(unsafe {
+        esp_netif_dhcps_option(
+            handle,
+            esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_GET,
+            esp_netif_dhcp_option_id_t_ESP_NETIF_IP_ADDRESS_LEASE_TIME,
+            &mut lease_time as *mut _ as *mut _,
+            core::mem::size_of_val(&lease_time) as u32,
+        )
+    })?;
+
+    Ok(core::time::Duration::from_secs(
+        lease_time as u64 * DHCPS_LEASE_UNIT_SECS,
+    ))
+}
+
 static INITALIZED: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
 fn initialize_netif_stack() -> Result<(), EspError> {
@@ -319,11 +448,76 @@ fn initialize_netif_stack() -> Result<(), EspError> {
     Ok(())
 }
 
+/// Applies the IPv6 configuration of a netif each time it gets an IP address
+#[cfg(esp_idf_lwip_ipv6)]
+#[derive(Debug)]
+struct Ipv6Hook {
+    netif: *mut esp_netif_t,
+    conf: Ipv6Configuration,
+    event_id: i32,
+    instance: esp_event_handler_instance_t,
+}
+
+#[cfg(esp_idf_lwip_ipv6)]
+impl Ipv6Hook {
+    unsafe extern "C" fn on_got_ip(
+        arg: *mut ffi::c_void,
+        _event_base: esp_event_base_t,
+        _event_id: i32,
+        event_data: *mut ffi::c_void,
+    ) {
+        let hook = unsafe { (arg as *const Self).as_ref() }.unwrap();
+        let event = unsafe { (event_data as *const ip_event_got_ip_t).as_ref() }.unwrap();
+
+        if event.esp_netif == hook.netif {
+            if let Err(e) = apply_ipv6_conf(hook.netif, &hook.conf) {
+                ::log::debug!("Applying the IPv6 configuration failed: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(esp_idf_lwip_ipv6)]
+fn apply_ipv6_conf(netif: *mut esp_netif_t, conf: &Ipv6Configuration) -> Result<(), EspError> {
+    if conf.link_local {
+        esp!(unsafe { esp_netif_create_ip6_linklocal(netif) })?;
+    }
+
+    #[cfg(esp_idf_version_at_least_5_1_0)]
+    if !conf.static_addresses.is_empty() {
+        let mut existing: [esp_ip6_addr_t; MAX_IPV6_ADDRESSES] = Default::default();
+        let count = unsafe { esp_netif_get_all_ip6(netif, existing.as_mut_ptr()) };
+        let existing = &existing[..(count.max(0) as usize).min(MAX_IPV6_ADDRESSES)];
+
+        for addr in &conf.static_addresses {
+            let addr = Newtype::<esp_ip6_addr_t>::from(*addr).0;
+
+            // The configuration is applied again on each got-IP event
+            if existing.iter().any(|existing| existing.addr == addr.addr) {
+                continue;
+            }
+
+            esp!(unsafe { esp_netif_add_ip6_address(netif, addr, true) })?;
+        }
+    }
+
+    #[cfg(not(esp_idf_version_at_least_5_1_0))]
+    if !conf.static_addresses.is_empty() {
+        return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct EspNetif {
     handle: *mut esp_netif_t,
     _got_ip_event_id: Option<NonZeroU32>,
     _lost_ip_event_id: Option<NonZeroU32>,
+    ipv6_conf: Option<Ipv6Configuration>,
+    #[cfg(esp_idf_lwip_ipv6)]
+    ipv6_hook: Option<alloc::boxed::Box<Ipv6Hook>>,
+    dhcps_captive_portal_uri: Option<CString>,
 }
 
 impl EspNetif {
@@ -331,6 +525,10 @@ impl EspNetif {
         Self::new_with_conf(&stack.default_configuration())
     }
 
+    /// Create a netif with the given configuration
+    ///
+    /// If the configuration has an `Ipv6Configuration` with addresses to assign,
+    /// the default event loop has to exist already (see `Ipv6Configuration`).
     pub fn new_with_conf(conf: &NetifConfiguration) -> Result<Self, EspError> {
         initialize_netif_stack()?;
 
@@ -457,6 +655,10 @@ impl EspNetif {
                 .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
             _got_ip_event_id: conf.got_ip_event_id,
             _lost_ip_event_id: conf.lost_ip_event_id,
+            ipv6_conf: conf.ipv6_configuration.clone(),
+            #[cfg(esp_idf_lwip_ipv6)]
+            ipv6_hook: None,
+            dhcps_captive_portal_uri: None,
         };
 
         if let Some(dns) = dns {
@@ -486,10 +688,31 @@ impl EspNetif {
             netif.set_secondary_dns(secondary_dns);
         }
 
+        if dhcps {
+            if let Some(dhcps_conf) = conf.dhcp_server_configuration.as_ref() {
+                netif.set_dhcps_conf(dhcps_conf)?;
+            }
+        }
+
         if let Some(hostname) = hostname {
             netif.set_hostname(hostname)?;
         }
 
+        #[cfg(esp_idf_lwip_ipv6)]
+        if let Some(ipv6_conf) = conf.ipv6_configuration.as_ref() {
+            if let Some(dns) = ipv6_conf.dns {
+                netif.set_dns_ipv6(dns)?;
+            }
+
+            if let Some(secondary_dns) = ipv6_conf.secondary_dns {
+                netif.set_secondary_dns_ipv6(secondary_dns)?;
+            }
+
+            if ipv6_conf.link_local || !ipv6_conf.static_addresses.is_empty() {
+                netif.hook_ipv6_conf()?;
+            }
+        }
+
         Ok(netif)
     }
 
@@ -629,6 +852,428 @@ impl EspNetif {
         Ok(())
     }
 
+    /// Return the IPv6 configuration this netif was created with, if any
+    pub fn get_ipv6_conf(&self) -> Option<&Ipv6Configuration> {
+        self.ipv6_conf.as_ref()
+    }
+
+    /// Apply the link-local and static addresses of the IPv6 configuration
+    /// the netif was created with
+    ///
+    /// ESP-IDF can only assign IPv6 addresses to an active interface. Therefore,
+    /// the netif applies them by itself when it is created already up, and each time
+    /// it gets an IPv4 address (the `got_ip_event_id` event of its configuration).
+    /// This method is only necessary for netifs without such event, or for IPv6-only
+    /// links, and should be called once the netif is up - i.e. after the underlying
+    /// driver is started and - for Wifi STA - connected.
+    ///
+    /// The IPv6 DNS servers of the configuration are applied already when
+    /// the netif is created.
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn apply_ipv6_conf(&mut self) -> Result<(), EspError> {
+        match self.ipv6_conf.as_ref() {
+            Some(conf) => apply_ipv6_conf(self.handle, conf),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn hook_ipv6_conf(&mut self) -> Result<(), EspError> {
+        let conf = self.ipv6_conf.clone().unwrap();
+
+        if self.is_netif_up()? {
+            apply_ipv6_conf(self.handle, &conf)?;
+        }
+
+        let Some(got_ip_event_id) = self._got_ip_event_id else {
+            return Ok(());
+        };
+
+        let mut hook = alloc::boxed::Box::new(Ipv6Hook {
+            netif: self.handle,
+            conf,
+            event_id: got_ip_event_id.get() as _,
+            instance: ptr::null_mut(),
+        });
+
+        let hook_ptr = hook.as_mut() as *mut Ipv6Hook as *mut ffi::c_void;
+
+        esp!(unsafe {
+            esp_event_handler_instance_register(
+                IP_EVENT,
+                hook.event_id,
+                Some(Ipv6Hook::on_got_ip),
+                hook_ptr,
+                &mut hook.instance,
+            )
+        })?;
+
+        self.ipv6_hook = Some(hook);
+
+        Ok(())
+    }
+
+    /// Create the IPv6 link-local address of the netif
+    ///
+    /// The address is derived from the MAC address of the netif and becomes
+    /// preferred once duplicate address detection completes.
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn create_ipv6_link_local(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_netif_create_ip6_linklocal(self.handle) })
+    }
+
+    /// Return the preferred IPv6 link-local address of the netif, if any
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn get_ipv6_link_local(&self) -> Result<Option<core::net::Ipv6Addr>, EspError> {
+        let mut addr: esp_ip6_addr_t = Default::default();
+
+        match unsafe { esp_netif_get_ip6_linklocal(self.handle, &mut addr) } {
+            ESP_OK => Ok(Some(Newtype(addr).into())),
+            ESP_FAIL => Ok(None),
+            err => Err(EspError::from(err).unwrap()),
+        }
+    }
+
+    /// Return the preferred IPv6 global address of the netif, if any
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn get_ipv6_global(&self) -> Result<Option<core::net::Ipv6Addr>, EspError> {
+        let mut addr: esp_ip6_addr_t = Default::default();
+
+        match unsafe { esp_netif_get_ip6_global(self.handle, &mut addr) } {
+            ESP_OK => Ok(Some(Newtype(addr).into())),
+            ESP_FAIL => Ok(None),
+            err => Err(EspError::from(err).unwrap()),
+        }
+    }
+
+    /// Return all IPv6 addresses of the netif, together with their scope and state
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn get_ipv6_addrs(
+        &self,
+    ) -> Result<heapless::Vec<Ipv6AddrInfo, MAX_IPV6_ADDRESSES>, EspError> {
+        let mut addrs: [esp_ip6_addr_t; MAX_IPV6_ADDRESSES] = Default::default();
+        let count = unsafe { esp_netif_get_all_ip6(self.handle, addrs.as_mut_ptr()) };
+        let count = (count.max(0) as usize).min(MAX_IPV6_ADDRESSES);
+
+        #[cfg(esp_idf_version_at_least_5_2_0)]
+        let (preferred, preferred_count) = {
+            let mut preferred: [esp_ip6_addr_t; MAX_IPV6_ADDRESSES] = Default::default();
+            let count =
+                unsafe { esp_netif_get_all_preferred_ip6(self.handle, preferred.as_mut_ptr()) };
+
+            (preferred, (count.max(0) as usize).min(MAX_IPV6_ADDRESSES))
+        };
+
+        let mut result = heapless::Vec::new();
+
+        for addr in &addrs[..count] {
+            #[cfg(esp_idf_version_at_least_5_2_0)]
+            let state = if preferred[..preferred_count]
+                .iter()
+                .any(|preferred| preferred.addr == addr.addr)
+            {
+                Ipv6AddrState::Preferred
+            } else {
+                Ipv6AddrState::NotPreferred
+            };
+
+            #[cfg(not(esp_idf_version_at_least_5_2_0))]
+            let state = Ipv6AddrState::Unknown;
+
+            let scope = Ipv6AddrScope::from_raw(unsafe {
+                esp_netif_ip6_get_addr_type(addr as *const _ as *mut _)
+            });
+
+            result
+                .push(Ipv6AddrInfo {
+                    addr: Newtype(*addr).into(),
+                    zone: addr.zone,
+                    scope,
+                    state,
+                })
+                .unwrap();
+        }
+
+        Ok(result)
+    }
+
+    /// Add a static IPv6 address to the netif
+    ///
+    /// Arguments:
+    /// - `addr` is the address to add
+    /// - `preferred` - whether the address should be marked as preferred
+    ///   right away, or should stay tentative until duplicate address detection completes
+    #[cfg(all(esp_idf_lwip_ipv6, esp_idf_version_at_least_5_1_0))]
+    pub fn add_ipv6_addr(
+        &mut self,
+        addr: core::net::Ipv6Addr,
+        preferred: bool,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_netif_add_ip6_address(
+                self.handle,
+                Newtype::<esp_ip6_addr_t>::from(addr).0,
+                preferred,
+            )
+        })
+    }
+
+    /// Remove a static IPv6 address from the netif
+    #[cfg(all(esp_idf_lwip_ipv6, esp_idf_version_at_least_5_1_0))]
+    pub fn remove_ipv6_addr(&mut self, addr: core::net::Ipv6Addr) -> Result<(), EspError> {
+        let addr = Newtype::<esp_ip6_addr_t>::from(addr).0;
+
+        esp!(unsafe { esp_netif_remove_ip6_address(self.handle, &addr) })
+    }
+
+    /// Return the main DNS server of the netif, which might be an IPv4 or an IPv6 address
+    pub fn get_dns_addr(&self) -> Result<core::net::IpAddr, EspError> {
+        self.get_dns_addr_raw(esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN)
+    }
+
+    /// Return the backup DNS server of the netif, which might be an IPv4 or an IPv6 address
+    pub fn get_secondary_dns_addr(&self) -> Result<core::net::IpAddr, EspError> {
+        self.get_dns_addr_raw(esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP)
+    }
+
+    /// Set an IPv6 address as the main DNS server of the netif
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn set_dns_ipv6(&mut self, dns: core::net::Ipv6Addr) -> Result<(), EspError> {
+        self.set_dns_ipv6_raw(esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, dns)
+    }
+
+    /// Set an IPv6 address as the backup DNS server of the netif
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn set_secondary_dns_ipv6(&mut self, dns: core::net::Ipv6Addr) -> Result<(), EspError> {
+        self.set_dns_ipv6_raw(esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP, dns)
+    }
+
+    fn get_dns_addr_raw(
+        &self,
+        dns_type: esp_netif_dns_type_t,
+    ) -> Result<core::net::IpAddr, EspError> {
+        let mut dns_info: esp_netif_dns_info_t = Default::default();
+
+        esp!(unsafe { esp_netif_get_dns_info(self.handle, dns_type, &mut dns_info) })?;
+
+        #[cfg(esp_idf_lwip_ipv6)]
+        if dns_info.ip.type_ as u32 == ESP_IPADDR_TYPE_V6 {
+            return Ok(core::net::IpAddr::V6(
+                Newtype(unsafe { dns_info.ip.u_addr.ip6 }).into(),
+            ));
+        }
+
+        Ok(core::net::IpAddr::V4(
+            Newtype(unsafe { dns_info.ip.u_addr.ip4 }).into(),
+        ))
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn set_dns_ipv6_raw(
+        &mut self,
+        dns_type: esp_netif_dns_type_t,
+        dns: core::net::Ipv6Addr,
+    ) -> Result<(), EspError> {
+        let mut dns_info: esp_netif_dns_info_t = Default::default();
+
+        dns_info.ip.u_addr.ip6 = Newtype::<esp_ip6_addr_t>::from(dns).0;
+        dns_info.ip.type_ = ESP_IPADDR_TYPE_V6 as _;
+
+        esp!(unsafe { esp_netif_set_dns_info(self.handle, dns_type, &mut dns_info) })
+    }
+
+    /// Apply the provided DHCP server configuration
+    ///
+    /// If the DHCP server is running, it is stopped and restarted for the
+    /// new configuration to take effect.
+    pub fn set_dhcps_conf(&mut self, conf: &DhcpServerConfiguration) -> Result<(), EspError> {
+        if let Some((start, end)) = conf.lease_range {
+            self.set_dhcps_lease_range(start, end)?;
+        }
+
+        if let Some(lease_time) = conf.lease_time {
+            self.set_dhcps_lease_time(lease_time)?;
+        }
+
+        #[cfg(esp_idf_version_at_least_5_4_0)]
+        if let Some(uri) = conf.captive_portal_uri.as_ref() {
+            self.set_dhcps_captive_portal_uri(uri)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the range of addresses (inclusive) leased by the DHCP server,
+    /// or `None` if the DHCP server uses its default range
+    pub fn get_dhcps_lease_range(
+        &self,
+    ) -> Result<Option<(ipv4::Ipv4Addr, ipv4::Ipv4Addr)>, EspError> {
+        let mut lease: dhcps_lease_t = Default::default();
+
+        esp!(unsafe {
+            esp_netif_dhcps_option(
+                self.handle,
+                esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_GET,
+                esp_netif_dhcp_option_id_t_ESP_NETIF_REQUESTED_IP_ADDRESS,
+                &mut lease as *mut _ as *mut _,
+                core::mem::size_of_val(&lease) as u32,
+            )
+        })?;
+
+        Ok(lease
+            .enable
+            .then(|| (Newtype(lease.start_ip).into(), Newtype(lease.end_ip).into())))
+    }
+
+    /// Set the range of addresses (inclusive) leased by the DHCP server
+    ///
+    /// Both addresses should be in the subnet of the netif, and the range
+    /// should not contain more than 100 addresses.
+    pub fn set_dhcps_lease_range(
+        &mut self,
+        start: ipv4::Ipv4Addr,
+        end: ipv4::Ipv4Addr,
+    ) -> Result<(), EspError> {
+        let mut lease = dhcps_lease_t {
+            enable: true,
+            start_ip: Newtype::<ip4_addr_t>::from(start).0,
+            end_ip: Newtype::<ip4_addr_t>::from(end).0,
+        };
+
+        self.dhcps_reconfigure(|handle| {
+            esp!(unsafe {
+                esp_netif_dhcps_option(
+                    handle,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_REQUESTED_IP_ADDRESS,
+                    &mut lease as *mut _ as *mut _,
+                    core::mem::size_of_val(&lease) as u32,
+                )
+            })
+        })
+    }
+
+    /// Return the lease time offered by the DHCP server
+    pub fn get_dhcps_lease_time(&self) -> Result<core::time::Duration, EspError> {
+        dhcps_lease_time(self.handle)
+    }
+
+    /// Set the lease time offered by the DHCP server
+    ///
+    /// ESP-IDF expresses the lease time in units of `CONFIG_LWIP_DHCPS_LEASE_UNIT`
+    /// (60 seconds by default), so the duration is rounded down to that granularity.
+    pub fn set_dhcps_lease_time(
+        &mut self,
+        lease_time: core::time::Duration,
+    ) -> Result<(), EspError> {
+        let mut lease_time: u32 = (lease_time.as_secs() / DHCPS_LEASE_UNIT_SECS)
+            .max(1)
+            .try_into()
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        self.dhcps_reconfigure(|handle| {
+            esp!(unsafe {
+                esp_netif_dhcps_option(
+                    handle,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_IP_ADDRESS_LEASE_TIME,
+                    &mut lease_time as *mut _ as *mut _,
+                    core::mem::size_of_val(&lease_time) as u32,
+                )
+            })
+        })
+    }
+
+    /// Set the captive portal URI (DHCP option 114, RFC 8910) offered by the DHCP server
+    ///
+    /// The ESP-IDF DHCP server has no way to stop offering the URI once set,
+    /// short of re-creating the netif.
+    #[cfg(esp_idf_version_at_least_5_4_0)]
+    pub fn set_dhcps_captive_portal_uri(&mut self, uri: &str) -> Result<(), EspError> {
+        // The DHCP server does not copy the URI, so it has to be kept alive
+        // for as long as it is offered
+        let uri = to_cstring_arg(uri)?;
+
+        let uri_ptr = uri.as_ptr() as *mut _;
+        let uri_len = uri.as_bytes().len() as u32;
+
+        self.dhcps_reconfigure(|handle| {
+            esp!(unsafe {
+                esp_netif_dhcps_option(
+                    handle,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_CAPTIVEPORTAL_URI,
+                    uri_ptr,
+                    uri_len,
+                )
+            })
+        })?;
+
+        self.dhcps_captive_portal_uri = Some(uri);
+
+        Ok(())
+    }
+
+    /// Return the addresses the DHCP server has leased to the clients with the provided MACs
+    ///
+    /// The returned vector has the same length as `macs`; MACs for which
+    /// there is no active lease are mapped to `None`.
+    pub fn get_dhcps_client_ips(
+        &self,
+        macs: &[[u8; 6]],
+    ) -> Result<alloc::vec::Vec<Option<ipv4::Ipv4Addr>>, EspError> {
+        let mut pairs = macs
+            .iter()
+            .map(|mac| esp_netif_pair_mac_ip_t {
+                mac: *mac,
+                ..Default::default()
+            })
+            .collect::<alloc::vec::Vec<_>>();
+
+        if !pairs.is_empty() {
+            esp!(unsafe {
+                esp_netif_dhcps_get_clients_by_mac(
+                    self.handle,
+                    pairs.len() as _,
+                    pairs.as_mut_ptr(),
+                )
+            })?;
+        }
+
+        Ok(pairs
+            .into_iter()
+            .map(|pair| {
+                let ip: ipv4::Ipv4Addr = Newtype(pair.ip).into();
+
+                (ip != ipv4::Ipv4Addr::UNSPECIFIED).then_some(ip)
+            })
+            .collect())
+    }
+
+    /// Run the provided closure with the DHCP server stopped, as ESP-IDF
+    /// does not allow changing most of the DHCP server options while it is running
+    fn dhcps_reconfigure<F>(&mut self, f: F) -> Result<(), EspError>
+    where
+        F: FnOnce(*mut esp_netif_t) -> Result<(), EspError>,
+    {
+        let mut status = esp_netif_dhcp_status_t_ESP_NETIF_DHCP_INIT;
+        esp!(unsafe { esp_netif_dhcps_get_status(self.handle, &mut status) })?;
+
+        let running = status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED;
+
+        if running {
+            esp!(unsafe { esp_netif_dhcps_stop(self.handle) })?;
+        }
+
+        let result = f(self.handle);
+
+        if running {
+            esp!(unsafe { esp_netif_dhcps_start(self.handle) })?;
+        }
+
+        result
+    }
+
     #[cfg(esp_idf_lwip_ipv4_napt)]
     pub fn enable_napt(&mut self, enable: bool) {
         unsafe {
@@ -642,6 +1287,15 @@ impl EspNetif {
 
 impl Drop for EspNetif {
     fn drop(&mut self) {
+        #[cfg(esp_idf_lwip_ipv6)]
+        if let Some(hook) = self.ipv6_hook.take() {
+            if let Err(e) = esp!(unsafe {
+                esp_event_handler_instance_unregister(IP_EVENT, hook.event_id, hook.instance)
+            }) {
+                ::log::warn!("Unregistering the IPv6 configuration hook failed: {e}");
+            }
+        }
+
         unsafe { esp_netif_destroy(self.handle) };
 
         info!("Dropped");
@@ -658,6 +1312,20 @@ impl RawHandle for EspNetif {
     }
 }
 
+impl Ipv6AddrScope {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(addr_type: esp_ip6_addr_type_t) -> Self {
+        match addr_type {
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL => Self::Global,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_LINK_LOCAL => Self::LinkLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_SITE_LOCAL => Self::SiteLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_UNIQUE_LOCAL => Self::UniqueLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_IPV4_MAPPED_IPV6 => Self::Ipv4Mapped,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct ApStaIpAssignment<'a>(&'a ip_event_ap_staipassigned_t);
 
@@ -879,6 +1547,107 @@ impl EspEventDeserializer for IpEvent<'_> {
     }
 }
 
+/// A lease handed out by the DHCP server of a router netif
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DhcpLease {
+    /// The MAC address of the client
+    pub mac: [u8; 6],
+    /// The address leased to the client
+    pub ip: ipv4::Ipv4Addr,
+    /// When the lease was (last) granted, as time since boot
+    pub granted_at: core::time::Duration,
+    /// When the lease expires, as time since boot
+    pub expires_at: core::time::Duration,
+}
+
+/// The lease table of the DHCP server of a router netif
+///
+/// ESP-IDF's DHCP server does not expose its lease table, so the table is
+/// built by tracking the `IpEvent::ApStaIpAssigned` events for the netif
+/// on the system event loop. Only leases granted after the table is
+/// created are therefore reported; clients which got their lease before,
+/// and do not renew it, are missing from the table until they do.
+#[cfg(not(esp_idf_version_major = "4"))]
+pub struct EspDhcpLeaseTable<T>
+where
+    T: core::borrow::Borrow<EspNetif>,
+{
+    _subscription: crate::eventloop::EspSystemSubscription<'static>,
+    leases: alloc::sync::Arc<mutex::Mutex<alloc::vec::Vec<DhcpLease>>>,
+    netif: T,
+}
+
+#[cfg(not(esp_idf_version_major = "4"))]
+impl<T> EspDhcpLeaseTable<T>
+where
+    T: core::borrow::Borrow<EspNetif>,
+{
+    /// Start tracking the leases granted by the DHCP server of the provided netif
+    pub fn new(netif: T, sysloop: &crate::eventloop::EspSystemEventLoop) -> Result<Self, EspError> {
+        let leases = alloc::sync::Arc::new(mutex::Mutex::new(alloc::vec::Vec::new()));
+
+        // Raw pointers are not `Send`, so the address of the netif is captured instead.
+        // The subscription is dropped before the netif, so the handle stays valid in the callback
+        let handle = netif.borrow().handle() as usize;
+
+        let subscription = {
+            let leases = leases.clone();
+
+            sysloop.subscribe::<IpEvent, _>(move |event| {
+                if let IpEvent::ApStaIpAssigned(assignment) = event {
+                    if assignment.netif_handle() as usize == handle {
+                        let now =
+                            core::time::Duration::from_micros(unsafe { esp_timer_get_time() as _ });
+
+                        let Ok(lease_time) = dhcps_lease_time(handle as *mut _) else {
+                            return;
+                        };
+
+                        let lease = DhcpLease {
+                            mac: assignment.mac(),
+                            ip: assignment.ip(),
+                            granted_at: now,
+                            expires_at: now + lease_time,
+                        };
+
+                        let mut leases = leases.lock();
+
+                        leases.retain(|existing| existing.mac != lease.mac);
+                        leases.push(lease);
+                    }
+                }
+            })?
+        };
+
+        Ok(Self {
+            _subscription: subscription,
+            leases,
+            netif,
+        })
+    }
+
+    /// Return the netif whose leases are tracked
+    pub fn netif(&self) -> &EspNetif {
+        self.netif.borrow()
+    }
+
+    /// Return all leases which are not expired yet
+    pub fn leases(&self) -> alloc::vec::Vec<DhcpLease> {
+        let now = core::time::Duration::from_micros(unsafe { esp_timer_get_time() as _ });
+
+        let mut leases = self.leases.lock();
+
+        leases.retain(|lease| lease.expires_at > now);
+
+        leases.clone()
+    }
+
+    /// Return the lease of the client with the provided MAC, if any
+    pub fn lease(&self, mac: &[u8; 6]) -> Option<DhcpLease> {
+        self.leases().into_iter().find(|lease| lease.mac == *mac)
+    }
+}
+
 pub trait NetifStatus {
     fn is_up(&self) -> Result<bool, EspError>;
 }
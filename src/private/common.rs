@@ -6,3 +6,21 @@ pub struct UnsafeCellSendSync<T>(pub UnsafeCell<T>);
 
 unsafe impl<T> Send for UnsafeCellSendSync<T> {}
 unsafe impl<T> Sync for UnsafeCellSendSync<T> {}
+
+/// Map a `std::io::Error` (as returned by the `std::net` sockets of ESP-IDF's LwIP) to an `EspError`
+#[cfg(feature = "std")]
+pub fn to_esp_error(err: std::io::Error) -> crate::sys::EspError {
+    use crate::sys::*;
+
+    match err.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+            EspError::from_infallible::<ESP_ERR_TIMEOUT>()
+        }
+        std::io::ErrorKind::InvalidInput => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        std::io::ErrorKind::OutOfMemory => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+        std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable => {
+            EspError::from_infallible::<ESP_ERR_INVALID_STATE>()
+        }
+        _ => EspError::from_infallible::<ESP_FAIL>(),
+    }
+}
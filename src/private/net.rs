@@ -112,3 +112,17 @@ impl From<Newtype<esp_ip6_addr_t>> for core::net::Ipv6Addr {
         core::net::Ipv6Addr::from(out)
     }
 }
+
+impl From<core::net::Ipv6Addr> for Newtype<esp_ip6_addr_t> {
+    fn from(ip: core::net::Ipv6Addr) -> Self {
+        let mut addr = [0; 4];
+
+        ip.octets()
+            .chunks_exact(4)
+            .map(|chunk| u32::to_be(u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])))
+            .zip(addr.iter_mut())
+            .for_each(|(i, o)| *o = i);
+
+        Newtype(esp_ip6_addr_t { addr, zone: 0 })
+    }
+}
@@ -0,0 +1,378 @@
+//! Socket helpers tied to network interfaces
+//!
+//! The helpers in this module work with any socket exposing its lwIP file
+//! descriptor via [`AsRawFd`] - i.e. the sockets from `std::net`, but also
+//! async wrappers like `async_io::Async<TcpStream>`.
+//!
+//! A socket prepared with them can then be handed over to ESP-TLS with
+//! [`EspSocket`], or - with the `async-io` feature - to the async ESP-TLS with
+//! [`EspAsyncSocket`], which implements [`crate::tls::PollableSocket`].
+//!
+//! They allow pinning a socket to a concrete [`EspNetif`] (so that e.g. traffic
+//! goes over Ethernet rather than over Wifi when both are up), configuring TCP
+//! keep-alive and managing UDP multicast group memberships per interface.
+use core::ffi::{c_int, c_void};
+use core::mem;
+use core::net::Ipv4Addr;
+#[cfg(esp_idf_lwip_ipv6)]
+use core::net::Ipv6Addr;
+#[cfg(feature = "async-io")]
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use std::os::fd::{AsRawFd, IntoRawFd};
+
+use crate::netif::EspNetif;
+use crate::sys::*;
+
+/// TCP keep-alive parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// Idle time before the first keep-alive probe is sent
+    pub idle: Duration,
+    /// Interval between consecutive keep-alive probes
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection is dropped
+    pub count: u32,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(7200),
+            interval: Duration::from_secs(75),
+            count: 9,
+        }
+    }
+}
+
+/// Extension methods for sockets, implemented for everything which is [`AsRawFd`].
+pub trait EspSocketExt {
+    /// Binds the socket to the supplied network interface, similarly to
+    /// `SO_BINDTODEVICE` on Linux.
+    ///
+    /// Outgoing traffic is sent only via that interface and incoming traffic
+    /// is only accepted from it.
+    fn bind_to_netif(&self, netif: &EspNetif) -> Result<(), EspError>;
+
+    /// Removes a binding previously established with [`EspSocketExt::bind_to_netif`].
+    fn unbind_from_netif(&self) -> Result<(), EspError>;
+
+    /// Enables TCP keep-alive with the supplied parameters, or disables it if `None`.
+    fn set_keepalive(&self, keepalive: Option<&KeepAlive>) -> Result<(), EspError>;
+
+    /// Joins the IPv4 multicast group `group` on the supplied network interface.
+    fn join_multicast_netif_v4(&self, group: Ipv4Addr, netif: &EspNetif) -> Result<(), EspError>;
+
+    /// Leaves the IPv4 multicast group `group` on the supplied network interface.
+    fn leave_multicast_netif_v4(&self, group: Ipv4Addr, netif: &EspNetif) -> Result<(), EspError>;
+
+    /// Sets the network interface used for sending IPv4 multicast datagrams.
+    fn set_multicast_netif_v4(&self, netif: &EspNetif) -> Result<(), EspError>;
+
+    /// Joins the IPv6 multicast group `group` on the supplied network interface.
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn join_multicast_netif_v6(&self, group: Ipv6Addr, netif: &EspNetif) -> Result<(), EspError>;
+
+    /// Leaves the IPv6 multicast group `group` on the supplied network interface.
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn leave_multicast_netif_v6(&self, group: Ipv6Addr, netif: &EspNetif) -> Result<(), EspError>;
+}
+
+impl<T> EspSocketExt for T
+where
+    T: AsRawFd + ?Sized,
+{
+    fn bind_to_netif(&self, netif: &EspNetif) -> Result<(), EspError> {
+        bind(self.as_raw_fd(), Some(&netif.get_name()))
+    }
+
+    fn unbind_from_netif(&self) -> Result<(), EspError> {
+        bind(self.as_raw_fd(), None)
+    }
+
+    fn set_keepalive(&self, keepalive: Option<&KeepAlive>) -> Result<(), EspError> {
+        let fd = self.as_raw_fd();
+
+        set_option(
+            fd,
+            SOL_SOCKET as _,
+            SO_KEEPALIVE as _,
+            &(keepalive.is_some() as c_int),
+        )?;
+
+        if let Some(keepalive) = keepalive {
+            set_option(
+                fd,
+                IPPROTO_TCP as _,
+                TCP_KEEPIDLE as _,
+                &(keepalive.idle.as_secs().max(1) as c_int),
+            )?;
+            set_option(
+                fd,
+                IPPROTO_TCP as _,
+                TCP_KEEPINTVL as _,
+                &(keepalive.interval.as_secs().max(1) as c_int),
+            )?;
+            set_option(
+                fd,
+                IPPROTO_TCP as _,
+                TCP_KEEPCNT as _,
+                &(keepalive.count as c_int),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn join_multicast_netif_v4(&self, group: Ipv4Addr, netif: &EspNetif) -> Result<(), EspError> {
+        set_option(
+            self.as_raw_fd(),
+            IPPROTO_IP as _,
+            IP_ADD_MEMBERSHIP as _,
+            &mreq_v4(group, netif)?,
+        )
+    }
+
+    fn leave_multicast_netif_v4(&self, group: Ipv4Addr, netif: &EspNetif) -> Result<(), EspError> {
+        set_option(
+            self.as_raw_fd(),
+            IPPROTO_IP as _,
+            IP_DROP_MEMBERSHIP as _,
+            &mreq_v4(group, netif)?,
+        )
+    }
+
+    fn set_multicast_netif_v4(&self, netif: &EspNetif) -> Result<(), EspError> {
+        set_option(
+            self.as_raw_fd(),
+            IPPROTO_IP as _,
+            IP_MULTICAST_IF as _,
+            &in_addr_v4(netif.get_ip_info()?.ip),
+        )
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn join_multicast_netif_v6(&self, group: Ipv6Addr, netif: &EspNetif) -> Result<(), EspError> {
+        set_option(
+            self.as_raw_fd(),
+            IPPROTO_IPV6 as _,
+            IPV6_JOIN_GROUP as _,
+            &mreq_v6(group, netif),
+        )
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    fn leave_multicast_netif_v6(&self, group: Ipv6Addr, netif: &EspNetif) -> Result<(), EspError> {
+        set_option(
+            self.as_raw_fd(),
+            IPPROTO_IPV6 as _,
+            IPV6_LEAVE_GROUP as _,
+            &mreq_v6(group, netif),
+        )
+    }
+}
+
+/// A wrapper around a socket which implements [`crate::tls::Socket`] for the
+/// `std::net` sockets, so that a connection prepared with the [`EspSocketExt`]
+/// helpers (bound to a network interface, with keep-alive enabled, etc.) can be
+/// handed over to [`crate::tls::EspTls`].
+pub struct EspSocket<S>(Option<S>)
+where
+    S: AsRawFd;
+
+impl<S> EspSocket<S>
+where
+    S: AsRawFd,
+{
+    /// Wraps the supplied socket.
+    pub const fn new(socket: S) -> Self {
+        Self(Some(socket))
+    }
+
+    /// Returns a reference to the wrapped socket.
+    ///
+    /// Panics if the socket was already released to ESP-TLS.
+    pub fn socket(&self) -> &S {
+        self.0.as_ref().unwrap()
+    }
+
+    /// Returns the wrapped socket, unless it was already released to ESP-TLS.
+    pub fn into_inner(self) -> Option<S> {
+        self.0
+    }
+}
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+))]
+impl<S> crate::tls::Socket for EspSocket<S>
+where
+    S: AsRawFd + IntoRawFd,
+{
+    fn handle(&self) -> i32 {
+        self.socket().as_raw_fd()
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        // ESP-TLS closes the file descriptor itself, so just give up its ownership
+        if let Some(socket) = self.0.take() {
+            let _ = socket.into_raw_fd();
+        }
+
+        Ok(())
+    }
+}
+
+/// The async counterpart of [`EspSocket`], wrapping an `async_io::Async` socket and
+/// implementing [`crate::tls::PollableSocket`], so that it can be handed over to
+/// [`crate::tls::EspAsyncTls`].
+#[cfg(feature = "async-io")]
+pub struct EspAsyncSocket<S>(Option<async_io::Async<S>>)
+where
+    S: AsRawFd + IntoRawFd;
+
+#[cfg(feature = "async-io")]
+impl<S> EspAsyncSocket<S>
+where
+    S: AsRawFd + IntoRawFd,
+{
+    /// Wraps the supplied socket.
+    pub const fn new(socket: async_io::Async<S>) -> Self {
+        Self(Some(socket))
+    }
+
+    /// Returns a reference to the wrapped socket.
+    ///
+    /// Panics if the socket was already released to ESP-TLS.
+    pub fn socket(&self) -> &async_io::Async<S> {
+        self.0.as_ref().unwrap()
+    }
+
+    /// Returns the wrapped socket, unless it was already released to ESP-TLS.
+    pub fn into_inner(self) -> Option<async_io::Async<S>> {
+        self.0
+    }
+}
+
+#[cfg(all(
+    feature = "async-io",
+    esp_idf_comp_esp_tls_enabled,
+    any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+))]
+impl<S> crate::tls::Socket for EspAsyncSocket<S>
+where
+    S: AsRawFd + IntoRawFd,
+{
+    fn handle(&self) -> i32 {
+        self.socket().as_raw_fd()
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        // Deregister the socket from the reactor, and give up the ownership of the
+        // file descriptor, which ESP-TLS closes itself
+        if let Some(socket) = self.0.take() {
+            let _ = socket.into_inner().map_err(map_io_err)?.into_raw_fd();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    feature = "async-io",
+    esp_idf_comp_esp_tls_enabled,
+    any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+))]
+impl<S> crate::tls::PollableSocket for EspAsyncSocket<S>
+where
+    S: AsRawFd + IntoRawFd,
+{
+    fn poll_readable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.socket().poll_readable(ctx).map_err(map_io_err)
+    }
+
+    fn poll_writable(&self, ctx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.socket().poll_writable(ctx).map_err(map_io_err)
+    }
+}
+
+#[cfg(feature = "async-io")]
+fn map_io_err(e: std::io::Error) -> EspError {
+    ::log::warn!("Socket error: {e}");
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
+
+/// Bind the socket to the netif with the provided (lwIP) name, or unbind it
+pub(crate) fn bind(fd: c_int, name: Option<&str>) -> Result<(), EspError> {
+    let mut req: ifreq = Default::default();
+
+    if let Some(name) = name {
+        // The name must leave room for the terminating zero
+        if name.len() >= req.ifr_name.len() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        for (dst, src) in req.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as _;
+        }
+    }
+
+    set_option(fd, SOL_SOCKET as _, SO_BINDTODEVICE as _, &req)
+}
+
+fn in_addr_v4(addr: Ipv4Addr) -> in_addr {
+    in_addr {
+        s_addr: u32::from_ne_bytes(addr.octets()),
+    }
+}
+
+fn mreq_v4(group: Ipv4Addr, netif: &EspNetif) -> Result<ip_mreq, EspError> {
+    Ok(ip_mreq {
+        imr_multiaddr: in_addr_v4(group),
+        imr_interface: in_addr_v4(netif.get_ip_info()?.ip),
+    })
+}
+
+#[cfg(esp_idf_lwip_ipv6)]
+fn mreq_v6(group: Ipv6Addr, netif: &EspNetif) -> ipv6_mreq {
+    let mut mreq: ipv6_mreq = Default::default();
+
+    mreq.ipv6mr_multiaddr.un.u8_addr = group.octets();
+    mreq.ipv6mr_interface = netif.get_index() as _;
+
+    mreq
+}
+
+fn set_option<V>(fd: c_int, level: c_int, name: c_int, value: &V) -> Result<(), EspError> {
+    let result = unsafe {
+        lwip_setsockopt(
+            fd,
+            level,
+            name,
+            value as *const V as *const c_void,
+            mem::size_of::<V>() as _,
+        )
+    };
+
+    if result == 0 {
+        return Ok(());
+    }
+
+    // lwIP returns -1 and reports the actual error in `errno`
+    let errno = std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or_default() as u32;
+
+    Err(match errno {
+        EBADF | ENOTSOCK => EspError::from_infallible::<ESP_ERR_INVALID_STATE>(),
+        EINVAL | EFAULT | ENOPROTOOPT | ENODEV => {
+            EspError::from_infallible::<ESP_ERR_INVALID_ARG>()
+        }
+        ENOMEM | ENOBUFS => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+        EOPNOTSUPP => EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>(),
+        _ => EspError::from_infallible::<ESP_FAIL>(),
+    })
+}
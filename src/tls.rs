@@ -17,13 +17,18 @@ pub use self::esptls::*;
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Psk<'a> {
     pub key: &'a [u8],
+    /// The PSK hint; ESP-TLS (and thus the MQTT client) sends it as the PSK identity
     pub hint: &'a str,
+    /// The PSK identity, which the client sends in the handshake so that the server can
+    /// select the key; used by `EspDtls`
+    pub identity: &'a str,
 }
 
 impl Debug for Psk<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         f.debug_struct("Psk")
             .field("hint", &self.hint)
+            .field("identity", &self.identity)
             .finish_non_exhaustive()
     }
 }
@@ -1255,3 +1260,413 @@ mod esptls {
         }
     }
 }
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_mbedtls_ssl_proto_dtls,
+    esp_idf_mbedtls_key_exchange_psk,
+    not(esp_idf_version_at_least_6_0_0)
+))]
+pub use self::dtls::*;
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_mbedtls_ssl_proto_dtls,
+    esp_idf_mbedtls_key_exchange_psk,
+    not(esp_idf_version_at_least_6_0_0)
+))]
+mod dtls {
+    use core::ffi::{c_int, c_uchar, c_void};
+    use core::time::Duration;
+
+    extern crate alloc;
+    use alloc::boxed::Box;
+
+    use ::log::warn;
+
+    use super::Psk;
+
+    use crate::sys::{self, EspError, ESP_ERR_TIMEOUT, ESP_FAIL};
+
+    /// A connected datagram socket, as used by `EspDtls`
+    pub trait DatagramSocket {
+        /// Send a datagram to the peer
+        fn send(&mut self, datagram: &[u8]) -> Result<(), EspError>;
+
+        /// Wait for a datagram from the peer - forever if `timeout` is `None` - and return
+        /// its length, or `None` on timeout
+        fn recv(
+            &mut self,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> Result<Option<usize>, EspError>;
+    }
+
+    #[cfg(feature = "std")]
+    impl DatagramSocket for std::net::UdpSocket {
+        fn send(&mut self, datagram: &[u8]) -> Result<(), EspError> {
+            std::net::UdpSocket::send(self, datagram)
+                .map(|_| ())
+                .map_err(map_io_err)
+        }
+
+        fn recv(
+            &mut self,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> Result<Option<usize>, EspError> {
+            self.set_read_timeout(timeout.map(|timeout| timeout.max(Duration::from_millis(1))))
+                .map_err(map_io_err)?;
+
+            match std::net::UdpSocket::recv(self, buf) {
+                Ok(len) => Ok(Some(len)),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    Ok(None)
+                }
+                Err(e) => Err(map_io_err(e)),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn map_io_err(e: std::io::Error) -> EspError {
+        warn!("DTLS socket error: {e}");
+
+        EspError::from_infallible::<ESP_FAIL>()
+    }
+
+    struct DtlsIo<S> {
+        socket: S,
+        /// The start of the retransmission timer, in microseconds
+        timer_start: i64,
+        /// The intermediate delay of the timer, in milliseconds
+        timer_int: u32,
+        /// The final delay of the timer, in milliseconds; 0 if the timer is cancelled
+        timer_fin: u32,
+    }
+
+    /// A DTLS session with a pre-shared key, on top of a datagram socket connected to
+    /// the peer - either the client side (`EspDtls::connect`) or the server side
+    /// (`EspDtls::accept`) of the session
+    ///
+    /// The session is synchronous, and based on Mbed TLS with DTLS support
+    /// (`CONFIG_MBEDTLS_SSL_PROTO_DTLS`) and the PSK key exchange
+    /// (`CONFIG_MBEDTLS_KEY_EXCHANGE_PSK`) enabled. It is not available on ESP-IDF >= 6,
+    /// whose Mbed TLS 4 has no RNG callbacks anymore.
+    pub struct EspDtls<S>
+    where
+        S: DatagramSocket,
+    {
+        ssl: Box<sys::mbedtls_ssl_context>,
+        conf: Box<sys::mbedtls_ssl_config>,
+        cookie: Option<Box<sys::mbedtls_ssl_cookie_ctx>>,
+        io: Box<DtlsIo<S>>,
+    }
+
+    impl<S> EspDtls<S>
+    where
+        S: DatagramSocket,
+    {
+        /// Perform the client side of the DTLS handshake with the peer of the socket,
+        /// using `psk.identity` as the PSK identity
+        ///
+        /// # Errors
+        ///
+        /// * `ESP_ERR_TIMEOUT` if the peer does not complete the handshake
+        /// * `ESP_FAIL` if the handshake fails
+        pub fn connect(socket: S, psk: &Psk<'_>) -> Result<Self, EspError> {
+            let mut this = Self::new(socket, psk, false)?;
+
+            this.handshake(None)?;
+
+            Ok(this)
+        }
+
+        /// Perform the server side of the DTLS handshake with the peer of the socket,
+        /// accepting only clients using `psk.identity` as the PSK identity
+        ///
+        /// `client_id` identifies the client - typically its address and port - and binds the
+        /// cookie of the `HelloVerifyRequest` to it, so that the server cannot be used to
+        /// flood spoofed addresses (RFC 6347, section 4.2.1).
+        ///
+        /// The socket must already be connected to the client, with the `ClientHello` of the
+        /// client still queued:
+        /// ```ignore
+        /// let socket = UdpSocket::bind("0.0.0.0:5684")?;
+        ///
+        /// let (_, client) = socket.peek_from(&mut [0; 1])?;
+        /// socket.connect(client)?;
+        ///
+        /// let session = EspDtls::accept(socket, &psk, client.to_string().as_bytes())?;
+        /// ```
+        ///
+        /// # Errors
+        ///
+        /// * `ESP_ERR_TIMEOUT` if the peer does not complete the handshake
+        /// * `ESP_FAIL` if the handshake fails
+        pub fn accept(socket: S, psk: &Psk<'_>, client_id: &[u8]) -> Result<Self, EspError> {
+            let mut this = Self::new(socket, psk, true)?;
+
+            this.set_client_id(client_id)?;
+            this.handshake(Some(client_id))?;
+
+            Ok(this)
+        }
+
+        fn new(socket: S, psk: &Psk<'_>, server: bool) -> Result<Self, EspError> {
+            let mut this = Self {
+                ssl: Box::new(unsafe { core::mem::zeroed() }),
+                conf: Box::new(unsafe { core::mem::zeroed() }),
+                cookie: None,
+                io: Box::new(DtlsIo {
+                    socket,
+                    timer_start: 0,
+                    timer_int: 0,
+                    timer_fin: 0,
+                }),
+            };
+
+            unsafe {
+                sys::mbedtls_ssl_init(&mut *this.ssl);
+                sys::mbedtls_ssl_config_init(&mut *this.conf);
+
+                check(sys::mbedtls_ssl_config_defaults(
+                    &mut *this.conf,
+                    if server {
+                        sys::MBEDTLS_SSL_IS_SERVER
+                    } else {
+                        sys::MBEDTLS_SSL_IS_CLIENT
+                    } as _,
+                    sys::MBEDTLS_SSL_TRANSPORT_DATAGRAM as _,
+                    sys::MBEDTLS_SSL_PRESET_DEFAULT as _,
+                ))?;
+
+                sys::mbedtls_ssl_conf_rng(&mut *this.conf, Some(rng), core::ptr::null_mut());
+
+                // The key and the identity are copied; a server without a PSK callback
+                // only accepts clients using the same identity
+                check(sys::mbedtls_ssl_conf_psk(
+                    &mut *this.conf,
+                    psk.key.as_ptr(),
+                    psk.key.len(),
+                    psk.identity.as_ptr(),
+                    psk.identity.len(),
+                ))?;
+
+                if server {
+                    let cookie = this
+                        .cookie
+                        .insert(Box::new(core::mem::zeroed::<sys::mbedtls_ssl_cookie_ctx>()));
+
+                    sys::mbedtls_ssl_cookie_init(&mut **cookie);
+
+                    check(sys::mbedtls_ssl_cookie_setup(
+                        &mut **cookie,
+                        Some(rng),
+                        core::ptr::null_mut(),
+                    ))?;
+
+                    sys::mbedtls_ssl_conf_dtls_cookies(
+                        &mut *this.conf,
+                        Some(sys::mbedtls_ssl_cookie_write),
+                        Some(sys::mbedtls_ssl_cookie_check),
+                        &mut **cookie as *mut sys::mbedtls_ssl_cookie_ctx as *mut c_void,
+                    );
+                }
+
+                check(sys::mbedtls_ssl_setup(&mut *this.ssl, &*this.conf))?;
+
+                let io = &mut *this.io as *mut DtlsIo<S> as *mut c_void;
+
+                sys::mbedtls_ssl_set_bio(
+                    &mut *this.ssl,
+                    io,
+                    Some(Self::bio_send),
+                    None,
+                    Some(Self::bio_recv_timeout),
+                );
+
+                sys::mbedtls_ssl_set_timer_cb(
+                    &mut *this.ssl,
+                    io,
+                    Some(Self::timer_set),
+                    Some(Self::timer_get),
+                );
+            }
+
+            Ok(this)
+        }
+
+        fn set_client_id(&mut self, client_id: &[u8]) -> Result<(), EspError> {
+            check(unsafe {
+                sys::mbedtls_ssl_set_client_transport_id(
+                    &mut *self.ssl,
+                    client_id.as_ptr(),
+                    client_id.len(),
+                )
+            })
+        }
+
+        fn handshake(&mut self, client_id: Option<&[u8]>) -> Result<(), EspError> {
+            loop {
+                match unsafe { sys::mbedtls_ssl_handshake(&mut *self.ssl) } {
+                    0 => return Ok(()),
+                    sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => (),
+                    sys::MBEDTLS_ERR_SSL_HELLO_VERIFY_REQUIRED if client_id.is_some() => {
+                        // The `HelloVerifyRequest` was sent, so start over and wait for
+                        // the client to repeat its `ClientHello`, this time with the cookie
+                        check(unsafe { sys::mbedtls_ssl_session_reset(&mut *self.ssl) })?;
+
+                        self.set_client_id(client_id.unwrap())?;
+                    }
+                    sys::MBEDTLS_ERR_SSL_TIMEOUT => {
+                        warn!("DTLS handshake timed out");
+
+                        return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                    }
+                    err => return check(err),
+                }
+            }
+        }
+
+        /// Send a datagram to the peer
+        pub fn send(&mut self, datagram: &[u8]) -> Result<(), EspError> {
+            loop {
+                let len = unsafe {
+                    sys::mbedtls_ssl_write(&mut *self.ssl, datagram.as_ptr(), datagram.len())
+                };
+
+                match len {
+                    sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => (),
+                    len if len < 0 => return check(len),
+                    _ => return Ok(()),
+                }
+            }
+        }
+
+        /// Wait for a datagram from the peer - forever if `timeout` is `None` - and return
+        /// its length, or `None` on timeout
+        pub fn recv(
+            &mut self,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> Result<Option<usize>, EspError> {
+            let timeout_ms = timeout
+                .map(|timeout| timeout.as_millis().clamp(1, u32::MAX as _) as u32)
+                .unwrap_or(0);
+
+            unsafe {
+                sys::mbedtls_ssl_conf_read_timeout(&mut *self.conf, timeout_ms);
+            }
+
+            loop {
+                let len =
+                    unsafe { sys::mbedtls_ssl_read(&mut *self.ssl, buf.as_mut_ptr(), buf.len()) };
+
+                match len {
+                    sys::MBEDTLS_ERR_SSL_WANT_READ | sys::MBEDTLS_ERR_SSL_WANT_WRITE => (),
+                    sys::MBEDTLS_ERR_SSL_TIMEOUT => return Ok(None),
+                    len if len < 0 => return check(len).map(|_| None),
+                    len => return Ok(Some(len as _)),
+                }
+            }
+        }
+
+        unsafe extern "C" fn bio_send(ctx: *mut c_void, buf: *const c_uchar, len: usize) -> c_int {
+            let io = (ctx as *mut DtlsIo<S>).as_mut().unwrap();
+
+            match io.socket.send(core::slice::from_raw_parts(buf, len)) {
+                Ok(()) => len as _,
+                Err(_) => sys::MBEDTLS_ERR_SSL_INTERNAL_ERROR,
+            }
+        }
+
+        unsafe extern "C" fn bio_recv_timeout(
+            ctx: *mut c_void,
+            buf: *mut c_uchar,
+            len: usize,
+            timeout: u32,
+        ) -> c_int {
+            let io = (ctx as *mut DtlsIo<S>).as_mut().unwrap();
+
+            let timeout = (timeout > 0).then(|| Duration::from_millis(timeout as _));
+
+            match io
+                .socket
+                .recv(core::slice::from_raw_parts_mut(buf, len), timeout)
+            {
+                Ok(Some(len)) => len as _,
+                Ok(None) => sys::MBEDTLS_ERR_SSL_TIMEOUT,
+                Err(_) => sys::MBEDTLS_ERR_SSL_INTERNAL_ERROR,
+            }
+        }
+
+        unsafe extern "C" fn timer_set(ctx: *mut c_void, int_ms: u32, fin_ms: u32) {
+            let io = (ctx as *mut DtlsIo<S>).as_mut().unwrap();
+
+            io.timer_start = sys::esp_timer_get_time();
+            io.timer_int = int_ms;
+            io.timer_fin = fin_ms;
+        }
+
+        unsafe extern "C" fn timer_get(ctx: *mut c_void) -> c_int {
+            let io = (ctx as *mut DtlsIo<S>).as_mut().unwrap();
+
+            if io.timer_fin == 0 {
+                return -1;
+            }
+
+            let elapsed_ms = (sys::esp_timer_get_time() - io.timer_start) / 1000;
+
+            if elapsed_ms >= io.timer_fin as i64 {
+                2
+            } else if elapsed_ms >= io.timer_int as i64 {
+                1
+            } else {
+                0
+            }
+        }
+    }
+
+    impl<S> Drop for EspDtls<S>
+    where
+        S: DatagramSocket,
+    {
+        fn drop(&mut self) {
+            unsafe {
+                sys::mbedtls_ssl_close_notify(&mut *self.ssl);
+                sys::mbedtls_ssl_free(&mut *self.ssl);
+                sys::mbedtls_ssl_config_free(&mut *self.conf);
+
+                if let Some(cookie) = self.cookie.as_mut() {
+                    sys::mbedtls_ssl_cookie_free(&mut **cookie);
+                }
+            }
+        }
+    }
+
+    unsafe impl<S> Send for EspDtls<S> where S: DatagramSocket + Send {}
+
+    unsafe extern "C" fn rng(_ctx: *mut c_void, buf: *mut c_uchar, len: usize) -> c_int {
+        sys::esp_fill_random(buf as *mut c_void, len as _);
+
+        0
+    }
+
+    fn check(err: c_int) -> Result<(), EspError> {
+        if err == 0 {
+            Ok(())
+        } else {
+            warn!("DTLS error: -0x{:04x}", -err);
+
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        }
+    }
+}
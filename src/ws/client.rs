@@ -1,12 +1,16 @@
 //! WebSocket client
 
+use core::ffi::CStr;
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{ffi, time};
 
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 
-use embedded_svc::ws::{ErrorType, Sender};
+use embedded_svc::ws::{asynch, ErrorType, Sender};
 
 use crate::hal::delay::TickType;
 
@@ -14,9 +18,13 @@ use crate::sys::*;
 
 use crate::handle::RawHandle;
 use crate::io::EspIOError;
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+use crate::io::Read;
 use crate::private::common::Newtype;
 use crate::private::cstr::RawCstrs;
 use crate::private::mutex::{Condvar, Mutex};
+use crate::private::unblocker::Unblocker;
+use crate::private::zerocopy::{Channel, Receiver};
 use crate::tls::X509;
 
 pub use embedded_svc::ws::{Final, Fragmented, FrameType};
@@ -206,6 +214,13 @@ pub struct EspWebSocketClientConfig<'a> {
     pub server_cert: Option<X509<'static>>,
     pub client_cert: Option<X509<'static>>,
     pub client_key: Option<X509<'static>>,
+    /// Reassemble the fragmented messages, as well as the frames exceeding `buffer_size`,
+    /// and deliver them as complete `Text`/`Binary` events of up to this size.
+    /// Larger messages are discarded.
+    ///
+    /// `None` (the default) delivers the frames in chunks, as received.
+    #[cfg(esp_idf_comp_espressif__esp_websocket_client_enabled)]
+    pub max_message_size: Option<usize>,
 }
 
 impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_config_t, RawCstrs) {
@@ -347,6 +362,14 @@ unsafe impl Send for Newtype<*mut esp_websocket_event_data_t> {}
 struct EspWebSocketConnectionState {
     message: Mutex<Option<(i32, Newtype<*mut esp_websocket_event_data_t>)>>,
     state_changed: Condvar,
+    // set (with `message` locked) once the `EspWebSocketConnection` is dropped
+    receiver_dropped: AtomicBool,
+}
+
+impl EspWebSocketConnectionState {
+    fn is_receiver_dropped(&self) -> bool {
+        self.receiver_dropped.load(Ordering::SeqCst)
+    }
 }
 
 impl Default for EspWebSocketConnectionState {
@@ -354,10 +377,16 @@ impl Default for EspWebSocketConnectionState {
         Self {
             message: Mutex::new(None),
             state_changed: Condvar::new(),
+            receiver_dropped: AtomicBool::new(false),
         }
     }
 }
 
+/// The events of an `EspWebSocketClient` created with `EspWebSocketClient::new_with_conn`
+///
+/// The client task waits for each event to be taken with `next` (and released by
+/// the following call), so the connection has to be polled continuously. Once it
+/// is dropped, the events are discarded instead.
 pub struct EspWebSocketConnection(Arc<EspWebSocketConnectionState>);
 
 impl EspWebSocketConnection {
@@ -388,6 +417,18 @@ impl EspWebSocketConnection {
     }
 }
 
+impl Drop for EspWebSocketConnection {
+    fn drop(&mut self) {
+        let mut message = self.0.message.lock();
+
+        // release the client task, should it wait for an event to be processed
+        self.0.receiver_dropped.store(true, Ordering::SeqCst);
+        *message = None;
+
+        self.0.state_changed.notify_all();
+    }
+}
+
 struct EspWebSocketPostbox(Arc<EspWebSocketConnectionState>);
 
 impl EspWebSocketPostbox {
@@ -395,12 +436,22 @@ impl EspWebSocketPostbox {
         let mut message = self.0.message.lock();
 
         // wait for a previous message to be processed
-        while message.is_some() {
+        while message.is_some() && !self.0.is_receiver_dropped() {
             message = self.0.state_changed.wait(message);
         }
 
+        // nobody is going to process the events anymore
+        if self.0.is_receiver_dropped() {
+            return;
+        }
+
         *message = Some((event_id, Newtype(event)));
         self.0.state_changed.notify_all();
+
+        // the event data is only valid during the callback, so wait for it to be processed
+        while message.is_some() && !self.0.is_receiver_dropped() {
+            message = self.0.state_changed.wait(message);
+        }
     }
 }
 
@@ -508,6 +559,12 @@ impl<'a> EspWebSocketClient<'a> {
         timeout: time::Duration,
         raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a>,
     ) -> Result<Self, EspIOError> {
+        #[cfg(esp_idf_comp_espressif__esp_websocket_client_enabled)]
+        let raw_callback = match config.max_message_size {
+            Some(max_message_size) => Reassembler::new(max_message_size).wrap(raw_callback),
+            None => raw_callback,
+        };
+
         let mut boxed_raw_callback = Box::new(raw_callback);
         let unsafe_callback = UnsafeCallback::from(&mut boxed_raw_callback);
 
@@ -568,6 +625,130 @@ impl<'a> EspWebSocketClient<'a> {
         Ok(())
     }
 
+    /// Sends a message with the data read from `read` until its end, in fragments of up to
+    /// `buf.len()` bytes, and returns the length of the message.
+    ///
+    /// `frame_type` must be `Text` or `Binary`. The fragments are sent as they are read,
+    /// followed by an empty final fragment, so the message is never buffered in full.
+    ///
+    /// If reading or sending fails after the first fragment was sent, the message cannot be
+    /// completed anymore, so the connection is closed.
+    ///
+    /// Requires version 1.2 or later of the `espressif/esp_websocket_client` component.
+    #[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+    pub fn send_stream<R>(
+        &mut self,
+        frame_type: FrameType,
+        read: &mut R,
+        buf: &mut [u8],
+    ) -> Result<usize, StreamError<R::Error>>
+    where
+        R: Read,
+    {
+        let text = match frame_type {
+            FrameType::Text(_) => true,
+            FrameType::Binary(_) => false,
+            _ => {
+                return Err(StreamError::Send(EspError::from_infallible::<
+                    ESP_ERR_INVALID_ARG,
+                >()))
+            }
+        };
+
+        if buf.is_empty() {
+            return Err(StreamError::Send(EspError::from_infallible::<
+                ESP_ERR_INVALID_ARG,
+            >()));
+        }
+
+        let mut sent = 0;
+
+        let result = self.send_fragments(text, read, buf, &mut sent);
+
+        if result.is_err() && sent > 0 {
+            // The peer would wait forever for the rest of the message
+            self.abort();
+        }
+
+        result
+    }
+
+    #[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+    fn send_fragments<R>(
+        &mut self,
+        text: bool,
+        read: &mut R,
+        buf: &mut [u8],
+        sent: &mut usize,
+    ) -> Result<usize, StreamError<R::Error>>
+    where
+        R: Read,
+    {
+        loop {
+            let len = read.read(buf).map_err(StreamError::Read)?;
+            if len == 0 {
+                break;
+            }
+
+            let content = buf.as_ptr();
+
+            Self::check(unsafe {
+                if *sent > 0 {
+                    esp_websocket_client_send_cont_msg(
+                        self.handle,
+                        content as _,
+                        len as _,
+                        self.timeout,
+                    )
+                } else if text {
+                    esp_websocket_client_send_text_partial(
+                        self.handle,
+                        content as _,
+                        len as _,
+                        self.timeout,
+                    )
+                } else {
+                    esp_websocket_client_send_bin_partial(
+                        self.handle,
+                        content as _,
+                        len as _,
+                        self.timeout,
+                    )
+                }
+            })
+            .map_err(StreamError::Send)?;
+
+            *sent += len;
+        }
+
+        if *sent > 0 {
+            Self::check(unsafe { esp_websocket_client_send_fin(self.handle, self.timeout) })
+                .map_err(StreamError::Send)?;
+        } else {
+            // Nothing was read, so send an empty, non-fragmented message
+            let frame_type = if text {
+                FrameType::Text(false)
+            } else {
+                FrameType::Binary(false)
+            };
+
+            self.send_data(frame_type, &[]).map_err(StreamError::Send)?;
+        }
+
+        Ok(*sent)
+    }
+
+    /// Close the connection in the middle of a fragmented message
+    #[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+    fn abort(&mut self) {
+        log::warn!("Sending a fragmented message failed, closing the connection");
+
+        // A close frame may be sent between the fragments; if even that fails, drop the connection
+        if esp!(unsafe { esp_websocket_client_close(self.handle, self.timeout) }).is_err() {
+            let _ = esp!(unsafe { esp_websocket_client_stop(self.handle) });
+        }
+    }
+
     pub fn is_connected(&self) -> bool {
         unsafe { esp_websocket_client_is_connected(self.handle) }
     }
@@ -649,3 +830,420 @@ impl Sender for EspWebSocketClient<'_> {
 }
 
 unsafe impl Send for EspWebSocketClient<'_> {}
+
+/// The error of `EspWebSocketClient::send_stream`
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StreamError<E> {
+    /// Reading the data to send failed
+    Read(E),
+    /// Sending a fragment failed
+    Send(EspError),
+}
+
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+impl<E> fmt::Display for StreamError<E>
+where
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "Read error: {e:?}"),
+            Self::Send(e) => write!(f, "Send error: {e}"),
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_espressif__esp_websocket_client_partial_send
+))]
+impl<E> std::error::Error for StreamError<E> where E: fmt::Debug {}
+
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_partial_send)]
+impl<E> crate::io::Error for StreamError<E>
+where
+    E: crate::io::Error,
+{
+    fn kind(&self) -> crate::io::ErrorKind {
+        match self {
+            Self::Read(e) => e.kind(),
+            Self::Send(_) => crate::io::ErrorKind::Other,
+        }
+    }
+}
+
+/// The outcome of feeding a received data chunk to the `Reassembler`
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_enabled)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Reassembly {
+    /// Not part of a larger message, deliver the chunk as is
+    Forward,
+    /// Part of a message which is not complete yet, or which is discarded
+    Pending,
+    /// The message with this op code is complete and available in the buffer
+    Complete(u8),
+}
+
+/// Reassembles the chunks of the data events into complete messages
+///
+/// The native client splits the frames which exceed its buffer into multiple data events
+/// with increasing payload offsets, and the fragments of a message arrive as continuation
+/// frames, with the FIN flag set on the last one. Control frames may be interleaved.
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_enabled)]
+struct Reassembler {
+    max_size: usize,
+    op_code: Option<u8>,
+    buf: alloc::vec::Vec<u8>,
+    overflow: bool,
+}
+
+#[cfg(esp_idf_comp_espressif__esp_websocket_client_enabled)]
+impl Reassembler {
+    const fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            op_code: None,
+            buf: alloc::vec::Vec::new(),
+            overflow: false,
+        }
+    }
+
+    fn wrap<'a>(
+        mut self,
+        mut raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a>,
+    ) -> Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a> {
+        Box::new(move |event_id, event_data| {
+            #[allow(non_upper_case_globals)]
+            match event_id {
+                esp_websocket_event_id_t_WEBSOCKET_EVENT_DATA => {}
+                esp_websocket_event_id_t_WEBSOCKET_EVENT_DISCONNECTED
+                | esp_websocket_event_id_t_WEBSOCKET_EVENT_CLOSED => {
+                    self.reset();
+                    return raw_callback(event_id, event_data);
+                }
+                _ => return raw_callback(event_id, event_data),
+            }
+
+            let Some(event) = (unsafe { event_data.as_ref() }) else {
+                return raw_callback(event_id, event_data);
+            };
+
+            let data = if event.data_len > 0 {
+                unsafe {
+                    core::slice::from_raw_parts(
+                        event.data_ptr as *const u8,
+                        event.data_len as usize,
+                    )
+                }
+            } else {
+                &[]
+            };
+
+            let frame_start = event.payload_offset <= 0;
+            let frame_end = event.payload_offset + event.data_len >= event.payload_len;
+
+            match self.push(event.op_code, event.fin, frame_start, frame_end, data) {
+                Reassembly::Forward => raw_callback(event_id, event_data),
+                Reassembly::Pending => (),
+                Reassembly::Complete(op_code) => {
+                    let message_data = core::mem::take(&mut self.buf);
+
+                    let mut message = *event;
+                    message.op_code = op_code;
+                    message.fin = true;
+                    message.data_ptr = message_data.as_ptr() as _;
+                    message.data_len = message_data.len() as _;
+                    message.payload_len = message_data.len() as _;
+                    message.payload_offset = 0;
+
+                    raw_callback(event_id, &mut message);
+                }
+            }
+        })
+    }
+
+    fn push(
+        &mut self,
+        op_code: u8,
+        fin: bool,
+        frame_start: bool,
+        frame_end: bool,
+        data: &[u8],
+    ) -> Reassembly {
+        let message_start = match op_code {
+            // Continuation frame
+            0 => false,
+            // Text and binary frames
+            1 | 2 => frame_start,
+            // Control frames are never fragmented
+            _ => return Reassembly::Forward,
+        };
+
+        if message_start {
+            if self.op_code.is_some() {
+                log::warn!("Incomplete WebSocket message discarded");
+            }
+
+            self.reset();
+
+            if fin && frame_end && data.len() <= self.max_size {
+                return Reassembly::Forward;
+            }
+
+            self.op_code = Some(op_code);
+        } else if self.op_code.is_none() {
+            // A chunk of a message whose start was missed
+            return Reassembly::Pending;
+        }
+
+        if !self.overflow {
+            if self.buf.len() + data.len() > self.max_size {
+                log::warn!(
+                    "WebSocket message exceeds {} bytes, discarding",
+                    self.max_size
+                );
+
+                self.overflow = true;
+                self.buf = alloc::vec::Vec::new();
+            } else {
+                self.buf.extend_from_slice(data);
+            }
+        }
+
+        if !(fin && frame_end) {
+            return Reassembly::Pending;
+        }
+
+        let op_code = self.op_code.take().unwrap();
+
+        if core::mem::take(&mut self.overflow) {
+            Reassembly::Pending
+        } else {
+            Reassembly::Complete(op_code)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.op_code = None;
+        self.buf.clear();
+        self.overflow = false;
+    }
+}
+
+enum AsyncCommand {
+    None,
+    Send(FrameType),
+}
+
+struct AsyncWork {
+    command: AsyncCommand,
+    frame_data: alloc::vec::Vec<u8>,
+    result: Result<(), EspError>,
+}
+
+/// An async WebSocket client
+///
+/// The frames are sent by a dedicated task wrapping an `EspWebSocketClient`, and the
+/// events of the client are received with the `EspAsyncWebSocketConnection` returned
+/// together with the client.
+pub struct EspAsyncWebSocketClient {
+    unblocker: Unblocker<AsyncWork>,
+    handle: esp_websocket_client_handle_t,
+}
+
+impl EspAsyncWebSocketClient {
+    pub fn new(
+        uri: &str,
+        config: &EspWebSocketClientConfig,
+        timeout: time::Duration,
+    ) -> Result<(Self, EspAsyncWebSocketConnection), EspIOError> {
+        Self::new_with_stack_size(uri, config, timeout, 4096)
+    }
+
+    /// Same as `new`, with a custom stack size of the sending task.
+    pub fn new_with_stack_size(
+        uri: &str,
+        config: &EspWebSocketClientConfig,
+        timeout: time::Duration,
+        stack_size: usize,
+    ) -> Result<(Self, EspAsyncWebSocketConnection), EspIOError> {
+        let (channel, receiver) = Channel::new();
+
+        let connection = EspAsyncWebSocketConnection {
+            receiver,
+            given: false,
+        };
+
+        let client = EspWebSocketClient::new_raw(
+            uri,
+            config,
+            timeout,
+            Box::new(move |event_id, event_handle| {
+                let mut event =
+                    WebSocketEvent::new(event_id, unsafe { event_handle.as_ref().unwrap() }, None);
+
+                let event: &mut Result<WebSocketEvent<'static>, EspIOError> =
+                    unsafe { core::mem::transmute(&mut event) };
+
+                channel.share(event);
+            }),
+        )?;
+
+        let handle = client.handle;
+
+        let unblocker = Unblocker::new(
+            CStr::from_bytes_until_nul(b"WS sending task\0").unwrap(),
+            stack_size,
+            None,
+            None,
+            move |channel| Self::work(channel, client),
+        )?;
+
+        Ok((Self { unblocker, handle }, connection))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        unsafe { esp_websocket_client_is_connected(self.handle) }
+    }
+
+    /// Sends a frame.
+    ///
+    /// As with `EspWebSocketClient::send`, only non-fragmented text and binary frames are supported;
+    /// `ESP_ERR_NOT_SUPPORTED` is returned for all other frame types.
+    pub async fn send(&mut self, frame_type: FrameType, frame_data: &[u8]) -> Result<(), EspError> {
+        if !matches!(
+            frame_type,
+            FrameType::Binary(false) | FrameType::Text(false)
+        ) {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        let work = self.unblocker.exec_in_out().await.unwrap();
+
+        work.command = AsyncCommand::Send(frame_type);
+        work.frame_data.clear();
+        work.frame_data.extend_from_slice(frame_data);
+
+        self.unblocker.do_exec().await;
+
+        let work = self.unblocker.exec_in_out().await.unwrap();
+
+        work.result
+    }
+
+    fn work(channel: Arc<Channel<AsyncWork>>, mut client: EspWebSocketClient<'static>) {
+        let mut work = AsyncWork {
+            command: AsyncCommand::None,
+            frame_data: alloc::vec::Vec::new(),
+            result: Ok(()),
+        };
+
+        while channel.share(&mut work) {
+            match work.command {
+                AsyncCommand::None => {}
+                AsyncCommand::Send(frame_type) => {
+                    work.result = client.send(frame_type, &work.frame_data);
+                }
+            }
+
+            work.command = AsyncCommand::None;
+        }
+    }
+}
+
+unsafe impl Send for EspAsyncWebSocketClient {}
+
+impl RawHandle for EspAsyncWebSocketClient {
+    type Handle = esp_websocket_client_handle_t;
+
+    fn handle(&self) -> Self::Handle {
+        self.handle
+    }
+}
+
+impl ErrorType for EspAsyncWebSocketClient {
+    type Error = EspIOError;
+}
+
+impl asynch::Sender for EspAsyncWebSocketClient {
+    async fn send(&mut self, frame_type: FrameType, frame_data: &[u8]) -> Result<(), Self::Error> {
+        EspAsyncWebSocketClient::send(self, frame_type, frame_data)
+            .await
+            .map_err(EspIOError)
+    }
+}
+
+/// The stream of the events of an `EspAsyncWebSocketClient`
+pub struct EspAsyncWebSocketConnection {
+    receiver: Receiver<Result<WebSocketEvent<'static>, EspIOError>>,
+    given: bool,
+}
+
+impl EspAsyncWebSocketConnection {
+    /// Waits for the next event of the client.
+    ///
+    /// The client task is blocked until the returned event is released, i.e. until the next
+    /// call to `next` or `recv`, so the event should be processed promptly.
+    /// Returns `ESP_ERR_INVALID_STATE` once the client is dropped.
+    pub async fn next(&mut self) -> Result<&WebSocketEvent<'_>, EspIOError> {
+        if self.given {
+            self.receiver.done();
+        }
+
+        if let Some(event) = self.receiver.get_shared_async().await {
+            self.given = true;
+
+            event.as_ref().map_err(|e| EspIOError(e.0))
+        } else {
+            self.given = false;
+
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>().into())
+        }
+    }
+
+    /// Receives the next frame, skipping the events which are not frames.
+    ///
+    /// As with the server `EspHttpWsConnection::recv`, if the buffer is too small for the frame,
+    /// only the frame type and length are returned and the frame is not consumed.
+    /// A disconnection of the client is reported as `FrameType::SocketClose`.
+    pub async fn recv(
+        &mut self,
+        frame_data_buf: &mut [u8],
+    ) -> Result<(FrameType, usize), EspIOError> {
+        loop {
+            let (frame_type, data) = match &self.next().await?.event_type {
+                WebSocketEventType::Text(text) => (FrameType::Text(false), text.as_bytes()),
+                WebSocketEventType::Binary(data) => (FrameType::Binary(false), *data),
+                WebSocketEventType::Ping => (FrameType::Ping, &[][..]),
+                WebSocketEventType::Pong => (FrameType::Pong, &[][..]),
+                WebSocketEventType::Close(_) => (FrameType::Close, &[][..]),
+                WebSocketEventType::Disconnected | WebSocketEventType::Closed => {
+                    (FrameType::SocketClose, &[][..])
+                }
+                WebSocketEventType::BeforeConnect | WebSocketEventType::Connected => continue,
+            };
+
+            let len = data.len();
+
+            if frame_data_buf.len() >= len {
+                frame_data_buf[..len].copy_from_slice(data);
+            } else {
+                // Keep the event, so that it is returned again by the next call
+                self.given = false;
+            }
+
+            return Ok((frame_type, len));
+        }
+    }
+}
+
+impl ErrorType for EspAsyncWebSocketConnection {
+    type Error = EspIOError;
+}
+
+impl asynch::Receiver for EspAsyncWebSocketConnection {
+    async fn recv(&mut self, frame_data_buf: &mut [u8]) -> Result<(FrameType, usize), Self::Error> {
+        EspAsyncWebSocketConnection::recv(self, frame_data_buf).await
+    }
+}